@@ -0,0 +1,46 @@
+"""Builds resources/cities.tsv from a GeoNames dump.
+
+Download cities15000.zip, admin1CodesASCII.txt and countryInfo.txt from
+https://download.geonames.org/export/dump/, unzip cities15000.zip next to them and run
+
+    python geonames.py <dump folder>
+"""
+import os
+import sys
+
+folder = sys.argv[1] if len(sys.argv) > 1 else '.'
+
+countries = {}
+with open(os.path.join(folder, 'countryInfo.txt'), encoding='utf-8') as f:
+    for line in f:
+        if line.startswith('#'):
+            continue
+        fields = line.rstrip('\n').split('\t')
+        countries[fields[0]] = fields[4]
+
+regions = {}
+with open(os.path.join(folder, 'admin1CodesASCII.txt'), encoding='utf-8') as f:
+    for line in f:
+        code, name = line.rstrip('\n').split('\t')[:2]
+        regions[code] = name
+
+rows = []
+with open(os.path.join(folder, 'cities15000.txt'), encoding='utf-8') as f:
+    for line in f:
+        fields = line.rstrip('\n').split('\t')
+        name, latitude, longitude = fields[1], fields[4], fields[5]
+        country, admin1 = fields[8], fields[10]
+        rows.append((
+            name,
+            regions.get(f'{country}.{admin1}', ''),
+            countries.get(country, ''),
+            f'{float(latitude):.4f}',
+            f'{float(longitude):.4f}',
+        ))
+
+with open(os.path.join(os.path.dirname(__file__), 'resources', 'cities.tsv'), 'w', encoding='utf-8') as f:
+    f.write('# Cities for offline reverse geocoding: name, region, country, latitude, longitude.\n')
+    f.write('# Derived from GeoNames (https://www.geonames.org), licensed CC BY 4.0.\n')
+    for row in rows:
+        f.write('\t'.join(row) + '\n')
+print(f'Wrote {len(rows)} cities')
@@ -0,0 +1,395 @@
+use crate::database::Database;
+use crate::migrations;
+use crate::pool::DbPool;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, DatabaseName};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Layout version of the archive itself, checked before anything is restored.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const DATABASE: &str = "siegu.db";
+const FACES: &str = "faces";
+
+/// Stored first in a library archive, describing what the rest of it holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// `PRAGMA user_version` of the database snapshot.
+    pub schema_version: u32,
+    /// Unix seconds.
+    pub created: u64,
+    /// Authorized folders at export time, which photo paths can be remapped from.
+    pub library_roots: Vec<String>,
+    /// Folder the face crops were kept in, which their stored paths start with.
+    pub faces_dir: String,
+    pub files: Vec<ArchivedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    /// Path inside the archive, `siegu.db` or `faces/<crop>.jpg`.
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 of the contents, hex encoded.
+    pub sha256: String,
+}
+
+/// Writes the library to a `.tar.gz` archive at `archive`: a snapshot of the database taken
+/// with SQLite's online backup, so the app keeps working meanwhile, and the face crops.
+/// Photos themselves are not included; they stay in their folders.
+pub fn export(db: &DbPool, config_path: &str, archive: &Path) -> Result<Manifest, String> {
+    let staging = staging_dir(config_path, "backup_tmp")?;
+    let result = write_archive(db, config_path, &staging, archive);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn write_archive(
+    db: &DbPool,
+    config_path: &str,
+    staging: &Path,
+    archive: &Path,
+) -> Result<Manifest, String> {
+    let snapshot = staging.join(DATABASE);
+    // Copied in a single step, so in one read transaction; copied a few pages at a time,
+    // the backup would start over whenever the writer commits, which it does all the time
+    // during a scan
+    let mut copy = Connection::open(&snapshot).map_err(|e| e.to_string())?;
+    let copied = db.read(|db| Backup::new(&db.connection, &mut copy)?.step(-1));
+    match copied {
+        Ok(StepResult::Done) => {}
+        Ok(_) => return Err("Failed to snapshot the database: it is locked".to_string()),
+        Err(e) => return Err(format!("Failed to snapshot the database: {e}")),
+    }
+    drop(copy);
+
+    let (schema_version, library_roots) = {
+        let conn = Connection::open(&snapshot).map_err(|e| e.to_string())?;
+        let version = migrations::current_version(&conn).map_err(|e| e.to_string())?;
+        let roots = conn
+            .prepare("SELECT name FROM directory ORDER BY name")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()
+            })
+            .map_err(|e| e.to_string())?;
+        (version, roots)
+    };
+
+    let faces_dir = Path::new(config_path).join(FACES);
+    let mut sources = vec![(DATABASE.to_string(), snapshot)];
+    if let Ok(entries) = fs::read_dir(&faces_dir) {
+        let mut crops: Vec<PathBuf> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect();
+        crops.sort();
+        for crop in crops {
+            let name = crop.file_name().unwrap_or_default().to_string_lossy();
+            sources.push((format!("{FACES}/{name}"), crop.clone()));
+        }
+    }
+
+    let mut files = Vec::with_capacity(sources.len());
+    for (path, source) in &sources {
+        let (size, sha256) =
+            checksum(source).map_err(|e| format!("Failed to read {}: {e}", source.display()))?;
+        files.push(ArchivedFile {
+            path: path.clone(),
+            size,
+            sha256,
+        });
+    }
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        schema_version,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        library_roots,
+        faces_dir: faces_dir.to_string_lossy().to_string(),
+        files,
+    };
+
+    // Written next to the target first, so a failed export never leaves a truncated archive
+    let partial = archive.with_extension("partial");
+    let write = || -> io::Result<()> {
+        let mut tar = tar::Builder::new(GzEncoder::new(
+            File::create(&partial)?,
+            Compression::default(),
+        ));
+        let json = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created);
+        tar.append_data(&mut header, MANIFEST, json.as_slice())?;
+        for (path, source) in &sources {
+            tar.append_path_with_name(source, path)?;
+        }
+        tar.into_inner()?.finish()?;
+        fs::rename(&partial, archive)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&partial);
+        format!("Failed to write {}: {e}", archive.display())
+    })?;
+    Ok(manifest)
+}
+
+/// Replaces the library with the one in `archive`. Every file is checked against the
+/// manifest before anything is touched, an older schema is migrated, and photo paths under
+/// each key of `roots` are moved to its value for libraries whose folders have moved.
+pub fn import(
+    db: &DbPool,
+    config_path: &str,
+    archive: &Path,
+    roots: &HashMap<String, String>,
+) -> Result<Manifest, String> {
+    let staging = staging_dir(config_path, "restore_tmp")?;
+    let result = restore(db, config_path, &staging, archive, roots);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn restore(
+    db: &DbPool,
+    config_path: &str,
+    staging: &Path,
+    archive: &Path,
+    roots: &HashMap<String, String>,
+) -> Result<Manifest, String> {
+    let manifest = unpack(staging, archive)?;
+    let staging_path = staging.to_string_lossy().to_string();
+
+    // The unpacked copy gets a connection of its own, being another file than the library.
+    // Database::new runs any migrations the archived schema is missing.
+    {
+        let mut staged = Database::new(&staging_path);
+        for (from, to) in roots {
+            staged.remap_paths(from, to)?;
+        }
+        let faces_dir = Path::new(config_path).join(FACES);
+        staged.remap_face_crops(&manifest.faces_dir, &faces_dir.to_string_lossy());
+    }
+
+    // SQLite's backup cannot write into a connection that has a transaction open
+    let snapshot = staging.join(DATABASE);
+    db.write_alone(move |live| {
+        live.connection.restore(
+            DatabaseName::Main,
+            snapshot,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        crate::migrations::rebuild_search_index(&live.connection)
+    })
+    .and_then(|restored| restored.map_err(|e| e.to_string()))
+    .map_err(|e| format!("Failed to restore the database: {e}"))?;
+
+    let faces_dir = Path::new(config_path).join(FACES);
+    fs::create_dir_all(&faces_dir).map_err(|e| e.to_string())?;
+    if let Ok(entries) = fs::read_dir(staging.join(FACES)) {
+        for entry in entries.flatten() {
+            let _ = fs::copy(entry.path(), faces_dir.join(entry.file_name()));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Extracts the archive into `staging`, rejecting it unless it holds exactly the files its
+/// manifest lists, with matching checksums, and was written by a version this build reads.
+fn unpack(staging: &Path, archive: &Path) -> Result<Manifest, String> {
+    let file = File::open(archive).map_err(|e| format!("Failed to open archive: {e}"))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let mut entries = tar
+        .entries()
+        .map_err(|e| format!("Not a library archive: {e}"))?;
+
+    let mut manifest = match entries.next() {
+        Some(Ok(mut entry)) if entry.path().is_ok_and(|p| p == Path::new(MANIFEST)) => {
+            let mut json = String::new();
+            entry
+                .read_to_string(&mut json)
+                .map_err(|e| format!("Not a library archive: {e}"))?;
+            serde_json::from_str::<Manifest>(&json)
+                .map_err(|e| format!("Invalid archive manifest: {e}"))?
+        }
+        _ => return Err("Not a library archive: manifest is missing".to_string()),
+    };
+    if manifest.format_version > FORMAT_VERSION
+        || manifest.schema_version > migrations::latest_version()
+    {
+        return Err("The archive was made by a newer version of Siegu".to_string());
+    }
+
+    let mut expected: HashMap<String, ArchivedFile> = manifest
+        .files
+        .iter()
+        .map(|f| (f.path.clone(), f.clone()))
+        .collect();
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Corrupt archive: {e}"))?;
+        let path = entry
+            .path()
+            .map_err(|e| format!("Corrupt archive: {e}"))?
+            .to_string_lossy()
+            .to_string();
+        let Some(file) = expected.remove(&path) else {
+            return Err(format!("Unexpected file in archive: {path}"));
+        };
+        if !Path::new(&path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("Unsafe path in archive: {path}"));
+        }
+        let target = staging.join(&path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        io::copy(
+            &mut entry,
+            &mut File::create(&target).map_err(|e| e.to_string())?,
+        )
+        .map_err(|e| format!("Corrupt archive: {e}"))?;
+        let (size, sha256) = checksum(&target).map_err(|e| e.to_string())?;
+        if size != file.size || sha256 != file.sha256 {
+            return Err(format!("Checksum mismatch for {path}"));
+        }
+    }
+    if let Some(path) = expected.keys().next() {
+        return Err(format!("Archive is missing {path}"));
+    }
+    if !manifest.files.iter().any(|f| f.path == DATABASE) {
+        return Err("Archive holds no database".to_string());
+    }
+    manifest.files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(manifest)
+}
+
+/// An empty working folder under the config directory, on the same drive as the library.
+fn staging_dir(config_path: &str, name: &str) -> Result<PathBuf, String> {
+    let dir = Path::new(config_path).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    Ok(dir)
+}
+
+fn checksum(path: &Path) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let source = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_str().unwrap().to_string();
+        let pool = DbPool::open(&source_path);
+        pool.write(|db| {
+            db.add_directory("/old/Pictures");
+            db.import_photo(
+                "a",
+                "/old/Pictures/2023/a.jpg",
+                "",
+                None,
+                None,
+                "[]",
+                "[]",
+                "",
+            );
+            db.import_photo("b", "/elsewhere/b.jpg", "", None, None, "[]", "[]", "");
+        })
+        .unwrap();
+        fs::create_dir_all(source.path().join(FACES)).unwrap();
+        fs::write(source.path().join(FACES).join("a_face.jpg"), b"crop").unwrap();
+
+        let archive = source.path().join("library.tar.gz");
+        let manifest = export(&pool, &source_path, &archive).unwrap();
+        assert_eq!(manifest.schema_version, migrations::latest_version());
+        assert_eq!(manifest.library_roots, vec!["/old/Pictures".to_string()]);
+        assert_eq!(manifest.files.len(), 2);
+
+        let target = tempfile::tempdir().unwrap();
+        let target_path = target.path().to_str().unwrap().to_string();
+        let live = DbPool::open(&target_path);
+        live.write(|db| db.add_directory("/to/be/replaced"))
+            .unwrap();
+        let roots = HashMap::from([("/old/Pictures".to_string(), "/new/Pictures/".to_string())]);
+        import(&live, &target_path, &archive, &roots).unwrap();
+
+        // Connections opened before the restore see the restored library
+        assert_eq!(
+            live.read(|db| db.list_directories()),
+            vec!["/new/Pictures".to_string()]
+        );
+        assert_eq!(
+            live.read(|db| db.get_photo("a")).unwrap().location,
+            "/new/Pictures/2023/a.jpg"
+        );
+        assert_eq!(
+            live.read(|db| db.get_photo("b")).unwrap().location,
+            "/elsewhere/b.jpg"
+        );
+        assert!(target.path().join(FACES).join("a_face.jpg").exists());
+        assert!(!target.path().join("restore_tmp").exists());
+    }
+
+    #[test]
+    fn tampered_archive_is_rejected() {
+        let source = tempfile::tempdir().unwrap();
+        let source_path = source.path().to_str().unwrap().to_string();
+        let pool = DbPool::open(&source_path);
+        pool.write(|db| db.add_directory("/photos")).unwrap();
+        let archive = source.path().join("library.tar.gz");
+        let mut manifest = export(&pool, &source_path, &archive).unwrap();
+
+        // Re-pack the same files under a manifest whose checksum no longer matches
+        manifest.files[0].sha256 = "0".repeat(64);
+        let staging = source.path().join("unpacked");
+        fs::create_dir_all(&staging).unwrap();
+        tar::Archive::new(GzDecoder::new(File::open(&archive).unwrap()))
+            .unpack(&staging)
+            .unwrap();
+        let tampered = source.path().join("tampered.tar.gz");
+        let mut tar = tar::Builder::new(GzEncoder::new(
+            File::create(&tampered).unwrap(),
+            Compression::default(),
+        ));
+        let json = serde_json::to_vec(&manifest).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        tar.append_data(&mut header, MANIFEST, json.as_slice())
+            .unwrap();
+        tar.append_path_with_name(staging.join(DATABASE), DATABASE)
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target_path = target.path().to_str().unwrap();
+        let live = DbPool::open(target_path);
+        live.write(|db| db.add_directory("/kept")).unwrap();
+        let error = import(&live, target_path, &tampered, &HashMap::new()).unwrap_err();
+        assert_eq!(error, format!("Checksum mismatch for {DATABASE}"));
+        assert_eq!(
+            live.read(|db| db.list_directories()),
+            vec!["/kept".to_string()]
+        );
+    }
+}
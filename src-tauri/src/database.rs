@@ -5,6 +5,14 @@ use std::{
 
 use rusqlite::Connection;
 use serde::Serialize;
+use tracing::error;
+
+use crate::metadata::{Facet, MediaInfo};
+use crate::migrations;
+use crate::query::{self, Filter, Query, QueryError, Range};
+use crate::reconcile::Fingerprint;
+use crate::smart_album::Rule;
+use crate::xmp::{Region, Sidecar, Xmp};
 
 pub struct Database {
     pub connection: Connection,
@@ -18,6 +26,12 @@ pub struct PhotoSyncInfo {
     pub longitude: Option<f64>,
     pub objects: String, // JSON array of {class, probability}
     pub faces: String,   // JSON array of {face_id, crop_path, encoded, person_id}
+    #[serde(default)]
+    pub hash: Option<String>, // Missing when the peer runs a build without content hashing
+    #[serde(default)]
+    pub albums: String, // JSON array of {id, name, description, position}
+    #[serde(default)]
+    pub culling: Culling,
 }
 
 #[derive(Debug, Clone, Serialize, serde::Deserialize)]
@@ -34,6 +48,48 @@ pub struct SyncFace {
     pub person_id: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct SyncAlbum {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub position: i64,
+    /// When the photo joined the album; missing from older peers and for memberships made
+    /// before it was recorded.
+    #[serde(default)]
+    pub added: Option<String>,
+}
+
+/// An album, or a photo's place in one when `photo_id` is set, that was removed and when,
+/// sent to peers so they remove it too unless they added it since.
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct AlbumRemoval {
+    pub album_id: String,
+    pub photo_id: Option<String>,
+    pub hash: Option<String>,
+    pub removed: String,
+}
+
+/// The albums a photo is in, as sent to peers alongside the manifest, matched by id or
+/// content hash.
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct PhotoAlbums {
+    pub id: String,
+    pub hash: Option<String>,
+    pub albums: Vec<SyncAlbum>,
+}
+
+/// What peers that send no album list carry in their manifest.
+impl From<&PhotoSyncInfo> for PhotoAlbums {
+    fn from(photo: &PhotoSyncInfo) -> Self {
+        Self {
+            id: photo.id.clone(),
+            hash: photo.hash.clone(),
+            albums: serde_json::from_str(&photo.albums).unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct FaceWithPerson {
     pub photo_id: String,
@@ -42,6 +98,7 @@ pub struct FaceWithPerson {
     pub encoded: String,
     pub person_id: Option<String>,
     pub person_name: Option<String>,
+    pub area: Option<FaceArea>,
 }
 
 impl Database {
@@ -49,9 +106,10 @@ impl Database {
         let mut results = Vec::new();
         // Only select photos that have been indexed (have at least one entry in object or faces table)
         // AND are NOT inside a 'siegu' folder (to prevent re-syncing synced files)
-        let sql = "SELECT id, location, created, latitude, longitude FROM photo p 
+        let sql = "SELECT id, location, created, latitude, longitude, hash, rating, flag, label, culled FROM photo p 
                    WHERE (EXISTS (SELECT 1 FROM object WHERE photo_id = p.id) 
                    OR EXISTS (SELECT 1 FROM faces WHERE photo_id = p.id))
+                   AND p.trashed IS NULL
                    AND p.location NOT LIKE '%/siegu/%'
                    AND p.location NOT LIKE '%\\siegu\\%'";
         if let Ok(mut stmt) = self.connection.prepare(sql) {
@@ -95,6 +153,7 @@ impl Database {
                     }
                 }
 
+                let albums = self.album_sync_json(&id);
                 Ok(PhotoSyncInfo {
                     id,
                     location: row.get(1)?,
@@ -103,6 +162,9 @@ impl Database {
                     longitude: row.get(4).ok(),
                     objects: serde_json::to_string(&objects).unwrap_or("[]".to_string()),
                     faces: serde_json::to_string(&faces).unwrap_or("[]".to_string()),
+                    hash: row.get(5).unwrap_or_default(),
+                    albums,
+                    culling: culling_from_row(row, 6)?,
                 })
             });
             if let Ok(iter) = iter {
@@ -115,7 +177,7 @@ impl Database {
     }
 
     pub fn get_photo_sync_info_by_id(&self, photo_id: &str) -> Result<PhotoSyncInfo, String> {
-        let sql = "SELECT id, location, created, latitude, longitude FROM photo WHERE id = ?1";
+        let sql = "SELECT id, location, created, latitude, longitude, hash, rating, flag, label, culled FROM photo WHERE id = ?1";
         self.connection
             .query_row(sql, [photo_id], |row| {
                 let id: String = row.get(0)?;
@@ -157,6 +219,7 @@ impl Database {
                     }
                 }
 
+                let albums = self.album_sync_json(&id);
                 Ok(PhotoSyncInfo {
                     id,
                     location: row.get(1)?,
@@ -165,67 +228,32 @@ impl Database {
                     longitude: row.get(4).ok(),
                     objects: serde_json::to_string(&objects).unwrap_or("[]".to_string()),
                     faces: serde_json::to_string(&faces).unwrap_or("[]".to_string()),
+                    hash: row.get(5).unwrap_or_default(),
+                    albums,
+                    culling: culling_from_row(row, 6)?,
                 })
             })
             .map_err(|e| e.to_string())
     }
 
+    /// Opens the database and brings its schema up to date.
     pub fn new(config_path: &str) -> Self {
+        let mut database = Self::open(config_path);
+        if let Err(e) = migrations::run(&mut database.connection) {
+            error!("Database migration failed: {e}");
+        }
+        database
+    }
+
+    /// Opens another connection to a database whose schema is already up to date.
+    pub fn open(config_path: &str) -> Self {
         let path = format!("{config_path}/siegu.db");
         let _ = fs::create_dir_all(config_path);
         let conn = Connection::open(&path).expect("Failed to open database connection");
 
         // Enable WAL mode for better concurrency and set a busy timeout
-        let _ = conn.execute("PRAGMA journal_mode=WAL;", ());
         let _ = conn.busy_timeout(std::time::Duration::from_secs(5));
-
-        let _ = conn.execute("CREATE TABLE IF NOT EXISTS photo (id STRING PRIMARY KEY, location STRING, encoded STRING, created DATE_TIME, latitude REAL, longitude REAL, indexed INTEGER DEFAULT 0);", ());
-
-        // Simple migration: try to add columns if they don't exist (ignore errors if they do)
-        let _ = conn.execute("ALTER TABLE photo ADD COLUMN latitude REAL;", ());
-        let _ = conn.execute("ALTER TABLE photo ADD COLUMN longitude REAL;", ());
-        let _ = conn.execute("ALTER TABLE photo ADD COLUMN created DATE_TIME;", ());
-        let _ = conn.execute(
-            "ALTER TABLE photo ADD COLUMN sync_needed INTEGER DEFAULT 0;",
-            (),
-        );
-        let _ = conn.execute(
-            "ALTER TABLE photo ADD COLUMN indexed INTEGER DEFAULT 0;",
-            (),
-        );
-
-        let _ = conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_photo_location ON photo(location);",
-            (),
-        );
-        let _ = conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_photo_created ON photo(created);",
-            (),
-        );
-        let _ = conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_photo_indexed ON photo(indexed);",
-            (),
-        );
-        let _ = conn.execute("CREATE TABLE IF NOT EXISTS directory (name STRING);", ());
-        let _ = conn.execute(
-            "CREATE TABLE IF NOT EXISTS object(photo_id STRING, class STRING, probability STRING);",
-            (),
-        );
-        let _ = conn.execute(
-            "CREATE TABLE IF NOT EXISTS properties (photo_id STRING, key STRING, value STRING);",
-            (),
-        );
-        let _ = conn.execute(
-            "CREATE TABLE IF NOT EXISTS device(ip STRING, name STRING, offer STRING);",
-            (),
-        );
-        let _ = conn.execute("CREATE TABLE IF NOT EXISTS faces (photo_id STRING, face_id STRING PRIMARY KEY, crop_path STRING, encoded STRING, embedding BLOB, person_id STRING);", ());
-        let _ = conn.execute("CREATE TABLE IF NOT EXISTS people (id STRING PRIMARY KEY, name STRING, embedding BLOB);", ());
-        let _ = conn.execute(
-            "CREATE TABLE IF NOT EXISTS config(key STRING, value STRING);",
-            (),
-        );
-        let _ = conn.execute("CREATE TABLE IF NOT EXISTS logs (timestamp DATETIME DEFAULT CURRENT_TIMESTAMP, level STRING, message TEXT);", ());
+        let _ = conn.execute("PRAGMA journal_mode=WAL;", ());
 
         Self { connection: conn }
     }
@@ -244,22 +272,63 @@ impl Database {
         map
     }
 
-    pub fn store_log(&self, level: &str, message: &str) {
-        let _ = self.connection.execute(
-            "INSERT INTO logs (level, message) VALUES (?1, ?2)",
-            (level, message),
-        );
+    pub fn store_logs(&self, records: &[LogRecord]) {
+        for record in records {
+            let _ = self.connection.execute(
+                "INSERT INTO logs (level, module, span, message) VALUES (?1, ?2, ?3, ?4)",
+                (&record.level, &record.module, &record.span, &record.message),
+            );
+        }
     }
 
-    pub fn get_logs(&self, limit: usize) -> Vec<LogEntry> {
+    /// The newest log entries matching `filter`, newest first.
+    pub fn get_logs(&self, filter: &LogFilter, limit: usize) -> Vec<LogEntry> {
+        let mut params = SqlParams::new(0);
+        let mut conditions = vec!["1 = 1".to_string()];
+        if let Some(level) = &filter.level {
+            let level = level.to_uppercase();
+            if let Some(rank) = LOG_LEVELS.iter().position(|l| *l == level) {
+                let levels = LOG_LEVELS[..=rank]
+                    .iter()
+                    .map(|l| format!("'{l}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                conditions.push(format!("level IN ({levels})"));
+            }
+        }
+        if let Some(module) = &filter.module {
+            conditions.push(format!(
+                "module LIKE ?{}",
+                params.bind(format!("%{module}%"))
+            ));
+        }
+        // Times may be given as ISO-8601 and are compared as UTC
+        if let Some(from) = &filter.from {
+            conditions.push(format!(
+                "timestamp >= datetime(?{})",
+                params.bind(from.clone())
+            ));
+        }
+        if let Some(to) = &filter.to {
+            conditions.push(format!(
+                "timestamp <= datetime(?{})",
+                params.bind(to.clone())
+            ));
+        }
+        let limit = params.bind(limit as i64);
+        let sql = format!(
+            "SELECT timestamp, level, module, span, message FROM logs WHERE {} ORDER BY id DESC LIMIT ?{limit}",
+            conditions.join(" AND ")
+        );
         let mut logs = Vec::new();
-        let sql = "SELECT timestamp, level, message FROM logs ORDER BY timestamp DESC LIMIT ?1";
-        if let Ok(mut stmt) = self.connection.prepare(sql) {
-            if let Ok(iter) = stmt.query_map([limit], |row| {
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map(rusqlite::params_from_iter(&params.values), |row| {
                 Ok(LogEntry {
                     timestamp: row.get(0)?,
                     level: row.get(1)?,
-                    message: row.get(2)?,
+                    module: row.get(2)?,
+                    span: row.get(3)?,
+                    message: row.get(4)?,
                 })
             }) {
                 for log in iter.flatten() {
@@ -270,6 +339,32 @@ impl Database {
         logs
     }
 
+    /// Drops log entries older than `max_age_days`, then the oldest ones until the rest take
+    /// up at most `max_bytes`. Returns how many were dropped.
+    pub fn prune_logs(&self, max_age_days: u32, max_bytes: u64) -> usize {
+        let expired = self
+            .connection
+            .execute(
+                "DELETE FROM logs WHERE timestamp < datetime('now', ?1)",
+                [format!("-{max_age_days} days")],
+            )
+            .unwrap_or(0);
+        let oversized = self
+            .connection
+            .execute(
+                "DELETE FROM logs WHERE id <= (
+                     SELECT id FROM (
+                         SELECT id, SUM(length(message) + IFNULL(length(module), 0) + IFNULL(length(span), 0))
+                             OVER (ORDER BY id DESC) AS total
+                         FROM logs
+                     ) WHERE total > ?1 ORDER BY id DESC LIMIT 1
+                 )",
+                [max_bytes as i64],
+            )
+            .unwrap_or(0);
+        expired + oversized
+    }
+
     pub fn clear_logs(&self) {
         let _ = self.connection.execute("DELETE FROM logs", ());
     }
@@ -296,7 +391,8 @@ impl Database {
 
     pub fn store_photo(&self, photo: Photo) {
         let _ = self.connection.execute(
-            "INSERT OR REPLACE INTO photo(id, location, encoded, latitude, longitude, created, indexed) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 2)",
+            "INSERT INTO photo(id, location, encoded, latitude, longitude, created, indexed) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 2)
+             ON CONFLICT(id) DO UPDATE SET location = excluded.location, encoded = excluded.encoded, latitude = excluded.latitude, longitude = excluded.longitude, created = excluded.created, indexed = excluded.indexed",
             (&photo.id, &photo.location, &photo.encoded, &photo.latitude, &photo.longitude, &photo.created),
         );
         for (object, probability) in photo.objects {
@@ -307,7 +403,7 @@ impl Database {
         }
         for (key, value) in photo.properties {
             let _ = self.connection.execute(
-                "INSERT into properties (photo_id, key, value) VALUES(?1, ?2, ?3)",
+                "INSERT OR REPLACE INTO properties (photo_id, key, value) VALUES(?1, ?2, ?3)",
                 (&photo.id, &key, &value),
             );
         }
@@ -319,11 +415,28 @@ impl Database {
             .execute("UPDATE photo SET encoded = ?1 WHERE id = ?2", (encoded, id));
     }
 
+    /// Tags and places to suggest for a search, from the photos whose tags or places match it,
+    /// most relevant first.
     pub fn list_objects(&self, query: &str) -> Vec<String> {
         let mut objects = Vec::new();
-        let sql = "SELECT class FROM object WHERE class LIKE ?1 UNION SELECT value FROM properties WHERE (key LIKE '%City%' OR key LIKE '%Country%' OR key LIKE '%State%') AND value LIKE ?1 GROUP BY 1";
-        if let Ok(mut stmt) = self.connection.prepare(sql) {
-            if let Ok(iter) = stmt.query_map([format!("%{query}%")], |row| row.get(0)) {
+        let terms = search_match(query);
+        if terms.is_empty() {
+            return objects;
+        }
+        // Materialized, since bm25() cannot be evaluated once flattened into the aggregate
+        let sql = format!(
+            "WITH s AS MATERIALIZED ({})
+             SELECT value FROM (
+                 SELECT photo_id, class AS value FROM object
+                 UNION ALL SELECT photo_id, value FROM properties WHERE key LIKE '%City%' OR key LIKE '%Country%' OR key LIKE '%State%'
+             ) v JOIN photo p ON p.id = v.photo_id JOIN s ON s.rowid = p.rowid WHERE v.value LIKE ?2
+             GROUP BY value ORDER BY SUM(s.score), COUNT(*) DESC",
+            search_matches(1)
+        );
+        let word = format!("%{}%", query.split_whitespace().last().unwrap_or_default());
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            let params = (format!("{{tags places}} : ({terms})"), word);
+            if let Ok(iter) = stmt.query_map(params, |row| row.get(0)) {
                 for item in iter.flatten() {
                     objects.push(item);
                 }
@@ -332,70 +445,270 @@ impl Database {
         objects
     }
 
-    pub fn list_photos(
-        &self,
-        query: &str,
-        offset: usize,
-        limit: usize,
-        favorites_only: bool,
-        videos_only: bool,
-    ) -> Vec<Photo> {
-        let mut photos = Vec::new();
-        let fav_filter = if favorites_only {
-            "AND EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite')"
-        } else {
-            ""
-        };
-        let video_filter = if videos_only {
-            "AND (p.location LIKE '%.mp4' OR p.location LIKE '%.mkv' OR p.location LIKE '%.mov' OR p.location LIKE '%.avi' OR p.location LIKE '%.webm')"
-        } else {
-            ""
-        };
-
-        let is_uuid = query.len() == 36 && query.chars().all(|c| c.is_alphanumeric() || c == '-');
-
-        let q_filter = if !query.is_empty() {
-            if is_uuid {
-                "AND (p.id = ?3 OR EXISTS(SELECT 1 FROM faces WHERE photo_id=p.id AND person_id = ?3))"
-            } else {
-                "AND (p.location LIKE ?3 OR p.id LIKE ?3 OR EXISTS(SELECT 1 FROM object WHERE photo_id=p.id AND class LIKE ?3) OR EXISTS(SELECT 1 FROM faces f JOIN people p_name ON f.person_id = p_name.id WHERE f.photo_id=p.id AND p_name.name LIKE ?3))"
+    /// Lists a page of the photos `filter` selects. Without an explicit sort they come newest
+    /// first or, for a text search, best match first.
+    pub fn list_photos(&self, filter: &PhotoFilter, page: &Page) -> Result<PhotoPage, QueryError> {
+        let mut params = SqlParams::new(1);
+        let (mut from, order) = self.library_sql(filter, page.sort, &mut params)?;
+        if let Some(cursor) = &page.after {
+            let after = decode_cursor(cursor, order.len())?;
+            from.push_str(" AND ");
+            from.push_str(&keyset_filter(&order, after, &mut params));
+        }
+        // One row past the page tells whether there is a next one
+        let limit = page.limit + 1;
+        let mut values: Vec<&dyn rusqlite::ToSql> = vec![&limit];
+        values.extend(params.values.iter().map(|v| v as &dyn rusqlite::ToSql));
+        let sql = format!(
+            "SELECT {PHOTO_COLUMNS}, {} {from} ORDER BY {} LIMIT ?1",
+            sort_keys(&order),
+            order_by(&order)
+        );
+        let mut rows = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map(values.as_slice(), |row| {
+                let keys = (0..order.len())
+                    .map(|i| row.get(PHOTO_COLUMN_COUNT + i))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok((photo_from_row(row)?, keys))
+            }) {
+                for row in iter.flatten() {
+                    rows.push(row);
+                }
             }
-        } else {
-            ""
-        };
+        }
+        let mut next = None;
+        if rows.len() > page.limit {
+            rows.truncate(page.limit);
+            next = rows.last().map(|(_, keys)| encode_cursor(keys));
+        }
+        Ok(PhotoPage {
+            photos: rows.into_iter().map(|(photo, _)| photo).collect(),
+            next,
+        })
+    }
 
-        let sql = format!("SELECT p.id, p.location, p.encoded, p.latitude, p.longitude, p.created, EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite'), p.indexed FROM photo p WHERE 1=1 {fav_filter} {video_filter} {q_filter} ORDER BY p.created DESC LIMIT ?1, ?2");
+    /// Photo and video counts per year, month or day the photos were taken, newest first,
+    /// for the photos `list_photos` would show with the same filter.
+    pub fn timeline(
+        &self,
+        granularity: Granularity,
+        filter: &PhotoFilter,
+    ) -> Result<Vec<TimelineBucket>, QueryError> {
+        let mut buckets = Vec::new();
+        let mut params = SqlParams::new(0);
+        let (from, _) = self.library_sql(filter, None, &mut params)?;
+        let key = granularity.key();
+        let sql = format!(
+            "SELECT {key} AS bucket, SUM(NOT {VIDEO_FILTER}), SUM({VIDEO_FILTER}) {from} AND p.taken_day IS NOT NULL GROUP BY bucket ORDER BY bucket DESC"
+        );
         if let Ok(mut stmt) = self.connection.prepare(&sql) {
-            let q_param = if is_uuid {
-                query.to_string()
-            } else {
-                format!("%{query}%")
-            };
-            let params: Vec<&dyn rusqlite::ToSql> = if !query.is_empty() {
-                vec![&offset, &limit, &q_param]
-            } else {
-                vec![&offset, &limit]
-            };
-            if let Ok(iter) = stmt.query_map(params.as_slice(), |row| {
-                Ok(Photo {
-                    id: row.get(0)?,
-                    location: row.get(1)?,
-                    encoded: row.get(2)?,
-                    created: row.get(5).unwrap_or_default(),
-                    objects: HashMap::new(),
-                    properties: HashMap::new(),
-                    latitude: row.get(3).unwrap_or(0.0),
-                    longitude: row.get(4).unwrap_or(0.0),
-                    favorite: row.get(6).unwrap_or(false),
-                    indexed: row.get(7).unwrap_or(0),
+            let values = rusqlite::params_from_iter(params.values.iter());
+            if let Ok(rows) = stmt.query_map(values, |row| {
+                Ok(TimelineBucket {
+                    key: row.get(0)?,
+                    photos: row.get(1)?,
+                    videos: row.get(2)?,
                 })
             }) {
-                for p in iter.flatten() {
-                    photos.push(p);
+                for row in rows.flatten() {
+                    buckets.push(row);
                 }
             }
         }
-        photos
+        Ok(buckets)
+    }
+
+    /// Where the first photo taken in `bucket`, a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` key from
+    /// `timeline`, falls in `list_photos` with the same filter and sort, or `None` when no
+    /// listed photo falls in it.
+    pub fn timeline_position(
+        &self,
+        bucket: &str,
+        filter: &PhotoFilter,
+        sort: Option<Sort>,
+    ) -> Result<Option<TimelinePosition>, QueryError> {
+        let mut params = SqlParams::new(1);
+        let (from, order) = self.library_sql(filter, sort, &mut params)?;
+        let mut values: Vec<&dyn rusqlite::ToSql> = vec![&bucket];
+        values.extend(params.values.iter().map(|v| v as &dyn rusqlite::ToSql));
+        // The sort keys of the photo before it make the cursor to list from
+        let previous: String = order
+            .iter()
+            .map(|term| format!(", LAG({}) OVER w", term.expr))
+            .collect();
+        let sql = format!(
+            "SELECT * FROM (SELECT p.taken_day, ROW_NUMBER() OVER w - 1 AS position{previous} {from} WINDOW w AS (ORDER BY {}))
+             WHERE substr(taken_day, 1, length(?1)) = ?1 ORDER BY position LIMIT 1",
+            order_by(&order)
+        );
+        Ok(self
+            .connection
+            .query_row(&sql, values.as_slice(), |row| {
+                let position: usize = row.get(1)?;
+                let keys = (0..order.len())
+                    .map(|i| row.get(2 + i))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(TimelinePosition {
+                    position,
+                    cursor: (position > 0).then(|| encode_cursor(&keys)),
+                })
+            })
+            .ok())
+    }
+
+    /// The `FROM ... WHERE ...` part shared by the photo grid and its timeline, over `photo p`,
+    /// and the order the grid lists the photos in, ending with the photo id so it is total.
+    /// Smart albums are evaluated live from their rules; without an explicit `sort`, regular
+    /// albums are shown in the order the user arranged them rather than by date.
+    fn library_sql(
+        &self,
+        filter: &PhotoFilter,
+        sort: Option<Sort>,
+        params: &mut SqlParams,
+    ) -> Result<(String, Vec<SortTerm>), QueryError> {
+        let query = query::parse(&filter.query)?;
+        let mut filters = Vec::new();
+        if filter.favorites_only {
+            filters.push(FAVORITE_FILTER.to_string());
+        }
+        if filter.videos_only {
+            filters.push(VIDEO_FILTER.to_string());
+        }
+        if let Some(event_id) = &filter.event_id {
+            filters.push(format!("p.event_id = ?{}", params.bind(event_id.clone())));
+        }
+        if let Some(cell) = &filter.map_cell {
+            filters.push(cell.to_sql(params));
+        }
+        let culling = &filter.culling;
+        if let Some(rating) = culling.min_rating {
+            filters.push(format!("p.rating >= ?{}", params.bind(rating)));
+        }
+        match culling.flag {
+            Some(flag) => filters.push(format!("p.flag = ?{}", params.bind(flag.value()))),
+            None if !culling.show_rejected => filters.push(NOT_REJECTED_FILTER.to_string()),
+            None => {}
+        }
+        if let Some(label) = culling.label {
+            filters.push(format!(
+                "p.label = ?{}",
+                params.bind(label.as_str().to_string())
+            ));
+        }
+        let search_join = self.query_sql(&query, params, &mut filters);
+        let mut order = Vec::new();
+        if !search_join.is_empty() {
+            // BM25 depends on the whole corpus, so a photo added or edited between two pages
+            // can move others across the cursor: search pages are best-effort, unlike the rest
+            order.push(SortTerm::new("s.score", false));
+        }
+        order.push(SortTerm::new(SortKey::CaptureDate.expr(), true));
+        order.push(SortTerm::new("p.id", true));
+
+        if let Some(album_id) = &filter.album_id {
+            match self.get_smart_album_rules(album_id) {
+                Some(rules) => filters.push(rules.to_sql(params)),
+                None => {
+                    let n = params.bind(album_id.clone());
+                    filters.push(format!(
+                        "EXISTS(SELECT 1 FROM album_photo WHERE album_id = ?{n} AND photo_id = p.id)"
+                    ));
+                    order = vec![
+                        SortTerm::new(
+                            &format!("(SELECT position FROM album_photo WHERE album_id = ?{n} AND photo_id = p.id)"),
+                            false,
+                        ),
+                        SortTerm::new("p.id", false),
+                    ];
+                }
+            }
+        }
+        if let Some(sort) = sort {
+            order = vec![
+                SortTerm::new(sort.key.expr(), !sort.ascending),
+                SortTerm::new("p.id", !sort.ascending),
+            ];
+        }
+        let filters: String = filters.iter().map(|f| format!(" AND {f}")).collect();
+        Ok((
+            format!("FROM photo p {search_join} WHERE p.trashed IS NULL{filters}"),
+            order,
+        ))
+    }
+
+    /// Compiles a parsed query into conditions on `photo p`, appended to `filters`. Free text
+    /// goes through the full-text index; when there is any, the returned join exposes its
+    /// matches as `s` for ranking, otherwise it is empty.
+    fn query_sql(
+        &self,
+        query: &Query,
+        params: &mut SqlParams,
+        filters: &mut Vec<String>,
+    ) -> String {
+        let mut text = Vec::new();
+        for term in &query.terms {
+            let condition = match &term.filter {
+                Filter::Text(value) => {
+                    let phrase = search_phrase(value);
+                    if phrase.is_empty() {
+                        continue;
+                    }
+                    if !term.negated {
+                        text.push(phrase);
+                        continue;
+                    }
+                    format!(
+                        "p.rowid IN (SELECT rowid FROM photo_search WHERE photo_search MATCH ?{})",
+                        params.bind(phrase)
+                    )
+                }
+                Filter::Person(person) => Rule::Person {
+                    person: person.clone(),
+                }
+                .to_sql(params),
+                Filter::Tag(tag) => Rule::Tag { tag: tag.clone() }.to_sql(params),
+                Filter::Keyword(keyword) => Rule::Keyword {
+                    keyword: keyword.clone(),
+                }
+                .to_sql(params),
+                Filter::After(date) => {
+                    format!("{CREATED_DATE} >= ?{}", params.bind(date.clone()))
+                }
+                Filter::Before(date) => {
+                    format!("{CREATED_DATE} < ?{}", params.bind(date.clone()))
+                }
+                Filter::In(name) => {
+                    let mut alternatives = vec![
+                        album_name_filter(params.bind(name.clone())),
+                        folder_name_filter(params.bind(name.clone())),
+                    ];
+                    for rules in self.get_smart_album_rules_by_name(name) {
+                        alternatives.push(rules.to_sql(params));
+                    }
+                    format!("({})", alternatives.join(" OR "))
+                }
+                Filter::Video(true) => VIDEO_FILTER.to_string(),
+                Filter::Video(false) => format!("NOT {VIDEO_FILTER}"),
+                Filter::Favorite(true) => FAVORITE_FILTER.to_string(),
+                Filter::Favorite(false) => format!("NOT {FAVORITE_FILTER}"),
+                Filter::Camera(camera) => camera_filter(params.bind(format!("%{camera}%"))),
+                Filter::Lens(lens) => format!("p.lens LIKE ?{}", params.bind(format!("%{lens}%"))),
+                Filter::Aperture(range) => range_filter("ROUND(p.aperture, 1)", range, params),
+                Filter::Iso(range) => range_filter("p.iso", range, params),
+                Filter::FocalLength(range) => range_filter("p.focal_length", range, params),
+            };
+            // A missing value (no date, no camera) counts as not matching
+            filters.push(if term.negated {
+                format!("NOT IFNULL({condition}, 0)")
+            } else {
+                format!("IFNULL({condition}, 0)")
+            });
+        }
+        if text.is_empty() {
+            return String::new();
+        }
+        search_join(params.bind(text.join(" ")))
     }
 
     pub fn toggle_favorite(&self, photo_id: &str) -> bool {
@@ -422,20 +735,165 @@ impl Database {
         }
     }
 
-    pub fn get_all_photos_with_location(&self) -> Vec<Photo> {
-        let mut photos = Vec::new();
-        if let Ok(mut stmt) = self.connection.prepare("SELECT p.id, p.location, p.encoded, p.latitude, p.longitude, p.created, EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite'), p.indexed FROM photo p WHERE (p.latitude != 0.0 OR p.longitude != 0.0)") {
-            if let Ok(iter) = stmt.query_map([], |row| {
-                Ok(Photo {
-                    id: row.get(0)?, location: row.get(1)?, encoded: row.get(2)?, created: row.get(5).unwrap_or_default(),
-                    objects: HashMap::new(), properties: HashMap::new(), latitude: row.get(3).unwrap_or(0.0), longitude: row.get(4).unwrap_or(0.0), favorite: row.get(6).unwrap_or(false),
-                    indexed: row.get(7).unwrap_or(0),
-                })
-            }) {
-                for p in iter.flatten() { photos.push(p); }
+    /// Sets the star rating, 0 to 5, of every photo in `ids`. Returns how many were found.
+    pub fn set_rating(&mut self, ids: &[String], rating: u8) -> Result<usize, String> {
+        if rating > 5 {
+            return Err("Ratings go from 0 to 5 stars".to_string());
+        }
+        self.set_culling(ids, "rating", rating.into())
+    }
+
+    pub fn set_flag(&mut self, ids: &[String], flag: Flag) -> Result<usize, String> {
+        self.set_culling(ids, "flag", flag.value().into())
+    }
+
+    pub fn set_color_label(
+        &mut self,
+        ids: &[String],
+        label: Option<ColorLabel>,
+    ) -> Result<usize, String> {
+        let value = match label {
+            Some(label) => label.as_str().to_string().into(),
+            None => rusqlite::types::Value::Null,
+        };
+        self.set_culling(ids, "label", value)
+    }
+
+    /// Sets one culling column on many photos at once, stamping when it changed so peers can
+    /// tell which side culled last.
+    fn set_culling(
+        &mut self,
+        ids: &[String],
+        column: &str,
+        value: rusqlite::types::Value,
+    ) -> Result<usize, String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let mut changed = 0;
+        for id in ids {
+            changed += tx
+                .execute(
+                    &format!(
+                        "UPDATE photo SET {column} = ?1, culled = strftime('%Y-%m-%dT%H:%M:%fZ', 'now') WHERE id = ?2"
+                    ),
+                    (&value, id),
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(changed)
+    }
+
+    /// The culling of every photo that was ever culled, trashed ones aside, for peers. Unlike
+    /// the manifest this includes photos not indexed yet and those received from peers.
+    pub fn get_culling_sync(&self) -> Vec<PhotoCulling> {
+        let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, hash, rating, flag, label, culled FROM photo
+             WHERE culled IS NOT NULL AND trashed IS NULL",
+        ) else {
+            return Vec::new();
+        };
+        stmt.query_map([], |row| {
+            Ok(PhotoCulling {
+                id: row.get(0)?,
+                hash: row.get(1)?,
+                culling: culling_from_row(row, 2)?,
+            })
+        })
+        .map(|rows| rows.flatten().collect())
+        .unwrap_or_default()
+    }
+
+    /// Applies a peer's culling to the photos this library holds too, matched by id or content
+    /// hash, where the peer culled them more recently.
+    pub fn merge_culling(&mut self, manifest: &[PhotoCulling]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for photo in manifest.iter().filter(|p| p.culling.culled.is_some()) {
+            tx.execute(
+                "UPDATE photo SET rating = ?3, flag = ?4, label = ?5, culled = ?6
+                 WHERE (id = ?1 OR (?2 IS NOT NULL AND hash = ?2)) AND (culled IS NULL OR culled < ?6)",
+                (
+                    &photo.id,
+                    &photo.hash,
+                    photo.culling.rating,
+                    photo.culling.flag.value(),
+                    photo.culling.label.map(ColorLabel::as_str),
+                    &photo.culling.culled,
+                ),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    pub fn photo_culling(&self, photo_id: &str) -> Culling {
+        self.connection
+            .query_row(
+                "SELECT rating, flag, label, culled FROM photo WHERE id = ?1",
+                [photo_id],
+                |row| culling_from_row(row, 0),
+            )
+            .unwrap_or_default()
+    }
+
+    /// Culling received with a photo from a peer.
+    pub fn import_photo_culling(&self, photo_id: &str, culling: &Culling) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET rating = ?2, flag = ?3, label = ?4, culled = ?5 WHERE id = ?1",
+            (
+                photo_id,
+                culling.rating,
+                culling.flag.value(),
+                culling.label.map(ColorLabel::as_str),
+                &culling.culled,
+            ),
+        );
+    }
+
+    /// Geotagged photos in `bounds`, clustered on the grid for `zoom`. The box is widened to
+    /// whole cells, so a cluster keeps its count and position while the map pans.
+    pub fn map_clusters(&self, bounds: &Bounds, zoom: u8) -> Vec<MapCluster> {
+        let zoom = zoom.min(MAX_MAP_ZOOM);
+        let size = MapCell::size(zoom);
+        let snap = |value: f64, offset: f64, up: bool| {
+            let cells = (value + offset) / size;
+            match up {
+                true => cells.ceil() * size - offset,
+                false => cells.floor() * size - offset,
+            }
+        };
+        let south = snap(bounds.south.max(-90.0), 90.0, false);
+        let north = snap(bounds.north.min(90.0), 90.0, true);
+        let (column, row) = map_cell_columns(1);
+        let sql = format!(
+            "SELECT {column} AS map_column, {row} AS map_row, COUNT(*), AVG(p.latitude), AVG(p.longitude),
+             MAX(IFNULL(p.created, '')), p.id, p.location
+             FROM photo_location l JOIN photo p ON p.rowid = l.id
+             WHERE l.max_latitude >= ?2 AND l.min_latitude <= ?3 AND l.max_longitude >= ?4 AND l.min_longitude <= ?5
+             AND p.latitude BETWEEN ?2 AND ?3 AND p.longitude BETWEEN ?4 AND ?5 AND p.trashed IS NULL
+             GROUP BY map_column, map_row"
+        );
+        let mut clusters = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            for (west, east) in bounds.spans() {
+                let (west, east) = (snap(west, 180.0, false), snap(east, 180.0, true));
+                // The photo picked by MAX() is the most recent one in the cell
+                if let Ok(iter) = stmt.query_map((size, south, north, west, east), |row| {
+                    Ok(MapCluster {
+                        id: format!("{zoom}/{}/{}", row.get::<_, i64>(0)?, row.get::<_, i64>(1)?),
+                        count: row.get(2)?,
+                        latitude: row.get(3)?,
+                        longitude: row.get(4)?,
+                        photo_id: row.get(6)?,
+                        location: row.get(7)?,
+                    })
+                }) {
+                    for cluster in iter.flatten() {
+                        clusters.push(cluster);
+                    }
+                }
             }
         }
-        photos
+        clusters
     }
 
     pub fn store_face(&self, face: Face) {
@@ -444,7 +902,16 @@ impl Database {
             .iter()
             .flat_map(|f| f.to_le_bytes())
             .collect();
-        let _ = self.connection.execute("INSERT OR REPLACE INTO faces(photo_id, face_id, crop_path, encoded, embedding, person_id) VALUES(?1, ?2, ?3, ?4, ?5, ?6)", (&face.photo_id, &face.face_id, &face.crop_path, &face.encoded, &embedding_bytes, &face.person_id));
+        let area = face.area.as_ref();
+        let _ = self.connection.execute("INSERT OR REPLACE INTO faces(photo_id, face_id, crop_path, encoded, embedding, person_id, x, y, width, height) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", (&face.photo_id, &face.face_id, &face.crop_path, &face.encoded, &embedding_bytes, &face.person_id, area.map(|a| a.x), area.map(|a| a.y), area.map(|a| a.width), area.map(|a| a.height)));
+    }
+
+    /// The face of a photo that covers much the same area, as (face id, person id), so a
+    /// face named in a sidecar before it was detected keeps its name.
+    pub fn face_at(&self, photo_id: &str, area: &FaceArea) -> Option<(String, Option<String>)> {
+        matching_face(&self.connection, photo_id, area)
+            .ok()
+            .flatten()
     }
 
     pub fn get_people(&self) -> Vec<PersonWithFace> {
@@ -602,7 +1069,7 @@ impl Database {
 
     pub fn get_faces_for_photo(&self, photo_id: &str) -> Vec<FaceWithPerson> {
         let mut faces = Vec::new();
-        let sql = "SELECT f.photo_id, f.face_id, f.crop_path, f.encoded, f.person_id, p.name FROM faces f LEFT JOIN people p ON f.person_id = p.id WHERE f.photo_id = ?1";
+        let sql = "SELECT f.photo_id, f.face_id, f.crop_path, f.encoded, f.person_id, p.name, f.x, f.y, f.width, f.height FROM faces f LEFT JOIN people p ON f.person_id = p.id WHERE f.photo_id = ?1";
         if let Ok(mut stmt) = self.connection.prepare(sql) {
             if let Ok(iter) = stmt.query_map([photo_id], |row| {
                 Ok(FaceWithPerson {
@@ -612,6 +1079,7 @@ impl Database {
                     encoded: row.get(3)?,
                     person_id: row.get(4)?,
                     person_name: row.get(5)?,
+                    area: face_area_from_row(row, 6)?,
                 })
             }) {
                 for f in iter.flatten() {
@@ -624,7 +1092,7 @@ impl Database {
 
     pub fn get_person_faces(&self, person_id: &str) -> Vec<Face> {
         let mut faces = Vec::new();
-        if let Ok(mut stmt) = self.connection.prepare("SELECT photo_id, face_id, crop_path, encoded, person_id FROM faces WHERE person_id = ?1") {
+        if let Ok(mut stmt) = self.connection.prepare("SELECT photo_id, face_id, crop_path, encoded, person_id, x, y, width, height FROM faces WHERE person_id = ?1") {
             if let Ok(iter) = stmt.query_map([person_id], |row| {
                 Ok(Face {
                     photo_id: row.get(0)?,
@@ -633,6 +1101,7 @@ impl Database {
                     encoded: row.get(3)?,
                     embedding: Vec::new(), // Not needed for UI
                     person_id: row.get(4)?,
+                    area: face_area_from_row(row, 5)?,
                 })
             }) {
                 for f in iter.flatten() { faces.push(f); }
@@ -643,15 +1112,12 @@ impl Database {
 
     pub fn get_photos_for_person(&self, person_id: &str) -> Vec<Photo> {
         let mut photos = Vec::new();
-        if let Ok(mut stmt) = self.connection.prepare("SELECT p.id, p.location, p.encoded, p.latitude, p.longitude, p.created, EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite'), p.indexed FROM photo p JOIN faces f ON p.id = f.photo_id WHERE f.person_id = ?1 GROUP BY p.id") {
-            if let Ok(iter) = stmt.query_map([person_id], |row| {
-                Ok(Photo {
-                    id: row.get(0)?, location: row.get(1)?, encoded: row.get(2)?, created: row.get(5).unwrap_or_default(),
-                    objects: HashMap::new(), properties: HashMap::new(), latitude: row.get(3).unwrap_or(0.0), longitude: row.get(4).unwrap_or(0.0), favorite: row.get(6).unwrap_or(false),
-                    indexed: 2, // These are linked photos, so they must be indexed
-                })
-            }) {
-                for p in iter.flatten() { photos.push(p); }
+        let sql = format!("SELECT {PHOTO_COLUMNS} FROM photo p JOIN faces f ON p.id = f.photo_id WHERE f.person_id = ?1 AND p.trashed IS NULL GROUP BY p.id");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([person_id], photo_from_row) {
+                for p in iter.flatten() {
+                    photos.push(p);
+                }
             }
         }
         photos
@@ -678,7 +1144,7 @@ impl Database {
     pub fn add_directory(&self, path: &str) {
         let _ = self
             .connection
-            .execute("INSERT INTO directory (name) VALUES(?1)", [&path]);
+            .execute("INSERT OR IGNORE INTO directory (name) VALUES(?1)", [&path]);
     }
 
     pub fn merge_people(&self, from_id: &str, to_id: &str) {
@@ -692,41 +1158,54 @@ impl Database {
         self.update_person_centroid(to_id);
     }
 
+    pub fn person_name(&self, id: &str) -> Option<String> {
+        self.connection
+            .query_row("SELECT name FROM people WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .ok()
+            .flatten()
+    }
+
     pub fn rename_person(&self, id: &str, new_name: &str) {
         let _ = self
             .connection
             .execute("UPDATE people SET name = ?1 WHERE id = ?2", (new_name, id));
     }
 
-    pub fn remove_directory_full(&self, path: &str) {
-        let mut photo_ids = Vec::new();
-        if let Ok(mut stmt) = self
+    /// Stops scanning the folder and deletes its photos, those in folders below it included.
+    pub fn remove_directory_full(&mut self, path: &str) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        // Objects, faces and properties follow through ON DELETE CASCADE
+        tx.execute(
+            &format!("DELETE FROM photo AS p WHERE {}", folder_filter(1)),
+            [folder_path(path)],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM directory WHERE name = ?1", [path])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Deletes every row in the library, settings included, keeping the schema.
+    pub fn clear(&self) {
+        let tables: Vec<String> = self
             .connection
-            .prepare("SELECT id FROM photo WHERE location LIKE ?1")
-        {
-            if let Ok(rows) = stmt.query_map([format!("{path}%")], |row| row.get::<_, String>(0)) {
-                for id in rows.flatten() {
-                    photo_ids.push(id);
-                }
-            }
-        }
-        for id in photo_ids {
-            let _ = self
-                .connection
-                .execute("DELETE FROM object WHERE photo_id = ?1", [&id]);
-            let _ = self
-                .connection
-                .execute("DELETE FROM faces WHERE photo_id = ?1", [&id]);
-            let _ = self
-                .connection
-                .execute("DELETE FROM properties WHERE photo_id = ?1", [&id]);
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table'
+                 AND name NOT LIKE 'sqlite%' AND name NOT GLOB 'photo_search_*'
+                 AND name NOT GLOB 'photo_location_*'",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()
+            })
+            .unwrap_or_default();
+        for table in tables {
             let _ = self
                 .connection
-                .execute("DELETE FROM photo WHERE id = ?1", [&id]);
+                .execute(&format!("DELETE FROM \"{table}\""), []);
         }
-        let _ = self
-            .connection
-            .execute("DELETE FROM directory WHERE name = ?1", [path]);
     }
 
     pub fn path_exists(&self, path: &str) -> bool {
@@ -749,7 +1228,8 @@ impl Database {
         encoded: &str,
     ) {
         let _ = self.connection.execute(
-            "INSERT OR REPLACE INTO photo (id, location, created, latitude, longitude, encoded) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO photo (id, location, created, latitude, longitude, encoded) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(id) DO UPDATE SET location = excluded.location, created = excluded.created, latitude = excluded.latitude, longitude = excluded.longitude, encoded = excluded.encoded",
             (id, location, created, latitude, longitude, encoded),
         );
 
@@ -783,11 +1263,127 @@ impl Database {
     }
 
     pub fn get_media_counts(&self) -> (i64, i64) {
-        let photo_count: i64 = self.connection.query_row("SELECT COUNT(*) FROM photo WHERE NOT (location LIKE '%.mp4' OR location LIKE '%.mkv' OR location LIKE '%.mov' OR location LIKE '%.avi' OR location LIKE '%.webm')", [], |r| r.get(0)).unwrap_or(0);
-        let video_count: i64 = self.connection.query_row("SELECT COUNT(*) FROM photo WHERE (location LIKE '%.mp4' OR location LIKE '%.mkv' OR location LIKE '%.mov' OR location LIKE '%.avi' OR location LIKE '%.webm')", [], |r| r.get(0)).unwrap_or(0);
+        let photo_count: i64 = self.connection.query_row("SELECT COUNT(*) FROM photo WHERE trashed IS NULL AND NOT (location LIKE '%.mp4' OR location LIKE '%.mkv' OR location LIKE '%.mov' OR location LIKE '%.avi' OR location LIKE '%.webm')", [], |r| r.get(0)).unwrap_or(0);
+        let video_count: i64 = self.connection.query_row("SELECT COUNT(*) FROM photo WHERE trashed IS NULL AND (location LIKE '%.mp4' OR location LIKE '%.mkv' OR location LIKE '%.mov' OR location LIKE '%.avi' OR location LIKE '%.webm')", [], |r| r.get(0)).unwrap_or(0);
         (photo_count, video_count)
     }
 
+    /// Photo counts and file sizes, in bytes, grouped by `key`, an expression over `photo p`,
+    /// largest first. Photos whose size was never read count as 0 bytes.
+    fn storage_by(&self, key: &str) -> Vec<StorageGroup> {
+        let sql = format!(
+            "SELECT {key} AS grp, COUNT(*), IFNULL(SUM(p.file_size), 0) FROM photo p
+             WHERE p.trashed IS NULL GROUP BY grp ORDER BY 3 DESC, 2 DESC, grp"
+        );
+        self.connection
+            .prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok(StorageGroup {
+                        key: row.get(0)?,
+                        count: row.get(1)?,
+                        bytes: row.get(2)?,
+                    })
+                })?
+                .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Storage per watched directory. A photo in a directory nested in another one counts
+    /// toward both.
+    pub fn storage_by_directory(&self) -> Vec<StorageGroup> {
+        let mut groups: Vec<StorageGroup> = self
+            .list_directories()
+            .into_iter()
+            .map(|directory| {
+                // Compared as text, so `/trip` is not a prefix of `/trip2` and `_` is no wildcard
+                let (count, bytes) = self
+                    .connection
+                    .query_row(
+                        "SELECT COUNT(*), IFNULL(SUM(file_size), 0) FROM photo
+                         WHERE trashed IS NULL AND substr(location, 1, length(?1) + 1) IN (?1 || '/', ?1 || '\\')",
+                        [directory.trim_end_matches(['/', '\\'])],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .unwrap_or((0, 0));
+                StorageGroup {
+                    key: Some(directory),
+                    count,
+                    bytes,
+                }
+            })
+            .collect();
+        groups.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.count.cmp(&a.count)));
+        groups
+    }
+
+    /// Storage per year taken, in local time. Photos without a capture time have no key.
+    pub fn storage_by_year(&self) -> Vec<StorageGroup> {
+        self.storage_by("substr(p.taken_day, 1, 4)")
+    }
+
+    pub fn storage_by_camera(&self) -> Vec<StorageGroup> {
+        self.storage_by("p.camera_model")
+    }
+
+    /// Storage per MIME type, which is not known until the file's details have been read.
+    pub fn storage_by_file_type(&self) -> Vec<StorageGroup> {
+        self.storage_by("p.mime")
+    }
+
+    /// How far the ML worker has got through the library.
+    pub fn index_coverage(&self) -> IndexCoverage {
+        self.connection
+            .query_row(
+                &format!(
+                    "SELECT COUNT(*),
+                         IFNULL(SUM(p.indexed >= 2), 0),
+                         IFNULL(SUM(p.indexed < 2), 0),
+                         IFNULL(SUM(p.index_error IS NOT NULL AND NOT {VIDEO_FILTER}), 0),
+                         IFNULL(SUM(p.indexed >= 2 AND p.index_error IS NULL AND NOT {VIDEO_FILTER}
+                             AND NOT EXISTS (SELECT 1 FROM faces f WHERE f.photo_id = p.id)), 0),
+                         IFNULL(SUM(p.indexed >= 2 AND p.index_error IS NULL AND NOT {VIDEO_FILTER}
+                             AND NOT EXISTS (SELECT 1 FROM object o WHERE o.photo_id = p.id)), 0)
+                     FROM photo p WHERE p.trashed IS NULL"
+                ),
+                [],
+                |row| {
+                    Ok(IndexCoverage {
+                        total: row.get(0)?,
+                        indexed: row.get(1)?,
+                        pending: row.get(2)?,
+                        failed: row.get(3)?,
+                        without_faces: row.get(4)?,
+                        without_tags: row.get(5)?,
+                    })
+                },
+            )
+            .unwrap_or_default()
+    }
+
+    /// Bytes taken up in the database by the photos' and faces' inline thumbnails.
+    pub fn thumbnail_bytes(&self) -> (i64, i64) {
+        let size = |table: &str| {
+            self.connection
+                .query_row(
+                    &format!("SELECT IFNULL(SUM(length(encoded)), 0) FROM {table}"),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap_or(0)
+        };
+        (size("photo"), size("faces"))
+    }
+
+    /// Records why the ML worker could not read a photo, or clears it once it could.
+    pub fn set_index_error(&self, id: &str, error: Option<&str>) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET index_error = ?2 WHERE id = ?1",
+            (id, error),
+        );
+    }
+
     pub fn list_devices(&self) -> Vec<DeviceInfo> {
         let mut results = Vec::new();
         if let Ok(mut stmt) = self.connection.prepare("SELECT ip, name FROM device") {
@@ -844,15 +1440,19 @@ impl Database {
         longitude: f64,
     ) {
         let _ = self.connection.execute(
-            "INSERT OR REPLACE INTO photo(id, location, encoded, created, latitude, longitude, indexed) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 1)",
+            "INSERT INTO photo(id, location, encoded, created, latitude, longitude, indexed) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 1)
+             ON CONFLICT(id) DO UPDATE SET location = excluded.location, encoded = excluded.encoded, created = excluded.created, latitude = excluded.latitude, longitude = excluded.longitude, indexed = excluded.indexed",
             (id, location, encoded, created, latitude, longitude),
         );
     }
 
     pub fn store_photo_batch(&mut self, photos: &[Photo]) -> Result<(), String> {
-        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
         {
-            let mut stmt = tx.prepare_cached("INSERT OR REPLACE INTO photo(id, location, encoded, created, latitude, longitude, indexed) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 1)").map_err(|e| e.to_string())?;
+            // An upsert rather than INSERT OR REPLACE: a replace deletes the old row first,
+            // which would cascade into the photo's objects, faces and properties.
+            let mut stmt = tx.prepare_cached("INSERT INTO photo(id, location, encoded, created, latitude, longitude, indexed, hash, created_offset) VALUES(?1, ?2, ?3, ?4, ?5, ?6, 1, ?7, ?8)
+                ON CONFLICT(id) DO UPDATE SET location = excluded.location, encoded = excluded.encoded, created = excluded.created, latitude = excluded.latitude, longitude = excluded.longitude, indexed = excluded.indexed, hash = excluded.hash, created_offset = excluded.created_offset").map_err(|e| e.to_string())?;
             for p in photos {
                 let _ = stmt.execute((
                     &p.id,
@@ -861,24 +1461,59 @@ impl Database {
                     &p.created,
                     &p.latitude,
                     &p.longitude,
+                    &p.hash,
+                    &p.created_offset,
                 ));
+                if let Some(media) = &p.media {
+                    let _ = write_media_info(&tx, &p.id, media);
+                }
+                if let Some(xmp) = &p.xmp {
+                    let _ = write_xmp(&tx, &p.id, xmp);
+                }
             }
         }
         tx.commit().map_err(|e| e.to_string())
     }
 
-    pub fn filter_new_paths(&self, paths: &[String]) -> Vec<String> {
+    /// Paths neither in the library nor recorded as a copy of a photo whose file is still
+    /// there, trashed or not. A copy `rescan` accepts, e.g. because its file has changed
+    /// or the photo it copies has gone missing since, is scanned again too.
+    pub fn filter_new_paths(
+        &self,
+        paths: &[String],
+        rescan: impl Fn(&SkippedCopy) -> bool,
+    ) -> Vec<String> {
         let mut new_paths = Vec::new();
         // Check in batches of 100 to avoid SQL variable limits
         for chunk in paths.chunks(100) {
             let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-            let sql = format!("SELECT location FROM photo WHERE location IN ({placeholders})");
+            let sql = format!(
+                "SELECT location, NULL, NULL, NULL FROM photo WHERE location IN ({placeholders})
+                 UNION ALL
+                 SELECT location, hash, file_size, file_mtime FROM duplicate_location d
+                 WHERE location IN ({placeholders})
+                     AND EXISTS (SELECT 1 FROM photo WHERE hash = d.hash AND missing IS NULL)"
+            );
             let mut existing = std::collections::HashSet::new();
             if let Ok(mut stmt) = self.connection.prepare(&sql) {
-                let params = rusqlite::params_from_iter(chunk);
-                if let Ok(rows) = stmt.query_map(params, |row| row.get::<_, String>(0)) {
-                    for row in rows.flatten() {
-                        existing.insert(row);
+                let params = rusqlite::params_from_iter(chunk.iter().chain(chunk));
+                if let Ok(rows) = stmt.query_map(params, |row| {
+                    let location: String = row.get(0)?;
+                    let copy = match row.get::<_, Option<String>>(1)? {
+                        Some(hash) => Some(SkippedCopy {
+                            location: location.clone(),
+                            hash,
+                            size: row.get::<_, Option<i64>>(2)?.map(|size| size as u64),
+                            modified: row.get(3)?,
+                        }),
+                        None => None,
+                    };
+                    Ok((location, copy))
+                }) {
+                    for (location, copy) in rows.flatten() {
+                        if !copy.is_some_and(|copy| rescan(&copy)) {
+                            existing.insert(location);
+                        }
                     }
                 }
             }
@@ -899,22 +1534,9 @@ impl Database {
 
     pub fn get_unindexed_photos(&self) -> Vec<Photo> {
         let mut photos = Vec::new();
-        let sql = "SELECT id, location, encoded, latitude, longitude, created, indexed FROM photo WHERE indexed < 2 LIMIT 50";
-        if let Ok(mut stmt) = self.connection.prepare(sql) {
-            if let Ok(iter) = stmt.query_map([], |row| {
-                Ok(Photo {
-                    id: row.get(0)?,
-                    location: row.get(1)?,
-                    encoded: row.get(2)?,
-                    created: row.get(5).unwrap_or_default(),
-                    objects: HashMap::new(),
-                    properties: HashMap::new(),
-                    latitude: row.get(3).unwrap_or(0.0),
-                    longitude: row.get(4).unwrap_or(0.0),
-                    favorite: false,
-                    indexed: row.get(6).unwrap_or(0),
-                })
-            }) {
+        let sql = format!("SELECT {PHOTO_COLUMNS} FROM photo p WHERE p.indexed < 2 LIMIT 50");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([], photo_from_row) {
                 for p in iter.flatten() {
                     photos.push(p);
                 }
@@ -922,20 +1544,2609 @@ impl Database {
         }
         photos
     }
-}
 
-#[derive(Debug, Clone, Serialize, serde::Deserialize)]
-pub struct Photo {
-    pub id: String,
-    pub location: String,
-    pub encoded: String,
-    pub created: String,
-    pub objects: HashMap<String, f64>,
-    pub properties: HashMap<String, String>,
-    pub latitude: f64,
-    pub longitude: f64,
-    pub favorite: bool,
+    pub fn get_photo(&self, id: &str) -> Option<Photo> {
+        let sql =
+            format!("SELECT {PHOTO_COLUMNS} FROM photo p WHERE p.id = ?1 AND p.trashed IS NULL");
+        self.connection.query_row(&sql, [id], photo_from_row).ok()
+    }
+
+    /// Returns the id and location of a photo outside the trash with the given content hash, if any.
+    pub fn find_photo_by_hash(&self, hash: &str) -> Option<(String, String)> {
+        self.connection
+            .query_row(
+                "SELECT id, location FROM photo WHERE hash = ?1 AND trashed IS NULL LIMIT 1",
+                [hash],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .ok()
+    }
+
+    /// Remembers that a file was skipped as a copy of the photo with its hash.
+    pub fn record_duplicate(&self, copy: &SkippedCopy) {
+        let _ = self.connection.execute(
+            "INSERT OR REPLACE INTO duplicate_location (location, hash, file_size, file_mtime)
+             VALUES (?1, ?2, ?3, ?4)",
+            (
+                &copy.location,
+                &copy.hash,
+                copy.size.map(|size| size as i64),
+                copy.modified,
+            ),
+        );
+    }
+
+    /// Whether a photo with the given content hash is in the trash.
+    pub fn is_hash_trashed(&self, hash: &str) -> bool {
+        self.connection
+            .query_row(
+                "SELECT 1 FROM photo WHERE hash = ?1 AND trashed IS NOT NULL",
+                [hash],
+                |_| Ok(true),
+            )
+            .unwrap_or(false)
+    }
+
+    /// Whether this library already holds a peer's photo, by content hash when the peer sent one
+    /// and by id otherwise.
+    pub fn has_photo(&self, photo: &PhotoSyncInfo) -> bool {
+        let sql = "SELECT 1 FROM photo WHERE id = ?1 OR (?2 IS NOT NULL AND hash = ?2)";
+        self.connection
+            .query_row(sql, (&photo.id, &photo.hash), |_| Ok(true))
+            .unwrap_or(false)
+    }
+
+    /// Ids of the peer's photos that this library is missing, one per distinct content hash.
+    pub fn missing_from_manifest(&self, manifest: &[PhotoSyncInfo]) -> Vec<String> {
+        let mut seen = std::collections::HashSet::new();
+        manifest
+            .iter()
+            .filter(|p| p.hash.as_ref().is_none_or(|h| seen.insert(h.clone())))
+            .filter(|p| !self.has_photo(p))
+            .map(|p| p.id.clone())
+            .collect()
+    }
+
+    pub fn set_photo_hash(&self, id: &str, hash: &str) {
+        let _ = self
+            .connection
+            .execute("UPDATE photo SET hash = ?1 WHERE id = ?2", (hash, id));
+    }
+
+    /// Points an existing photo at a new location, keeping its faces, tags and properties.
+    /// Trashed photos are only ever brought back by `restore_photos`, which moves their file.
+    pub fn relink_photo(&self, id: &str, location: &str) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET location = ?1, missing = NULL WHERE id = ?2 AND trashed IS NULL",
+            (location, id),
+        );
+    }
+
+    /// Moves every photo and authorized folder under the `from` folder to the same place
+    /// under `to`, e.g. after the library was copied to another drive.
+    pub fn remap_paths(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let from = from.trim_end_matches(['/', '\\']);
+        let to = to.trim_end_matches(['/', '\\']);
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for (table, column) in [
+            ("photo", "location"),
+            ("directory", "name"),
+            ("duplicate_location", "location"),
+        ] {
+            tx.execute(
+                &format!(
+                    "UPDATE {table} SET {column} = ?2 || substr({column}, length(?1) + 1)
+                     WHERE {column} = ?1 OR (substr({column}, 1, length(?1)) = ?1
+                         AND substr({column}, length(?1) + 1, 1) IN ('/', '\\'))"
+                ),
+                (from, to),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Points face crops kept in the `from` folder at the same files in `to`.
+    pub fn remap_face_crops(&self, from: &str, to: &str) {
+        let _ = self.connection.execute(
+            "UPDATE faces SET crop_path = ?2 || substr(crop_path, length(?1) + 1)
+             WHERE substr(crop_path, 1, length(?1)) = ?1",
+            (from, to),
+        );
+    }
+
+    /// Photos under `directory` that were imported before content hashing, as (id, location).
+    pub fn get_unhashed_photos(&self, directory: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self
+            .connection
+            .prepare("SELECT id, location FROM photo WHERE hash IS NULL AND trashed IS NULL AND location LIKE ?1")
+        {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn set_perceptual_hash(&self, id: &str, phash: u64, width: u32, height: u32) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET phash = ?1, width = ?2, height = ?3 WHERE id = ?4",
+            (phash as i64, width, height, id),
+        );
+    }
+
+    /// Every (id, perceptual hash) pair in the library, for near-duplicate clustering.
+    pub fn get_perceptual_hashes(&self) -> Vec<(String, u64)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self
+            .connection
+            .prepare("SELECT id, phash FROM photo WHERE phash IS NOT NULL AND trashed IS NULL")
+        {
+            if let Ok(rows) = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    /// Fully indexed still images under `directory` that predate perceptual hashing,
+    /// as (id, location). Limited to formats the `image` crate can decode.
+    pub fn get_photos_missing_perceptual_hash(&self, directory: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        let sql = "SELECT id, location FROM photo WHERE phash IS NULL AND indexed = 2 AND trashed IS NULL AND location LIKE ?1
+            AND (location LIKE '%.jpg' OR location LIKE '%.jpeg' OR location LIKE '%.png' OR location LIKE '%.webp')";
+        if let Ok(mut stmt) = self.connection.prepare(sql) {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    /// Loads a group of near-duplicates, ordered so the copy worth keeping comes first.
+    pub fn rank_duplicates(&self, ids: &[String], keep: KeepStrategy) -> Vec<DuplicateCandidate> {
+        let mut candidates = Vec::new();
+        let order = match keep {
+            KeepStrategy::Resolution => "COALESCE(p.width * p.height, 0) DESC, p.created IS NULL OR p.created = '', p.created ASC",
+            KeepStrategy::Oldest => "p.created IS NULL OR p.created = '', p.created ASC, COALESCE(p.width * p.height, 0) DESC",
+        };
+        let placeholders = ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+        let sql = format!("SELECT {PHOTO_COLUMNS}, p.width, p.height FROM photo p WHERE p.id IN ({placeholders}) AND p.trashed IS NULL ORDER BY {order}, p.id");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(rows) = stmt.query_map(rusqlite::params_from_iter(ids), |row| {
+                Ok(DuplicateCandidate {
+                    photo: photo_from_row(row)?,
+                    width: row.get(PHOTO_COLUMN_COUNT)?,
+                    height: row.get(PHOTO_COLUMN_COUNT + 1)?,
+                })
+            }) {
+                for candidate in rows.flatten() {
+                    candidates.push(candidate);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Removes a photo from the library. Its objects, faces and properties follow through
+    /// ON DELETE CASCADE; the file itself is left to the caller.
+    pub fn delete_photo(&self, id: &str) -> Result<(), String> {
+        self.connection
+            .execute("DELETE FROM photo WHERE id = ?1", [id])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn create_album(&self, name: &str, description: &str) -> Option<Album> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let _ = self.connection.execute(
+            "INSERT INTO album (id, name, description) VALUES (?1, ?2, ?3)",
+            (&id, name, description),
+        );
+        self.get_album(&id)
+    }
+
+    pub fn get_album(&self, id: &str) -> Option<Album> {
+        let sql = format!("{ALBUM_SELECT} WHERE id = ?1");
+        self.connection.query_row(&sql, [id], album_from_row).ok()
+    }
+
+    pub fn list_albums(&self) -> Vec<Album> {
+        let mut albums = Vec::new();
+        let sql = format!("{ALBUM_SELECT} ORDER BY name COLLATE NOCASE");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([], album_from_row) {
+                for album in iter.flatten() {
+                    albums.push(album);
+                }
+            }
+        }
+        albums
+    }
+
+    pub fn rename_album(&self, id: &str, name: &str) {
+        let _ = self.connection.execute(
+            "UPDATE album SET name = ?1, updated = CURRENT_TIMESTAMP WHERE id = ?2",
+            (name, id),
+        );
+    }
+
+    pub fn set_album_description(&self, id: &str, description: &str) {
+        let _ = self.connection.execute(
+            "UPDATE album SET description = ?1, updated = CURRENT_TIMESTAMP WHERE id = ?2",
+            (description, id),
+        );
+    }
+
+    /// Sets the cover to one of the album's photos, or back to the first photo with `None`.
+    pub fn set_album_cover(&self, id: &str, photo_id: Option<&str>) -> Result<(), String> {
+        let changed = self
+            .connection
+            .execute(
+                "UPDATE album SET cover_photo_id = ?1, updated = CURRENT_TIMESTAMP WHERE id = ?2
+                 AND (?1 IS NULL OR EXISTS(SELECT 1 FROM album_photo WHERE album_id = ?2 AND photo_id = ?1))",
+                (photo_id, id),
+            )
+            .map_err(|e| e.to_string())?;
+        if changed == 0 {
+            return Err("Cover photo is not in this album".to_string());
+        }
+        Ok(())
+    }
+
+    /// Deletes an album and remembers when, so peers delete their copy too.
+    pub fn delete_album(&mut self, id: &str) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        // Membership follows through ON DELETE CASCADE; the photos themselves are untouched
+        if tx
+            .execute("DELETE FROM album WHERE id = ?1", [id])
+            .map_err(|e| e.to_string())?
+            > 0
+        {
+            tx.execute(
+                "INSERT OR REPLACE INTO album_removed (id, removed)
+                 VALUES (?1, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))",
+                [id],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Appends photos to the end of an album, skipping ones that are already in it.
+    pub fn add_photos_to_album(&mut self, id: &str, photo_ids: &[String]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let mut position: i64 = tx
+            .query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM album_photo WHERE album_id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        for photo_id in photo_ids {
+            position += tx
+                .execute(
+                    "INSERT OR IGNORE INTO album_photo (album_id, photo_id, position, added)
+                     VALUES (?1, ?2, ?3, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))",
+                    (id, photo_id, position),
+                )
+                .map_err(|e| e.to_string())? as i64;
+            tx.execute(
+                "DELETE FROM album_photo_removed WHERE album_id = ?1 AND photo_id = ?2",
+                (id, photo_id),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.execute(
+            "UPDATE album SET updated = CURRENT_TIMESTAMP WHERE id = ?1",
+            [id],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    pub fn remove_photos_from_album(
+        &mut self,
+        id: &str,
+        photo_ids: &[String],
+    ) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for photo_id in photo_ids {
+            // Remembered with the hash, as peers may hold the photo under another id
+            tx.execute(
+                "INSERT OR REPLACE INTO album_photo_removed (album_id, photo_id, hash, removed)
+                 SELECT ap.album_id, ap.photo_id, p.hash, strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+                 FROM album_photo ap JOIN photo p ON p.id = ap.photo_id
+                 WHERE ap.album_id = ?1 AND ap.photo_id = ?2",
+                (id, photo_id),
+            )
+            .map_err(|e| e.to_string())?;
+            tx.execute(
+                "DELETE FROM album_photo WHERE album_id = ?1 AND photo_id = ?2",
+                (id, photo_id),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.execute(
+            "UPDATE album SET updated = CURRENT_TIMESTAMP,
+             cover_photo_id = CASE WHEN cover_photo_id IN (SELECT photo_id FROM album_photo WHERE album_id = ?1) THEN cover_photo_id END
+             WHERE id = ?1",
+            [id],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Moves the given photos to the front of the album in the given order. Members that are
+    /// not listed keep their relative order after them, so a partial list is never lossy.
+    pub fn reorder_album(&mut self, id: &str, photo_ids: &[String]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let current: Vec<String> = {
+            let mut stmt = tx
+                .prepare("SELECT photo_id FROM album_photo WHERE album_id = ?1 ORDER BY position")
+                .map_err(|e| e.to_string())?;
+            let rows = stmt
+                .query_map([id], |row| row.get(0))
+                .map_err(|e| e.to_string())?;
+            rows.flatten().collect()
+        };
+        let ordered = photo_ids
+            .iter()
+            .filter(|p| current.contains(p))
+            .chain(current.iter().filter(|p| !photo_ids.contains(p)));
+        for (position, photo_id) in ordered.enumerate() {
+            tx.execute(
+                "UPDATE album_photo SET position = ?1 WHERE album_id = ?2 AND photo_id = ?3",
+                (position as i64, id, photo_id),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.execute(
+            "UPDATE album SET updated = CURRENT_TIMESTAMP WHERE id = ?1",
+            [id],
+        )
+        .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Albums a photo belongs to, as the JSON carried in `PhotoSyncInfo::albums`.
+    pub fn album_sync_json(&self, photo_id: &str) -> String {
+        self.connection
+            .query_row(
+                "SELECT json_group_array(json_object('id', a.id, 'name', a.name, 'description', a.description, 'position', ap.position, 'added', ap.added))
+                 FROM album_photo ap JOIN album a ON a.id = ap.album_id WHERE ap.photo_id = ?1",
+                [photo_id],
+                |row| row.get(0),
+            )
+            .unwrap_or("[]".to_string())
+    }
+
+    /// The albums of every photo in one, trashed ones aside, for peers. Unlike the manifest
+    /// this includes photos not indexed yet and those received from peers.
+    pub fn get_album_sync(&self) -> Vec<PhotoAlbums> {
+        let Ok(mut stmt) = self.connection.prepare(
+            "SELECT p.id, p.hash, a.id, a.name, a.description, ap.position, ap.added
+             FROM album_photo ap JOIN album a ON a.id = ap.album_id JOIN photo p ON p.id = ap.photo_id
+             WHERE p.trashed IS NULL ORDER BY p.id",
+        ) else {
+            return Vec::new();
+        };
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                SyncAlbum {
+                    id: row.get(2)?,
+                    name: row.get(3)?,
+                    description: row.get(4)?,
+                    position: row.get(5)?,
+                    added: row.get(6)?,
+                },
+            ))
+        });
+        let mut photos: Vec<PhotoAlbums> = Vec::new();
+        for (id, hash, album) in rows.into_iter().flatten().flatten() {
+            match photos.last_mut() {
+                Some(photo) if photo.id == id => photo.albums.push(album),
+                _ => photos.push(PhotoAlbums {
+                    id,
+                    hash,
+                    albums: vec![album],
+                }),
+            }
+        }
+        photos
+    }
+
+    /// Albums and memberships removed on this device, for peers.
+    pub fn get_album_removals(&self) -> Vec<AlbumRemoval> {
+        let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, NULL, NULL, removed FROM album_removed
+             UNION ALL SELECT album_id, photo_id, hash, removed FROM album_photo_removed",
+        ) else {
+            return Vec::new();
+        };
+        stmt.query_map([], |row| {
+            Ok(AlbumRemoval {
+                album_id: row.get(0)?,
+                photo_id: row.get(1)?,
+                hash: row.get(2)?,
+                removed: row.get(3)?,
+            })
+        })
+        .map(|rows| rows.flatten().collect())
+        .unwrap_or_default()
+    }
+
+    /// Applies a peer's albums to the photos this library holds too, matched by id or content
+    /// hash, whichever of adding and removing happened last. Removals take out albums and
+    /// members added here before them, and are kept to pass on. Peer memberships not removed
+    /// here since add the photo, creating albums this device has not seen; photos already in
+    /// an album keep their place in it.
+    pub fn merge_albums(
+        &mut self,
+        manifest: &[PhotoAlbums],
+        removals: &[AlbumRemoval],
+    ) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for removal in removals {
+            match &removal.photo_id {
+                None => {
+                    tx.execute(
+                        "INSERT INTO album_removed (id, removed) VALUES (?1, ?2)
+                         ON CONFLICT(id) DO UPDATE SET removed = MAX(removed, excluded.removed)",
+                        (&removal.album_id, &removal.removed),
+                    )
+                    .map_err(|e| e.to_string())?;
+                    tx.execute(
+                        "DELETE FROM album WHERE id = ?1
+                         AND NOT EXISTS (SELECT 1 FROM album_photo WHERE album_id = ?1 AND added > ?2)",
+                        (&removal.album_id, &removal.removed),
+                    )
+                    .map_err(|e| e.to_string())?;
+                }
+                Some(photo_id) => {
+                    tx.execute(
+                        "INSERT INTO album_photo_removed (album_id, photo_id, hash, removed)
+                         VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT(album_id, photo_id) DO UPDATE SET
+                         hash = IFNULL(excluded.hash, hash), removed = MAX(removed, excluded.removed)",
+                        (&removal.album_id, photo_id, &removal.hash, &removal.removed),
+                    )
+                    .map_err(|e| e.to_string())?;
+                    tx.execute(
+                        "DELETE FROM album_photo WHERE album_id = ?1 AND (added IS NULL OR added < ?4)
+                         AND photo_id IN (SELECT id FROM photo WHERE id = ?2 OR (?3 IS NOT NULL AND hash = ?3))",
+                        (&removal.album_id, photo_id, &removal.hash, &removal.removed),
+                    )
+                    .map_err(|e| e.to_string())?;
+                    tx.execute(
+                        "UPDATE album SET
+                         cover_photo_id = CASE WHEN cover_photo_id IN (SELECT photo_id FROM album_photo WHERE album_id = ?1) THEN cover_photo_id END
+                         WHERE id = ?1",
+                        [&removal.album_id],
+                    )
+                    .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        for photo in manifest {
+            for album in &photo.albums {
+                if removed_since(&tx, album, &photo.id, photo.hash.as_deref())
+                    .map_err(|e| e.to_string())?
+                {
+                    continue;
+                }
+                tx.execute(
+                    "INSERT INTO album (id, name, description) VALUES (?1, ?2, ?3) ON CONFLICT(id) DO NOTHING",
+                    (&album.id, &album.name, &album.description),
+                )
+                .map_err(|e| e.to_string())?;
+                tx.execute(
+                    "INSERT INTO album_photo (album_id, photo_id, position, added)
+                     SELECT ?1, id, ?4, ?5 FROM photo
+                     WHERE (id = ?2 OR (?3 IS NOT NULL AND hash = ?3)) AND trashed IS NULL
+                     ON CONFLICT(album_id, photo_id) DO UPDATE SET added = excluded.added
+                     WHERE added IS NULL OR added < excluded.added",
+                    (
+                        &album.id,
+                        &photo.id,
+                        &photo.hash,
+                        album.position,
+                        &album.added,
+                    ),
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Adds a received photo to the peer's albums, creating albums this device has not seen,
+    /// unless they or the photo's place in them were removed here since. Albums that already
+    /// exist keep their local name and description.
+    pub fn import_photo_albums(&self, photo_id: &str, albums_json: &str) {
+        if let Ok(albums) = serde_json::from_str::<Vec<SyncAlbum>>(albums_json) {
+            for album in albums {
+                if removed_since(&self.connection, &album, photo_id, None).unwrap_or(false) {
+                    continue;
+                }
+                let _ = self.connection.execute(
+                    "INSERT INTO album (id, name, description) VALUES (?1, ?2, ?3) ON CONFLICT(id) DO NOTHING",
+                    (&album.id, &album.name, &album.description),
+                );
+                let _ = self.connection.execute(
+                    "INSERT INTO album_photo (album_id, photo_id, position, added) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT(album_id, photo_id) DO UPDATE SET position = excluded.position",
+                    (&album.id, photo_id, album.position, &album.added),
+                );
+            }
+        }
+    }
+
+    pub fn create_smart_album(&self, name: &str, rules: &Rule) -> Option<SmartAlbum> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let rules_json = serde_json::to_string(rules).ok()?;
+        let _ = self.connection.execute(
+            "INSERT INTO smart_album (id, name, rules) VALUES (?1, ?2, ?3)",
+            (&id, name, rules_json),
+        );
+        self.list_smart_albums().into_iter().find(|a| a.id == id)
+    }
+
+    pub fn update_smart_album(&self, id: &str, name: &str, rules: &Rule) {
+        if let Ok(rules_json) = serde_json::to_string(rules) {
+            let _ = self.connection.execute(
+                "UPDATE smart_album SET name = ?1, rules = ?2, updated = CURRENT_TIMESTAMP WHERE id = ?3",
+                (name, rules_json, id),
+            );
+        }
+    }
+
+    pub fn delete_smart_album(&self, id: &str) {
+        let _ = self
+            .connection
+            .execute("DELETE FROM smart_album WHERE id = ?1", [id]);
+    }
+
+    pub fn get_smart_album_rules(&self, id: &str) -> Option<Rule> {
+        let rules: String = self
+            .connection
+            .query_row("SELECT rules FROM smart_album WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .ok()?;
+        serde_json::from_str(&rules).ok()
+    }
+
+    /// Rules of the smart albums with this name, for `in:` searches.
+    fn get_smart_album_rules_by_name(&self, name: &str) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        if let Ok(mut stmt) = self
+            .connection
+            .prepare("SELECT rules FROM smart_album WHERE name = ?1 COLLATE NOCASE")
+        {
+            if let Ok(iter) = stmt.query_map([name], |row| row.get::<_, String>(0)) {
+                for json in iter.flatten() {
+                    if let Ok(rule) = serde_json::from_str(&json) {
+                        rules.push(rule);
+                    }
+                }
+            }
+        }
+        rules
+    }
+
+    /// Smart albums with their photo count and cover worked out from the current library,
+    /// so they reflect photos indexed since the rules were saved.
+    pub fn list_smart_albums(&self) -> Vec<SmartAlbum> {
+        let mut albums = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, name, rules, created, updated FROM smart_album ORDER BY name COLLATE NOCASE",
+        ) {
+            if let Ok(rows) = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            }) {
+                for (id, name, rules, created, updated) in rows.flatten() {
+                    let Ok(rules) = serde_json::from_str::<Rule>(&rules) else {
+                        continue;
+                    };
+                    let mut params = SqlParams::new(0);
+                    let filter = rules.to_sql(&mut params);
+                    let sql = format!(
+                        "SELECT COUNT(*), (SELECT p.location FROM photo p WHERE p.trashed IS NULL AND {filter} ORDER BY p.created DESC LIMIT 1) FROM photo p WHERE p.trashed IS NULL AND {filter}"
+                    );
+                    let (photo_count, cover_location) = self
+                        .connection
+                        .query_row(&sql, rusqlite::params_from_iter(&params.values), |row| {
+                            Ok((row.get(0)?, row.get(1)?))
+                        })
+                        .unwrap_or((0, None));
+                    albums.push(SmartAlbum {
+                        id,
+                        name,
+                        rules,
+                        photo_count,
+                        cover_location,
+                        created,
+                        updated,
+                    });
+                }
+            }
+        }
+        albums
+    }
+
+    /// Every tag, parents before their children, with the photos tagged with it directly.
+    pub fn list_tags(&self) -> Vec<Tag> {
+        let mut tags = Vec::new();
+        let sql = format!("{TAG_SELECT} ORDER BY t.path");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(rows) = stmt.query_map([], tag_from_row) {
+                tags.extend(rows.flatten());
+            }
+        }
+        tags
+    }
+
+    pub fn get_tag(&self, id: i64) -> Option<Tag> {
+        let sql = format!("{TAG_SELECT} WHERE t.id = ?1");
+        self.connection.query_row(&sql, [id], tag_from_row).ok()
+    }
+
+    /// Creates the tag at `path` along with any missing parents, or returns the existing one.
+    pub fn create_tag(&mut self, path: &str) -> Result<Tag, String> {
+        let path = tag_path(path).ok_or("Tag name is empty")?;
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let id = tag_for_path(&tx, &path).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        self.get_tag(id).ok_or("Tag not found".to_string())
+    }
+
+    /// Tags every photo with every path, creating the tags that do not exist yet.
+    pub fn assign_tags(&mut self, photo_ids: &[String], paths: &[String]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for path in paths.iter().filter_map(|path| tag_path(path)) {
+            let tag_id = tag_for_path(&tx, &path).map_err(|e| e.to_string())?;
+            for photo_id in photo_ids {
+                // Tagging by hand what a sidecar tagged makes it the user's own
+                tx.execute(
+                    "INSERT INTO photo_tag (photo_id, tag_id, source) VALUES (?1, ?2, 'manual')
+                     ON CONFLICT(photo_id, tag_id) DO UPDATE SET source = 'manual'",
+                    (photo_id, tag_id),
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Untags the photos; the tags themselves stay, even when no photo is left with them.
+    pub fn remove_tags(&mut self, photo_ids: &[String], tag_ids: &[i64]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for photo_id in photo_ids {
+            for tag_id in tag_ids {
+                tx.execute(
+                    "DELETE FROM photo_tag WHERE photo_id = ?1 AND tag_id = ?2",
+                    (photo_id, tag_id),
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    pub fn rename_tag(&mut self, id: i64, name: &str) -> Result<Tag, String> {
+        let name = name.trim();
+        if name.is_empty() || name.contains('/') {
+            return Err("Tag names cannot be empty or contain `/`".to_string());
+        }
+        let parent_id = self
+            .get_tag(id)
+            .ok_or("Tag not found".to_string())?
+            .parent_id;
+        self.relocate_tag(id, parent_id, name)
+    }
+
+    /// Moves a tag under another one, or to the top level with `None`.
+    pub fn move_tag(&mut self, id: i64, parent_id: Option<i64>) -> Result<Tag, String> {
+        let name = self.get_tag(id).ok_or("Tag not found".to_string())?.name;
+        self.relocate_tag(id, parent_id, &name)
+    }
+
+    /// Gives a tag a new parent and name, its children following along.
+    fn relocate_tag(&mut self, id: i64, parent_id: Option<i64>, name: &str) -> Result<Tag, String> {
+        let tag = self.get_tag(id).ok_or("Tag not found".to_string())?;
+        let path = match parent_id {
+            Some(parent_id) => {
+                let parent = self
+                    .get_tag(parent_id)
+                    .ok_or("Parent tag not found".to_string())?;
+                if is_tag_descendant(&parent.path, &tag.path) {
+                    return Err("A tag cannot be moved under itself".to_string());
+                }
+                format!("{}/{name}", parent.path)
+            }
+            None => name.to_string(),
+        };
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let taken: bool = tx
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM tag WHERE path = ?1 AND id != ?2)",
+                (&path, id),
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if taken {
+            return Err(format!("Tag `{path}` already exists"));
+        }
+        tx.execute(
+            "UPDATE tag SET path = ?1 || substr(path, length(?2) + 1)
+             WHERE substr(path, 1, length(?2) + 1) = (?2 || '/') COLLATE NOCASE",
+            (&path, &tag.path),
+        )
+        .map_err(|e| e.to_string())?;
+        tx.execute(
+            "UPDATE tag SET parent_id = ?1, name = ?2, path = ?3 WHERE id = ?4",
+            (parent_id, name, &path, id),
+        )
+        .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        self.get_tag(id).ok_or("Tag not found".to_string())
+    }
+
+    /// Deletes a tag and everything below it, untagging their photos.
+    pub fn delete_tag(&self, id: i64) {
+        let _ = self
+            .connection
+            .execute("DELETE FROM tag WHERE id = ?1", [id]);
+    }
+
+    /// Ids of the photos (not videos) matching `filter` over `photo p`, most representative
+    /// first: favorites, then photos with more faces and more confident tags.
+    pub fn best_photos(&self, filter: &str, params: &SqlParams, limit: usize) -> Vec<String> {
+        let sql = format!(
+            "SELECT p.id FROM photo p WHERE p.trashed IS NULL AND NOT {VIDEO_FILTER} AND {NOT_REJECTED_FILTER} AND {filter}
+             ORDER BY {MEMORY_SCORE} DESC, p.created DESC LIMIT {limit}"
+        );
+        self.connection
+            .prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::params_from_iter(&params.values), |row| row.get(0))?
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The years, oldest first, in which photos matching `filter` over `photo p` were taken.
+    pub fn photo_years(&self, filter: &str, params: &SqlParams) -> Vec<i32> {
+        let sql = format!(
+            "SELECT DISTINCT CAST(substr(p.taken_day, 1, 4) AS INTEGER) AS year FROM photo p
+             WHERE p.trashed IS NULL AND NOT {VIDEO_FILTER} AND p.taken_day IS NOT NULL AND {filter} ORDER BY year"
+        );
+        self.connection
+            .prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::params_from_iter(&params.values), |row| row.get(0))?
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Saves a memory and its photos, in order. Returns whether it is new; a memory that was
+    /// dismissed is left as it is.
+    pub fn store_memory(
+        &mut self,
+        id: &str,
+        kind: &str,
+        title: &str,
+        photo_ids: &[String],
+    ) -> Result<bool, String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        let created = tx
+            .execute(
+                "INSERT OR IGNORE INTO memories (id, kind, title) VALUES (?1, ?2, ?3)",
+                (id, kind, title),
+            )
+            .map_err(|e| e.to_string())?
+            > 0;
+        let updated = tx
+            .execute(
+                "UPDATE memories SET title = ?2 WHERE id = ?1 AND dismissed IS NULL",
+                (id, title),
+            )
+            .map_err(|e| e.to_string())?;
+        if updated > 0 {
+            tx.execute("DELETE FROM memory_photo WHERE memory_id = ?1", [id])
+                .map_err(|e| e.to_string())?;
+            for (position, photo_id) in photo_ids.iter().enumerate() {
+                tx.execute(
+                    "INSERT OR IGNORE INTO memory_photo (memory_id, photo_id, position) VALUES (?1, ?2, ?3)",
+                    (id, photo_id, position as i64),
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(created)
+    }
+
+    /// Deletes the memories of `kind` whose id does not start with `keep`, dismissed or not.
+    pub fn prune_memories(&self, kind: &str, keep: &str) -> Result<(), String> {
+        self.connection
+            .execute(
+                "DELETE FROM memories WHERE kind = ?1 AND substr(id, 1, length(?2)) != ?2",
+                (kind, keep),
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Deletes the memories of `kind`, with ids of the form `<kind>:<person id>`, whose person
+    /// no longer exists, e.g. after being merged into another.
+    pub fn prune_person_memories(&self, kind: &str) -> Result<(), String> {
+        self.connection
+            .execute(
+                "DELETE FROM memories WHERE kind = ?1
+                 AND substr(id, length(?1) + 2) NOT IN (SELECT id FROM people)",
+                [kind],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Memories that have not been dismissed, newest first.
+    pub fn list_memories(&self) -> Vec<Memory> {
+        let mut memories = Vec::new();
+        let sql = format!(
+            "{MEMORY_SELECT} WHERE dismissed IS NULL AND photo_count > 0 ORDER BY created DESC, id"
+        );
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([], memory_from_row) {
+                for memory in iter.flatten() {
+                    memories.push(memory);
+                }
+            }
+        }
+        memories
+    }
+
+    pub fn list_memory_photos(&self, id: &str) -> Vec<Photo> {
+        let mut photos = Vec::new();
+        let sql = format!(
+            "SELECT {PHOTO_COLUMNS} FROM memory_photo mp JOIN photo p ON p.id = mp.photo_id
+             WHERE mp.memory_id = ?1 AND p.trashed IS NULL ORDER BY mp.position"
+        );
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([id], photo_from_row) {
+                for p in iter.flatten() {
+                    photos.push(p);
+                }
+            }
+        }
+        photos
+    }
+
+    pub fn dismiss_memory(&self, id: &str) {
+        let _ = self.connection.execute(
+            "UPDATE memories SET dismissed = CURRENT_TIMESTAMP WHERE id = ?1",
+            [id],
+        );
+    }
+
+    /// Geotagged photos whose place names have not been looked up since they were added or
+    /// their position changed, as (id, latitude, longitude).
+    pub fn photos_to_geocode(&self) -> Vec<(String, f64, f64)> {
+        let mut photos = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, latitude, longitude FROM photo
+             WHERE geocoded = 0 AND trashed IS NULL AND latitude IS NOT NULL AND longitude IS NOT NULL
+             AND (latitude != 0 OR longitude != 0)",
+        ) {
+            if let Ok(iter) = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            {
+                for p in iter.flatten() {
+                    photos.push(p);
+                }
+            }
+        }
+        photos
+    }
+
+    /// Stores the place names found for each photo as its `City`, `State` and `Country`
+    /// properties, replacing those of an earlier position, and marks the photos geocoded.
+    pub fn set_places(&mut self, places: &[(String, Place)]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for (photo_id, place) in places {
+            for (key, value) in [
+                ("City", &place.city),
+                ("State", &place.state),
+                ("Country", &place.country),
+            ] {
+                match value {
+                    Some(value) => tx.execute(
+                        "INSERT INTO properties (photo_id, key, value) VALUES (?1, ?2, ?3)
+                         ON CONFLICT(photo_id, key) DO UPDATE SET value = excluded.value",
+                        (photo_id, key, value),
+                    ),
+                    None => tx.execute(
+                        "DELETE FROM properties WHERE photo_id = ?1 AND key = ?2",
+                        (photo_id, key),
+                    ),
+                }
+                .map_err(|e| e.to_string())?;
+            }
+            tx.execute("UPDATE photo SET geocoded = 1 WHERE id = ?1", [photo_id])
+                .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Where event clustering has to resume from, in seconds since the epoch: the earliest
+    /// photo not in an event yet, or the start of an event that ends within `max_gap` before
+    /// it, since that photo may still join it. `None` when every photo is in an event.
+    pub fn event_rebuild_start(&self, max_gap: i64) -> Option<i64> {
+        let earliest: i64 = self
+            .connection
+            .query_row(
+                "SELECT MIN(unixepoch(created)) FROM photo WHERE event_id IS NULL AND trashed IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .ok()?;
+        let joined: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT MIN(unixepoch(start_time)) FROM event WHERE unixepoch(end_time) >= ?1",
+                [earliest - max_gap],
+                |row| row.get(0),
+            )
+            .unwrap_or(None);
+        Some(joined.map_or(earliest, |start| start.min(earliest)))
+    }
+
+    /// Photos taken at or after `from`, in the order they were taken. Photos whose capture
+    /// time is not a resolved timestamp are left out.
+    pub fn event_photos(&self, from: i64) -> Vec<EventPhoto> {
+        let mut photos = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, unixepoch(created) AS time, latitude, longitude FROM photo
+             WHERE trashed IS NULL AND time >= ?1 ORDER BY time, id",
+        ) {
+            if let Ok(iter) = stmt.query_map([from], |row| {
+                let latitude: Option<f64> = row.get(2)?;
+                let longitude: Option<f64> = row.get(3)?;
+                Ok(EventPhoto {
+                    id: row.get(0)?,
+                    time: row.get(1)?,
+                    // 0, 0 is what photos without GPS data are stored with
+                    position: match (latitude, longitude) {
+                        (Some(lat), Some(lon)) if lat != 0.0 || lon != 0.0 => Some((lat, lon)),
+                        _ => None,
+                    },
+                })
+            }) {
+                for p in iter.flatten() {
+                    photos.push(p);
+                }
+            }
+        }
+        photos
+    }
+
+    /// Replaces the events starting at or after `from` with `events`, assigning their photos.
+    pub fn replace_events(&mut self, from: i64, events: &[NewEvent]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        tx.execute(
+            "DELETE FROM event WHERE unixepoch(start_time) >= ?1",
+            [from],
+        )
+        .map_err(|e| e.to_string())?;
+        for event in events {
+            tx.execute(
+                "INSERT INTO event (id, title, start_time, end_time, latitude, longitude)
+                 VALUES (?1, ?2, strftime('%Y-%m-%dT%H:%M:%SZ', ?3, 'unixepoch'), strftime('%Y-%m-%dT%H:%M:%SZ', ?4, 'unixepoch'), ?5, ?6)",
+                (
+                    &event.id,
+                    &event.title,
+                    event.start,
+                    event.end,
+                    event.centroid.map(|c| c.0),
+                    event.centroid.map(|c| c.1),
+                ),
+            )
+            .map_err(|e| e.to_string())?;
+            for photo_id in &event.photo_ids {
+                tx.execute(
+                    "UPDATE photo SET event_id = ?1 WHERE id = ?2",
+                    (&event.id, photo_id),
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// The place most of an event's photos were taken in, preferring cities over countries,
+    /// and the ML tag found on most of them.
+    pub fn event_title_parts(&self, photo_ids: &[String]) -> (Option<String>, Option<String>) {
+        let placeholders = vec!["?"; photo_ids.len()].join(", ");
+        let values = rusqlite::params_from_iter(photo_ids);
+        let place = self
+            .connection
+            .query_row(
+                &format!(
+                    "SELECT value FROM properties WHERE photo_id IN ({placeholders})
+                     AND (key LIKE '%City%' OR key LIKE '%Country%')
+                     GROUP BY value ORDER BY MAX(key LIKE '%City%') DESC, COUNT(*) DESC LIMIT 1"
+                ),
+                values.clone(),
+                |row| row.get(0),
+            )
+            .ok();
+        let tag = self
+            .connection
+            .query_row(
+                &format!(
+                    "SELECT class FROM object WHERE photo_id IN ({placeholders})
+                     GROUP BY class ORDER BY COUNT(*) DESC, SUM(CAST(probability AS REAL)) DESC LIMIT 1"
+                ),
+                values,
+                |row| row.get(0),
+            )
+            .ok();
+        (place, tag)
+    }
+
+    /// Events, most recent first.
+    pub fn list_events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        let sql = format!("{EVENT_SELECT} WHERE photo_count > 0 ORDER BY start_time DESC");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([], event_from_row) {
+                for event in iter.flatten() {
+                    events.push(event);
+                }
+            }
+        }
+        events
+    }
+
+    /// Marks photos as trashed along with where their file went, all of them or none. Fails
+    /// if one has been trashed or moved since its entry was made.
+    pub fn mark_trashed(&mut self, entries: &[TrashEntry]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for entry in entries {
+            let changed = tx
+                .execute(
+                    "UPDATE photo SET trashed = CURRENT_TIMESTAMP, trash_location = ?1
+                     WHERE id = ?2 AND location = ?3 AND trashed IS NULL",
+                    (&entry.trash_location, &entry.id, &entry.location),
+                )
+                .map_err(|e| e.to_string())?;
+            if changed == 0 {
+                return Err(format!("{} has changed since", entry.location));
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Takes photos out of the trash, all of them or none. Fails if one has left the trash
+    /// or been purged since its entry was read.
+    pub fn restore_trashed(&mut self, entries: &[TrashEntry]) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        for entry in entries {
+            let changed = tx
+                .execute(
+                    "UPDATE photo SET trashed = NULL, trash_location = NULL
+                     WHERE id = ?1 AND trashed IS NOT NULL AND trash_location IS ?2",
+                    (&entry.id, &entry.trash_location),
+                )
+                .map_err(|e| e.to_string())?;
+            if changed == 0 {
+                return Err(format!("{} has changed since", entry.location));
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Original and trash location of a trashed photo.
+    pub fn get_trash_entry(&self, id: &str) -> Option<TrashEntry> {
+        self.connection
+            .query_row(
+                "SELECT id, location, trash_location FROM photo WHERE id = ?1 AND trashed IS NOT NULL",
+                [id],
+                |row| {
+                    Ok(TrashEntry {
+                        id: row.get(0)?,
+                        location: row.get(1)?,
+                        trash_location: row.get(2)?,
+                    })
+                },
+            )
+            .ok()
+    }
+
+    /// Trashed photos, most recently trashed first.
+    pub fn list_trash(&self) -> Vec<TrashedPhoto> {
+        let mut photos = Vec::new();
+        let sql = format!("SELECT {PHOTO_COLUMNS}, p.trashed FROM photo p WHERE p.trashed IS NOT NULL ORDER BY p.trashed DESC");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            if let Ok(iter) = stmt.query_map([], |row| {
+                Ok(TrashedPhoto {
+                    photo: photo_from_row(row)?,
+                    trashed: row.get(PHOTO_COLUMN_COUNT)?,
+                })
+            }) {
+                for p in iter.flatten() {
+                    photos.push(p);
+                }
+            }
+        }
+        photos
+    }
+
+    /// Trashed photos as (id, trash location), limited to those trashed more than
+    /// `older_than_days` ago when given.
+    pub fn get_trashed_before(
+        &self,
+        older_than_days: Option<u32>,
+    ) -> Vec<(String, Option<String>)> {
+        let mut results = Vec::new();
+        let sql = "SELECT id, trash_location FROM photo WHERE trashed IS NOT NULL
+                   AND (?1 IS NULL OR trashed <= datetime('now', '-' || ?1 || ' days'))";
+        if let Ok(mut stmt) = self.connection.prepare(sql) {
+            if let Ok(rows) =
+                stmt.query_map([older_than_days], |row| Ok((row.get(0)?, row.get(1)?)))
+            {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn set_capture_time(&self, id: &str, created: &str, offset_seconds: i32) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET created = ?1, created_offset = ?2, event_id = NULL WHERE id = ?3",
+            (created, offset_seconds, id),
+        );
+    }
+
+    /// Photos under `directory` whose capture time has not been resolved yet, as (id, location):
+    /// those scanned before the resolver existed, whose `created` is raw EXIF text or empty.
+    /// Photos from a peer come with the time it resolved but no offset, and keep that time.
+    pub fn get_unresolved_capture_times(&self, directory: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, location FROM photo WHERE created_offset IS NULL AND trashed IS NULL AND location LIKE ?1
+             AND IFNULL(created, '') NOT GLOB '[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]T[0-9][0-9]:[0-9][0-9]:[0-9][0-9]Z'",
+        ) {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn set_media_info(&self, id: &str, info: &MediaInfo) {
+        let _ = write_media_info(&self.connection, id, info);
+    }
+
+    pub fn get_media_info(&self, id: &str) -> Option<MediaInfo> {
+        self.connection
+            .query_row(
+                "SELECT camera_make, camera_model, lens, focal_length, aperture, iso, exposure_time, flash, orientation, width, height, file_size, mime, latitude, longitude FROM photo WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(MediaInfo {
+                        camera_make: row.get(0)?,
+                        camera_model: row.get(1)?,
+                        lens: row.get(2)?,
+                        focal_length: row.get(3)?,
+                        aperture: row.get(4)?,
+                        iso: row.get(5)?,
+                        exposure_time: row.get(6)?,
+                        flash: row.get(7)?,
+                        orientation: row.get(8)?,
+                        width: row.get(9)?,
+                        height: row.get(10)?,
+                        file_size: row.get::<_, Option<u64>>(11)?.unwrap_or(0),
+                        mime: row.get::<_, Option<String>>(12)?.unwrap_or_default(),
+                        latitude: row.get(13)?,
+                        longitude: row.get(14)?,
+                    })
+                },
+            )
+            .ok()
+    }
+
+    /// Photos in `directory` whose technical metadata has not been read yet.
+    pub fn get_photos_missing_media_info(&self, directory: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, location FROM photo WHERE mime IS NULL AND trashed IS NULL AND location LIKE ?1",
+        ) {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn get_photos_missing_fingerprint(&self, directory: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, location FROM photo WHERE partial_hash IS NULL AND trashed IS NULL AND location LIKE ?1",
+        ) {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn set_fingerprint(&self, id: &str, fingerprint: &Fingerprint) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET file_size = ?2, file_mtime = ?3, partial_hash = ?4 WHERE id = ?1",
+            (
+                id,
+                fingerprint.size as i64,
+                fingerprint.modified,
+                &fingerprint.partial_hash,
+            ),
+        );
+    }
+
+    /// Where every photo outside the trash is expected to be, with what its file is recognized by.
+    pub fn photo_files(&self) -> Vec<PhotoFile> {
+        let sql = "SELECT id, location, hash, file_size, file_mtime, partial_hash FROM photo WHERE trashed IS NULL";
+        self.connection
+            .prepare(sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    let size: Option<i64> = row.get(3)?;
+                    let modified: Option<i64> = row.get(4)?;
+                    let partial_hash: Option<String> = row.get(5)?;
+                    Ok(PhotoFile {
+                        id: row.get(0)?,
+                        location: row.get(1)?,
+                        hash: row.get(2)?,
+                        fingerprint: match (size, modified, partial_hash) {
+                            (Some(size), Some(modified), Some(partial_hash)) => Some(Fingerprint {
+                                size: size as u64,
+                                modified,
+                                partial_hash,
+                            }),
+                            _ => None,
+                        },
+                    })
+                })?
+                .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Marks `ids` as missing their file, keeping when a photo was first found missing, and
+    /// clears the mark from photos whose file is back.
+    pub fn set_missing(&self, ids: &[String]) {
+        let ids = serde_json::to_string(ids).unwrap_or("[]".to_string());
+        let _ = self.connection.execute(
+            "UPDATE photo SET missing = NULL
+             WHERE missing IS NOT NULL AND id NOT IN (SELECT value FROM json_each(?1))",
+            [&ids],
+        );
+        let _ = self.connection.execute(
+            "UPDATE photo SET missing = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+             WHERE missing IS NULL AND id IN (SELECT value FROM json_each(?1))",
+            [&ids],
+        );
+    }
+
+    /// Photos whose file was gone at the last scan and turned up nowhere else, oldest first.
+    pub fn list_missing(&self) -> Vec<MissingPhoto> {
+        let sql = "SELECT id, location, missing FROM photo
+                   WHERE missing IS NOT NULL AND trashed IS NULL ORDER BY missing, location";
+        self.connection
+            .prepare(sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok(MissingPhoto {
+                        id: row.get(0)?,
+                        location: row.get(1)?,
+                        since: row.get(2)?,
+                    })
+                })?
+                .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Applies XMP read from a photo's sidecar or from the file itself, and remembers when
+    /// the sidecar was modified so it is only read again once it changes.
+    pub fn apply_xmp(&mut self, id: &str, xmp: &Xmp) -> Result<(), String> {
+        let tx = self.connection.savepoint().map_err(|e| e.to_string())?;
+        write_xmp(&tx, id, xmp).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Photos under `directory` with when their sidecar was last read or written, as
+    /// (id, location, modified), for the scanner to spot sidecars changed by other tools.
+    pub fn get_sidecar_times(&self, directory: &str) -> Vec<(String, String, Option<i64>)> {
+        let mut results = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, location, sidecar_modified FROM photo WHERE trashed IS NULL AND location LIKE ?1",
+        ) {
+            if let Ok(rows) = stmt.query_map([format!("{directory}%")], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            }) {
+                for row in rows.flatten() {
+                    results.push(row);
+                }
+            }
+        }
+        results
+    }
+
+    /// Up to `limit` photos after `after`, by id, whose sidecar is out of date, with what to
+    /// write into it. They are marked up to date, so a failed write has to mark them again.
+    pub fn take_stale_sidecars(&mut self, after: &str, limit: usize) -> Vec<Sidecar> {
+        let mut sidecars = Vec::new();
+        if let Ok(mut stmt) = self.connection.prepare(
+            "SELECT id, location, rating, flag, label, width, height, sidecar_modified FROM photo
+             WHERE sidecar_stale = 1 AND trashed IS NULL AND id > ?1 ORDER BY id LIMIT ?2",
+        ) {
+            if let Ok(rows) = stmt.query_map((after, limit as i64), |row| {
+                let width: Option<u32> = row.get(5)?;
+                let height: Option<u32> = row.get(6)?;
+                Ok(Sidecar {
+                    id: row.get(0)?,
+                    location: row.get(1)?,
+                    rating: row.get(2)?,
+                    flag: Flag::from_value(row.get(3)?),
+                    label: row
+                        .get::<_, Option<String>>(4)?
+                        .and_then(|label| ColorLabel::parse(&label)),
+                    dimensions: width.zip(height),
+                    modified: row.get(7)?,
+                    ..Default::default()
+                })
+            }) {
+                sidecars.extend(rows.flatten());
+            }
+        }
+        let strings = |sql: &str, id: &str| -> Vec<String> {
+            self.connection
+                .prepare(sql)
+                .and_then(|mut stmt| {
+                    stmt.query_map([id], |row| row.get(0))?
+                        .collect::<rusqlite::Result<_>>()
+                })
+                .unwrap_or_default()
+        };
+        for sidecar in &mut sidecars {
+            sidecar.keywords = strings(
+                "SELECT t.path FROM photo_tag pt JOIN tag t ON t.id = pt.tag_id
+                 WHERE pt.photo_id = ?1 ORDER BY t.path",
+                &sidecar.id,
+            );
+            sidecar.tags = strings(
+                "SELECT DISTINCT class FROM object WHERE photo_id = ?1 ORDER BY class",
+                &sidecar.id,
+            );
+            if let Ok(mut stmt) = self.connection.prepare(
+                "SELECT p.name, f.x, f.y, f.width, f.height FROM faces f JOIN people p ON p.id = f.person_id
+                 WHERE f.photo_id = ?1 AND p.name IS NOT NULL AND f.x IS NOT NULL ORDER BY p.name",
+            ) {
+                if let Ok(rows) = stmt.query_map([&sidecar.id], |row| {
+                    Ok(Region {
+                        name: row.get(0)?,
+                        area: FaceArea {
+                            x: row.get(1)?,
+                            y: row.get(2)?,
+                            width: row.get(3)?,
+                            height: row.get(4)?,
+                        },
+                    })
+                }) {
+                    sidecar.faces = rows.flatten().collect();
+                }
+            }
+            let _ = self.connection.execute(
+                "UPDATE photo SET sidecar_stale = 0 WHERE id = ?1",
+                [&sidecar.id],
+            );
+        }
+        sidecars
+    }
+
+    pub fn set_sidecar_modified(&self, id: &str, modified: i64) {
+        let _ = self.connection.execute(
+            "UPDATE photo SET sidecar_modified = ?2 WHERE id = ?1",
+            (id, modified),
+        );
+    }
+
+    pub fn mark_sidecar_stale(&self, id: &str) {
+        let _ = self
+            .connection
+            .execute("UPDATE photo SET sidecar_stale = 1 WHERE id = ?1", [id]);
+    }
+
+    /// Counts the photos matching a search box query by each value of `facet`, most common
+    /// first. Photos without a value are left out.
+    pub fn facet(&self, facet: Facet, query: &str) -> Result<Vec<FacetCount>, QueryError> {
+        let mut counts = Vec::new();
+        let query = query::parse(query)?;
+        let mut params = SqlParams::new(0);
+        let mut filters = Vec::new();
+        let search_join = self.query_sql(&query, &mut params, &mut filters);
+        let filters: String = filters.iter().map(|f| format!(" AND {f}")).collect();
+        let column = facet.column();
+        let sql = format!("SELECT CAST({column} AS TEXT) AS value, COUNT(*) FROM photo p {search_join} WHERE p.trashed IS NULL AND {column} IS NOT NULL{filters} GROUP BY value ORDER BY COUNT(*) DESC, value");
+        if let Ok(mut stmt) = self.connection.prepare(&sql) {
+            let values = rusqlite::params_from_iter(params.values.iter());
+            if let Ok(rows) = stmt.query_map(values, |row| {
+                Ok(FacetCount {
+                    value: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            }) {
+                for row in rows.flatten() {
+                    counts.push(row);
+                }
+            }
+        }
+        Ok(counts)
+    }
+}
+
+fn write_media_info(
+    connection: &Connection,
+    id: &str,
+    info: &MediaInfo,
+) -> rusqlite::Result<usize> {
+    connection.execute(
+        "UPDATE photo SET camera_make = ?2, camera_model = ?3, lens = ?4, focal_length = ?5, aperture = ?6, iso = ?7, exposure_time = ?8, flash = ?9, orientation = ?10, width = IFNULL(?11, width), height = IFNULL(?12, height), file_size = ?13, mime = ?14 WHERE id = ?1",
+        rusqlite::params![
+            id,
+            info.camera_make,
+            info.camera_model,
+            info.lens,
+            info.focal_length,
+            info.aperture,
+            info.iso,
+            info.exposure_time,
+            info.flash,
+            info.orientation,
+            info.width,
+            info.height,
+            info.file_size,
+            info.mime,
+        ],
+    )
+}
+
+/// How much a face region read from XMP has to overlap a detected face to be taken for it.
+const FACE_MATCH_OVERLAP: f32 = 0.5;
+
+/// Applies what a photo's XMP sets: the rating, where -1 rejects the photo, the color label,
+/// the description as its `caption`, its keywords as tags and named faces. Tags an earlier
+/// sidecar gave are replaced by those of a sidecar, the newest source, while a packet embedded
+/// in the file only adds. Tags the user added in Siegu are left alone either way. What it
+/// applies does not mark the sidecar stale, so it is not written straight back.
+fn write_xmp(connection: &Connection, id: &str, xmp: &Xmp) -> rusqlite::Result<()> {
+    const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+    let stale: bool = connection
+        .query_row(
+            "SELECT sidecar_stale FROM photo WHERE id = ?1",
+            [id],
+            |row| row.get(0),
+        )
+        .unwrap_or_default();
+    match xmp.rating {
+        Some(-1) => connection.execute(
+            &format!("UPDATE photo SET flag = -1, culled = {NOW} WHERE id = ?1 AND flag != -1"),
+            [id],
+        )?,
+        Some(rating) => connection.execute(
+            &format!(
+                "UPDATE photo SET rating = ?2, flag = MAX(flag, 0), culled = {NOW}
+                 WHERE id = ?1 AND (rating != ?2 OR flag = -1)"
+            ),
+            (id, rating.clamp(0, 5)),
+        )?,
+        None => 0,
+    };
+    if let Some(label) = xmp.label {
+        connection.execute(
+            &format!(
+                "UPDATE photo SET label = ?2, culled = {NOW} WHERE id = ?1 AND label IS NOT ?2"
+            ),
+            (id, label.as_str()),
+        )?;
+    }
+    if let Some(description) = xmp.description.as_ref().filter(|d| !d.is_empty()) {
+        connection.execute(
+            "INSERT INTO properties (photo_id, key, value) VALUES (?1, 'caption', ?2)
+             ON CONFLICT(photo_id, key) DO UPDATE SET value = excluded.value",
+            (id, description),
+        )?;
+    }
+    let mut tag_ids = Vec::new();
+    for path in xmp.keywords.iter().filter_map(|keyword| tag_path(keyword)) {
+        tag_ids.push(tag_for_path(connection, &path)?);
+    }
+    if xmp.modified.is_some() {
+        connection.execute(
+            &format!(
+                "DELETE FROM photo_tag WHERE photo_id = ?1 AND source = 'xmp' AND tag_id NOT IN ({})",
+                tag_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            [id],
+        )?;
+    }
+    for tag_id in tag_ids {
+        connection.execute(
+            "INSERT OR IGNORE INTO photo_tag (photo_id, tag_id, source) VALUES (?1, ?2, 'xmp')",
+            (id, tag_id),
+        )?;
+    }
+    for region in &xmp.faces {
+        let person_id = match connection
+            .query_row(
+                "SELECT id FROM people WHERE name = ?1",
+                [&region.name],
+                |row| row.get::<_, String>(0),
+            )
+            .ok()
+        {
+            Some(person_id) => person_id,
+            None => {
+                let person_id = uuid::Uuid::new_v4().to_string();
+                connection.execute(
+                    "INSERT INTO people (id, name) VALUES (?1, ?2)",
+                    (&person_id, &region.name),
+                )?;
+                person_id
+            }
+        };
+        let area = &region.area;
+        match matching_face(connection, id, area)? {
+            Some((face_id, current)) if current.as_ref() != Some(&person_id) => {
+                connection.execute(
+                    "UPDATE faces SET person_id = ?2 WHERE face_id = ?1",
+                    (face_id, &person_id),
+                )?;
+            }
+            Some(_) => {}
+            // Not detected yet: the ML worker fills in the crop once it finds the face here
+            None => {
+                let face_id = format!(
+                    "{id}_region_{}_{}",
+                    (area.x * 10000.0).round() as i64,
+                    (area.y * 10000.0).round() as i64
+                );
+                connection.execute(
+                    "INSERT OR REPLACE INTO faces (photo_id, face_id, crop_path, encoded, person_id, x, y, width, height)
+                     VALUES (?1, ?2, '', '', ?3, ?4, ?5, ?6, ?7)",
+                    (id, face_id, &person_id, area.x, area.y, area.width, area.height),
+                )?;
+            }
+        }
+    }
+    if let Some(modified) = xmp.modified {
+        connection.execute(
+            "UPDATE photo SET sidecar_modified = ?2 WHERE id = ?1",
+            (id, modified),
+        )?;
+    }
+    // Changes still waiting to be written before this stay marked
+    connection.execute(
+        "UPDATE photo SET sidecar_stale = ?2 WHERE id = ?1",
+        (id, stale),
+    )?;
+    Ok(())
+}
+
+/// The face of a photo that best overlaps `area`, as (face id, person id).
+fn matching_face(
+    connection: &Connection,
+    photo_id: &str,
+    area: &FaceArea,
+) -> rusqlite::Result<Option<(String, Option<String>)>> {
+    let mut stmt = connection.prepare(
+        "SELECT face_id, person_id, x, y, width, height FROM faces WHERE photo_id = ?1 AND x IS NOT NULL",
+    )?;
+    let faces = stmt.query_map([photo_id], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            face_area_from_row(row, 2)?,
+        ))
+    })?;
+    let mut best: Option<(f32, String, Option<String>)> = None;
+    for (face_id, person_id, face_area) in faces.flatten() {
+        let overlap = face_area.map_or(0.0, |face_area| face_area.overlap(area));
+        if overlap >= FACE_MATCH_OVERLAP && best.as_ref().is_none_or(|(b, _, _)| overlap > *b) {
+            best = Some((overlap, face_id, person_id));
+        }
+    }
+    Ok(best.map(|(_, face_id, person_id)| (face_id, person_id)))
+}
+
+fn face_area_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Option<FaceArea>> {
+    let x: Option<f32> = row.get(first)?;
+    Ok(match x {
+        Some(x) => Some(FaceArea {
+            x,
+            y: row.get(first + 1)?,
+            width: row.get(first + 2)?,
+            height: row.get(first + 3)?,
+        }),
+        None => None,
+    })
+}
+
+/// Album columns in the order `album_from_row` reads them. The cover falls back to the
+/// first photo in the album when none was picked.
+const ALBUM_SELECT: &str = "SELECT id, name, description, cover, (SELECT location FROM photo WHERE id = cover), photo_count, created, updated FROM (
+    SELECT a.*,
+        COALESCE(a.cover_photo_id, (SELECT photo_id FROM album_photo WHERE album_id = a.id AND photo_id IN (SELECT id FROM photo WHERE trashed IS NULL) ORDER BY position LIMIT 1)) AS cover,
+        (SELECT COUNT(*) FROM album_photo WHERE album_id = a.id AND photo_id IN (SELECT id FROM photo WHERE trashed IS NULL)) AS photo_count
+    FROM album a)";
+
+const EVENT_SELECT: &str = "SELECT id, title, start_time, end_time, latitude, longitude, photo_count, cover, (SELECT location FROM photo WHERE id = cover) FROM (
+    SELECT e.*,
+        (SELECT COUNT(*) FROM photo WHERE event_id = e.id AND trashed IS NULL) AS photo_count,
+        (SELECT id FROM photo WHERE event_id = e.id AND trashed IS NULL ORDER BY created LIMIT 1) AS cover
+    FROM event e)";
+
+fn event_from_row(row: &rusqlite::Row) -> rusqlite::Result<Event> {
+    Ok(Event {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        start: row.get(2)?,
+        end: row.get(3)?,
+        latitude: row.get(4)?,
+        longitude: row.get(5)?,
+        photo_count: row.get(6)?,
+        cover_photo_id: row.get(7)?,
+        cover_location: row.get(8)?,
+    })
+}
+
+const MEMORY_SELECT: &str = "SELECT id, kind, title, cover, (SELECT location FROM photo WHERE id = cover), photo_count, created FROM (
+    SELECT m.*,
+        (SELECT photo_id FROM memory_photo WHERE memory_id = m.id AND photo_id IN (SELECT id FROM photo WHERE trashed IS NULL) ORDER BY position LIMIT 1) AS cover,
+        (SELECT COUNT(*) FROM memory_photo WHERE memory_id = m.id AND photo_id IN (SELECT id FROM photo WHERE trashed IS NULL)) AS photo_count
+    FROM memories m)";
+
+fn memory_from_row(row: &rusqlite::Row) -> rusqlite::Result<Memory> {
+    Ok(Memory {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        title: row.get(2)?,
+        cover_photo_id: row.get(3)?,
+        cover_location: row.get(4)?,
+        photo_count: row.get(5)?,
+        created: row.get(6)?,
+    })
+}
+
+fn album_from_row(row: &rusqlite::Row) -> rusqlite::Result<Album> {
+    Ok(Album {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        cover_photo_id: row.get(3)?,
+        cover_location: row.get(4)?,
+        photo_count: row.get(5)?,
+        created: row.get(6)?,
+        updated: row.get(7)?,
+    })
+}
+
+/// Whether the album, or this photo's place in it, was removed here at or after a peer's
+/// membership was added. A membership without a stamp loses to any removal.
+fn removed_since(
+    connection: &Connection,
+    album: &SyncAlbum,
+    photo_id: &str,
+    hash: Option<&str>,
+) -> rusqlite::Result<bool> {
+    connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM album_removed WHERE id = ?1 AND removed >= IFNULL(?4, ''))
+             OR EXISTS (SELECT 1 FROM album_photo_removed WHERE album_id = ?1
+                 AND (photo_id = ?2 OR (?3 IS NOT NULL AND hash = ?3)) AND removed >= IFNULL(?4, ''))",
+        (&album.id, photo_id, hash, &album.added),
+        |row| row.get(0),
+    )
+}
+
+const TAG_SELECT: &str = "SELECT t.id, t.parent_id, t.name, t.path,
+    (SELECT COUNT(*) FROM photo_tag pt JOIN photo p ON p.id = pt.photo_id WHERE pt.tag_id = t.id AND p.trashed IS NULL)
+    FROM tag t";
+
+fn tag_from_row(row: &rusqlite::Row) -> rusqlite::Result<Tag> {
+    Ok(Tag {
+        id: row.get(0)?,
+        parent_id: row.get(1)?,
+        name: row.get(2)?,
+        path: row.get(3)?,
+        photo_count: row.get(4)?,
+    })
+}
+
+/// A tag path with the whitespace around each level trimmed and empty levels dropped, so
+/// ` Places//Lisbon ` becomes `Places/Lisbon`; `None` if nothing is left.
+pub fn tag_path(path: &str) -> Option<String> {
+    let names: Vec<&str> = path
+        .split('/')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    (!names.is_empty()).then(|| names.join("/"))
+}
+
+/// The id of the tag at a normalized `path`, creating it and any missing parents. Existing
+/// levels are matched ignoring case and keep their spelling.
+fn tag_for_path(connection: &Connection, path: &str) -> rusqlite::Result<i64> {
+    let mut parent: Option<(i64, String)> = None;
+    for name in path.split('/') {
+        let path = match &parent {
+            Some((_, path)) => format!("{path}/{name}"),
+            None => name.to_string(),
+        };
+        connection.execute(
+            "INSERT INTO tag (parent_id, name, path) VALUES (?1, ?2, ?3) ON CONFLICT(path) DO NOTHING",
+            (parent.as_ref().map(|(id, _)| *id), name, &path),
+        )?;
+        parent = Some(connection.query_row(
+            "SELECT id, path FROM tag WHERE path = ?1",
+            [&path],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?);
+    }
+    parent
+        .map(|(id, _)| id)
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Whether `path` is `ancestor` itself or somewhere below it.
+fn is_tag_descendant(path: &str, ancestor: &str) -> bool {
+    let (path, ancestor) = (path.to_lowercase(), ancestor.to_lowercase());
+    path == ancestor || path.starts_with(&format!("{ancestor}/"))
+}
+
+/// Filter fragments shared by `list_photos` and smart album rules.
+/// Like `PHOTO_COLUMNS`, they expect the photo table to be aliased as `p`.
+pub const FAVORITE_FILTER: &str =
+    "EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite')";
+/// Capture date as `YYYY-MM-DD`, the day it was where the photo was taken. Dates read before
+/// capture times were normalized to ISO-8601 have no `taken_day` and may use colons in the
+/// date part.
+pub const CREATED_DATE: &str = "IFNULL(p.taken_day, substr(replace(p.created, ':', '-'), 1, 10))";
+pub const VIDEO_FILTER: &str = "(p.location LIKE '%.mp4' OR p.location LIKE '%.mkv' OR p.location LIKE '%.mov' OR p.location LIKE '%.avi' OR p.location LIKE '%.webm')";
+/// Photos not rejected while culling.
+const NOT_REJECTED_FILTER: &str = "p.flag != -1";
+
+/// How representative a photo is for a memory. Favorites outweigh everything else; faces
+/// count up to four, and the most confident ML tag adds its probability.
+const MEMORY_SCORE: &str =
+    "3.0 * EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite')
+    + 0.5 * MIN((SELECT COUNT(*) FROM faces WHERE photo_id = p.id), 4)
+    + IFNULL((SELECT MAX(CAST(probability AS REAL)) FROM object WHERE photo_id = p.id), 0)";
+
+pub fn tag_filter(param: usize) -> String {
+    format!("EXISTS(SELECT 1 FROM object WHERE photo_id=p.id AND class LIKE ?{param})")
+}
+
+/// User tags whose full path or own name is the value, along with everything below them, so
+/// `keyword:Portugal` also finds photos tagged `Places/Portugal/Lisbon`.
+pub fn keyword_filter(param: usize) -> String {
+    format!(
+        "EXISTS(SELECT 1 FROM photo_tag pt JOIN tag t ON t.id = pt.tag_id
+         JOIN tag a ON t.id = a.id OR substr(t.path, 1, length(a.path) + 1) = (a.path || '/') COLLATE NOCASE
+         WHERE pt.photo_id = p.id AND (a.path = ?{param} OR a.name = ?{param} COLLATE NOCASE))"
+    )
+}
+
+/// Photos in the folder bound at `param` or anywhere below it, whichever separator their
+/// paths use, but not those in a sibling folder whose name merely starts the same. The
+/// folder is bound as `folder_path` gives it.
+pub fn folder_filter(param: usize) -> String {
+    format!(
+        "(p.location = ?{param} OR substr(p.location, 1, length(?{param}) + 1) IN (?{param} || '/', ?{param} || '\\'))"
+    )
+}
+
+/// A folder without its trailing separators, as `folder_filter` expects it.
+pub fn folder_path(path: &str) -> String {
+    path.trim_end_matches(['/', '\\']).to_string()
+}
+
+pub fn person_id_filter(param: usize) -> String {
+    format!("EXISTS(SELECT 1 FROM faces WHERE photo_id=p.id AND person_id = ?{param})")
+}
+
+pub fn album_name_filter(param: usize) -> String {
+    format!("EXISTS(SELECT 1 FROM album_photo ap JOIN album a ON a.id = ap.album_id WHERE ap.photo_id = p.id AND a.name = ?{param} COLLATE NOCASE)")
+}
+
+/// Photos in a folder named as bound at `param`, at any depth, ignoring ASCII case the way
+/// `LIKE` does. Matched as plain text, so `%` and `_` in the name stand for themselves.
+fn folder_name_filter(param: usize) -> String {
+    format!(
+        "(instr(lower(p.location), lower('/' || ?{param} || '/')) > 0 OR instr(lower(p.location), lower('\\' || ?{param} || '\\')) > 0)"
+    )
+}
+
+/// Make and model as one string, so both `camera:Pixel` and `camera:"Google Pixel"` match.
+pub fn camera_filter(param: usize) -> String {
+    format!("(IFNULL(p.camera_make, '') || ' ' || IFNULL(p.camera_model, '')) LIKE ?{param}")
+}
+
+/// Bounds on a numeric column; photos without a value match neither a filter nor its negation
+/// until wrapped by the caller.
+fn range_filter(column: &str, range: &Range, params: &mut SqlParams) -> String {
+    let mut parts = vec![format!("{column} IS NOT NULL")];
+    if let Some(min) = range.min {
+        parts.push(format!("{column} >= ?{}", params.bind(min)));
+    }
+    if let Some(max) = range.max {
+        parts.push(format!("{column} <= ?{}", params.bind(max)));
+    }
+    format!("({})", parts.join(" AND "))
+}
+
+pub fn person_name_filter(param: usize) -> String {
+    format!("EXISTS(SELECT 1 FROM faces f JOIN people p_name ON f.person_id = p_name.id WHERE f.photo_id=p.id AND p_name.name LIKE ?{param})")
+}
+
+/// Relative weight of a match in each `photo_search` column: path, filename, tags, people,
+/// places and captions.
+const SEARCH_SCORE: &str = "bm25(photo_search, 1.0, 2.0, 1.0, 4.0, 2.0, 2.0)";
+
+/// Full-text matches for the query bound at `param`, as photo rowids with their BM25 `score`
+/// (lower is better).
+pub fn search_matches(param: usize) -> String {
+    format!(
+        "SELECT rowid, {SEARCH_SCORE} AS score FROM photo_search WHERE photo_search MATCH ?{param}"
+    )
+}
+
+/// Joins `search_matches` as `s`.
+pub fn search_join(param: usize) -> String {
+    format!("JOIN ({}) s ON s.rowid = p.rowid", search_matches(param))
+}
+
+/// Turns free text into an FTS5 query where every word must match as a prefix, so results
+/// narrow as the user types. Returns an empty string when there is nothing to search for.
+pub fn search_match(text: &str) -> String {
+    text.split_whitespace()
+        .map(search_phrase)
+        .filter(|phrase| !phrase.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quotes text as one FTS5 phrase whose last word may be a prefix, or returns an empty
+/// string when it has no letters or digits to match.
+pub fn search_phrase(text: &str) -> String {
+    if !text.chars().any(char::is_alphanumeric) {
+        return String::new();
+    }
+    format!("\"{}\"*", text.replace('"', "\"\""))
+}
+
+/// Values bound while building a query, numbered after the `first` placeholders the
+/// surrounding statement already uses.
+pub struct SqlParams {
+    first: usize,
+    pub values: Vec<rusqlite::types::Value>,
+}
+
+impl SqlParams {
+    pub fn new(first: usize) -> Self {
+        Self {
+            first,
+            values: Vec::new(),
+        }
+    }
+
+    /// Binds a value and returns its placeholder number.
+    pub fn bind(&mut self, value: impl Into<rusqlite::types::Value>) -> usize {
+        self.values.push(value.into());
+        self.first + self.values.len()
+    }
+}
+
+/// Columns selected for a `Photo`, in the order `photo_from_row` reads them.
+/// Queries using it must alias the photo table as `p`.
+const PHOTO_COLUMNS: &str = "p.id, p.location, p.encoded, p.latitude, p.longitude, p.created, EXISTS(SELECT 1 FROM properties WHERE photo_id=p.id AND key='favorite'), p.indexed, p.hash, p.created_offset, p.rating, p.flag, p.label";
+/// Index of the first column selected after `PHOTO_COLUMNS`.
+const PHOTO_COLUMN_COUNT: usize = 13;
+
+fn photo_from_row(row: &rusqlite::Row) -> rusqlite::Result<Photo> {
+    Ok(Photo {
+        id: row.get(0)?,
+        location: row.get(1)?,
+        encoded: row.get(2)?,
+        created: row.get(5).unwrap_or_default(),
+        objects: HashMap::new(),
+        properties: HashMap::new(),
+        latitude: row.get(3).unwrap_or(0.0),
+        longitude: row.get(4).unwrap_or(0.0),
+        favorite: row.get(6).unwrap_or(false),
+        indexed: row.get(7).unwrap_or(0),
+        hash: row.get(8).unwrap_or_default(),
+        created_offset: row.get(9).unwrap_or_default(),
+        rating: row.get(10).unwrap_or_default(),
+        flag: Flag::from_value(row.get(11).unwrap_or_default()),
+        label: row
+            .get::<_, Option<String>>(12)
+            .unwrap_or_default()
+            .as_deref()
+            .and_then(ColorLabel::parse),
+        media: None,
+        xmp: None,
+    })
+}
+
+/// Reads the `rating, flag, label, culled` columns starting at `first`.
+fn culling_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Culling> {
+    let label: Option<String> = row.get(first + 2)?;
+    Ok(Culling {
+        rating: row.get(first)?,
+        flag: Flag::from_value(row.get(first + 1)?),
+        label: label.as_deref().and_then(ColorLabel::parse),
+        culled: row.get(first + 3)?,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct Photo {
+    pub id: String,
+    pub location: String,
+    pub encoded: String,
+    pub created: String,
+    pub objects: HashMap<String, f64>,
+    pub properties: HashMap<String, String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub favorite: bool,
     pub indexed: i32, // 0: new, 1: metadata only, 2: fully processed
+    #[serde(default)]
+    pub hash: Option<String>, // SHA-256 of the file contents, hex encoded
+    #[serde(default)]
+    pub created_offset: Option<i32>, // Seconds east of UTC where `created` was captured
+    #[serde(default)]
+    pub rating: u8, // 0-5 stars
+    #[serde(default)]
+    pub flag: Flag,
+    #[serde(default)]
+    pub label: Option<ColorLabel>,
+    #[serde(skip)]
+    pub media: Option<MediaInfo>, // Read by the scanner and stored with the photo, never listed
+    #[serde(skip)]
+    pub xmp: Option<Xmp>, // Sidecar or embedded XMP read by the scanner, applied when stored
+}
+
+/// A photo's file as the library knows it, for finding where it went once it is gone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotoFile {
+    pub id: String,
+    pub location: String,
+    pub hash: Option<String>,
+    /// `None` until the file has been read once since fingerprints were introduced.
+    pub fingerprint: Option<Fingerprint>,
+}
+
+/// A file a scan skipped for holding the same content as the photo with `hash`, with its
+/// size and modification time then; `None` for copies recorded before those were kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedCopy {
+    pub location: String,
+    pub hash: String,
+    pub size: Option<u64>,
+    /// Unix seconds.
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissingPhoto {
+    pub id: String,
+    pub location: String,
+    /// When a scan first found the file gone, ISO-8601 UTC.
+    pub since: String,
+}
+
+/// Photos in one directory, year, camera model or file type. `key` is `None` for photos where
+/// it is not known, e.g. the year of a photo without a capture time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageGroup {
+    pub key: Option<String>,
+    pub count: i64,
+    pub bytes: i64,
+}
+
+/// Photos by how far the ML worker has got with them. `failed` photos could not be decoded;
+/// `without_faces` and `without_tags` count indexed photos it found nothing in. Videos are
+/// never decoded, so they count toward neither.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IndexCoverage {
+    pub total: i64,
+    pub indexed: i64,
+    pub pending: i64,
+    pub failed: i64,
+    pub without_faces: i64,
+    pub without_tags: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: i64,
+}
+
+/// How finely `Database::timeline` groups photos by the local day they were taken.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Year,
+    Month,
+    Day,
+}
+
+impl Granularity {
+    /// The bucket key over `photo p`: `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+    fn key(self) -> &'static str {
+        match self {
+            Granularity::Year => "substr(p.taken_day, 1, 4)",
+            Granularity::Month => "substr(p.taken_day, 1, 7)",
+            Granularity::Day => "p.taken_day",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelineBucket {
+    pub key: String,
+    pub photos: usize,
+    pub videos: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelinePosition {
+    /// Number of photos listed before the bucket's first photo.
+    pub position: usize,
+    /// `Page::after` cursor that lists from the bucket's first photo, `None` at the start.
+    pub cursor: Option<String>,
+}
+
+/// What the photo grid can be sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    CaptureDate,
+    ImportDate,
+    FileName,
+    FileSize,
+    Rating,
+}
+
+impl SortKey {
+    /// The value sorted on, over `photo p`. None of them is ever NULL, which keyset
+    /// comparisons rely on.
+    fn expr(self) -> &'static str {
+        match self {
+            SortKey::CaptureDate => "IFNULL(p.created, '')",
+            SortKey::ImportDate => "IFNULL(p.imported, '')",
+            SortKey::FileName => "p.file_name",
+            SortKey::FileSize => "IFNULL(p.file_size, 0)",
+            SortKey::Rating => "p.rating",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub struct Sort {
+    pub key: SortKey,
+    #[serde(default)]
+    pub ascending: bool,
+}
+
+/// Whether a photo was picked or rejected while culling, stored in `photo.flag` as 1 or -1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    Rejected,
+    #[default]
+    None,
+    Picked,
+}
+
+impl Flag {
+    fn value(self) -> i64 {
+        match self {
+            Flag::Rejected => -1,
+            Flag::None => 0,
+            Flag::Picked => 1,
+        }
+    }
+
+    fn from_value(value: i64) -> Flag {
+        match value {
+            v if v < 0 => Flag::Rejected,
+            0 => Flag::None,
+            _ => Flag::Picked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorLabel {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl ColorLabel {
+    pub const ALL: [ColorLabel; 5] = [
+        ColorLabel::Red,
+        ColorLabel::Yellow,
+        ColorLabel::Green,
+        ColorLabel::Blue,
+        ColorLabel::Purple,
+    ];
+
+    /// The name stored in `photo.label`, as serialized.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorLabel::Red => "red",
+            ColorLabel::Yellow => "yellow",
+            ColorLabel::Green => "green",
+            ColorLabel::Blue => "blue",
+            ColorLabel::Purple => "purple",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ColorLabel> {
+        Self::ALL.into_iter().find(|label| label.as_str() == name)
+    }
+}
+
+/// A photo's rating, flag and color label, with when any of them last changed, as sent to
+/// peers. The most recent change wins when both devices culled the same photo.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, serde::Deserialize)]
+pub struct Culling {
+    pub rating: u8,
+    pub flag: Flag,
+    pub label: Option<ColorLabel>,
+    pub culled: Option<String>,
+}
+
+/// A photo's culling as sent to peers alongside the manifest, matched by id or content hash.
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct PhotoCulling {
+    pub id: String,
+    pub hash: Option<String>,
+    pub culling: Culling,
+}
+
+/// What peers that send no culling list carry in their manifest.
+impl From<&PhotoSyncInfo> for PhotoCulling {
+    fn from(photo: &PhotoSyncInfo) -> Self {
+        Self {
+            id: photo.id.clone(),
+            hash: photo.hash.clone(),
+            culling: photo.culling.clone(),
+        }
+    }
+}
+
+/// Narrows the grid by culling state. Rejected photos are hidden unless `show_rejected` is
+/// set or `flag` asks for them.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct CullingFilter {
+    pub min_rating: Option<u8>,
+    pub flag: Option<Flag>,
+    pub label: Option<ColorLabel>,
+    pub show_rejected: bool,
+}
+
+/// Which photos the grid shows: those matching a search box query (see `query::parse`),
+/// optionally narrowed to favorites, videos, one album, one event, one map cluster or by
+/// culling state.
+#[derive(Debug, Clone, Default)]
+pub struct PhotoFilter {
+    pub query: String,
+    pub favorites_only: bool,
+    pub videos_only: bool,
+    pub album_id: Option<String>,
+    pub event_id: Option<String>,
+    pub map_cell: Option<MapCell>,
+    pub culling: CullingFilter,
+}
+
+/// Cells per side of a 256-pixel map tile, so a cluster spans about 64 pixels on screen.
+const MAP_CELLS_PER_TILE: f64 = 4.0;
+/// Deepest zoom level photos are clustered at; closer in, they stay grouped as at this one.
+const MAX_MAP_ZOOM: u8 = 22;
+
+/// A latitude/longitude box on the map. `west` greater than `east` wraps across the
+/// antimeridian, and a box wider than the world covers every longitude.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub struct Bounds {
+    pub north: f64,
+    pub south: f64,
+    pub east: f64,
+    pub west: f64,
+}
+
+impl Bounds {
+    /// The box's longitudes as one or two ranges within -180..=180, split at the antimeridian.
+    fn spans(&self) -> Vec<(f64, f64)> {
+        if self.east - self.west >= 360.0 {
+            return vec![(-180.0, 180.0)];
+        }
+        let wrap = |longitude: f64| (longitude + 180.0).rem_euclid(360.0) - 180.0;
+        let (west, east) = (wrap(self.west), wrap(self.east));
+        match west <= east {
+            true => vec![(west, east)],
+            false => vec![(west, 180.0), (-180.0, east)],
+        }
+    }
+}
+
+/// One cell of the grid photos are clustered on at a zoom level, counted in columns from
+/// longitude -180 and rows from latitude -90. Its id reads `zoom/column/row`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapCell {
+    pub zoom: u8,
+    pub column: i64,
+    pub row: i64,
+}
+
+impl MapCell {
+    pub fn parse(id: &str) -> Option<MapCell> {
+        let parts: Vec<&str> = id.split('/').collect();
+        let [zoom, column, row] = parts[..] else {
+            return None;
+        };
+        Some(MapCell {
+            zoom: zoom.parse().ok().filter(|zoom| *zoom <= MAX_MAP_ZOOM)?,
+            column: column.parse().ok()?,
+            row: row.parse().ok()?,
+        })
+    }
+
+    /// Side of a cell at `zoom`, in degrees.
+    fn size(zoom: u8) -> f64 {
+        360.0 / (MAP_CELLS_PER_TILE * f64::from(1u32 << zoom.min(MAX_MAP_ZOOM)))
+    }
+
+    /// Condition on `photo p` matching the photos in this cell. The R*Tree narrows them down
+    /// before the exact cell is checked the way `map_clusters` assigns it.
+    fn to_sql(&self, params: &mut SqlParams) -> String {
+        let size = Self::size(self.zoom);
+        let (south, west) = (
+            self.row as f64 * size - 90.0,
+            self.column as f64 * size - 180.0,
+        );
+        let (south, north) = (params.bind(south), params.bind(south + size));
+        let (west, east) = (params.bind(west), params.bind(west + size));
+        let (column, row) = map_cell_columns(params.bind(size));
+        format!(
+            "p.rowid IN (SELECT id FROM photo_location WHERE max_latitude >= ?{south} AND min_latitude <= ?{north} AND max_longitude >= ?{west} AND min_longitude <= ?{east})
+             AND {column} = ?{} AND {row} = ?{}",
+            params.bind(self.column),
+            params.bind(self.row)
+        )
+    }
+}
+
+/// Expressions for the grid column and row of `photo p` with cells of the size bound to
+/// `?size`.
+fn map_cell_columns(size: usize) -> (String, String) {
+    (
+        format!("CAST((p.longitude + 180) / ?{size} AS INTEGER)"),
+        format!("CAST((p.latitude + 90) / ?{size} AS INTEGER)"),
+    )
+}
+
+/// Geotagged photos grouped for the map: how many fall in one grid cell, their average
+/// position and the most recent of them to show.
+#[derive(Debug, Clone, Serialize)]
+pub struct MapCluster {
+    pub id: String, // The cell's `MapCell` id
+    pub latitude: f64,
+    pub longitude: f64,
+    pub count: i64,
+    pub photo_id: String,
+    pub location: String,
+}
+
+/// Which page of photos `Database::list_photos` returns: up to `limit` photos following the
+/// `after` cursor from a previous page, or from the start. `sort` overrides the view's own
+/// order (date, relevance or album position).
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub sort: Option<Sort>,
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PhotoPage {
+    pub photos: Vec<Photo>,
+    /// Cursor for the following page, `None` on the last one.
+    pub next: Option<String>,
+}
+
+/// One column of the order the grid lists photos in.
+struct SortTerm {
+    expr: String,
+    descending: bool,
+}
+
+impl SortTerm {
+    fn new(expr: &str, descending: bool) -> Self {
+        Self {
+            expr: expr.to_string(),
+            descending,
+        }
+    }
+}
+
+fn order_by(order: &[SortTerm]) -> String {
+    order
+        .iter()
+        .map(|term| match term.descending {
+            true => format!("{} DESC", term.expr),
+            false => term.expr.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn sort_keys(order: &[SortTerm]) -> String {
+    order
+        .iter()
+        .map(|term| term.expr.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Matches the photos that come after the one whose sort keys are `after`. Because every
+/// order ends with the unique photo id, no photo is skipped or repeated between pages, even
+/// when photos are added or removed in the meantime. Orders in a single direction compare
+/// row values, which SQLite can answer from the sort indexes.
+fn keyset_filter(
+    order: &[SortTerm],
+    after: Vec<rusqlite::types::Value>,
+    params: &mut SqlParams,
+) -> String {
+    let placeholders: Vec<String> = after
+        .into_iter()
+        .map(|value| format!("?{}", params.bind(value)))
+        .collect();
+    let descending = order[0].descending;
+    if order.iter().all(|term| term.descending == descending) {
+        let op = if descending { "<" } else { ">" };
+        return format!("({}) {op} ({})", sort_keys(order), placeholders.join(", "));
+    }
+    let mut filter = String::new();
+    for (term, placeholder) in order.iter().zip(&placeholders).rev() {
+        let op = if term.descending { "<" } else { ">" };
+        filter = match filter.is_empty() {
+            true => format!("{} {op} {placeholder}", term.expr),
+            false => format!(
+                "({expr} {op} {placeholder} OR ({expr} = {placeholder} AND {filter}))",
+                expr = term.expr
+            ),
+        };
+    }
+    filter
+}
+
+/// Page cursors are the last photo's sort keys as a JSON array. Clients treat them as opaque.
+fn encode_cursor(keys: &[rusqlite::types::Value]) -> String {
+    use rusqlite::types::Value;
+    let keys: Vec<serde_json::Value> = keys
+        .iter()
+        .map(|key| match key {
+            Value::Integer(i) => (*i).into(),
+            Value::Real(f) => (*f).into(),
+            Value::Text(s) => s.as_str().into(),
+            _ => serde_json::Value::Null,
+        })
+        .collect();
+    serde_json::to_string(&keys).unwrap_or_default()
+}
+
+fn decode_cursor(cursor: &str, len: usize) -> Result<Vec<rusqlite::types::Value>, QueryError> {
+    use rusqlite::types::Value;
+    let invalid = || QueryError {
+        message: "This page cursor belongs to a different listing".to_string(),
+        start: 0,
+        end: 0,
+    };
+    let keys: Vec<serde_json::Value> = serde_json::from_str(cursor).map_err(|_| invalid())?;
+    if keys.len() != len {
+        return Err(invalid());
+    }
+    keys.into_iter()
+        .map(|key| match key {
+            serde_json::Value::String(s) => Some(Value::Text(s)),
+            serde_json::Value::Number(n) => n
+                .as_i64()
+                .map(Value::Integer)
+                .or(n.as_f64().map(Value::Real)),
+            _ => None,
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)
+}
+
+/// Which copy of a near-duplicate group to keep when resolving it.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepStrategy {
+    #[default]
+    Resolution,
+    Oldest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateCandidate {
+    #[serde(flatten)]
+    pub photo: Photo,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub cover_photo_id: Option<String>,
+    pub cover_location: Option<String>,
+    pub photo_count: i64,
+    pub created: String,
+    pub updated: String,
+}
+
+/// A user tag. `path` is the full path from the top level, e.g. `Places/Portugal/Lisbon`,
+/// and `photo_count` counts the photos tagged with it directly.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tag {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub name: String,
+    pub path: String,
+    pub photo_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub title: String,
+    pub start: String,
+    pub end: String,
+    pub latitude: Option<f64>, // Centroid of the geotagged photos, if any
+    pub longitude: Option<f64>,
+    pub photo_count: i64,
+    pub cover_photo_id: Option<String>,
+    pub cover_location: Option<String>,
+}
+
+/// Place names for a position, from the nearest known city.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Place {
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub country: Option<String>,
+}
+
+/// A photo as event clustering sees it: when it was taken, in seconds since the epoch, and
+/// where, if known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventPhoto {
+    pub id: String,
+    pub time: i64,
+    pub position: Option<(f64, f64)>,
+}
+
+/// An event as built by `events::update`, before it is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewEvent {
+    pub id: String,
+    pub title: String,
+    pub start: i64,
+    pub end: i64,
+    pub centroid: Option<(f64, f64)>,
+    pub photo_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Memory {
+    pub id: String,
+    pub kind: String, // One of the kinds in `memories`
+    pub title: String,
+    pub cover_photo_id: Option<String>,
+    pub cover_location: Option<String>,
+    pub photo_count: i64,
+    pub created: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SmartAlbum {
+    pub id: String,
+    pub name: String,
+    pub rules: Rule,
+    pub photo_count: i64,
+    pub cover_location: Option<String>,
+    pub created: String,
+    pub updated: String,
+}
+
+/// A photo's file on its way into or out of the trash. `trash_location` is `None` for a
+/// photo trashed without its file.
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    pub id: String,
+    pub location: String,
+    pub trash_location: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedPhoto {
+    #[serde(flatten)]
+    pub photo: Photo,
+    pub trashed: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -946,6 +4157,32 @@ pub struct Face {
     pub encoded: String,
     pub embedding: Vec<f32>,
     pub person_id: Option<String>,
+    pub area: Option<FaceArea>,
+}
+
+/// Where a face is in its photo, as fractions of the image width and height from the
+/// top-left corner. Faces detected before this was kept have none.
+#[derive(Debug, Clone, PartialEq, Serialize, serde::Deserialize)]
+pub struct FaceArea {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl FaceArea {
+    /// Intersection over union of two areas, from 0 for disjoint areas to 1 for the same.
+    pub fn overlap(&self, other: &FaceArea) -> f32 {
+        let width = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let height = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        let intersection = width.max(0.0) * height.max(0.0);
+        let union = self.width * self.height + other.width * other.height - intersection;
+        if union > 0.0 {
+            intersection / union
+        } else {
+            0.0
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -963,9 +4200,33 @@ pub struct PersonWithFace {
 pub struct LogEntry {
     pub timestamp: String,
     pub level: String,
+    pub module: Option<String>,
+    pub span: Option<String>,
+    pub message: String,
+}
+
+/// A `tracing` event on its way into `logs`.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub level: String,
+    pub module: String,
+    pub span: String,
     pub message: String,
 }
 
+/// Narrows `get_logs` to entries at `level` or more severe, from modules whose path contains
+/// `module`, logged between `from` and `to`.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub level: Option<String>,
+    pub module: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Log levels, most severe first.
+const LOG_LEVELS: &[&str] = &["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
 #[derive(Debug, Clone, Serialize)]
 pub struct DeviceInfo {
     pub id: String,
@@ -977,3 +4238,943 @@ pub struct DeviceInfo {
     pub video_count: i64,
     pub os: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_info(id: &str, hash: Option<&str>) -> PhotoSyncInfo {
+        PhotoSyncInfo {
+            id: id.to_string(),
+            location: format!("/peer/{id}.jpg"),
+            created: String::new(),
+            latitude: None,
+            longitude: None,
+            objects: "[]".to_string(),
+            faces: "[]".to_string(),
+            hash: hash.map(str::to_string),
+            albums: String::new(),
+            culling: Culling::default(),
+        }
+    }
+
+    #[test]
+    fn manifest_diff_uses_content_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        database.import_photo("local", "/a.jpg", "", None, None, "[]", "[]", "");
+        database.set_photo_hash("local", "aaaa");
+
+        let manifest = vec![
+            // Same content under a different id: already here
+            sync_info("peer-1", Some("aaaa")),
+            // Two copies of one new file: requested once
+            sync_info("peer-2", Some("bbbb")),
+            sync_info("peer-3", Some("bbbb")),
+            // Older peers send no hash and fall back to the id
+            sync_info("local", None),
+            sync_info("peer-4", None),
+        ];
+        assert_eq!(
+            database.missing_from_manifest(&manifest),
+            vec!["peer-2".to_string(), "peer-4".to_string()]
+        );
+    }
+
+    #[test]
+    fn removing_a_folder_spares_its_siblings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        for (id, location) in [
+            ("a", "/pics/2023/a.jpg"),
+            ("b", "/pics/2023/trip/b.jpg"),
+            ("c", "C:\\pics\\2023\\c.jpg"),
+            ("d", "/pics/2023-old/d.jpg"),
+        ] {
+            database.import_photo(id, location, "", None, None, "[]", "[]", "");
+        }
+        database.remove_directory_full("/pics/2023/").unwrap();
+        database.remove_directory_full("C:\\pics\\2023").unwrap();
+        let left: Vec<bool> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|id| database.get_photo(id).is_some())
+            .collect();
+        assert_eq!(left, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn skipped_copies_stay_out_of_scans() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        database.import_photo("a", "/pics/a.jpg", "", None, None, "[]", "[]", "");
+        database.set_photo_hash("a", "aaaa");
+        database.record_duplicate(&SkippedCopy {
+            location: "/pics/copy of a.jpg".to_string(),
+            hash: "aaaa".to_string(),
+            size: Some(10),
+            modified: Some(100),
+        });
+        let unchanged = |copy: &SkippedCopy| copy.size != Some(10);
+        let paths = ["/pics/a.jpg", "/pics/copy of a.jpg", "/pics/b.jpg"].map(String::from);
+        assert_eq!(
+            database.filter_new_paths(&paths, unchanged),
+            vec!["/pics/b.jpg"]
+        );
+        // A copy that changed since is scanned again
+        assert_eq!(
+            database.filter_new_paths(&paths, |_| true),
+            vec!["/pics/copy of a.jpg", "/pics/b.jpg"]
+        );
+
+        // Copies follow the library to another drive
+        database.remap_paths("/pics", "/mnt/pics").unwrap();
+        let moved = ["/mnt/pics/copy of a.jpg".to_string()];
+        assert!(database.filter_new_paths(&moved, unchanged).is_empty());
+
+        // Once the photo is missing or gone for good, its copy is scanned again
+        database.set_missing(&["a".to_string()]);
+        assert_eq!(database.filter_new_paths(&moved, unchanged), moved);
+        database.relink_photo("a", "/mnt/pics/a.jpg");
+        assert!(database.filter_new_paths(&moved, unchanged).is_empty());
+        database.delete_photo("a").unwrap();
+        assert_eq!(database.filter_new_paths(&moved, unchanged), moved);
+    }
+
+    #[test]
+    fn albums_keep_order_and_follow_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        for id in ["a", "b", "c"] {
+            database.import_photo(id, &format!("/{id}.jpg"), "", None, None, "[]", "[]", "");
+        }
+        let album = database.create_album("Trip", "").unwrap();
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        database
+            .add_photos_to_album(&album.id, &ids(&["a", "b", "c", "a"]))
+            .unwrap();
+        database.reorder_album(&album.id, &ids(&["c"])).unwrap();
+        database.set_album_cover(&album.id, Some("b")).unwrap();
+        assert!(database.set_album_cover(&album.id, Some("x")).is_err());
+
+        let listed: Vec<String> = database
+            .list_photos(
+                &PhotoFilter {
+                    album_id: Some(album.id.clone()),
+                    ..Default::default()
+                },
+                &Page {
+                    limit: 10,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .photos
+            .into_iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(listed, ids(&["c", "a", "b"]));
+
+        // Deleting the cover photo drops it from the album and the cover falls back
+        database.delete_photo("b").unwrap();
+        let album = database.get_album(&album.id).unwrap();
+        assert_eq!(album.photo_count, 2);
+        assert_eq!(album.cover_photo_id.as_deref(), Some("c"));
+
+        // A peer holding the same photos, one of them by content hash only, gets the album
+        let peer_dir = tempfile::tempdir().unwrap();
+        let mut peer = Database::new(peer_dir.path().to_str().unwrap());
+        peer.import_photo("a", "/a.jpg", "", None, None, "[]", "[]", "");
+        peer.import_photo("peer-c", "/c.jpg", "", None, None, "[]", "[]", "");
+        peer.set_photo_hash("peer-c", "cccc");
+        database.set_photo_hash("c", "cccc");
+        let manifest = database.get_album_sync();
+        assert_eq!(manifest.len(), 2);
+        peer.merge_albums(&manifest, &[]).unwrap();
+        peer.merge_albums(&manifest, &[]).unwrap();
+        let merged = peer.get_album(&album.id).unwrap();
+        assert_eq!((merged.name.as_str(), merged.photo_count), ("Trip", 2));
+
+        // A removed photo is not put back by the peer's older membership, and leaves the
+        // peer's album too
+        let stale = peer.get_album_sync();
+        database
+            .remove_photos_from_album(&album.id, &ids(&["c"]))
+            .unwrap();
+        database.merge_albums(&stale, &[]).unwrap();
+        assert_eq!(database.get_album(&album.id).unwrap().photo_count, 1);
+        let removals = database.get_album_removals();
+        peer.merge_albums(&database.get_album_sync(), &removals)
+            .unwrap();
+        assert_eq!(peer.get_album(&album.id).unwrap().photo_count, 1);
+
+        // Adding it back afterwards wins over the removal
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        peer.add_photos_to_album(&album.id, &ids(&["peer-c"]))
+            .unwrap();
+        database
+            .merge_albums(&peer.get_album_sync(), &peer.get_album_removals())
+            .unwrap();
+        assert_eq!(database.get_album(&album.id).unwrap().photo_count, 2);
+
+        // A deleted album is deleted on the peer and not recreated from its older copy
+        let stale = peer.get_album_sync();
+        database.delete_album(&album.id).unwrap();
+        database.merge_albums(&stale, &[]).unwrap();
+        assert!(database.get_album(&album.id).is_none());
+        peer.merge_albums(&[], &database.get_album_removals())
+            .unwrap();
+        assert!(peer.get_album(&album.id).is_none());
+    }
+
+    #[test]
+    fn tags_form_a_tree_apart_from_ml_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        for id in ["a", "b"] {
+            database.import_photo(id, &format!("/{id}.jpg"), "", None, None, "[]", "[]", "");
+        }
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        database
+            .assign_tags(&ids(&["a", "b"]), &ids(&[" Places / Portugal/Lisbon"]))
+            .unwrap();
+        database
+            .assign_tags(
+                &ids(&["b"]),
+                &ids(&["places/Spain", "Projects/Kitchen remodel"]),
+            )
+            .unwrap();
+        let paths = |database: &Database| -> Vec<String> {
+            database.list_tags().into_iter().map(|t| t.path).collect()
+        };
+        assert_eq!(
+            paths(&database),
+            ids(&[
+                "Places",
+                "Places/Portugal",
+                "Places/Portugal/Lisbon",
+                "Places/Spain",
+                "Projects",
+                "Projects/Kitchen remodel"
+            ])
+        );
+        let search = |database: &Database, query: &str| -> Vec<String> {
+            let filter = PhotoFilter {
+                query: query.to_string(),
+                ..Default::default()
+            };
+            let page = Page {
+                limit: 10,
+                ..Default::default()
+            };
+            let mut found: Vec<String> = database
+                .list_photos(&filter, &page)
+                .unwrap()
+                .photos
+                .into_iter()
+                .map(|p| p.id)
+                .collect();
+            found.sort();
+            found
+        };
+        // A parent finds the photos tagged anywhere below it, by path or by name
+        assert_eq!(search(&database, "keyword:places"), ids(&["a", "b"]));
+        assert_eq!(search(&database, "keyword:Spain"), ids(&["b"]));
+        assert_eq!(search(&database, "-keyword:\"Projects\""), ids(&["a"]));
+
+        // Re-indexing the ML tags leaves the user's tags alone
+        let cat = r#"[{"class":"a cat","probability":"0.4"}]"#;
+        database.import_photo("a", "/a.jpg", "", None, None, cat, "[]", "");
+        assert_eq!(search(&database, "keyword:Lisbon"), ids(&["a", "b"]));
+        assert_eq!(search(&database, "tag:cat"), ids(&["a"]));
+
+        // Renaming and moving take the children along, and the search index follows
+        let places = database.list_tags()[0].clone();
+        database.rename_tag(places.id, "Travel").unwrap();
+        assert_eq!(search(&database, "Travel"), ids(&["a", "b"]));
+        let lisbon = database
+            .list_tags()
+            .into_iter()
+            .find(|t| t.name == "Lisbon")
+            .unwrap();
+        assert_eq!(lisbon.path, "Travel/Portugal/Lisbon");
+        assert!(database.move_tag(places.id, Some(lisbon.id)).is_err());
+        assert!(database.rename_tag(lisbon.id, "a/b").is_err());
+        let lisbon = database.move_tag(lisbon.id, None).unwrap();
+        assert_eq!(lisbon.path, "Lisbon");
+        assert_eq!(lisbon.photo_count, 2);
+
+        // Deleting a tag removes its subtree and untags the photos
+        database.delete_tag(places.id);
+        assert_eq!(
+            paths(&database),
+            ids(&["Lisbon", "Projects", "Projects/Kitchen remodel"])
+        );
+        assert!(search(&database, "keyword:Spain").is_empty());
+    }
+
+    #[test]
+    fn search_index_follows_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        let beach = r#"[{"class":"a beach","probability":"0.4"}]"#;
+        database.import_photo("a", "/pics/beach/a.jpg", "", None, None, beach, "[]", "");
+        database.import_photo(
+            "b",
+            "/pics/IMG_beach_party.jpg",
+            "",
+            None,
+            None,
+            "[]",
+            "[]",
+            "",
+        );
+        database.import_photo("c", "/pics/c.jpg", "", None, None, "[]", "[]", "");
+        let search = |query: &str| -> Vec<String> {
+            database
+                .list_photos(
+                    &PhotoFilter {
+                        query: query.to_string(),
+                        ..Default::default()
+                    },
+                    &Page {
+                        limit: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .photos
+                .into_iter()
+                .map(|p| p.id)
+                .collect()
+        };
+
+        // Prefix matching across folders, filenames and tags; the filename match ranks first
+        assert_eq!(search("bea"), vec!["b", "a"]);
+        assert_eq!(search("beach party"), vec!["b"]);
+        assert_eq!(database.list_objects("bea"), vec!["a beach"]);
+
+        // Tags written after the photo and renamed people are picked up by the triggers
+        database.import_photo("c", "/pics/c.jpg", "", None, None, beach, "[]", "");
+        assert_eq!(search("beach").len(), 3);
+        let face = r#"[{"face_id":"f","crop_path":"","encoded":"","person_id":"p"}]"#;
+        database.import_photo("c", "/pics/c.jpg", "", None, None, "[]", face, "");
+        let _ = database
+            .connection
+            .execute("INSERT INTO people (id, name) VALUES ('p', 'Alice')", []);
+        database.rename_person("p", "Alicia");
+        assert_eq!(search("alic"), vec!["c"]);
+        assert_eq!(search("beach"), vec!["b", "a"]);
+        // People added after their faces, removed ones and ML tags changed in place
+        let face = r#"[{"face_id":"g","crop_path":"","encoded":"","person_id":"q"}]"#;
+        database.import_photo(
+            "b",
+            "/pics/IMG_beach_party.jpg",
+            "",
+            None,
+            None,
+            "[]",
+            face,
+            "",
+        );
+        let _ = database
+            .connection
+            .execute("INSERT INTO people (id, name) VALUES ('q', 'Bruno')", []);
+        assert_eq!(search("bruno"), vec!["b"]);
+        let _ = database
+            .connection
+            .execute("DELETE FROM people WHERE id = 'p'", []);
+        assert!(search("alicia").is_empty());
+        let _ = database.connection.execute(
+            "UPDATE object SET class = 'a sunset' WHERE photo_id = 'a'",
+            [],
+        );
+        assert_eq!(search("sunset"), vec!["a"]);
+        // Rebuilt after the index lost track
+        let _ = database.connection.execute("DELETE FROM photo_search", []);
+        crate::migrations::rebuild_search_index(&database.connection).unwrap();
+        assert_eq!(search("bruno"), vec!["b"]);
+
+        let _ = database.connection.execute(
+            "UPDATE photo SET trashed = CURRENT_TIMESTAMP WHERE id = 'a'",
+            [],
+        );
+        assert_eq!(search("beach"), vec!["b"]);
+        // Punctuation alone is not a search
+        assert_eq!(search("- !").len(), 2);
+    }
+
+    #[test]
+    fn query_filters_compile_to_sql() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        let tags = |tag: &str| format!(r#"[{{"class":"{tag}","probability":"0.4"}}]"#);
+        database.import_photo(
+            "a",
+            "/pics/Holidays/a.jpg",
+            "2022-07-01T10:00:00Z",
+            None,
+            None,
+            &tags("a beach"),
+            "[]",
+            "",
+        );
+        database.import_photo(
+            "b",
+            "/pics/b.mp4",
+            "2023:02:01 10:00:00",
+            None,
+            None,
+            &tags("a beach"),
+            "[]",
+            "",
+        );
+        database.import_photo(
+            "c",
+            "/pics/c.jpg",
+            "2021-07-01T10:00:00Z",
+            None,
+            None,
+            &tags("a screenshot"),
+            "[]",
+            "",
+        );
+        database.toggle_favorite("a");
+        let camera = |make: &str, model: &str, aperture: f64| MediaInfo {
+            camera_make: Some(make.to_string()),
+            camera_model: Some(model.to_string()),
+            aperture: Some(aperture),
+            ..Default::default()
+        };
+        database.set_media_info("a", &camera("FUJIFILM", "X100V", 2.0));
+        database.set_media_info("c", &camera("Google", "Pixel 7", 1.85));
+        let album = database.create_album("Best of", "").unwrap();
+        database
+            .add_photos_to_album(&album.id, &["b".to_string()])
+            .unwrap();
+        let search = |query: &str| -> Vec<String> {
+            database
+                .list_photos(
+                    &PhotoFilter {
+                        query: query.to_string(),
+                        ..Default::default()
+                    },
+                    &Page {
+                        limit: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .photos
+                .into_iter()
+                .map(|p| p.id)
+                .collect()
+        };
+
+        assert_eq!(search("tag:beach after:2022-06 before:2023"), vec!["a"]);
+        // Dates are the local day, not the UTC one
+        database.set_capture_time("c", "2021-06-30T23:30:00Z", 3600);
+        assert_eq!(search("tag:screenshot after:2021-07-01"), vec!["c"]);
+        assert_eq!(search("-tag:screenshot type:photo"), vec!["a"]);
+        assert_eq!(search("fav:no -camera:pixel"), vec!["b"]);
+        assert_eq!(search(r#"camera:"Google Pixel""#), vec!["c"]);
+        assert_eq!(search("camera:X100V f:2"), vec!["a"]);
+        let facet = |facet: Facet, query: &str| -> Vec<(String, i64)> {
+            database
+                .facet(facet, query)
+                .unwrap()
+                .into_iter()
+                .map(|f| (f.value, f.count))
+                .collect()
+        };
+        assert_eq!(
+            facet(Facet::Camera, ""),
+            vec![
+                ("FUJIFILM X100V".to_string(), 1),
+                ("Google Pixel 7".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            facet(Facet::Aperture, "camera:pixel"),
+            vec![("1.9".to_string(), 1)]
+        );
+        assert_eq!(search("in:Holidays"), vec!["a"]);
+        assert_eq!(search(r#"in:"best of" beach"#), vec!["b"]);
+        // Names match whole, with no wildcards
+        assert!(search("in:best").is_empty());
+        assert!(search("in:Holi%").is_empty());
+        assert!(search("in:H_lidays").is_empty());
+        assert_eq!(search("beach -b.mp4"), vec!["a"]);
+        assert!(database
+            .list_photos(
+                &PhotoFilter {
+                    query: "after:last-week".to_string(),
+                    ..Default::default()
+                },
+                &Page {
+                    limit: 10,
+                    ..Default::default()
+                }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn timeline_buckets_by_local_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        for (id, location, created) in [
+            ("legacy", "/pics/legacy.jpg", "2023:02:01 10:00:00"),
+            ("new-year", "/pics/new-year.jpg", "2020-01-01T00:00:00Z"),
+            ("late", "/pics/late.jpg", "2019-03-31T23:30:00Z"),
+            ("clip", "/pics/clip.mp4", "2019-03-20T10:00:00Z"),
+            ("mid", "/pics/mid.jpg", "2019-03-15T10:00:00Z"),
+        ] {
+            database.import_photo(id, location, created, None, None, "[]", "[]", "");
+        }
+        // Taken just before midnight UTC, but already April where it was taken
+        database.set_capture_time("late", "2019-03-31T23:30:00Z", 3600);
+        // Times resolved elsewhere, as a peer sends them, are not resolved again
+        assert_eq!(
+            database.get_unresolved_capture_times("/pics"),
+            vec![("legacy".to_string(), "/pics/legacy.jpg".to_string())]
+        );
+
+        let bucket = |key: &str, photos, videos| TimelineBucket {
+            key: key.to_string(),
+            photos,
+            videos,
+        };
+        assert_eq!(
+            database
+                .timeline(Granularity::Month, &PhotoFilter::default())
+                .unwrap(),
+            vec![
+                bucket("2020-01", 1, 0),
+                bucket("2019-04", 1, 0),
+                bucket("2019-03", 1, 1),
+            ]
+        );
+        assert_eq!(
+            database
+                .timeline(
+                    Granularity::Year,
+                    &PhotoFilter {
+                        videos_only: true,
+                        ..Default::default()
+                    }
+                )
+                .unwrap(),
+            vec![bucket("2019", 0, 1)]
+        );
+
+        let position = |bucket: &str, videos_only| {
+            database
+                .timeline_position(
+                    bucket,
+                    &PhotoFilter {
+                        videos_only,
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .unwrap()
+                .map(|found| found.position)
+        };
+        assert_eq!(position("2019-04", false), Some(2));
+        assert_eq!(position("2019-03", false), Some(3));
+        assert_eq!(position("2019-03-15", false), Some(4));
+        assert_eq!(position("2019", true), Some(0));
+        assert_eq!(position("2018", false), None);
+        // Ranked search results resolve through the same order the grid uses
+        assert_eq!(
+            database
+                .timeline_position(
+                    "2019",
+                    &PhotoFilter {
+                        query: "mid".to_string(),
+                        ..Default::default()
+                    },
+                    None
+                )
+                .unwrap(),
+            Some(TimelinePosition {
+                position: 0,
+                cursor: None
+            })
+        );
+    }
+
+    #[test]
+    fn keyset_pages_survive_ties_and_inserts() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        let import = |id: &str, location: &str, created: &str| {
+            database.import_photo(id, location, created, None, None, "[]", "[]", "");
+        };
+        for id in ["a", "b", "c", "d", "e"] {
+            import(id, &format!("/pics/{id}.jpg"), "2020-01-01T00:00:00Z");
+        }
+        let list = |query: &str, sort: Option<Sort>, after: Option<String>| {
+            let page = Page {
+                sort,
+                after,
+                limit: 2,
+            };
+            let filter = PhotoFilter {
+                query: query.to_string(),
+                ..Default::default()
+            };
+            database.list_photos(&filter, &page)
+        };
+        let ids = |page: &PhotoPage| page.photos.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+
+        let first = list("", None, None).unwrap();
+        assert_eq!(ids(&first), vec!["e", "d"]);
+        // Taken at the same time as the rest, but ordered before the cursor: nothing shifts
+        import("f", "/pics/f.jpg", "2020-01-01T00:00:00Z");
+        let second = list("", None, first.next).unwrap();
+        assert_eq!(ids(&second), vec!["c", "b"]);
+        let last = list("", None, second.next).unwrap();
+        assert_eq!(ids(&last), vec!["a"]);
+        assert_eq!(last.next, None);
+
+        import("g", "C:\\Users\\me\\A.jpg", "2019-05-01T00:00:00Z");
+        let all = |query: &str, sort: Option<Sort>| {
+            let mut listed = Vec::new();
+            let mut after = None;
+            loop {
+                let page = list(query, sort, after).unwrap();
+                listed.extend(ids(&page));
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => return listed,
+                }
+            }
+        };
+        let by_name = Sort {
+            key: SortKey::FileName,
+            ascending: true,
+        };
+        // Names compare case-insensitively, so A.jpg ties with a.jpg and falls back to the id
+        assert_eq!(
+            all("", Some(by_name)),
+            vec!["a", "g", "b", "c", "d", "e", "f"]
+        );
+        // Relevance ranks ascending and dates descending, so pages use the expanded filter
+        let mut searched = all("pics", None);
+        searched.sort();
+        assert_eq!(searched, vec!["a", "b", "c", "d", "e", "f"]);
+
+        let found = database
+            .timeline_position("2019", &PhotoFilter::default(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.position, 6);
+        assert_eq!(ids(&list("", None, found.cursor).unwrap()), vec!["g"]);
+        assert!(list("", Some(by_name), Some("[1]".to_string())).is_err());
+    }
+
+    #[test]
+    fn map_clusters_follow_zoom_and_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        for (id, created, latitude, longitude) in [
+            ("a", "2023-01-01T10:00:00Z", 38.7223, -9.1393),
+            ("b", "2023-02-01T10:00:00Z", 38.7230, -9.1400),
+            ("c", "2023-03-01T10:00:00Z", 41.1579, -8.6291),
+            ("d", "2023-04-01T10:00:00Z", -17.8, 177.4),
+            ("e", "2023-05-01T10:00:00Z", -17.5, -179.9),
+            // No GPS data
+            ("f", "2023-06-01T10:00:00Z", 0.0, 0.0),
+        ] {
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                created,
+                Some(latitude),
+                Some(longitude),
+                "[]",
+                "[]",
+                "",
+            );
+        }
+        let clusters = |bounds: Bounds, zoom: u8| {
+            let mut clusters: Vec<(String, i64, String)> = database
+                .map_clusters(&bounds, zoom)
+                .into_iter()
+                .map(|c| (c.id, c.count, c.photo_id))
+                .collect();
+            clusters.sort();
+            clusters
+        };
+        let portugal = Bounds {
+            north: 42.0,
+            south: 37.0,
+            east: -6.0,
+            west: -10.0,
+        };
+        assert_eq!(
+            clusters(portugal, 2),
+            vec![("2/7/5".to_string(), 3, "c".to_string())]
+        );
+        assert_eq!(
+            clusters(portugal, 10),
+            vec![
+                ("10/1944/1464".to_string(), 2, "b".to_string()),
+                ("10/1949/1492".to_string(), 1, "c".to_string()),
+            ]
+        );
+        // Across the antimeridian
+        let fiji = Bounds {
+            north: -10.0,
+            south: -25.0,
+            east: -170.0,
+            west: 170.0,
+        };
+        assert_eq!(
+            clusters(fiji, 4),
+            vec![
+                ("4/0/12".to_string(), 1, "e".to_string()),
+                ("4/63/12".to_string(), 1, "d".to_string()),
+            ]
+        );
+        let world = Bounds {
+            north: 90.0,
+            south: -90.0,
+            east: 540.0,
+            west: -180.0,
+        };
+        let total: i64 = database
+            .map_clusters(&world, 0)
+            .iter()
+            .map(|c| c.count)
+            .sum();
+        assert_eq!(total, 5);
+
+        let filter = PhotoFilter {
+            map_cell: MapCell::parse("10/1944/1464"),
+            ..Default::default()
+        };
+        let page = |after| Page {
+            limit: 1,
+            after,
+            ..Default::default()
+        };
+        let first = database.list_photos(&filter, &page(None)).unwrap();
+        assert_eq!(first.photos[0].id, "b");
+        let second = database.list_photos(&filter, &page(first.next)).unwrap();
+        assert_eq!(second.photos[0].id, "a");
+        assert_eq!(second.next, None);
+
+        // Moving a photo moves it in the index
+        database
+            .connection
+            .execute(
+                "UPDATE photo SET latitude = 41.1579, longitude = -8.6291 WHERE id = 'b'",
+                [],
+            )
+            .unwrap();
+        assert_eq!(
+            clusters(portugal, 10),
+            vec![
+                ("10/1944/1464".to_string(), 1, "a".to_string()),
+                ("10/1949/1492".to_string(), 2, "c".to_string()),
+            ]
+        );
+        assert!(MapCell::parse("99/1/2").is_none());
+    }
+
+    #[test]
+    fn culling_filters_and_syncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        let peer_dir = tempfile::tempdir().unwrap();
+        let mut peer = Database::new(peer_dir.path().to_str().unwrap());
+        for id in ["a", "b", "c"] {
+            let created = format!("2023-01-0{}T10:00:00Z", id.as_bytes()[0] - b'a' + 1);
+            for db in [&database, &peer] {
+                db.import_photo(
+                    id,
+                    &format!("/{id}.jpg"),
+                    &created,
+                    None,
+                    None,
+                    "[]",
+                    "[]",
+                    "",
+                );
+            }
+        }
+        // Rated on the peer first, so the local rating of b is newer
+        peer.set_rating(&["b".to_string(), "c".to_string()], 1)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let ids = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(database.set_rating(&ids(&["a", "b", "x"]), 4), Ok(2));
+        assert!(database.set_rating(&ids(&["a"]), 6).is_err());
+        database.set_flag(&ids(&["c"]), Flag::Rejected).unwrap();
+        database.set_flag(&ids(&["a"]), Flag::Picked).unwrap();
+        database
+            .set_color_label(&ids(&["b"]), Some(ColorLabel::Red))
+            .unwrap();
+
+        let list = |db: &Database, culling: CullingFilter, sort: Option<Sort>| {
+            let filter = PhotoFilter {
+                culling,
+                ..Default::default()
+            };
+            let page = Page {
+                sort,
+                limit: 10,
+                ..Default::default()
+            };
+            let photos = db.list_photos(&filter, &page).unwrap().photos;
+            photos.into_iter().map(|p| p.id).collect::<Vec<_>>()
+        };
+        // Rejected photos are hidden unless asked for
+        assert_eq!(
+            list(&database, CullingFilter::default(), None),
+            vec!["b", "a"]
+        );
+        let rejected = CullingFilter {
+            flag: Some(Flag::Rejected),
+            ..Default::default()
+        };
+        assert_eq!(list(&database, rejected, None), vec!["c"]);
+        let all = CullingFilter {
+            show_rejected: true,
+            ..Default::default()
+        };
+        let by_rating = Sort {
+            key: SortKey::Rating,
+            ascending: true,
+        };
+        assert_eq!(list(&database, all, Some(by_rating)), vec!["c", "a", "b"]);
+        let red = CullingFilter {
+            label: Some(ColorLabel::Red),
+            ..Default::default()
+        };
+        assert_eq!(list(&database, red, None), vec!["b"]);
+        let starred = CullingFilter {
+            min_rating: Some(3),
+            flag: Some(Flag::Picked),
+            ..Default::default()
+        };
+        assert_eq!(list(&database, starred, None), vec!["a"]);
+
+        // The local side culled everything more recently than the peer. None of the photos
+        // are indexed, so they are missing from the manifest but not from the culling list.
+        assert!(database.get_photo_sync_info().is_empty());
+        let mut manifest = database.get_culling_sync();
+        manifest.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest[1].culling.label, Some(ColorLabel::Red));
+        peer.merge_culling(&manifest).unwrap();
+        for id in ["a", "b", "c"] {
+            let (local, remote) = (database.photo_culling(id), peer.photo_culling(id));
+            assert_eq!(local, remote);
+        }
+        // An older change from the peer does not undo the newer local one
+        let mut stale = PhotoCulling::from(&peer.get_photo_sync_info_by_id("b").unwrap());
+        stale.culling.rating = 2;
+        stale.culling.culled = Some("2000-01-01T00:00:00.000Z".to_string());
+        database.merge_culling(&[stale]).unwrap();
+        assert_eq!(database.photo_culling("b").rating, 4);
+    }
+
+    #[test]
+    fn logs_filter_and_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        let record = |level: &str, module: &str, message: &str| LogRecord {
+            level: level.to_string(),
+            module: module.to_string(),
+            span: "scan:folder{directory=/photos}".to_string(),
+            message: message.to_string(),
+        };
+        database.store_logs(&[
+            record("WARN", "siegu_lib::file", "unreadable"),
+            record("ERROR", "siegu_lib::ml", "model missing"),
+            record("INFO", "siegu_lib::file", "scanned"),
+        ]);
+        let messages = |filter: LogFilter| {
+            let logs = database.get_logs(&filter, 10);
+            logs.into_iter().map(|l| l.message).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(LogFilter::default()),
+            vec!["scanned", "model missing", "unreadable"]
+        );
+        let warnings = LogFilter {
+            level: Some("warn".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(messages(warnings), vec!["model missing", "unreadable"]);
+        let file = LogFilter {
+            module: Some("file".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(messages(file), vec!["scanned", "unreadable"]);
+        let future = LogFilter {
+            from: Some("2999-01-01T00:00:00".to_string()),
+            ..Default::default()
+        };
+        assert!(messages(future).is_empty());
+        let logs = database.get_logs(&LogFilter::default(), 1);
+        assert_eq!(
+            logs[0].span.as_deref(),
+            Some("scan:folder{directory=/photos}")
+        );
+
+        // Old entries go first, then the oldest until the rest fit
+        database
+            .connection
+            .execute(
+                "UPDATE logs SET timestamp = datetime('now', '-60 days') WHERE message = 'unreadable'",
+                (),
+            )
+            .unwrap();
+        assert_eq!(database.prune_logs(30, 1024), 1);
+        assert_eq!(database.prune_logs(30, 100), 1);
+        assert_eq!(messages(LogFilter::default()), vec!["scanned"]);
+    }
+
+    #[test]
+    fn missing_photos_are_reported_until_relinked() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        for id in ["a", "b"] {
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                "",
+                None,
+                None,
+                "[]",
+                "[]",
+                "",
+            );
+        }
+        let fingerprint = Fingerprint {
+            size: 10,
+            modified: 1_700_000_000,
+            partial_hash: "ends".to_string(),
+        };
+        database.set_fingerprint("a", &fingerprint);
+        let files = database.photo_files();
+        let file = |id: &str| files.iter().find(|f| f.id == id).unwrap();
+        assert_eq!(file("a").fingerprint, Some(fingerprint));
+        assert_eq!(file("b").fingerprint, None);
+
+        database.set_missing(&["a".to_string(), "b".to_string()]);
+        let since = database.list_missing()[0].since.clone();
+        // Still missing keeps its date; found again clears it
+        database.set_missing(&["a".to_string()]);
+        let missing = database.list_missing();
+        assert_eq!(missing.len(), 1);
+        assert_eq!((missing[0].id.as_str(), &missing[0].since), ("a", &since));
+        database.relink_photo("a", "/moved/a.jpg");
+        assert!(database.list_missing().is_empty());
+    }
+}
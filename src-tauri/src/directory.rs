@@ -1,19 +1,27 @@
-use super::database;
-pub(crate) fn list_directories(config_path: &str) -> Vec<String> {
-    let database = database::Database::new(config_path);
+use crate::journal;
+use crate::pool::DbPool;
+use tracing::debug;
 
-    database.list_directories()
+pub(crate) fn list_directories(db: &DbPool) -> Vec<String> {
+    db.read(|db| db.list_directories())
 }
 
-pub(crate) fn remove_directory(path: String, config_path: &str) {
-    let database = database::Database::new(config_path);
-    database.remove_directory(path);
+/// Stops scanning the folder; its photos stay in the library.
+pub(crate) fn remove_directory(path: String, db: &DbPool) -> Result<(), String> {
+    let detail = path.clone();
+    db.write(move |db| {
+        journal::record(db, "Remove folder", &detail, move |db| {
+            db.remove_directory(path)
+        })
+    })
 }
 
-pub(crate) fn add_directory(path: String, config_path: &str) {
-    println!("directory::add_directory called with: {path}");
-    let database = database::Database::new(config_path);
-    database.add_directory(&path);
+pub(crate) fn add_directory(path: String, db: &DbPool) -> Result<(), String> {
+    debug!("directory::add_directory called with: {path}");
+    let detail = path.clone();
+    db.write(move |db| {
+        journal::record(db, "Add folder", &detail, move |db| db.add_directory(&path))
+    })
 }
 
 #[cfg(test)]
@@ -25,11 +33,15 @@ mod test {
 
     #[test]
     fn test_add_directories() {
-        let database = database::Database::new("./tests/");
+        let dir = tempfile::tempdir().unwrap();
+        let db = DbPool::open(dir.path().to_str().unwrap());
         let path = "test";
-        database.add_directory(path);
-        let directory = database.list_directories();
+        add_directory(path.to_string(), &db).unwrap();
+        let directory = list_directories(&db);
 
-        assert_eq!(path, directory.first().unwrap())
+        assert_eq!(path, directory.first().unwrap());
+
+        remove_directory(path.to_string(), &db).unwrap();
+        assert!(list_directories(&db).is_empty());
     }
 }
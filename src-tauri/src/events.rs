@@ -0,0 +1,188 @@
+use crate::database::{Database, EventPhoto, NewEvent};
+use crate::geocode::distance_km;
+
+/// Time without photos after which a new event starts.
+pub const MAX_GAP_SECONDS: i64 = 6 * 60 * 60;
+/// Distance from the last known position after which a new event starts.
+pub const MAX_DISTANCE_KM: f64 = 50.0;
+
+/// Splits photos, sorted by the time they were taken, into runs that form one event each.
+/// A run ends after `MAX_GAP_SECONDS` without photos or when a photo was taken more than
+/// `MAX_DISTANCE_KM` from the previous geotagged one.
+pub fn cluster(photos: &[EventPhoto]) -> Vec<&[EventPhoto]> {
+    let mut events = Vec::new();
+    let mut start = 0;
+    let mut last_position = None;
+    for (i, photo) in photos.iter().enumerate() {
+        if i > start {
+            let gap = photo.time - photos[i - 1].time;
+            let moved = match (last_position, photo.position) {
+                (Some(from), Some(to)) => distance_km(from, to) > MAX_DISTANCE_KM,
+                _ => false,
+            };
+            if gap > MAX_GAP_SECONDS || moved {
+                events.push(&photos[start..i]);
+                start = i;
+                last_position = None;
+            }
+        }
+        last_position = photo.position.or(last_position);
+    }
+    if start < photos.len() {
+        events.push(&photos[start..]);
+    }
+    events
+}
+
+/// Suggested event title from where most photos were taken and the tag most of them share,
+/// e.g. "Beach in Lisbon", falling back to the day the event started.
+fn title(place: Option<String>, tag: Option<String>, start: i64) -> String {
+    let tag = tag.map(|tag| {
+        let tag = ["a ", "an ", "the "]
+            .iter()
+            .find_map(|article| tag.strip_prefix(article))
+            .unwrap_or(&tag);
+        let mut chars = tag.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    });
+    match (tag, place) {
+        (Some(tag), Some(place)) => format!("{tag} in {place}"),
+        (None, Some(place)) => place,
+        (Some(tag), None) => tag,
+        (None, None) => chrono::DateTime::from_timestamp(start, 0)
+            .map(|date| date.format("%-d %B %Y").to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Clusters the photos added since the last run into events, rebuilding only the events they
+/// may join. Returns the number of events built.
+pub fn update(db: &mut Database) -> Result<usize, String> {
+    let Some(from) = db.event_rebuild_start(MAX_GAP_SECONDS) else {
+        return Ok(0);
+    };
+    let photos = db.event_photos(from);
+    let events: Vec<NewEvent> = cluster(&photos)
+        .into_iter()
+        .map(|run| {
+            let photo_ids: Vec<String> = run.iter().map(|p| p.id.clone()).collect();
+            let positions: Vec<(f64, f64)> = run.iter().filter_map(|p| p.position).collect();
+            let centroid = (!positions.is_empty()).then(|| {
+                let n = positions.len() as f64;
+                (
+                    positions.iter().map(|p| p.0).sum::<f64>() / n,
+                    positions.iter().map(|p| p.1).sum::<f64>() / n,
+                )
+            });
+            let (place, tag) = db.event_title_parts(&photo_ids);
+            NewEvent {
+                id: photo_ids[0].clone(),
+                title: title(place, tag, run[0].time),
+                start: run[0].time,
+                end: run[run.len() - 1].time,
+                centroid,
+                photo_ids,
+            }
+        })
+        .collect();
+    db.replace_events(from, &events)?;
+    Ok(events.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Page, PhotoFilter};
+
+    #[test]
+    fn splits_on_time_gaps_and_distance() {
+        let photo = |id: &str, hours: i64, position| EventPhoto {
+            id: id.to_string(),
+            time: hours * 3600,
+            position,
+        };
+        let lisbon = Some((38.72, -9.14));
+        let sintra = Some((38.80, -9.38));
+        let porto = Some((41.15, -8.61));
+        let photos = vec![
+            photo("a", 0, lisbon),
+            photo("b", 2, None),
+            photo("c", 5, sintra),
+            // 270 km north within the hour
+            photo("d", 6, porto),
+            // Seven hours later
+            photo("e", 13, porto),
+        ];
+        let ids: Vec<Vec<&str>> = cluster(&photos)
+            .iter()
+            .map(|run| run.iter().map(|p| p.id.as_str()).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["a", "b", "c"], vec!["d"], vec!["e"]]);
+    }
+
+    #[test]
+    fn updates_incrementally() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        let beach = r#"[{"class":"a beach","probability":"0.4"}]"#;
+        for (id, created) in [
+            ("a", "2023-07-01T10:00:00Z"),
+            ("b", "2023-07-01T12:00:00Z"),
+            ("c", "2023-07-02T09:00:00Z"),
+        ] {
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                created,
+                None,
+                None,
+                beach,
+                "[]",
+                "",
+            );
+        }
+        assert_eq!(update(&mut database).unwrap(), 2);
+        assert_eq!(update(&mut database).unwrap(), 0);
+
+        // Bridges the gap between both events, which merge into one
+        for (id, created) in [
+            ("d", "2023-07-01T17:00:00Z"),
+            ("e", "2023-07-01T22:00:00Z"),
+            ("f", "2023-07-02T04:00:00Z"),
+        ] {
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                created,
+                None,
+                None,
+                "[]",
+                "[]",
+                "",
+            );
+        }
+        assert_eq!(update(&mut database).unwrap(), 1);
+        let events = database.list_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, "a");
+        assert_eq!(events[0].title, "Beach");
+        assert_eq!(events[0].photo_count, 6);
+        assert_eq!(events[0].end, "2023-07-02T09:00:00Z");
+
+        let filter = PhotoFilter {
+            event_id: Some("a".to_string()),
+            ..Default::default()
+        };
+        let page = Page {
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            database.list_photos(&filter, &page).unwrap().photos.len(),
+            6
+        );
+    }
+}
@@ -1,23 +1,29 @@
 use crate::database;
 use base64::{engine::general_purpose, Engine as _};
-use exif::Reader;
 use jwalk::WalkDir;
 use notify::event::{CreateKind, ModifyKind};
 use notify::{EventKind, RecursiveMode, Watcher};
-use rand::{distributions::Alphanumeric, Rng};
 use tauri_plugin_notification::NotificationExt;
 
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::path::Path;
 
 use std::io::BufReader;
 use std::string::String;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::metadata;
 use crate::ml::MlContext;
-use tauri::{Emitter, Manager};
+use crate::pool::DbPool;
+use crate::reconcile::{self, Missing};
+use crate::timestamp;
+use crate::xmp;
+use tauri::Manager;
 use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, warn};
 
 pub async fn start_watcher(app: tauri::AppHandle) {
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
@@ -34,8 +40,7 @@ pub async fn start_watcher(app: tauri::AppHandle) {
 
     let config_path = crate::get_config_path(&app);
     if !config_path.is_empty() {
-        let database = crate::database::Database::new(&config_path);
-        let folders = database.list_directories();
+        let folders = app.state::<DbPool>().read(|db| db.list_directories());
         for folder in folders {
             if Path::new(&folder).exists() {
                 let _ = watcher.watch(Path::new(&folder), RecursiveMode::Recursive);
@@ -52,7 +57,7 @@ pub async fn start_watcher(app: tauri::AppHandle) {
         let mut last_scan = tokio::time::Instant::now();
 
         while let Some(event) = rx.recv().await {
-            println!("Watcher received event: {event:?}");
+            debug!("Watcher received event: {event:?}");
             match event.kind {
                 EventKind::Create(CreateKind::File)
                 | EventKind::Modify(ModifyKind::Name(_))
@@ -85,23 +90,17 @@ pub async fn start_watcher(app: tauri::AppHandle) {
     });
 }
 
-fn emit_log(app: &tauri::AppHandle, message: String) {
-    println!("{message}");
-    let _ = app.emit("log-message", message);
-}
-
 ///
 /// This is will scan a folder recursively and store all the images in the database.
 pub fn scan_folder(
     app: &tauri::AppHandle,
     directory: String,
-    path: &str,
+    db: &DbPool,
     batch_tx: &UnboundedSender<database::Photo>,
+    missing: &Missing,
 ) {
-    let db_instance = database::Database::new(path);
-
     // Load thread config
-    let config = db_instance.get_state();
+    let config = db.read(|db| db.get_state());
     let num_threads: usize = config
         .get("scan_threads")
         .and_then(|s| s.parse().ok())
@@ -113,23 +112,20 @@ pub fn scan_folder(
             }
         });
 
-    emit_log(
-        app,
-        format!("Starting Discovery Pass with {num_threads} threads in: {directory}"),
-    );
+    let span = tracing::info_span!("folder", %directory);
+    let _folder = span.enter();
+    info!("Starting Discovery Pass with {num_threads} threads in: {directory}");
 
     // Collect all valid image and video paths first
     let mut image_paths = Vec::new();
-    let video_extensions = ["mp4", "mkv", "mov", "avi", "webm"];
     let image_extensions = ["png", "jpg", "jpeg", "webp", "heic", "avif"];
 
-    use std::sync::atomic::{AtomicBool, Ordering};
     let abort_flag = app
         .try_state::<MlContext>()
         .map(|s| s.abort.clone())
         .unwrap_or_else(|| Arc::new(AtomicBool::new(false)));
 
-    for entry in WalkDir::new(directory).follow_links(false) {
+    for entry in WalkDir::new(&directory).follow_links(false) {
         if abort_flag.load(Ordering::SeqCst) {
             return;
         }
@@ -138,7 +134,7 @@ pub fn scan_folder(
             if let Some(extension) = path.extension() {
                 let ext = extension.to_string_lossy().to_lowercase();
                 if image_extensions.contains(&ext.as_str())
-                    || video_extensions.contains(&ext.as_str())
+                    || VIDEO_EXTENSIONS.contains(&ext.as_str())
                 {
                     if let Ok(path) = fs::canonicalize(path) {
                         image_paths.push(path);
@@ -149,129 +145,234 @@ pub fn scan_folder(
     }
 
     use std::sync::{Arc, Mutex};
-    let database_arc = Arc::new(Mutex::new(db_instance));
+
+    let app_handle = Arc::new(app.clone());
+    let abort_flag_task = Arc::clone(&abort_flag);
+
+    // Create a local thread pool for this scan to avoid blocking the global one
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap();
+
+    use rayon::prelude::*;
+
+    // Photos imported before content hashing existed get their hash backfilled here
+    let unhashed = db.read(|db| db.get_unhashed_photos(&directory));
+    backfill(
+        &pool,
+        &abort_flag,
+        "content hashes",
+        unhashed,
+        |id, location| {
+            if let Ok(hash) = hash_file(Path::new(location)) {
+                let id = id.to_string();
+                if let Err(e) = db.write(move |db| db.set_photo_hash(&id, &hash)) {
+                    warn!("Failed to save the content hash of {location}: {e}");
+                }
+            }
+        },
+    );
+
+    // Photos indexed before near-duplicate detection existed get their perceptual hash here;
+    // new photos get theirs from the ML worker, which decodes them anyway.
+    let unhashed = db.read(|db| db.get_photos_missing_perceptual_hash(&directory));
+    backfill(
+        &pool,
+        &abort_flag,
+        "perceptual hashes",
+        unhashed,
+        |id, location| {
+            if let Ok(img) = image::open(location) {
+                let phash = crate::similarity::dhash(&img);
+                let (id, width, height) = (id.to_string(), img.width(), img.height());
+                if let Err(e) =
+                    db.write(move |db| db.set_perceptual_hash(&id, phash, width, height))
+                {
+                    warn!("Failed to save the perceptual hash of {location}: {e}");
+                }
+            }
+        },
+    );
+
+    // Capture times stored before the resolver existed were raw EXIF text or empty
+    let unresolved = db.read(|db| db.get_unresolved_capture_times(&directory));
+    backfill(
+        &pool,
+        &abort_flag,
+        "capture times",
+        unresolved,
+        |id, location| {
+            if let Some(time) = timestamp::resolve(Path::new(location)) {
+                let id = id.to_string();
+                if let Err(e) = db
+                    .write(move |db| db.set_capture_time(&id, &time.to_iso(), time.offset_seconds))
+                {
+                    warn!("Failed to save the capture time of {location}: {e}");
+                }
+            }
+        },
+    );
+
+    // Camera and file details were not kept before they got their own columns
+    let unread = db.read(|db| db.get_photos_missing_media_info(&directory));
+    backfill(
+        &pool,
+        &abort_flag,
+        "camera details",
+        unread,
+        |id, location| {
+            let media = metadata::read(Path::new(location));
+            let id = id.to_string();
+            if let Err(e) = db.write(move |db| db.set_media_info(&id, &media)) {
+                warn!("Failed to save the camera details of {location}: {e}");
+            }
+        },
+    );
+
+    // What a photo is recognized by once it has been moved, for the scans after that
+    let unprinted = db.read(|db| db.get_photos_missing_fingerprint(&directory));
+    backfill(
+        &pool,
+        &abort_flag,
+        "file fingerprints",
+        unprinted,
+        |id, location| {
+            if let Ok(fingerprint) = reconcile::fingerprint(Path::new(location)) {
+                let id = id.to_string();
+                if let Err(e) = db.write(move |db| db.set_fingerprint(&id, &fingerprint)) {
+                    warn!("Failed to save the fingerprint of {location}: {e}");
+                }
+            }
+        },
+    );
+
+    // Sidecars that darktable, digiKam or Lightroom wrote or changed since they were read
+    let changed: Vec<(String, String)> = db
+        .read(|db| db.get_sidecar_times(&directory))
+        .into_iter()
+        .filter(|(_, location, read)| {
+            xmp::sidecar_path(Path::new(location)).is_some_and(|path| xmp::modified(&path) != *read)
+        })
+        .map(|(id, location, _)| (id, location))
+        .collect();
+    backfill(
+        &pool,
+        &abort_flag,
+        "XMP sidecars",
+        changed,
+        |id, location| {
+            if let Some(sidecar) = xmp::read_sidecar(Path::new(location)) {
+                let id = id.to_string();
+                match db.write(move |db| db.apply_xmp(&id, &sidecar)) {
+                    Ok(Err(e)) | Err(e) => warn!("Failed to apply the sidecar of {location}: {e}"),
+                    Ok(Ok(_)) => {}
+                }
+            }
+        },
+    );
 
     // 1. Filter out already indexed paths in a single pass
     let all_paths: Vec<String> = image_paths
         .iter()
         .map(|p| p.display().to_string())
         .collect();
-    let new_paths_to_process = {
-        let db = database_arc.lock().unwrap();
-        db.filter_new_paths(&all_paths)
-    };
+    // A skipped copy goes through again once it changes, or when the photo it copies has
+    // gone missing, which it may then turn out to be
+    let new_paths_to_process = db.read(|db| {
+        db.filter_new_paths(&all_paths, |copy| {
+            reconcile::size_and_time(Path::new(&copy.location)) != copy.size.zip(copy.modified)
+                || missing.has_hash(&copy.hash)
+        })
+    });
 
     if new_paths_to_process.is_empty() {
-        emit_log(app, "No new photos found.".to_string());
+        info!("No new photos found.");
         return;
     }
 
-    emit_log(
-        app,
-        format!("Processing {} new photos...", new_paths_to_process.len()),
-    );
+    info!("Processing {} new photos...", new_paths_to_process.len());
 
-    let app_handle = Arc::new(app.clone());
-    let abort_flag_task = Arc::clone(&abort_flag);
-
-    // Create a local thread pool for this scan to avoid blocking the global one
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .build()
-        .unwrap();
-
-    use rayon::prelude::*;
     let tx_clone = app_handle
         .try_state::<MlContext>()
         .map(|state| state.tx.clone());
+    let seen_hashes = Mutex::new(HashSet::new());
 
     pool.install(|| {
         new_paths_to_process.into_par_iter().for_each(|path_str| {
+            let _folder = span.enter();
             if abort_flag_task.load(Ordering::SeqCst) {
                 return;
             }
             let path = Path::new(&path_str);
-            let id: String = rand::thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(7)
-                .map(char::from)
-                .collect();
+
+            // A photo moved or renamed since the last scan keeps its row, found without
+            // reading the whole file when its size and modification time are unchanged
+            if let Some(moved) = missing.claim_by_fingerprint(path) {
+                relink(db, moved, &path_str);
+                return;
+            }
+
+            // The content hash is the photo's identity, so the same file keeps its id
+            // across rescans, renames and devices.
+            let id = match hash_file(path) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    error!("Failed to hash {path_str}: {e}");
+                    return;
+                }
+            };
+            if !seen_hashes.lock().unwrap().insert(id.clone()) {
+                record_duplicate(db, &path_str, &id);
+                return;
+            }
+            if let Some(moved) = missing.claim_by_hash(&id) {
+                relink(db, moved, &path_str);
+                return;
+            }
+            // Photos whose file is gone were all claimable above, so this one is still there
+            if let Some((_, existing_location)) = db.read(|db| db.find_photo_by_hash(&id)) {
+                info!("Skipping {path_str}: duplicate of {existing_location}");
+                record_duplicate(db, &path_str, &id);
+                return;
+            }
+            // The user deleted this photo; a copy showing up does not undo that
+            if db.read(|db| db.is_hash_trashed(&id)) {
+                info!("Skipping {path_str}: a copy of a photo in the trash");
+                record_duplicate(db, &path_str, &id);
+                return;
+            }
 
             let ext = path
                 .extension()
                 .and_then(|e| e.to_str())
                 .unwrap_or("")
                 .to_lowercase();
-            let _is_video = ["mp4", "mkv", "mov", "avi", "webm"].contains(&ext.as_str());
+            let _is_video = VIDEO_EXTENSIONS.contains(&ext.as_str());
 
-            let mut latitude = 0.0;
-            let mut longitude = 0.0;
-            let mut created = String::new();
             let encoded = String::new();
-
-            if let Ok(file) = File::open(path) {
-                let mut buff = BufReader::new(&file);
-
-                if let Ok(exif) = Reader::new().read_from_container(&mut buff) {
-                    // Extract Created Date
-                    if let (Some(date_field), _) = (
-                        exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
-                            .or_else(|| exif.get_field(exif::Tag::DateTime, exif::In::PRIMARY)),
-                        (),
-                    ) {
-                        created = format!("{}", date_field.display_value());
-                    }
-
-                    // Extract GPS Coordinates
-                    if let (Some(lat_field), Some(lat_ref)) = (
-                        exif.get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY),
-                        exif.get_field(exif::Tag::GPSLatitudeRef, exif::In::PRIMARY),
-                    ) {
-                        if let exif::Value::Rational(lat_values) = &lat_field.value {
-                            if lat_values.len() == 3 {
-                                let lat = lat_values[0].to_f64()
-                                    + lat_values[1].to_f64() / 60.0
-                                    + lat_values[2].to_f64() / 3600.0;
-                                latitude = if format!("{}", lat_ref.display_value()) == "S" {
-                                    -lat
-                                } else {
-                                    lat
-                                };
-                            }
-                        }
-                    }
-
-                    if let (Some(lon_field), Some(lon_ref)) = (
-                        exif.get_field(exif::Tag::GPSLongitude, exif::In::PRIMARY),
-                        exif.get_field(exif::Tag::GPSLongitudeRef, exif::In::PRIMARY),
-                    ) {
-                        if let exif::Value::Rational(lon_values) = &lon_field.value {
-                            if lon_values.len() == 3 {
-                                let lon = lon_values[0].to_f64()
-                                    + lon_values[1].to_f64() / 60.0
-                                    + lon_values[2].to_f64() / 3600.0;
-                                longitude = if format!("{}", lon_ref.display_value()) == "W" {
-                                    -lon
-                                } else {
-                                    lon
-                                };
-                            }
-                        }
-                    }
-                }
-            }
+            let capture_time = timestamp::resolve(path);
+            let media = metadata::read(path);
+            let xmp = xmp::read(path);
 
             let photo = database::Photo {
                 id: id.clone(),
                 encoded: encoded.clone(),
                 location: path_str.clone(),
-                created,
+                created: capture_time.map(|t| t.to_iso()).unwrap_or_default(),
                 objects: HashMap::new(),
                 properties: HashMap::new(),
-                latitude,
-                longitude,
+                latitude: media.latitude.unwrap_or(0.0),
+                longitude: media.longitude.unwrap_or(0.0),
                 favorite: false,
                 indexed: 1,
+                hash: Some(id.clone()),
+                created_offset: capture_time.map(|t| t.offset_seconds),
+                rating: 0,
+                flag: database::Flag::None,
+                label: None,
+                media: Some(media),
+                xmp,
             };
 
             let _ = batch_tx.send(photo);
@@ -288,13 +389,88 @@ pub fn scan_folder(
         });
     });
 
-    emit_log(app, "Done with Discovery Pass".to_string());
+    info!("Done with Discovery Pass");
+}
+
+/// Points a photo whose file was moved outside Siegu at where it went, and takes the
+/// fingerprint of the file there, which a copy made by another tool may not share.
+fn relink(db: &DbPool, moved: database::PhotoFile, location: &str) {
+    info!("Relinked moved photo {} -> {location}", moved.location);
+    let fingerprint = reconcile::fingerprint(Path::new(location)).ok();
+    let target = location.to_string();
+    let relinked = db.write(move |db| {
+        db.relink_photo(&moved.id, &target);
+        if let Some(fingerprint) = &fingerprint {
+            db.set_fingerprint(&moved.id, fingerprint);
+        }
+    });
+    if let Err(e) = relinked {
+        warn!("Failed to relink {location}: {e}");
+    }
+}
+
+/// Keeps a skipped copy out of later scans, which would otherwise hash it every time.
+fn record_duplicate(db: &DbPool, location: &str, hash: &str) {
+    let (size, modified) = reconcile::size_and_time(Path::new(location)).unzip();
+    let copy = database::SkippedCopy {
+        location: location.to_string(),
+        hash: hash.to_string(),
+        size,
+        modified,
+    };
+    if let Err(e) = db.write(move |db| db.record_duplicate(&copy)) {
+        warn!("Failed to record the duplicate {location}: {e}");
+    }
+}
+
+/// Runs `f` over (id, location) rows that predate a newer column, in parallel on the scan's pool.
+fn backfill<F>(
+    pool: &rayon::ThreadPool,
+    abort: &AtomicBool,
+    what: &str,
+    rows: Vec<(String, String)>,
+    f: F,
+) where
+    F: Fn(&str, &str) + Sync,
+{
+    use rayon::prelude::*;
+    if rows.is_empty() {
+        return;
+    }
+    info!("Computing {what} for {} photos...", rows.len());
+    // Pool threads don't inherit the caller's spans
+    let span = tracing::Span::current();
+    pool.install(|| {
+        rows.into_par_iter().for_each(|(id, location)| {
+            let _folder = span.enter();
+            if !abort.load(Ordering::SeqCst) {
+                f(&id, &location);
+            }
+        });
+    });
+}
+
+/// Extensions of the videos a scan picks up, the same ones `VIDEO_FILTER` matches.
+pub const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "mkv", "mov", "avi", "webm"];
+
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Content hash used as a photo's identity: SHA-256 over the file bytes, hex encoded.
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
 }
 
 pub fn read_file_base64(path: String) -> String {
     match fs::read(&path) {
         Ok(bytes) => {
-            println!("Reading original file: {} ({} bytes)", path, bytes.len());
+            debug!("Reading original file: {} ({} bytes)", path, bytes.len());
             let encoded = general_purpose::STANDARD.encode(bytes);
             let ext = Path::new(&path)
                 .extension()
@@ -313,7 +489,7 @@ pub fn read_file_base64(path: String) -> String {
             format!("data:{mime};base64,{encoded}")
         }
         Err(e) => {
-            eprintln!("Failed to read file {path}: {e}");
+            warn!("Failed to read file {path}: {e}");
             String::new()
         }
     }
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::database::{Database, Place};
+
+/// Cities bundled with the app, one per line: name, region, country, latitude and longitude,
+/// separated by tabs. A few hundred major cities picked from GeoNames; `geonames.py` builds
+/// the full list of cities over 15,000 people instead. With so few, photos away from them
+/// often get only a region or country, and near a border may get the neighbour's.
+const CITIES: &str = include_str!("../resources/cities.tsv");
+
+/// Farthest a photo can be from a city to be placed in it.
+const CITY_RADIUS_KM: f64 = 25.0;
+/// Farthest a photo can be from a city to be placed in its region.
+const REGION_RADIUS_KM: f64 = 100.0;
+/// Farthest a photo can be from a city to be placed in its country. Photos farther from
+/// every city, out at sea for instance, get no place names. Wide enough for the bundled
+/// extract, which leaves whole provinces without a listed city.
+const COUNTRY_RADIUS_KM: f64 = 300.0;
+/// Kilometres per degree of latitude.
+const KM_PER_DEGREE: f64 = 111.2;
+
+/// Great-circle distance between two latitude/longitude points, in kilometres.
+pub fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let (dlat, dlon) = (lat2 - lat1, (b.1 - a.1).to_radians());
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * 6371.0 * h.sqrt().asin()
+}
+
+struct City {
+    name: String,
+    region: String,
+    country: String,
+    position: (f64, f64),
+}
+
+/// Cities indexed by the whole-degree cell of the grid they fall in.
+pub struct Gazetteer {
+    cities: Vec<City>,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+fn cell(latitude: f64, longitude: f64) -> (i32, i32) {
+    (latitude.floor() as i32, longitude.floor() as i32)
+}
+
+impl Gazetteer {
+    /// Reads cities in the format of `resources/cities.tsv`, skipping comments and lines
+    /// that do not parse.
+    pub fn parse(tsv: &str) -> Gazetteer {
+        let mut gazetteer = Gazetteer {
+            cities: Vec::new(),
+            cells: HashMap::new(),
+        };
+        for line in tsv.lines().filter(|line| !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [name, region, country, latitude, longitude] = fields[..] else {
+                continue;
+            };
+            let (Ok(latitude), Ok(longitude)) = (latitude.parse(), longitude.parse()) else {
+                continue;
+            };
+            gazetteer
+                .cells
+                .entry(cell(latitude, longitude))
+                .or_default()
+                .push(gazetteer.cities.len());
+            gazetteer.cities.push(City {
+                name: name.to_string(),
+                region: region.to_string(),
+                country: country.to_string(),
+                position: (latitude, longitude),
+            });
+        }
+        gazetteer
+    }
+
+    /// Place names for a position from the nearest city, dropping the city and then the
+    /// region the farther away it is.
+    pub fn lookup(&self, latitude: f64, longitude: f64) -> Option<Place> {
+        // Every cell that may hold a city within COUNTRY_RADIUS_KM
+        let lat_cells = (COUNTRY_RADIUS_KM / KM_PER_DEGREE).ceil() as i32;
+        let lon_km = KM_PER_DEGREE * latitude.to_radians().cos();
+        let lon_cells = ((COUNTRY_RADIUS_KM / lon_km.max(1.0)).ceil() as i32).min(180);
+        let (row, column) = cell(latitude, longitude);
+        let mut nearest: Option<(f64, &City)> = None;
+        for r in row - lat_cells..=row + lat_cells {
+            for c in column - lon_cells..=column + lon_cells {
+                // Wrap around the antimeridian
+                let c = (c + 180).rem_euclid(360) - 180;
+                for &i in self.cells.get(&(r, c)).into_iter().flatten() {
+                    let city = &self.cities[i];
+                    let distance = distance_km((latitude, longitude), city.position);
+                    if nearest.is_none_or(|(best, _)| distance < best) {
+                        nearest = Some((distance, city));
+                    }
+                }
+            }
+        }
+        let (distance, city) = nearest.filter(|(distance, _)| *distance <= COUNTRY_RADIUS_KM)?;
+        let within = |radius: f64, name: &str| {
+            (distance <= radius && !name.is_empty()).then(|| name.to_string())
+        };
+        Some(Place {
+            city: within(CITY_RADIUS_KM, &city.name),
+            state: within(REGION_RADIUS_KM, &city.region),
+            country: within(COUNTRY_RADIUS_KM, &city.country),
+        })
+    }
+}
+
+/// The bundled cities, parsed on first use.
+pub fn gazetteer() -> &'static Gazetteer {
+    static GAZETTEER: OnceLock<Gazetteer> = OnceLock::new();
+    GAZETTEER.get_or_init(|| Gazetteer::parse(CITIES))
+}
+
+/// Looks up place names for the geotagged photos added or moved since the last run, so they
+/// can be searched by city, region or country. Returns the number of photos placed.
+pub fn backfill(db: &mut Database) -> Result<usize, String> {
+    let gazetteer = gazetteer();
+    let places: Vec<(String, Place)> = db
+        .photos_to_geocode()
+        .into_iter()
+        .map(|(id, latitude, longitude)| {
+            let place = gazetteer.lookup(latitude, longitude).unwrap_or_default();
+            (id, place)
+        })
+        .collect();
+    db.set_places(&places)?;
+    Ok(places
+        .iter()
+        .filter(|(_, place)| place.country.is_some())
+        .count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Page, PhotoFilter};
+
+    #[test]
+    fn finds_the_nearest_city() {
+        let gazetteer = Gazetteer::parse(
+            "# name\tregion\tcountry\tlatitude\tlongitude
+Lisbon\tLisbon\tPortugal\t38.7167\t-9.1333
+Porto\tPorto\tPortugal\t41.1496\t-8.6110
+Waiyevo\tNorthern\tFiji\t-16.7906\t179.9882",
+        );
+        let place = |city: Option<&str>, state: Option<&str>, country: Option<&str>| Place {
+            city: city.map(str::to_string),
+            state: state.map(str::to_string),
+            country: country.map(str::to_string),
+        };
+        // Belém, a few kilometres west of the centre
+        assert_eq!(
+            gazetteer.lookup(38.6979, -9.2061),
+            Some(place(Some("Lisbon"), Some("Lisbon"), Some("Portugal")))
+        );
+        // Mafra is too far out to be in Lisbon itself
+        assert_eq!(
+            gazetteer.lookup(38.9369, -9.3276),
+            Some(place(None, Some("Lisbon"), Some("Portugal")))
+        );
+        // Across the antimeridian from Waiyevo
+        assert_eq!(
+            gazetteer.lookup(-17.8, -179.8),
+            Some(place(None, None, Some("Fiji")))
+        );
+        // Mid-Atlantic
+        assert_eq!(gazetteer.lookup(30.0, -40.0), None);
+    }
+
+    #[test]
+    fn backfills_searchable_places() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        for (id, position) in [
+            ("a", Some((38.7223, -9.1393))),
+            ("b", Some((35.6762, 139.6503))),
+            ("c", None),
+        ] {
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                "2023-07-01T10:00:00Z",
+                position.map(|p| p.0),
+                position.map(|p| p.1),
+                "[]",
+                "[]",
+                "",
+            );
+        }
+        assert_eq!(backfill(&mut database).unwrap(), 2);
+        assert_eq!(backfill(&mut database).unwrap(), 0);
+
+        let search = |database: &Database, query: &str| -> Vec<String> {
+            let filter = PhotoFilter {
+                query: query.to_string(),
+                ..Default::default()
+            };
+            let page = Page {
+                limit: 10,
+                ..Default::default()
+            };
+            let photos = database.list_photos(&filter, &page).unwrap().photos;
+            photos.into_iter().map(|p| p.id).collect()
+        };
+        assert_eq!(search(&database, "Lisbon"), vec!["a"]);
+        assert_eq!(search(&database, "Japan"), vec!["b"]);
+        // South Windsor is nearer Windsor than Detroit across the river
+        assert_eq!(
+            gazetteer()
+                .lookup(42.2745, -82.9960)
+                .unwrap()
+                .country
+                .as_deref(),
+            Some("Canada")
+        );
+
+        // A photo that moves is placed again
+        database
+            .connection
+            .execute(
+                "UPDATE photo SET latitude = 41.1579, longitude = -8.6291 WHERE id = 'a'",
+                [],
+            )
+            .unwrap();
+        assert_eq!(backfill(&mut database).unwrap(), 1);
+        assert!(search(&database, "Lisbon").is_empty());
+        assert_eq!(search(&database, "Porto"), vec!["a"]);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::database::Database;
+use crate::pool::DbPool;
+use crate::trash;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Operations kept for undo; older ones are dropped as new ones are recorded.
+pub const MAX_OPERATIONS: i64 = 100;
+
+/// Tables whose changes are never journaled: the journal itself, settings, logs and peers.
+/// The search and map indexes are left out too, since triggers rebuild them from the rest.
+const UNJOURNALED: &[&str] = &["operation", "operation_step", "config", "logs", "device"];
+
+/// A user operation in the journal, newest first in `history`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    pub id: i64,
+    pub name: String,
+    pub detail: String,
+    pub created: String,
+    pub undone: bool,
+    /// Locations of photos that undo or redo brought back but whose file is gone; they are
+    /// marked missing. Always empty in `history`.
+    pub missing: Vec<String>,
+}
+
+/// Runs `f` as one user operation, recording the SQL that reverses each row it changes,
+/// cascades included, so it can be undone. An operation that changes nothing is not kept;
+/// one that does drops the operations undone before it, which can then no longer be redone.
+pub fn record<T>(
+    db: &mut Database,
+    name: &str,
+    detail: &str,
+    f: impl FnOnce(&mut Database) -> T,
+) -> T {
+    let id = match begin(&db.connection, name, detail) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Failed to journal {name}: {e}");
+            None
+        }
+    };
+    let value = f(db);
+    if let Some(id) = id {
+        if let Err(e) = finish(&db.connection, id) {
+            warn!("Failed to journal {name}: {e}");
+        }
+    }
+    value
+}
+
+fn begin(connection: &Connection, name: &str, detail: &str) -> rusqlite::Result<i64> {
+    install(connection)?;
+    connection.execute(
+        "INSERT INTO operation (name, detail) VALUES (?1, ?2)",
+        (name, detail),
+    )?;
+    let id = connection.last_insert_rowid();
+    connection.execute("DELETE FROM journal_capture", [])?;
+    connection.execute(
+        "INSERT INTO journal_capture (operation_id) VALUES (?1)",
+        [id],
+    )?;
+    Ok(id)
+}
+
+fn finish(connection: &Connection, id: i64) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM journal_capture", [])?;
+    let changed: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM operation_step WHERE operation_id = ?1)",
+        [id],
+        |row| row.get(0),
+    )?;
+    if !changed {
+        connection.execute("DELETE FROM operation WHERE id = ?1", [id])?;
+        return Ok(());
+    }
+    connection.execute("DELETE FROM operation WHERE undone = 1", [])?;
+    connection.execute(
+        "DELETE FROM operation WHERE id NOT IN (SELECT id FROM operation ORDER BY id DESC LIMIT ?1)",
+        [MAX_OPERATIONS],
+    )?;
+    Ok(())
+}
+
+/// Reverses the latest operation that is not undone yet, returning it, or `None` if there
+/// is nothing left to undo.
+pub fn undo_last(db: &DbPool) -> Result<Option<Operation>, String> {
+    replay(db, "SELECT MAX(id) FROM operation WHERE undone = 0")
+}
+
+/// Applies again the operation undone last, returning it, or `None` if there is nothing to
+/// redo.
+pub fn redo(db: &DbPool) -> Result<Option<Operation>, String> {
+    replay(db, "SELECT MIN(id) FROM operation WHERE undone = 1")
+}
+
+/// A photo a replay took out of or put back into the trash, or brought back.
+#[derive(PartialEq)]
+struct FileChange {
+    id: String,
+    location: String,
+    old_trash: Option<String>,
+    new_trash: Option<String>,
+    inserted: bool,
+}
+
+/// Replays the operation `latest` selects. Files follow the trash state of the photos: a
+/// photo leaving the trash is moved back from it and one going in is moved there again,
+/// and a photo brought back whose file is gone is marked missing and reported. The steps
+/// are tried first and rolled back to learn which files those are, then the files are
+/// moved outside the writer, which a copy to another drive would hold up, and the steps
+/// run for good. The files go back if that fails or the steps touch other photos by then.
+fn replay(db: &DbPool, latest: &'static str) -> Result<Option<Operation>, String> {
+    let planned = db
+        .write(move |db| {
+            let id = db
+                .connection
+                .query_row(latest, [], |row| row.get::<_, Option<i64>>(0))
+                .map_err(|e| e.to_string())?;
+            let Some(id) = id else {
+                return Ok(None);
+            };
+            install(&db.connection).map_err(|e| e.to_string())?;
+            // Dropped without committing, so the trial leaves nothing behind
+            let tx = db.connection.savepoint().map_err(|e| e.to_string())?;
+            Ok(Some((id, run_steps(&tx, id)?)))
+        })
+        .and_then(|planned| planned)?;
+    let Some((id, changes)) = planned else {
+        return Ok(None);
+    };
+
+    let mut moves = Vec::new();
+    let mut missing = Vec::new();
+    for change in &changes {
+        match (change.inserted, &change.old_trash, &change.new_trash) {
+            (true, _, None) if !Path::new(&change.location).exists() => {
+                missing.push((change.id.clone(), change.location.clone()));
+            }
+            (false, Some(trash), None) => {
+                moves.push((PathBuf::from(trash), PathBuf::from(&change.location)));
+            }
+            (false, None, Some(trash)) => {
+                moves.push((PathBuf::from(&change.location), PathBuf::from(trash)));
+            }
+            _ => {}
+        }
+    }
+    trash::move_files(&moves)?;
+
+    let replayed = db
+        .write(move |db| {
+            install(&db.connection).map_err(|e| e.to_string())?;
+            let tx = db.connection.savepoint().map_err(|e| e.to_string())?;
+            if run_steps(&tx, id)? != changes {
+                return Err("The library has changed since".to_string());
+            }
+            for (id, _) in &missing {
+                tx.execute(
+                    "UPDATE photo SET missing = IFNULL(missing, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')) WHERE id = ?1",
+                    [id],
+                )
+                .map_err(|e| e.to_string())?;
+            }
+            tx.execute(
+                "UPDATE operation SET undone = NOT undone WHERE id = ?1",
+                [id],
+            )
+            .map_err(|e| e.to_string())?;
+            let mut operation = tx
+                .query_row(
+                    &format!("{OPERATION_SELECT} WHERE id = ?1"),
+                    [id],
+                    operation_from_row,
+                )
+                .map_err(|e| e.to_string())?;
+            operation.missing = missing.into_iter().map(|(_, location)| location).collect();
+            tx.commit().map_err(|e| e.to_string())?;
+            Ok(operation)
+        })
+        .and_then(|replayed| replayed);
+    if replayed.is_err() {
+        trash::move_back(&moves);
+    }
+    replayed.map(Some)
+}
+
+/// Runs an operation's steps newest first, recording the steps that reverse them in their
+/// place, so undoing and redoing alternate. Fails if a step fails or finds no row to
+/// change, e.g. because a photo brought back by undo has been scanned in again since, or
+/// one taken out of the trash has been purged. Returns the photos whose file follows.
+fn run_steps(connection: &Connection, id: i64) -> Result<Vec<FileChange>, String> {
+    let steps: Vec<String> = connection
+        .prepare("SELECT statement FROM operation_step WHERE operation_id = ?1 ORDER BY id DESC")
+        .and_then(|mut stmt| {
+            stmt.query_map([id], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()
+        })
+        .map_err(|e| e.to_string())?;
+    connection
+        .execute("DELETE FROM operation_step WHERE operation_id = ?1", [id])
+        .map_err(|e| e.to_string())?;
+    connection
+        .execute_batch("DELETE FROM journal_file;")
+        .map_err(|e| e.to_string())?;
+    connection
+        .execute(
+            "INSERT INTO journal_capture (operation_id, replaying) VALUES (?1, 1)",
+            [id],
+        )
+        .map_err(|e| e.to_string())?;
+    for step in &steps {
+        match connection.execute(step, []) {
+            Ok(0) => return Err("The library has changed since".to_string()),
+            Ok(_) => {}
+            Err(e) => return Err(format!("The library has changed since: {e}")),
+        }
+    }
+    connection
+        .execute("DELETE FROM journal_capture", [])
+        .map_err(|e| e.to_string())?;
+    connection
+        .prepare("SELECT photo_id, location, old_trash, new_trash, inserted FROM journal_file ORDER BY rowid")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(FileChange {
+                    id: row.get(0)?,
+                    location: row.get(1)?,
+                    old_trash: row.get(2)?,
+                    new_trash: row.get(3)?,
+                    inserted: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// The most recent operations, newest first, undone ones included.
+pub fn history(db: &Database, limit: usize) -> Vec<Operation> {
+    let mut operations = Vec::new();
+    let sql = format!("{OPERATION_SELECT} ORDER BY id DESC LIMIT ?1");
+    if let Ok(mut stmt) = db.connection.prepare(&sql) {
+        if let Ok(rows) = stmt.query_map([limit as i64], operation_from_row) {
+            operations.extend(rows.flatten());
+        }
+    }
+    operations
+}
+
+const OPERATION_SELECT: &str = "SELECT id, name, detail, created, undone FROM operation";
+
+fn operation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Operation> {
+    Ok(Operation {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        detail: row.get(2)?,
+        created: row.get(3)?,
+        undone: row.get(4)?,
+        missing: Vec::new(),
+    })
+}
+
+/// Creates, once per connection, the TEMP triggers that write the reversing SQL of every
+/// change to a journaled table into `operation_step` while `journal_capture` names an
+/// operation. They are built from the schema as it is, after migrations, and exist only on
+/// the connection that records, so writes made elsewhere are never journaled. While a
+/// replay runs, `journal_file` collects the photos whose file it has to move or check.
+fn install(connection: &Connection) -> rusqlite::Result<()> {
+    let installed: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_temp_master WHERE name = 'journal_capture')",
+        [],
+        |row| row.get(0),
+    )?;
+    if installed {
+        return Ok(());
+    }
+    let tables: Vec<String> = connection
+        .prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND sql NOT LIKE 'CREATE VIRTUAL%'
+             AND name NOT LIKE 'sqlite%' AND name NOT GLOB 'photo_search_*'
+             AND name NOT GLOB 'photo_location_*'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut sql = String::from(
+        "CREATE TEMP TABLE journal_capture (
+             operation_id INTEGER NOT NULL,
+             replaying INTEGER NOT NULL DEFAULT 0
+         );
+         CREATE TEMP TABLE journal_file (
+             photo_id TEXT NOT NULL,
+             location TEXT NOT NULL,
+             old_trash TEXT,
+             new_trash TEXT,
+             inserted INTEGER NOT NULL
+         );
+         CREATE TEMP TRIGGER journal_file_trash AFTER UPDATE OF trashed ON photo
+         WHEN (old.trashed IS NULL) != (new.trashed IS NULL) BEGIN
+             INSERT INTO journal_file SELECT new.id, new.location, old.trash_location, new.trash_location, 0
+             FROM journal_capture WHERE replaying;
+         END;
+         CREATE TEMP TRIGGER journal_file_insert AFTER INSERT ON photo BEGIN
+             INSERT INTO journal_file SELECT new.id, new.location, NULL, new.trash_location, 1
+             FROM journal_capture WHERE replaying;
+         END;",
+    );
+    for table in tables
+        .iter()
+        .filter(|table| !UNJOURNALED.contains(&table.as_str()))
+    {
+        let columns: Vec<(String, String, i64)> = connection
+            .prepare("SELECT name, type, pk FROM pragma_table_info(?1)")?
+            .query_map([table], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        sql += &capture_triggers(table, &columns);
+    }
+    connection.execute_batch(&sql)
+}
+
+/// The three triggers journaling `table`, given its columns as (name, type, pk).
+fn capture_triggers(table: &str, columns: &[(String, String, i64)]) -> String {
+    // A lone INTEGER PRIMARY KEY is the rowid itself, which the steps set directly
+    let keys: Vec<&str> = columns
+        .iter()
+        .filter(|(_, _, pk)| *pk > 0)
+        .map(|(name, _, _)| name.as_str())
+        .collect();
+    let alias = match columns.iter().find(|(_, _, pk)| *pk > 0) {
+        Some((name, kind, _)) if keys.len() == 1 && kind.eq_ignore_ascii_case("INTEGER") => {
+            Some(name.as_str())
+        }
+        _ => None,
+    };
+    let columns: Vec<&str> = columns
+        .iter()
+        .map(|(name, _, _)| name.as_str())
+        .filter(|name| Some(*name) != alias)
+        .collect();
+    // Other rowids are handed out again once a row is deleted, so a step also matches the
+    // primary key, or every column of a table without one, to never touch another row
+    let matched = match (alias, keys.is_empty()) {
+        (Some(_), _) => Vec::new(),
+        (None, false) => keys,
+        (None, true) => columns.clone(),
+    };
+    let row = |version: &str| {
+        let mut condition = format!("' WHERE rowid = ' || {version}.rowid");
+        for column in &matched {
+            condition += &format!(" || ' AND \"{column}\" IS ' || quote({version}.\"{column}\")");
+        }
+        condition
+    };
+    let trigger = |event: &str, when: String, statement: String| {
+        format!(
+            "CREATE TEMP TRIGGER \"journal_{table}_{event}\" AFTER {event} ON \"{table}\" {when} BEGIN
+                 INSERT INTO operation_step (operation_id, statement)
+                     SELECT operation_id, {statement} FROM journal_capture;
+             END;"
+        )
+    };
+    let names = columns
+        .iter()
+        .map(|column| format!(", \"{column}\""))
+        .collect::<String>();
+    let values = columns
+        .iter()
+        .map(|column| format!(" || ', ' || quote(old.\"{column}\")"))
+        .collect::<String>();
+    let mut sql = trigger(
+        "INSERT",
+        String::new(),
+        format!("'DELETE FROM \"{table}\"' || {}", row("new")),
+    );
+    sql += &trigger(
+        "DELETE",
+        String::new(),
+        format!("'INSERT INTO \"{table}\" (rowid{names}) VALUES (' || old.rowid{values} || ')'"),
+    );
+    if !columns.is_empty() {
+        let changed = |column: &str| format!("old.\"{column}\" IS NOT new.\"{column}\"");
+        let when = columns
+            .iter()
+            .map(|column| changed(column))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        // Only the columns that changed are set back, keeping later edits to the others
+        let set = columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "CASE WHEN {} THEN ', \"{column}\" = ' || quote(old.\"{column}\") ELSE '' END",
+                    changed(column)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" || ");
+        sql += &trigger(
+            "UPDATE",
+            format!("WHEN {when}"),
+            format!(
+                "'UPDATE \"{table}\" SET ' || substr({set}, 3) || {}",
+                row("new")
+            ),
+        );
+    }
+    sql
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undoes_and_redoes_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        let pool = DbPool::open(dir.path().to_str().unwrap());
+        for id in ["a", "b"] {
+            let faces = format!(
+                r#"[{{"face_id":"{id}_face","crop_path":"","encoded":"","person_id":null}}]"#
+            );
+            database.import_photo(
+                id,
+                &format!("/pics/{id}.jpg"),
+                "",
+                None,
+                None,
+                "[]",
+                &faces,
+                "",
+            );
+        }
+        database.add_directory("/pics/");
+        let anna = database.assign_name_to_face("a_face", "Anna");
+        let bob = database.assign_name_to_face("b_face", "Bob");
+        let people = |database: &Database| -> Vec<String> {
+            let mut names: Vec<String> =
+                database.get_people().into_iter().map(|p| p.name).collect();
+            names.sort();
+            names
+        };
+
+        // Work done outside an operation is not journaled
+        assert!(history(&database, 10).is_empty());
+
+        record(&mut database, "Merge people", "Anna into Bob", |db| {
+            db.merge_people(&anna, &bob)
+        });
+        assert_eq!(people(&database), vec!["Bob"]);
+        let undone = undo_last(&pool).unwrap().unwrap();
+        assert_eq!(undone.name, "Merge people");
+        assert!(undone.undone);
+        assert_eq!(people(&database), vec!["Anna", "Bob"]);
+        assert_eq!(database.get_person_faces(&anna)[0].face_id, "a_face");
+        redo(&pool).unwrap().unwrap();
+        assert_eq!(people(&database), vec!["Bob"]);
+        assert_eq!(database.get_person_faces(&bob).len(), 2);
+        assert!(redo(&pool).unwrap().is_none());
+
+        // Removing a folder takes the photos' faces along, and undo brings all of it back
+        record(&mut database, "Remove folder", "/pics/", |db| {
+            db.remove_directory_full("/pics/")
+        })
+        .unwrap();
+        assert!(database.get_photo("a").is_none());
+        undo_last(&pool).unwrap().unwrap();
+        assert!(database.get_photo("a").is_some());
+        assert_eq!(database.list_directories(), vec!["/pics/"]);
+        assert_eq!(database.get_person_faces(&bob).len(), 2);
+
+        // A new operation cannot be redone over; one that changes nothing is not kept
+        record(&mut database, "Rename person", "Bob", |db| {
+            db.rename_person(&bob, "Robert")
+        });
+        record(&mut database, "Rename person", "Robert", |db| {
+            db.rename_person(&bob, "Robert")
+        });
+        assert!(redo(&pool).unwrap().is_none());
+        let names: Vec<String> = history(&database, 10)
+            .into_iter()
+            .map(|operation| operation.name)
+            .collect();
+        assert_eq!(names, vec!["Rename person", "Merge people"]);
+    }
+
+    #[test]
+    fn replays_move_files_and_leave_other_rows_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config").to_string_lossy().to_string();
+        let pictures = dir.path().join("pics");
+        std::fs::create_dir_all(&pictures).unwrap();
+        let photo_path = pictures.join("a.jpg");
+        std::fs::write(&photo_path, b"jpeg").unwrap();
+        let location = photo_path.to_string_lossy().to_string();
+        let mut database = Database::new(&config_path);
+        let pool = DbPool::open(&config_path);
+        database.import_photo("a", &location, "", None, None, "[]", "[]", "");
+        let ids = vec!["a".to_string()];
+
+        // The file follows the photo out of the trash and back in
+        trash::trash_photos(&config_path, &pool, &ids, "Delete", "1 photo".to_string()).unwrap();
+        assert!(!photo_path.exists());
+        undo_last(&pool).unwrap().unwrap();
+        assert!(photo_path.exists());
+        assert!(database.list_trash().is_empty());
+        redo(&pool).unwrap().unwrap();
+        assert!(!photo_path.exists());
+        assert_eq!(database.list_trash().len(), 1);
+        // A file that cannot be moved back keeps the photo in the trash
+        let trashed = trash::trash_dir(&config_path).join("a_a.jpg");
+        let aside = dir.path().join("aside.jpg");
+        std::fs::rename(&trashed, &aside).unwrap();
+        assert!(undo_last(&pool).is_err());
+        assert_eq!(database.list_trash().len(), 1);
+        std::fs::rename(&aside, &trashed).unwrap();
+        undo_last(&pool).unwrap().unwrap();
+
+        // Photos brought back without their file are reported and marked missing
+        let folder = pictures.to_string_lossy().to_string();
+        record(&mut database, "Remove folder", &folder, |db| {
+            db.remove_directory_full(&folder)
+        })
+        .unwrap();
+        std::fs::remove_file(&photo_path).unwrap();
+        let undone = undo_last(&pool).unwrap().unwrap();
+        assert_eq!(undone.missing, vec![location]);
+        assert_eq!(database.list_missing()[0].id, "a");
+
+        // Another album given the deleted one's rowid is not removed by undo
+        let album = record(&mut database, "Create album", "Trip", |db| {
+            db.create_album("Trip", "")
+        })
+        .unwrap();
+        database.delete_album(&album.id).unwrap();
+        let other = database.create_album("Other", "").unwrap();
+        assert!(undo_last(&pool).is_err());
+        assert!(database.get_album(&other.id).is_some());
+    }
+}
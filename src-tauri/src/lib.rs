@@ -4,15 +4,33 @@ use std::sync::Arc;
 use std::time::SystemTime;
 use tauri::Emitter;
 use tauri::Manager;
+use tracing::{debug, error, info, warn, Instrument};
 
+mod backup;
 mod config;
 mod database;
 mod directory;
+mod events;
 mod face_detector;
 mod file;
+mod geocode;
+mod journal;
+mod logging;
+mod memories;
+mod metadata;
+mod migrations;
 mod ml;
+mod pool;
+mod query;
+mod reconcile;
 mod server;
+mod similarity;
+mod smart_album;
+mod stats;
+mod timestamp;
 mod transport;
+mod trash;
+mod xmp;
 
 struct WebRtcState {
     active_session: std::sync::Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
@@ -29,15 +47,23 @@ fn get_config_path(app: &tauri::AppHandle) -> String {
 
 #[tauri::command]
 fn scan_files(app: tauri::AppHandle) {
-    println!("Starting media scan...");
+    info!("Starting media scan...");
     let path = get_config_path(&app);
     if path.is_empty() {
-        println!("Error: Config path is empty, cannot scan.");
+        error!("Config path is empty, cannot scan.");
         return;
     }
-    let database = database::Database::new(&path);
-    let folders = database.list_directories();
-    println!("Found {} folders to scan in database.", folders.len());
+    let database = app.state::<pool::DbPool>().inner().clone();
+    match database.write({
+        let path = path.clone();
+        move |db| trash::purge_expired(&path, db)
+    }) {
+        Ok(0) => {}
+        Ok(purged) => info!("Purged {purged} photos past their trash retention period."),
+        Err(e) => warn!("Failed to purge expired trash: {e}"),
+    }
+    let folders = database.read(|db| db.list_directories());
+    info!("Found {} folders to scan in database.", folders.len());
 
     if !folders.is_empty() {
         use tauri_plugin_notification::NotificationExt;
@@ -60,14 +86,11 @@ fn scan_files(app: tauri::AppHandle) {
     // Shared batcher for all folders in this scan session
     let (batch_tx, mut batch_rx) = tokio::sync::mpsc::unbounded_channel::<database::Photo>();
     let app_handle_for_batch = app.clone();
-    let path_for_batch = path.clone();
+    let database_for_batch = database.clone();
 
     tauri::async_runtime::spawn(async move {
         let mut buffer: Vec<database::Photo> = Vec::new();
         let mut interval = tokio::time::interval(std::time::Duration::from_millis(1000)); // Slower UI updates
-        let database = Arc::new(std::sync::Mutex::new(database::Database::new(
-            &path_for_batch,
-        )));
 
         loop {
             tokio::select! {
@@ -78,12 +101,13 @@ fn scan_files(app: tauri::AppHandle) {
                             let _ = app_handle_for_batch.emit("photos-discovered", &ui_batch);
                         }
                         let db_to_save = buffer.clone();
-                        let db_arc = Arc::clone(&database);
-                        let _ = tauri::async_runtime::spawn_blocking(move || {
-                            if let Ok(mut db) = db_arc.lock() {
-                                let _ = db.store_photo_batch(&db_to_save);
-                            }
+                        let database = database_for_batch.clone();
+                        let saved = tauri::async_runtime::spawn_blocking(move || {
+                            database.write(move |db| db.store_photo_batch(&db_to_save))
                         }).await;
+                        if let Ok(Err(e) | Ok(Err(e))) = saved {
+                            error!("Failed to save scanned photos: {e}");
+                        }
                         buffer.clear();
                     }
                 }
@@ -95,12 +119,13 @@ fn scan_files(app: tauri::AppHandle) {
                             let _ = app_handle_for_batch.emit("photos-discovered", &ui_batch);
                         }
                         let db_to_save = buffer.clone();
-                        let db_arc = Arc::clone(&database);
-                        let _ = tauri::async_runtime::spawn_blocking(move || {
-                            if let Ok(mut db) = db_arc.lock() {
-                                let _ = db.store_photo_batch(&db_to_save);
-                            }
+                        let database = database_for_batch.clone();
+                        let saved = tauri::async_runtime::spawn_blocking(move || {
+                            database.write(move |db| db.store_photo_batch(&db_to_save))
                         }).await;
+                        if let Ok(Err(e) | Ok(Err(e))) = saved {
+                            error!("Failed to save scanned photos: {e}");
+                        }
                         buffer.clear();
                     }
                 }
@@ -114,30 +139,45 @@ fn scan_files(app: tauri::AppHandle) {
 
     std::thread::spawn(move || {
         let total = folders.len();
+        let _scan = tracing::info_span!("scan", folders = total).entered();
         if total == 0 {
-            println!("No folders to scan. Skipping scan thread.");
+            info!("No folders to scan. Skipping scan thread.");
             return;
         }
+        let missing = reconcile::Missing::find(&database);
 
         for (i, folder) in folders.iter().enumerate() {
             if abort_flag.load(std::sync::atomic::Ordering::SeqCst) {
-                println!("Scan aborted by user.");
+                info!("Scan aborted by user.");
                 return;
             }
             let progress = (i as f32 / total as f32 * 100.0) as u32;
             let _ = app.emit("scan-progress", serde_json::json!({ "status": "scanning", "progress": progress, "current": i + 1, "total": total, "current_directory": folder }));
-            println!("Scanning folder {} of {}: {}", i + 1, total, folder);
-            file::scan_folder(&app, folder.clone(), &path, &batch_tx_shared);
+            info!("Scanning folder {} of {}: {}", i + 1, total, folder);
+            file::scan_folder(&app, folder.clone(), &database, &batch_tx_shared, &missing);
         }
 
-        println!("Finished scanning all folders. Updating last scan time...");
-        let database = database::Database::new(&path);
+        // Only a scan that went through every folder knows a file turned up nowhere
+        if !abort_flag.load(std::sync::atomic::Ordering::SeqCst) {
+            let missing: Vec<String> = missing.remaining().into_iter().map(|p| p.id).collect();
+            if !missing.is_empty() {
+                warn!("{} photos are missing their file", missing.len());
+            }
+            let _ = app.emit("photos-missing", missing.len());
+            if let Err(e) = database.write(move |db| db.set_missing(&missing)) {
+                warn!("Failed to mark missing photos: {e}");
+            }
+        }
+
+        info!("Finished scanning all folders. Updating last scan time...");
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs()
             .to_string();
-        database.set_last_scan_time(timestamp);
+        if let Err(e) = database.write(move |db| db.set_last_scan_time(timestamp)) {
+            warn!("Failed to save the last scan time: {e}");
+        }
         let _ = app.emit(
             "scan-progress",
             serde_json::json!({ "status": "complete", "progress": 100 }),
@@ -151,6 +191,47 @@ fn scan_files(app: tauri::AppHandle) {
             .body("Media scan complete")
             .show();
 
+        // Before events, whose titles name the place they happened in
+        if let Err(e) = database
+            .write(geocode::backfill)
+            .and_then(|looked_up| looked_up)
+        {
+            warn!("Failed to look up photo places: {e}");
+        }
+
+        if let Err(e) = database.write(events::update).and_then(|grouped| grouped) {
+            warn!("Failed to group photos into events: {e}");
+        }
+
+        let today = chrono::Local::now().date_naive();
+        match database
+            .write(move |db| memories::refresh(db, today))
+            .and_then(|ready| ready)
+        {
+            Ok(ready) if !ready.is_empty() => {
+                let _ = app.emit("memories-ready", &ready);
+                let notify = database
+                    .read(|db| db.get_state())
+                    .get("memory_notifications")
+                    .is_none_or(|value| value != "false");
+                if notify {
+                    let _ = app
+                        .notification()
+                        .builder()
+                        .title("New memories")
+                        .body(ready.join(", "))
+                        .show();
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to build memories: {e}"),
+        }
+
+        let written = xmp::write_back(&database);
+        if written > 0 {
+            info!("Wrote {written} XMP sidecars.");
+        }
+
         // Final signal to process everything found in the discovery pass
         if let Some(state) = app.try_state::<ml::MlContext>() {
             let _ = state.tx.send("__START__".to_string());
@@ -237,12 +318,9 @@ async fn download_models(
     let tx = state.tx.clone();
 
     tauri::async_runtime::spawn(async move {
-        emit_log(
-            &app,
-            format!(
-                "Download sequence started. Queue size: {}",
-                files_to_download.len()
-            ),
+        info!(
+            "Download sequence started. Queue size: {}",
+            files_to_download.len()
         );
 
         let client = match reqwest::Client::builder()
@@ -253,35 +331,29 @@ async fn download_models(
             .build() {
                 Ok(c) => c,
                 Err(e) => {
-                    emit_log(&app, format!("ERROR: Failed to create HTTP client: {e}"));
+                    error!("Failed to create HTTP client: {e}");
                     return;
                 }
             };
 
         for (model_name, url, filename) in files_to_download {
             let path = models_dir.join(&filename);
-            emit_log(&app, format!("Initiating download: {filename}"));
+            info!("Initiating download: {filename}");
             let mut response = match client.get(&url).send().await {
                 Ok(r) => {
-                    emit_log(
-                        &app,
-                        format!("Response received for {}: Status {}", filename, r.status()),
-                    );
+                    info!("Response received for {}: Status {}", filename, r.status());
                     r
                 }
                 Err(e) => {
-                    emit_log(&app, format!("ERROR: Request failed for {filename}: {e}"));
+                    error!("Request failed for {filename}: {e}");
                     continue;
                 }
             };
 
             if !response.status().is_success() {
-                emit_log(
-                    &app,
-                    format!(
-                        "ERROR: Download failed for {filename}: Status {}",
-                        response.status()
-                    ),
+                error!(
+                    "Download failed for {filename}: Status {}",
+                    response.status()
                 );
                 continue;
             }
@@ -290,10 +362,7 @@ async fn download_models(
             let mut file = match tokio::fs::File::create(&tmp_path).await {
                 Ok(f) => f,
                 Err(e) => {
-                    emit_log(
-                        &app,
-                        format!("ERROR: Failed to create temp file {filename}: {e}"),
-                    );
+                    error!("Failed to create temp file {filename}: {e}");
                     continue;
                 }
             };
@@ -324,14 +393,14 @@ async fn download_models(
             if success {
                 drop(file);
                 if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
-                    emit_log(&app, format!("ERROR: Failed to move {filename}: {e}"));
+                    error!("Failed to move {filename}: {e}");
                     let _ = tokio::fs::remove_file(&tmp_path).await;
                 } else {
-                    emit_log(&app, format!("SUCCESS: Finished downloading {filename}"));
+                    info!("Finished downloading {filename}");
                 }
             } else {
                 let _ = tokio::fs::remove_file(&tmp_path).await;
-                emit_log(&app, format!("ERROR: Download interrupted for {filename}"));
+                error!("Download interrupted for {filename}");
             }
         }
         let _ = tx.send("__RELOAD_MODELS__".to_string());
@@ -342,31 +411,45 @@ async fn download_models(
 }
 
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 async fn list_files(
     app: tauri::AppHandle,
-    offset: usize,
+    cursor: Option<String>,
     limit: usize,
+    sort: Option<database::Sort>,
     query: String,
     scan: bool,
     favoritesOnly: bool,
     videosOnly: bool,
-) -> Result<String, String> {
+    albumId: Option<String>,
+    event_id: Option<String>,
+    culling: Option<database::CullingFilter>,
+) -> Result<String, query::QueryError> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return Ok("[]".to_string());
+        return Ok(serde_json::to_string(&database::PhotoPage::default()).unwrap_or_default());
     }
     if scan {
         scan_files(app.clone());
     }
-    let database = database::Database::new(&path);
-    Ok(serde_json::to_string(&database.list_photos(
-        &query,
-        offset,
+    let page = database::Page {
+        sort,
+        after: cursor,
         limit,
-        favoritesOnly,
-        videosOnly,
-    ))
-    .unwrap_or("[]".to_string()))
+    };
+    let filter = database::PhotoFilter {
+        query,
+        favorites_only: favoritesOnly,
+        videos_only: videosOnly,
+        album_id: albumId,
+        event_id,
+        map_cell: None,
+        culling: culling.unwrap_or_default(),
+    };
+    let photos = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_photos(&filter, &page))?;
+    Ok(serde_json::to_string(&photos).unwrap_or_default())
 }
 
 #[tauri::command]
@@ -375,28 +458,108 @@ async fn get_last_scan_time(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "Never".to_string();
     }
-    let database = database::Database::new(&path);
-    database.get_last_scan_time().unwrap_or("Never".to_string())
+    app.state::<pool::DbPool>()
+        .read(|db| db.get_last_scan_time())
+        .unwrap_or("Never".to_string())
 }
 
 #[tauri::command]
-async fn toggle_favorite(app: tauri::AppHandle, id: String) -> bool {
+async fn toggle_favorite(app: tauri::AppHandle, id: String) -> Result<bool, String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return false;
+        return Ok(false);
     }
-    let database = database::Database::new(&path);
-    database.toggle_favorite(&id)
+    journaled(&app, "Favorite", photos(1), move |db| {
+        db.toggle_favorite(&id)
+    })
+}
+
+/// Runs a user's edit on the writer as one operation of the journal, so it can be undone.
+fn journaled<T, F>(
+    app: &tauri::AppHandle,
+    name: &'static str,
+    detail: String,
+    f: F,
+) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut database::Database) -> T + Send + 'static,
+{
+    app.state::<pool::DbPool>()
+        .write(move |db| journal::record(db, name, &detail, f))
+}
+
+/// "1 photo" or "3 photos", for operation details.
+fn photos(count: usize) -> String {
+    match count {
+        1 => "1 photo".to_string(),
+        n => format!("{n} photos"),
+    }
+}
+
+/// Writes changed ratings, labels and names to XMP sidecars in the background, when enabled.
+fn write_sidecars(app: &tauri::AppHandle) {
+    let database = app.state::<pool::DbPool>().inner().clone();
+    std::thread::spawn(move || xmp::write_back(&database));
+}
+
+#[tauri::command]
+async fn set_rating(app: tauri::AppHandle, ids: Vec<String>, rating: u8) -> Result<usize, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = format!("{rating} stars on {}", photos(ids.len()));
+    let changed = journaled(&app, "Rate", detail, move |db| db.set_rating(&ids, rating))?;
+    write_sidecars(&app);
+    changed
+}
+
+#[tauri::command]
+async fn set_flag(
+    app: tauri::AppHandle,
+    ids: Vec<String>,
+    flag: database::Flag,
+) -> Result<usize, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = format!("{flag:?} on {}", photos(ids.len()));
+    let changed = journaled(&app, "Flag", detail, move |db| db.set_flag(&ids, flag))?;
+    write_sidecars(&app);
+    changed
+}
+
+#[tauri::command]
+async fn set_color_label(
+    app: tauri::AppHandle,
+    ids: Vec<String>,
+    label: Option<database::ColorLabel>,
+) -> Result<usize, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = format!(
+        "{} on {}",
+        label.map_or("No label", |label| label.as_str()),
+        photos(ids.len())
+    );
+    let changed = journaled(&app, "Color label", detail, move |db| {
+        db.set_color_label(&ids, label)
+    })?;
+    write_sidecars(&app);
+    changed
 }
 
 #[tauri::command]
-async fn add_directory(app: tauri::AppHandle, path: String) {
+async fn add_directory(app: tauri::AppHandle, path: String) -> Result<(), String> {
     let config_path = get_config_path(&app);
     if config_path.is_empty() {
-        return;
+        return Ok(());
     }
-    let database = database::Database::new(&config_path);
-    database.add_directory(&path);
+    directory::add_directory(path, &app.state::<pool::DbPool>())
 }
 
 #[tauri::command]
@@ -405,18 +568,17 @@ async fn list_directories(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.list_directories()).unwrap_or("[]".to_string())
+    let directories = directory::list_directories(&app.state::<pool::DbPool>());
+    serde_json::to_string(&directories).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
-async fn remove_directory(app: tauri::AppHandle, path: String) {
+async fn remove_directory(app: tauri::AppHandle, path: String) -> Result<(), String> {
     let config_path = get_config_path(&app);
     if config_path.is_empty() {
-        return;
+        return Ok(());
     }
-    let database = database::Database::new(&config_path);
-    database.remove_directory(path);
+    directory::remove_directory(path, &app.state::<pool::DbPool>())
 }
 
 #[tauri::command]
@@ -424,6 +586,69 @@ async fn read_file_base64(path: String) -> String {
     file::read_file_base64(path)
 }
 
+/// Warns about photos undo or redo brought back without their file.
+fn report_missing(app: &tauri::AppHandle, operation: Option<&journal::Operation>) {
+    let Some(operation) = operation.filter(|o| !o.missing.is_empty()) else {
+        return;
+    };
+    warn!(
+        "{} photos brought back by \"{}\" are missing their file",
+        operation.missing.len(),
+        operation.name
+    );
+    let missing = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_missing().len());
+    let _ = app.emit("photos-missing", missing);
+}
+
+/// Reverses the latest journaled operation, returning it, or `null` with nothing to undo.
+#[tauri::command]
+async fn undo_last(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, ml::MlContext>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let operation = journal::undo_last(&app.state::<pool::DbPool>())?;
+    report_missing(&app, operation.as_ref());
+    write_sidecars(&app);
+    let _ = state.tx.send("__RELOAD_MODELS__".to_string());
+    serde_json::to_string(&operation).map_err(|e| e.to_string())
+}
+
+/// Applies again the operation undone last, returning it, or `null` with nothing to redo.
+#[tauri::command]
+async fn redo(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, ml::MlContext>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let operation = journal::redo(&app.state::<pool::DbPool>())?;
+    report_missing(&app, operation.as_ref());
+    write_sidecars(&app);
+    let _ = state.tx.send("__RELOAD_MODELS__".to_string());
+    serde_json::to_string(&operation).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_history(app: tauri::AppHandle, limit: Option<usize>) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let limit = limit.unwrap_or(journal::MAX_OPERATIONS as usize);
+    let operations = app
+        .state::<pool::DbPool>()
+        .read(|db| journal::history(db, limit));
+    serde_json::to_string(&operations).unwrap_or("[]".to_string())
+}
+
 #[tauri::command]
 async fn get_raw_photo(path: String) -> String {
     file::read_file_base64(path)
@@ -435,8 +660,8 @@ async fn get_people(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.get_people()).unwrap_or("[]".to_string())
+    let people = app.state::<pool::DbPool>().read(|db| db.get_people());
+    serde_json::to_string(&people).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
@@ -445,8 +670,10 @@ async fn get_unnamed_faces(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.get_anonymous_people_groups()).unwrap_or("[]".to_string())
+    let groups = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_anonymous_people_groups());
+    serde_json::to_string(&groups).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
@@ -455,16 +682,18 @@ fn assign_name_to_face(
     state: tauri::State<'_, ml::MlContext>,
     face_id: String,
     name: String,
-) -> String {
+) -> Result<String, String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return "".to_string();
+        return Ok("".to_string());
     }
-    let database = database::Database::new(&path);
-    let id = database.assign_name_to_face(&face_id, &name);
+    let id = journaled(&app, "Name face", name.clone(), move |db| {
+        db.assign_name_to_face(&face_id, &name)
+    })?;
+    write_sidecars(&app);
 
     let _ = state.tx.send("__RELOAD_MODELS__".to_string());
-    id
+    Ok(id)
 }
 
 #[tauri::command]
@@ -473,11 +702,10 @@ async fn get_person_photos(app: tauri::AppHandle, person_id: String) -> Result<S
     if path.is_empty() {
         return Ok("[]".to_string());
     }
-    let database = database::Database::new(&path);
-    Ok(
-        serde_json::to_string(&database.get_photos_for_person(&person_id))
-            .unwrap_or("[]".to_string()),
-    )
+    let photos = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_photos_for_person(&person_id));
+    Ok(serde_json::to_string(&photos).unwrap_or("[]".to_string()))
 }
 
 #[tauri::command]
@@ -486,8 +714,9 @@ async fn is_initialized(app: tauri::AppHandle) -> bool {
     if path.is_empty() {
         return false;
     }
-    let database = database::Database::new(&path);
-    !database.list_directories().is_empty()
+    !app.state::<pool::DbPool>()
+        .read(|db| db.list_directories())
+        .is_empty()
 }
 
 #[tauri::command]
@@ -496,8 +725,10 @@ async fn get_person_faces(app: tauri::AppHandle, person_id: String) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.get_person_faces(&person_id)).unwrap_or("[]".to_string())
+    let faces = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_person_faces(&person_id));
+    serde_json::to_string(&faces).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
@@ -506,20 +737,24 @@ async fn get_faces_for_photo(app: tauri::AppHandle, photo_id: String) -> String
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.get_faces_for_photo(&photo_id)).unwrap_or("[]".to_string())
+    let faces = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_faces_for_photo(&photo_id));
+    serde_json::to_string(&faces).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
-async fn delete_face(app: tauri::AppHandle, face_id: String) {
+async fn delete_face(app: tauri::AppHandle, face_id: String) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
     }
-    let database = database::Database::new(&path);
-    let _ = database
-        .connection
-        .execute("DELETE FROM faces WHERE face_id = ?1", [&face_id]);
+    journaled(&app, "Delete face", String::new(), move |db| {
+        let _ = db
+            .connection
+            .execute("DELETE FROM faces WHERE face_id = ?1", [&face_id]);
+    })?;
+    Ok(())
 }
 
 #[tauri::command]
@@ -528,18 +763,20 @@ async fn get_top_tags(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    let mut tags: Vec<String> = Vec::new();
-    if let Ok(mut stmt) = database
-        .connection
-        .prepare("SELECT class FROM object GROUP BY class ORDER BY COUNT(*) DESC LIMIT 5")
-    {
-        if let Ok(iter) = stmt.query_map([], |row| row.get(0)) {
-            for item in iter.flatten() {
-                tags.push(item);
+    let tags = app.state::<pool::DbPool>().read(|db| {
+        let mut tags: Vec<String> = Vec::new();
+        if let Ok(mut stmt) = db
+            .connection
+            .prepare("SELECT class FROM object o JOIN photo p ON p.id = o.photo_id WHERE p.trashed IS NULL GROUP BY class ORDER BY COUNT(*) DESC LIMIT 5")
+        {
+            if let Ok(iter) = stmt.query_map([], |row| row.get(0)) {
+                for item in iter.flatten() {
+                    tags.push(item);
+                }
             }
         }
-    }
+        tags
+    });
     serde_json::to_string(&tags).unwrap_or("[]".to_string())
 }
 
@@ -549,47 +786,105 @@ fn merge_people(
     state: tauri::State<'_, ml::MlContext>,
     from_id: String,
     to_id: String,
-) {
+) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
     }
-    let db = database::Database::new(&path);
-    db.merge_people(&from_id, &to_id);
+    app.state::<pool::DbPool>().write(move |db| {
+        let detail = format!(
+            "{} into {}",
+            db.person_name(&from_id).unwrap_or_default(),
+            db.person_name(&to_id).unwrap_or_default()
+        );
+        journal::record(db, "Merge people", &detail, |db| {
+            db.merge_people(&from_id, &to_id)
+        })
+    })?;
+    write_sidecars(&app);
 
     let _ = state.tx.send("__RELOAD_MODELS__".to_string());
+    Ok(())
 }
 
 #[tauri::command]
-async fn rename_person(app: tauri::AppHandle, id: String, new_name: String) {
+async fn rename_person(app: tauri::AppHandle, id: String, new_name: String) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
     }
-    let db = database::Database::new(&path);
-    db.rename_person(&id, &new_name);
+    journaled(&app, "Rename person", new_name.clone(), move |db| {
+        db.rename_person(&id, &new_name)
+    })?;
+    write_sidecars(&app);
+    Ok(())
 }
 
 #[tauri::command]
-async fn cleanup_database(app: tauri::AppHandle) {
+async fn cleanup_database(app: tauri::AppHandle) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
+    }
+    // The connections stay open, so the library is emptied in place rather than by
+    // deleting siegu.db from under them
+    app.state::<pool::DbPool>().write(|db| db.clear())?;
+    Ok(())
+}
+
+/// Writes the database and face crops to a single archive at `path`.
+#[tauri::command]
+async fn export_library(app: tauri::AppHandle, path: String) -> Result<String, String> {
+    let config_path = get_config_path(&app);
+    if config_path.is_empty() {
+        return Err("Config error".to_string());
     }
-    let db_path = std::path::Path::new(&path).join("siegu.db");
-    if db_path.exists() {
-        let _ = std::fs::remove_file(db_path);
+    let db = app.state::<pool::DbPool>().inner().clone();
+    let manifest = tauri::async_runtime::spawn_blocking(move || {
+        backup::export(&db, &config_path, std::path::Path::new(&path))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    serde_json::to_string(&manifest).map_err(|e| e.to_string())
+}
+
+/// Replaces the library with an archive written by `export_library`. `roots` maps folders
+/// listed in the archive's manifest to where those photos are now.
+#[tauri::command]
+async fn import_library(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, ml::MlContext>,
+    path: String,
+    roots: Option<HashMap<String, String>>,
+) -> Result<String, String> {
+    let config_path = get_config_path(&app);
+    if config_path.is_empty() {
+        return Err("Config error".to_string());
     }
+    let db = app.state::<pool::DbPool>().inner().clone();
+    let manifest = tauri::async_runtime::spawn_blocking(move || {
+        backup::import(
+            &db,
+            &config_path,
+            std::path::Path::new(&path),
+            &roots.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    let _ = state.tx.send("__RELOAD_MODELS__".to_string());
+    serde_json::to_string(&manifest).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn remove_directory_full(app: tauri::AppHandle, path: String) {
+async fn remove_directory_full(app: tauri::AppHandle, path: String) -> Result<(), String> {
     let config_path = get_config_path(&app);
     if config_path.is_empty() {
-        return;
+        return Err("Config error".to_string());
     }
-    let db = database::Database::new(&config_path);
-    db.remove_directory_full(&path);
+    journaled(&app, "Remove folder and photos", path.clone(), move |db| {
+        db.remove_directory_full(&path)
+    })?
 }
 
 #[tauri::command]
@@ -609,12 +904,14 @@ async fn start_webrtc_session(
     // Abort existing session if any
     if let Ok(mut session) = state.active_session.lock() {
         if let Some(handle) = session.take() {
-            println!("Aborting previous WebRTC session");
+            info!("Aborting previous WebRTC session");
             handle.abort();
         }
 
         let sync_tx_inner = Arc::clone(&state.sync_tx);
+        let db = app.state::<pool::DbPool>().inner().clone();
 
+        let span = tracing::info_span!("sync", room = %roomId, initiator = isInitiator);
         let handle = tauri::async_runtime::spawn(async move {
             let client = transport::WebRtcClient {
                 room_id: roomId,
@@ -622,9 +919,10 @@ async fn start_webrtc_session(
                 signaling_url: signalingUrl,
                 app_handle: Some(app_handle),
                 config_path,
+                db,
                 sync_tx: sync_tx_inner,
             };
-            let _ = client.start().await;
+            let _ = client.start().instrument(span).await;
         });
 
         *session = Some(handle);
@@ -637,7 +935,7 @@ async fn start_webrtc_session(
 async fn stop_webrtc_session(state: tauri::State<'_, WebRtcState>) -> Result<(), String> {
     if let Ok(mut session) = state.active_session.lock() {
         if let Some(handle) = session.take() {
-            println!("Stopping WebRTC session");
+            info!("Stopping WebRTC session");
             handle.abort();
         }
     }
@@ -656,17 +954,18 @@ fn get_indexing_status(state: tauri::State<'_, ml::MlContext>) -> usize {
 }
 
 #[tauri::command]
-async fn join_network(app: tauri::AppHandle, ip: String, name: String) {
-    println!("Adding new device: {name} at {ip}");
+async fn join_network(app: tauri::AppHandle, ip: String, name: String) -> Result<(), String> {
+    info!("Adding new device: {name} at {ip}");
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
     }
-    let db = database::Database::new(&path);
-    let _ = db.connection.execute(
-        "INSERT OR REPLACE INTO device(ip, name) VALUES(?1, ?2)",
-        (ip, name),
-    );
+    app.state::<pool::DbPool>().write(move |db| {
+        let _ = db.connection.execute(
+            "INSERT OR REPLACE INTO device(ip, name) VALUES(?1, ?2)",
+            (ip, name),
+        );
+    })
 }
 
 #[tauri::command]
@@ -675,9 +974,11 @@ async fn remove_device(app: tauri::AppHandle, name: String) -> Result<(), String
     if path.is_empty() {
         return Err("Config error".to_string());
     }
-    let db = database::Database::new(&path);
-    db.connection
-        .execute("DELETE FROM device WHERE name = ?1", [name])
+    app.state::<pool::DbPool>()
+        .write(move |db| {
+            db.connection
+                .execute("DELETE FROM device WHERE name = ?1", [name])
+        })?
         .map_err(|e| e.to_string())?;
     Ok(())
 }
@@ -688,12 +989,12 @@ async fn list_devices(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "[]".to_string();
     }
-    let db = database::Database::new(&path);
-    let mut devices = db.list_devices();
+    let (mut devices, (photo_count, video_count)) = app
+        .state::<pool::DbPool>()
+        .read(|db| (db.list_devices(), db.get_media_counts()));
 
     // Add current host
     let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
-    let (photo_count, video_count) = db.get_media_counts();
 
     devices.insert(
         0,
@@ -718,8 +1019,129 @@ async fn list_objects(app: tauri::AppHandle, query: String) -> Result<String, St
     if path.is_empty() {
         return Ok("[]".to_string());
     }
-    let db = database::Database::new(&path);
-    Ok(serde_json::to_string(&db.list_objects(&query)).unwrap_or("[]".to_string()))
+    let objects = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_objects(&query));
+    Ok(serde_json::to_string(&objects).unwrap_or("[]".to_string()))
+}
+
+#[tauri::command]
+async fn get_media_info(app: tauri::AppHandle, id: String) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok("null".to_string());
+    }
+    let info = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_media_info(&id));
+    Ok(serde_json::to_string(&info).unwrap_or("null".to_string()))
+}
+
+/// Counts photos by camera, lens, aperture, focal length, ISO or file type, optionally
+/// within a search box query, e.g. the apertures used with one camera.
+#[tauri::command]
+async fn list_facets(
+    app: tauri::AppHandle,
+    field: metadata::Facet,
+    query: Option<String>,
+) -> Result<String, query::QueryError> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok("[]".to_string());
+    }
+    let counts = app
+        .state::<pool::DbPool>()
+        .read(|db| db.facet(field, query.as_deref().unwrap_or("")))?;
+    Ok(serde_json::to_string(&counts).unwrap_or("[]".to_string()))
+}
+
+/// Photos whose file was gone at the last scan and was not found moved anywhere in the library.
+#[tauri::command]
+async fn list_missing(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let missing = app.state::<pool::DbPool>().read(|db| db.list_missing());
+    serde_json::to_string(&missing).unwrap_or("[]".to_string())
+}
+
+/// Photo counts and sizes per directory, year, camera and file type, how far ML indexing has
+/// got, and the size of the caches and the database.
+#[tauri::command]
+async fn get_library_stats(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "{}".to_string();
+    }
+    let stats = app
+        .state::<pool::DbPool>()
+        .read(|db| stats::collect(&path, db));
+    serde_json::to_string(&stats).unwrap_or("{}".to_string())
+}
+
+/// Photo and video counts per year, month or day, for the same filters as `list_files`.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn get_timeline(
+    app: tauri::AppHandle,
+    granularity: database::Granularity,
+    query: Option<String>,
+    favorites_only: Option<bool>,
+    videos_only: Option<bool>,
+    album_id: Option<String>,
+    event_id: Option<String>,
+    culling: Option<database::CullingFilter>,
+) -> Result<String, query::QueryError> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok("[]".to_string());
+    }
+    let filter = database::PhotoFilter {
+        query: query.unwrap_or_default(),
+        favorites_only: favorites_only.unwrap_or(false),
+        videos_only: videos_only.unwrap_or(false),
+        album_id,
+        event_id,
+        map_cell: None,
+        culling: culling.unwrap_or_default(),
+    };
+    let buckets = app
+        .state::<pool::DbPool>()
+        .read(|db| db.timeline(granularity, &filter))?;
+    Ok(serde_json::to_string(&buckets).unwrap_or("[]".to_string()))
+}
+
+/// Where a timeline bucket such as `2019-03` starts in `list_files`: the number of photos
+/// before it and the cursor to list from.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn get_timeline_position(
+    app: tauri::AppHandle,
+    bucket: String,
+    sort: Option<database::Sort>,
+    query: Option<String>,
+    favorites_only: Option<bool>,
+    videos_only: Option<bool>,
+    album_id: Option<String>,
+    event_id: Option<String>,
+    culling: Option<database::CullingFilter>,
+) -> Result<Option<database::TimelinePosition>, query::QueryError> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let filter = database::PhotoFilter {
+        query: query.unwrap_or_default(),
+        favorites_only: favorites_only.unwrap_or(false),
+        videos_only: videos_only.unwrap_or(false),
+        album_id,
+        event_id,
+        map_cell: None,
+        culling: culling.unwrap_or_default(),
+    };
+    app.state::<pool::DbPool>()
+        .read(|db| db.timeline_position(&bucket, &filter, sort))
 }
 
 #[tauri::command]
@@ -750,27 +1172,33 @@ async fn index_faces(
     app: tauri::AppHandle,
     state: tauri::State<'_, ml::MlContext>,
 ) -> Result<(), String> {
-    println!("Face indexing requested...");
+    info!("Face indexing requested...");
     let path = get_config_path(&app);
     if path.is_empty() {
         return Err("Config error".to_string());
     }
-    let db = database::Database::new(&path);
+    let database = app.state::<pool::DbPool>();
 
     // Force indexing_mode to immediate so the worker actually processes the items
     let mut state_map = std::collections::HashMap::new();
     state_map.insert("indexing_mode".to_string(), "immediate".to_string());
-    db.set_state(state_map);
-
-    let mut photo_ids = Vec::new();
-    if let Ok(mut stmt) = db.connection.prepare("SELECT id FROM photo") {
-        if let Ok(rows) = stmt.query_map([], |row| row.get::<_, String>(0)) {
-            for id in rows.flatten() {
-                photo_ids.push(id);
+    database.write(move |db| db.set_state(state_map))?;
+
+    let photo_ids = database.read(|db| {
+        let mut photo_ids = Vec::new();
+        if let Ok(mut stmt) = db
+            .connection
+            .prepare("SELECT id FROM photo WHERE trashed IS NULL")
+        {
+            if let Ok(rows) = stmt.query_map([], |row| row.get::<_, String>(0)) {
+                for id in rows.flatten() {
+                    photo_ids.push(id);
+                }
             }
         }
-    }
-    println!("Found {} photos to index", photo_ids.len());
+        photo_ids
+    });
+    info!("Found {} photos to index", photo_ids.len());
     let count = photo_ids.len();
     let total = state
         .pending_count
@@ -794,17 +1222,491 @@ async fn abort_indexing(state: tauri::State<'_, ml::MlContext>) -> Result<(), St
 }
 
 #[tauri::command]
-async fn get_heatmap_data(app: tauri::AppHandle) -> String {
+async fn get_map_clusters(app: tauri::AppHandle, bounds: database::Bounds, zoom: u8) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let clusters = app
+        .state::<pool::DbPool>()
+        .read(|db| db.map_clusters(&bounds, zoom));
+    serde_json::to_string(&clusters).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn list_map_cluster_photos(
+    app: tauri::AppHandle,
+    cluster: String,
+    cursor: Option<String>,
+    limit: usize,
+    sort: Option<database::Sort>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(serde_json::to_string(&database::PhotoPage::default()).unwrap_or_default());
+    }
+    let filter = database::PhotoFilter {
+        map_cell: Some(database::MapCell::parse(&cluster).ok_or("Unknown map cluster")?),
+        ..Default::default()
+    };
+    let page = database::Page {
+        sort,
+        after: cursor,
+        limit,
+    };
+    let photos = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_photos(&filter, &page))
+        .map_err(|e| e.message)?;
+    Ok(serde_json::to_string(&photos).unwrap_or_default())
+}
+
+#[tauri::command]
+async fn find_duplicates(
+    app: tauri::AppHandle,
+    max_distance: Option<u32>,
+    keep: Option<database::KeepStrategy>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok("[]".to_string());
+    }
+    let clusters: Vec<Vec<database::DuplicateCandidate>> = app.state::<pool::DbPool>().read(|db| {
+        let max_distance = duplicate_distance(db, max_distance);
+        similarity::cluster(&db.get_perceptual_hashes(), max_distance)
+            .iter()
+            .map(|ids| db.rank_duplicates(ids, keep.unwrap_or_default()))
+            .collect()
+    });
+    Ok(serde_json::to_string(&clusters).unwrap_or("[]".to_string()))
+}
+
+fn duplicate_distance(db: &database::Database, max_distance: Option<u32>) -> u32 {
+    max_distance
+        .or_else(|| {
+            db.get_state()
+                .get("duplicate_distance")
+                .and_then(|s| s.parse().ok())
+        })
+        .unwrap_or(similarity::DEFAULT_MAX_DISTANCE)
+}
+
+/// Keeps the best copy of a near-duplicate group and moves the others to the trash. Photos
+/// further than `max_distance` from the kept one stay where they are. Returns the id of the
+/// photo that was kept.
+#[tauri::command]
+async fn resolve_duplicates(
+    app: tauri::AppHandle,
+    ids: Vec<String>,
+    keep: Option<database::KeepStrategy>,
+    max_distance: Option<u32>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let database = app.state::<pool::DbPool>();
+    let (ranked, hashes, max_distance) = database.read(|db| {
+        let hashes: HashMap<String, u64> = db
+            .get_perceptual_hashes()
+            .into_iter()
+            .filter(|(id, _)| ids.contains(id))
+            .collect();
+        (
+            db.rank_duplicates(&ids, keep.unwrap_or_default()),
+            hashes,
+            duplicate_distance(db, max_distance),
+        )
+    });
+    let Some((kept, rest)) = ranked.split_first() else {
+        return Err("No photos to resolve".to_string());
+    };
+    let Some(&kept_hash) = hashes.get(&kept.photo.id) else {
+        return Err("The kept photo has no perceptual hash".to_string());
+    };
+    let others: Vec<(String, u64)> = rest
+        .iter()
+        .filter_map(|c| Some((c.photo.id.clone(), *hashes.get(&c.photo.id)?)))
+        .collect();
+    let duplicates = similarity::copies_of(kept_hash, &others, max_distance);
+    let count = duplicates.len();
+    let detail = format!("Kept {}", kept.photo.location);
+    trash::trash_photos(&path, &database, &duplicates, "Resolve duplicates", detail)?;
+    info!("Trashed {count} duplicate(s) of {}", kept.photo.location);
+    Ok(kept.photo.id.clone())
+}
+
+#[tauri::command]
+async fn delete_photos(app: tauri::AppHandle, ids: Vec<String>) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = photos(ids.len());
+    trash::trash_photos(&path, &app.state::<pool::DbPool>(), &ids, "Delete", detail)
+}
+
+#[tauri::command]
+async fn restore_photos(app: tauri::AppHandle, ids: Vec<String>) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = photos(ids.len());
+    trash::restore_photos(&app.state::<pool::DbPool>(), &ids, detail)
+}
+
+#[tauri::command]
+async fn list_trash(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let trash = app.state::<pool::DbPool>().read(|db| db.list_trash());
+    serde_json::to_string(&trash).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn empty_trash(app: tauri::AppHandle) -> Result<usize, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(0);
+    }
+    app.state::<pool::DbPool>()
+        .write(move |db| trash::purge(&path, db, None))
+}
+
+#[tauri::command]
+async fn list_albums(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let albums = app.state::<pool::DbPool>().read(|db| db.list_albums());
+    serde_json::to_string(&albums).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn create_album(
+    app: tauri::AppHandle,
+    name: String,
+    description: Option<String>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let album = journaled(&app, "Create album", name.clone(), move |db| {
+        db.create_album(&name, &description.unwrap_or_default())
+    })?
+    .ok_or("Failed to create album")?;
+    serde_json::to_string(&album).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rename_album(app: tauri::AppHandle, id: String, name: String) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Rename album", name.clone(), move |db| {
+        db.rename_album(&id, &name)
+    })?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_album_description(
+    app: tauri::AppHandle,
+    id: String,
+    description: String,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Describe album", String::new(), move |db| {
+        db.set_album_description(&id, &description)
+    })?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_album_cover(
+    app: tauri::AppHandle,
+    id: String,
+    photo_id: Option<String>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    journaled(&app, "Set album cover", String::new(), move |db| {
+        db.set_album_cover(&id, photo_id.as_deref())
+    })?
+}
+
+#[tauri::command]
+async fn delete_album(app: tauri::AppHandle, id: String) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Delete album", String::new(), move |db| {
+        db.delete_album(&id)
+    })?
+}
+
+#[tauri::command]
+async fn add_to_album(
+    app: tauri::AppHandle,
+    id: String,
+    photo_ids: Vec<String>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    journaled(&app, "Add to album", photos(photo_ids.len()), move |db| {
+        db.add_photos_to_album(&id, &photo_ids)
+    })?
+}
+
+#[tauri::command]
+async fn remove_from_album(
+    app: tauri::AppHandle,
+    id: String,
+    photo_ids: Vec<String>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    journaled(
+        &app,
+        "Remove from album",
+        photos(photo_ids.len()),
+        move |db| db.remove_photos_from_album(&id, &photo_ids),
+    )?
+}
+
+#[tauri::command]
+async fn reorder_album(
+    app: tauri::AppHandle,
+    id: String,
+    photo_ids: Vec<String>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    journaled(&app, "Reorder album", photos(photo_ids.len()), move |db| {
+        db.reorder_album(&id, &photo_ids)
+    })?
+}
+
+#[tauri::command]
+async fn list_smart_albums(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let albums = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_smart_albums());
+    serde_json::to_string(&albums).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn create_smart_album(
+    app: tauri::AppHandle,
+    name: String,
+    rules: smart_album::Rule,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let album = journaled(&app, "Create smart album", name.clone(), move |db| {
+        db.create_smart_album(&name, &rules)
+    })?
+    .ok_or("Failed to create smart album")?;
+    serde_json::to_string(&album).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn update_smart_album(
+    app: tauri::AppHandle,
+    id: String,
+    name: String,
+    rules: smart_album::Rule,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Edit smart album", name.clone(), move |db| {
+        db.update_smart_album(&id, &name, &rules)
+    })?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn delete_smart_album(app: tauri::AppHandle, id: String) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Delete smart album", String::new(), move |db| {
+        db.delete_smart_album(&id)
+    })?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_tags(app: tauri::AppHandle) -> String {
     let path = get_config_path(&app);
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    let photos = database.get_all_photos_with_location();
-    println!("DEBUG: Found {} photos with GPS for heatmap", photos.len());
+    let tags = app.state::<pool::DbPool>().read(|db| db.list_tags());
+    serde_json::to_string(&tags).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn create_tag(app: tauri::AppHandle, tag_path: String) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let tag = journaled(&app, "Create tag", tag_path.clone(), move |db| {
+        db.create_tag(&tag_path)
+    })??;
+    serde_json::to_string(&tag).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn assign_tags(
+    app: tauri::AppHandle,
+    photo_ids: Vec<String>,
+    paths: Vec<String>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let detail = format!("{} on {}", paths.join(", "), photos(photo_ids.len()));
+    journaled(&app, "Tag", detail, move |db| {
+        db.assign_tags(&photo_ids, &paths)
+    })??;
+    write_sidecars(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn remove_tags(
+    app: tauri::AppHandle,
+    photo_ids: Vec<String>,
+    tag_ids: Vec<i64>,
+) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    journaled(&app, "Untag", photos(photo_ids.len()), move |db| {
+        db.remove_tags(&photo_ids, &tag_ids)
+    })??;
+    write_sidecars(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn rename_tag(app: tauri::AppHandle, id: i64, name: String) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let tag = journaled(&app, "Rename tag", name.clone(), move |db| {
+        db.rename_tag(id, &name)
+    })??;
+    write_sidecars(&app);
+    serde_json::to_string(&tag).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn move_tag(
+    app: tauri::AppHandle,
+    id: i64,
+    parent_id: Option<i64>,
+) -> Result<String, String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Err("Config error".to_string());
+    }
+    let tag = journaled(&app, "Move tag", String::new(), move |db| {
+        db.move_tag(id, parent_id)
+    })??;
+    write_sidecars(&app);
+    serde_json::to_string(&tag).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_tag(app: tauri::AppHandle, id: i64) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Delete tag", String::new(), move |db| {
+        db.delete_tag(id)
+    })?;
+    write_sidecars(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_events(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let events = app.state::<pool::DbPool>().read(|db| db.list_events());
+    serde_json::to_string(&events).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn list_memories(app: tauri::AppHandle) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let memories = app.state::<pool::DbPool>().read(|db| db.list_memories());
+    serde_json::to_string(&memories).unwrap_or("[]".to_string())
+}
+
+#[tauri::command]
+async fn get_memory_photos(app: tauri::AppHandle, id: String) -> String {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return "[]".to_string();
+    }
+    let photos = app
+        .state::<pool::DbPool>()
+        .read(|db| db.list_memory_photos(&id));
     serde_json::to_string(&photos).unwrap_or("[]".to_string())
 }
 
+#[tauri::command]
+async fn dismiss_memory(app: tauri::AppHandle, id: String) -> Result<(), String> {
+    let path = get_config_path(&app);
+    if path.is_empty() {
+        return Ok(());
+    }
+    journaled(&app, "Dismiss memory", String::new(), move |db| {
+        db.dismiss_memory(&id)
+    })?;
+    Ok(())
+}
+
 #[tauri::command]
 async fn get_os() -> String {
     std::env::consts::OS.to_string()
@@ -818,20 +1720,20 @@ async fn initialize_sync_folder(app: tauri::AppHandle, path: String) -> Result<(
     }
     // Also add it to authorized directories
     let path_clone = path.clone();
-    add_directory(app, path_clone).await;
-    Ok(())
+    add_directory(app, path_clone).await
 }
 
 #[tauri::command]
-async fn save_config(app: tauri::AppHandle, key: String, value: String) {
+async fn save_config(app: tauri::AppHandle, key: String, value: String) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
+        return Ok(());
     }
-    let db = database::Database::new(&path);
     let mut state = HashMap::new();
     state.insert(key, value);
-    db.set_state(state);
+    app.state::<pool::DbPool>()
+        .write(move |db| db.set_state(state))?;
+    Ok(())
 }
 
 #[tauri::command]
@@ -840,43 +1742,43 @@ async fn get_config(app: tauri::AppHandle) -> String {
     if path.is_empty() {
         return "{}".to_string();
     }
-    let db = database::Database::new(&path);
-    serde_json::to_string(&db.get_state()).unwrap_or("{}".to_string())
+    let state = app.state::<pool::DbPool>().read(|db| db.get_state());
+    serde_json::to_string(&state).unwrap_or("{}".to_string())
 }
 
 #[tauri::command]
-async fn get_logs(app: tauri::AppHandle, limit: usize) -> String {
+async fn get_logs(
+    app: tauri::AppHandle,
+    limit: usize,
+    level: Option<String>,
+    module: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+) -> String {
     let path = get_config_path(&app);
     if path.is_empty() {
         return "[]".to_string();
     }
-    let database = database::Database::new(&path);
-    serde_json::to_string(&database.get_logs(limit)).unwrap_or("[]".to_string())
+    let filter = database::LogFilter {
+        level,
+        module,
+        from,
+        to,
+    };
+    let logs = app
+        .state::<pool::DbPool>()
+        .read(|db| db.get_logs(&filter, limit));
+    serde_json::to_string(&logs).unwrap_or("[]".to_string())
 }
 
 #[tauri::command]
-async fn clear_logs(app: tauri::AppHandle) {
+async fn clear_logs(app: tauri::AppHandle) -> Result<(), String> {
     let path = get_config_path(&app);
     if path.is_empty() {
-        return;
-    }
-    let database = database::Database::new(&path);
-    database.clear_logs();
-}
-
-pub fn emit_log(app: &tauri::AppHandle, message: String) {
-    println!("{message}");
-    let _ = app.emit("log-message", message.clone());
-    let path = get_config_path(app);
-    if !path.is_empty() {
-        let database = database::Database::new(&path);
-        let level = if message.to_lowercase().contains("error") {
-            "error"
-        } else {
-            "info"
-        };
-        database.store_log(level, &message);
+        return Ok(());
     }
+    app.state::<pool::DbPool>().write(|db| db.clear_logs())?;
+    Ok(())
 }
 
 #[tauri::command]
@@ -897,6 +1799,7 @@ use tauri::{
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    logging::init();
     tauri::Builder::default()
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_os::init())
@@ -942,7 +1845,7 @@ pub fn run() {
                     .build(app)?;
             }
 
-            println!("App is setting up background tasks...");
+            info!("App is setting up background tasks...");
             use tauri_plugin_notification::NotificationExt;
             let app_handle = app.handle().clone();
             tauri::async_runtime::spawn(async move {
@@ -955,8 +1858,12 @@ pub fn run() {
             });
 
             let config_path = get_config_path(app.handle());
+            let db = pool::DbPool::open(&config_path);
+            app.manage(db.clone());
+            logging::attach(app.handle(), db.clone());
+
             let (tx, pending_count, abort) =
-                ml::start_background_worker(app.handle(), config_path.clone());
+                ml::start_background_worker(app.handle(), config_path.clone(), db);
             app.manage(ml::MlContext {
                 tx,
                 pending_count,
@@ -978,7 +1885,7 @@ pub fn run() {
             tauri::async_runtime::spawn(async move {
                 let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600)); // 1 hour
                 loop {
-                    println!("Interval tick: checking for media updates...");
+                    debug!("Interval tick: checking for media updates...");
                     interval.tick().await;
                     scan_files(app_handle_for_interval.clone());
                 }
@@ -1009,6 +1916,9 @@ pub fn run() {
             list_files,
             get_last_scan_time,
             toggle_favorite,
+            set_rating,
+            set_flag,
+            set_color_label,
             add_directory,
             list_directories,
             remove_directory,
@@ -1022,6 +1932,13 @@ pub fn run() {
             merge_people,
             is_initialized,
             get_top_tags,
+            list_objects,
+            get_media_info,
+            list_facets,
+            get_library_stats,
+            list_missing,
+            get_timeline,
+            get_timeline_position,
             get_person_faces,
             get_faces_for_photo,
             delete_face,
@@ -1038,6 +1955,8 @@ pub fn run() {
             rename_person,
             cleanup_database,
             remove_directory_full,
+            export_library,
+            import_library,
             get_media_server_port,
             index_faces,
             abort_indexing,
@@ -1045,8 +1964,42 @@ pub fn run() {
             save_config,
             get_config,
             get_indexing_status,
-            get_heatmap_data,
+            get_map_clusters,
+            list_map_cluster_photos,
             initialize_sync_folder,
+            find_duplicates,
+            resolve_duplicates,
+            delete_photos,
+            restore_photos,
+            list_trash,
+            empty_trash,
+            list_albums,
+            create_album,
+            rename_album,
+            set_album_description,
+            set_album_cover,
+            delete_album,
+            add_to_album,
+            remove_from_album,
+            reorder_album,
+            list_smart_albums,
+            create_smart_album,
+            update_smart_album,
+            delete_smart_album,
+            list_tags,
+            create_tag,
+            assign_tags,
+            remove_tags,
+            rename_tag,
+            move_tag,
+            delete_tag,
+            undo_last,
+            redo,
+            get_history,
+            list_events,
+            list_memories,
+            get_memory_photos,
+            dismiss_memory,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
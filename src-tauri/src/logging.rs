@@ -0,0 +1,240 @@
+//! Routes `tracing` output. Everything the app logs at INFO and above, and other crates' WARN
+//! and above, is printed; the app's own messages also go to the settings panel as
+//! `log-message` events, and anything at WARN or above is kept in `logs` with the module and
+//! spans it came from. Sessions open spans (`scan`, `index`, `sync`) so a stored warning says
+//! which folder, photo or room it was about.
+
+use crate::database::LogRecord;
+use crate::pool::DbPool;
+use std::fmt::Write as _;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Stored log entries older than this many days are dropped, unless `log_retention_days` is set.
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
+/// Stored log entries are trimmed, oldest first, to this many megabytes of text, unless
+/// `log_retention_mb` is set.
+pub const DEFAULT_RETENTION_MB: u64 = 5;
+
+/// The target prefix of everything this crate logs.
+const APP_TARGET: &str = "siegu_lib";
+/// The writer's own failures are not stored: storing them would queue another write.
+const POOL_TARGET: &str = "siegu_lib::pool";
+/// How long the log thread gathers records before writing them together.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+/// Records stored between retention passes.
+const PRUNE_EVERY: usize = 200;
+
+static RECORDS: OnceLock<Sender<LogRecord>> = OnceLock::new();
+static PENDING: Mutex<Option<Receiver<LogRecord>>> = Mutex::new(None);
+static PANEL: OnceLock<tauri::AppHandle> = OnceLock::new();
+
+/// Installs the global subscriber. Records to store queue up until `attach` is called.
+pub fn init() {
+    let (sender, receiver) = mpsc::channel();
+    if RECORDS.set(sender).is_ok() {
+        *PENDING.lock().unwrap() = Some(receiver);
+    }
+
+    let targets = Targets::new()
+        .with_target(APP_TARGET, Level::INFO)
+        .with_default(Level::WARN);
+    let _ = tracing_subscriber::registry()
+        .with(targets)
+        .with(tracing_subscriber::fmt::layer())
+        .with(Persist)
+        .with(Panel)
+        .try_init();
+}
+
+/// Starts storing records in `db` and sending the app's messages to the settings panel.
+pub fn attach(app: &tauri::AppHandle, db: DbPool) {
+    let _ = PANEL.set(app.clone());
+    let Some(records) = PENDING.lock().unwrap().take() else {
+        return;
+    };
+    std::thread::spawn(move || store(records, db));
+}
+
+/// Writes records in batches and applies retention every `PRUNE_EVERY` of them, and once at
+/// start for whatever earlier runs left behind.
+fn store(records: Receiver<LogRecord>, db: DbPool) {
+    let mut since_prune = PRUNE_EVERY;
+    loop {
+        let mut batch = Vec::new();
+        match records.recv() {
+            Ok(record) => batch.push(record),
+            Err(_) => return,
+        }
+        let deadline = Instant::now() + FLUSH_INTERVAL;
+        while let Ok(record) =
+            records.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            batch.push(record);
+        }
+
+        since_prune += batch.len();
+        let prune = since_prune >= PRUNE_EVERY;
+        if prune {
+            since_prune = 0;
+        }
+        // The writer logs a batch it failed to save; logging that again would only add to it
+        let _ = db.write(move |db| {
+            db.store_logs(&batch);
+            if prune {
+                let state = db.get_state();
+                let days = state
+                    .get("log_retention_days")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_RETENTION_DAYS);
+                let megabytes = state
+                    .get("log_retention_mb")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_RETENTION_MB);
+                db.prune_logs(days, megabytes * 1024 * 1024);
+            }
+        });
+    }
+}
+
+/// A span's fields as `key=value` pairs, kept in its extensions.
+struct SpanFields(String);
+
+/// Formats an event's or span's fields: `message` as is, the rest as `key=value`.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    rest: String,
+}
+
+impl Fields {
+    fn into_message(self) -> String {
+        match (self.message.is_empty(), self.rest.is_empty()) {
+            (_, true) => self.message,
+            (true, false) => self.rest,
+            (false, false) => format!("{} {}", self.message, self.rest),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &format_args!("{value}"));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+            return;
+        }
+        if !self.rest.is_empty() {
+            self.rest.push(' ');
+        }
+        let _ = write!(self.rest, "{}={value:?}", field.name());
+    }
+}
+
+/// Queues WARN and above for `logs`.
+struct Persist;
+
+impl<S> Layer<S> for Persist
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut()
+                .insert(SpanFields(fields.into_message()));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(existing)) = extensions.get_mut::<SpanFields>() {
+            if !existing.is_empty() {
+                existing.push(' ');
+            }
+            existing.push_str(&fields.into_message());
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN || metadata.target().starts_with(POOL_TARGET) {
+            return;
+        }
+        let Some(records) = RECORDS.get() else {
+            return;
+        };
+
+        let mut span = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for entered in scope.from_root() {
+                if !span.is_empty() {
+                    span.push(':');
+                }
+                span.push_str(entered.name());
+                if let Some(SpanFields(fields)) = entered.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        let _ = write!(span, "{{{fields}}}");
+                    }
+                }
+            }
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let _ = records.send(LogRecord {
+            level: metadata.level().to_string(),
+            module: metadata
+                .module_path()
+                .unwrap_or(metadata.target())
+                .to_string(),
+            span,
+            message: fields.into_message(),
+        });
+    }
+}
+
+/// Sends the app's own messages to the settings panel.
+struct Panel;
+
+impl<S: Subscriber> Layer<S> for Panel {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::INFO || !metadata.target().starts_with(APP_TARGET) {
+            return;
+        }
+        let Some(app) = PANEL.get() else {
+            return;
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = match *metadata.level() {
+            Level::ERROR => format!("ERROR: {}", fields.into_message()),
+            Level::WARN => format!("WARN: {}", fields.into_message()),
+            _ => fields.into_message(),
+        };
+        let _ = app.emit("log-message", message);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::database::{person_id_filter, Database, SqlParams};
+use chrono::{Datelike, NaiveDate};
+
+pub const ON_THIS_DAY: &str = "on_this_day";
+pub const BEST_OF_MONTH: &str = "best_of_month";
+pub const PERSON_YEARS: &str = "person_years";
+
+/// Fewest photos worth making a memory of.
+const MIN_PHOTOS: usize = 3;
+/// Most photos kept in one memory, best first.
+const MAX_PHOTOS: usize = 24;
+/// Years a person has to appear in before they get a "through the years" memory.
+const MIN_YEARS: usize = 3;
+/// Photos picked from each of those years.
+const PER_YEAR: usize = 4;
+
+/// A memory as built from the library, before it is stored.
+struct Draft {
+    id: String,
+    kind: &'static str,
+    title: String,
+    photos: Vec<String>,
+}
+
+/// Rebuilds the memories due on `today`, the local date: "On this day N years ago", "Best
+/// of" the previous month and "<Person> through the years". Returns the titles of the ones
+/// that are new. "On this day" memories from other days are dropped, and so are those of
+/// people no longer in the library.
+pub fn refresh(db: &mut Database, today: NaiveDate) -> Result<Vec<String>, String> {
+    let mut drafts = on_this_day(db, today);
+    drafts.extend(best_of_last_month(db, today));
+    drafts.extend(through_the_years(db));
+
+    db.prune_memories(ON_THIS_DAY, &format!("{ON_THIS_DAY}:{today}:"))?;
+    db.prune_person_memories(PERSON_YEARS)?;
+    let mut new = Vec::new();
+    for draft in drafts {
+        if draft.photos.len() < MIN_PHOTOS {
+            continue;
+        }
+        if db.store_memory(&draft.id, draft.kind, &draft.title, &draft.photos)? {
+            new.push(draft.title);
+        }
+    }
+    Ok(new)
+}
+
+/// One memory per earlier year with photos taken on today's month and day.
+fn on_this_day(db: &Database, today: NaiveDate) -> Vec<Draft> {
+    let month_day = today.format("%m-%d").to_string();
+    let mut params = SqlParams::new(0);
+    let filter = format!(
+        "substr(p.taken_day, 6) = ?{} AND substr(p.taken_day, 1, 4) < ?{}",
+        params.bind(month_day.clone()),
+        params.bind(today.year().to_string())
+    );
+    db.photo_years(&filter, &params)
+        .into_iter()
+        .map(|year| {
+            let mut params = SqlParams::new(0);
+            let filter = format!(
+                "p.taken_day = ?{}",
+                params.bind(format!("{year}-{month_day}"))
+            );
+            let ago = today.year() - year;
+            Draft {
+                id: format!("{ON_THIS_DAY}:{today}:{year}"),
+                kind: ON_THIS_DAY,
+                title: match ago {
+                    1 => "On this day 1 year ago".to_string(),
+                    _ => format!("On this day {ago} years ago"),
+                },
+                photos: db.best_photos(&filter, &params, MAX_PHOTOS),
+            }
+        })
+        .collect()
+}
+
+fn best_of_last_month(db: &Database, today: NaiveDate) -> Option<Draft> {
+    let month = today.with_day(1)?.pred_opt()?;
+    let key = month.format("%Y-%m").to_string();
+    let mut params = SqlParams::new(0);
+    let filter = format!("substr(p.taken_day, 1, 7) = ?{}", params.bind(key.clone()));
+    Some(Draft {
+        id: format!("{BEST_OF_MONTH}:{key}"),
+        kind: BEST_OF_MONTH,
+        title: format!("Best of {}", month.format("%B %Y")),
+        photos: db.best_photos(&filter, &params, MAX_PHOTOS),
+    })
+}
+
+/// The best few photos of each named person from every year they appear in, oldest first.
+fn through_the_years(db: &Database) -> Vec<Draft> {
+    let mut drafts = Vec::new();
+    for person in db.get_people() {
+        let mut params = SqlParams::new(0);
+        let filter = person_id_filter(params.bind(person.id.clone()));
+        let years = db.photo_years(&filter, &params);
+        if years.len() < MIN_YEARS {
+            continue;
+        }
+        let mut photos = Vec::new();
+        for year in years {
+            let mut params = SqlParams::new(0);
+            let filter = format!(
+                "{} AND substr(p.taken_day, 1, 4) = ?{}",
+                person_id_filter(params.bind(person.id.clone())),
+                params.bind(year.to_string())
+            );
+            photos.extend(db.best_photos(&filter, &params, PER_YEAR));
+        }
+        drafts.push(Draft {
+            id: format!("{PERSON_YEARS}:{}", person.id),
+            kind: PERSON_YEARS,
+            title: format!("{} through the years", person.name),
+            photos,
+        });
+    }
+    drafts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_memories_and_keeps_dismissals() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        let face = |n: usize| {
+            format!(r#"[{{"face_id":"f{n}","crop_path":"","encoded":"","person_id":"alice"}}]"#)
+        };
+        database
+            .connection
+            .execute(
+                "INSERT INTO people (id, name) VALUES ('alice', 'Alice')",
+                [],
+            )
+            .unwrap();
+        for (n, created) in [
+            "2021-10-18T09:00:00Z",
+            "2021-10-18T10:00:00Z",
+            "2021-10-18T11:00:00Z",
+            "2022-10-18T12:00:00Z",
+            "2023-09-02T10:00:00Z",
+            "2024-09-20T10:00:00Z",
+            "2024-09-21T10:00:00Z",
+            "2024-09-22T10:00:00Z",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let faces = if n % 3 == 0 {
+                face(n)
+            } else {
+                "[]".to_string()
+            };
+            let id = format!("p{n}");
+            database.import_photo(
+                &id,
+                &format!("/pics/{id}.jpg"),
+                created,
+                None,
+                None,
+                "[]",
+                &faces,
+                "",
+            );
+        }
+        database.toggle_favorite("p2");
+
+        let today = NaiveDate::from_ymd_opt(2024, 10, 18).unwrap();
+        let new = refresh(&mut database, today).unwrap();
+        // Two years ago has too few photos for a memory
+        assert_eq!(
+            new,
+            vec![
+                "On this day 3 years ago",
+                "Best of September 2024",
+                "Alice through the years"
+            ]
+        );
+        let on_this_day = format!("{ON_THIS_DAY}:2024-10-18:2021");
+        let photos: Vec<String> = database
+            .list_memory_photos(&on_this_day)
+            .into_iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(photos, vec!["p2", "p0", "p1"]);
+
+        database.dismiss_memory(&on_this_day);
+        assert!(refresh(&mut database, today).unwrap().is_empty());
+        assert_eq!(database.list_memories().len(), 2);
+
+        // A day later, yesterday's memories are gone
+        refresh(&mut database, today.succ_opt().unwrap()).unwrap();
+        assert!(database.list_memory_photos(&on_this_day).is_empty());
+
+        // And so are those of people who were removed
+        let person_years = format!("{PERSON_YEARS}:alice");
+        assert!(!database.list_memory_photos(&person_years).is_empty());
+        database
+            .connection
+            .execute("DELETE FROM people WHERE id = 'alice'", [])
+            .unwrap();
+        refresh(&mut database, today.succ_opt().unwrap()).unwrap();
+        assert!(database.list_memory_photos(&person_years).is_empty());
+        assert_eq!(database.list_memories().len(), 1);
+    }
+}
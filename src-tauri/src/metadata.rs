@@ -0,0 +1,139 @@
+use exif::{Exif, In, Reader, Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// Camera settings and technical details of a photo or video, read once at scan time.
+/// Videos and images without EXIF only get their file details and, for images, dimensions.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    /// Millimetres.
+    pub focal_length: Option<f64>,
+    /// f-number.
+    pub aperture: Option<f64>,
+    pub iso: Option<u32>,
+    /// Seconds.
+    pub exposure_time: Option<f64>,
+    pub flash: Option<bool>,
+    /// EXIF orientation, 1 to 8.
+    pub orientation: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub file_size: u64,
+    pub mime: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Fields photos can be grouped by with `Database::facet`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Facet {
+    Camera,
+    Lens,
+    Aperture,
+    FocalLength,
+    Iso,
+    Mime,
+}
+
+impl Facet {
+    /// The expression over `photo p` whose values are counted.
+    pub fn column(self) -> &'static str {
+        match self {
+            Facet::Camera => CAMERA_NAME,
+            Facet::Lens => "p.lens",
+            Facet::Aperture => "ROUND(p.aperture, 1)",
+            Facet::FocalLength => "ROUND(p.focal_length)",
+            Facet::Iso => "p.iso",
+            Facet::Mime => "p.mime",
+        }
+    }
+}
+
+/// Make and model as one name, without repeating the make when the model already starts
+/// with it (`Canon` + `Canon EOS R5`, but `FUJIFILM` + `X100V`).
+pub const CAMERA_NAME: &str = "CASE WHEN p.camera_model IS NULL THEN p.camera_make WHEN p.camera_make IS NULL OR p.camera_model LIKE p.camera_make || '%' THEN p.camera_model ELSE p.camera_make || ' ' || p.camera_model END";
+
+pub fn read(path: &Path) -> MediaInfo {
+    let mut info = MediaInfo {
+        file_size: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        mime: mime_guess::from_path(path)
+            .first_raw()
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        ..Default::default()
+    };
+
+    let exif = File::open(path).ok().and_then(|file| {
+        Reader::new()
+            .read_from_container(&mut BufReader::new(file))
+            .ok()
+    });
+    if let Some(exif) = exif {
+        info.camera_make = text(&exif, Tag::Make);
+        info.camera_model = text(&exif, Tag::Model);
+        info.lens = text(&exif, Tag::LensModel);
+        info.focal_length = rational(&exif, Tag::FocalLength);
+        info.aperture = rational(&exif, Tag::FNumber);
+        info.iso = uint(&exif, Tag::PhotographicSensitivity);
+        info.exposure_time = rational(&exif, Tag::ExposureTime);
+        // Bit 0 of the flash field records whether it fired
+        info.flash = uint(&exif, Tag::Flash).map(|flash| flash & 1 == 1);
+        info.orientation = uint(&exif, Tag::Orientation);
+        info.width = uint(&exif, Tag::PixelXDimension);
+        info.height = uint(&exif, Tag::PixelYDimension);
+        info.latitude = coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+        info.longitude = coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    }
+    if info.width.is_none() && info.mime.starts_with("image/") {
+        if let Ok((width, height)) = image::image_dimensions(path) {
+            info.width = Some(width);
+            info.height = Some(height);
+        }
+    }
+    info
+}
+
+fn text(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn rational(exif: &Exif, tag: Tag) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.first()?.denom != 0 => Some(values[0].to_f64()),
+        _ => None,
+    }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+/// Degrees, minutes and seconds as signed decimal degrees.
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative: &str) -> Option<f64> {
+    let Value::Rational(values) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    if values.len() != 3 {
+        return None;
+    }
+    let degrees = values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0;
+    let reference = exif.get_field(ref_tag, In::PRIMARY)?;
+    Some(if format!("{}", reference.display_value()) == negative {
+        -degrees
+    } else {
+        degrees
+    })
+}
@@ -0,0 +1,1022 @@
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+use tracing::{info, warn};
+
+/// A single schema upgrade step. Its version is its position in [`MIGRATIONS`] plus one.
+struct Migration {
+    name: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// Ordered list of schema migrations, tracked through `PRAGMA user_version`.
+/// Only ever append to this list; never reorder or edit a migration that has shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "typed schema with foreign keys",
+        up: typed_schema,
+    },
+    Migration {
+        name: "content hash",
+        up: content_hash,
+    },
+    Migration {
+        name: "perceptual hash and dimensions",
+        up: perceptual_hash,
+    },
+    Migration {
+        name: "albums",
+        up: albums,
+    },
+    Migration {
+        name: "smart albums",
+        up: smart_albums,
+    },
+    Migration {
+        name: "trash",
+        up: trash,
+    },
+    Migration {
+        name: "capture time offset",
+        up: capture_time_offset,
+    },
+    Migration {
+        name: "full-text search index",
+        up: search_index,
+    },
+    Migration {
+        name: "technical metadata",
+        up: technical_metadata,
+    },
+    Migration {
+        name: "local capture day",
+        up: local_capture_day,
+    },
+    Migration {
+        name: "sort keys",
+        up: sort_keys,
+    },
+    Migration {
+        name: "memories",
+        up: memories,
+    },
+    Migration {
+        name: "events",
+        up: events,
+    },
+    Migration {
+        name: "reverse geocoding",
+        up: reverse_geocoding,
+    },
+    Migration {
+        name: "map index",
+        up: map_index,
+    },
+    Migration {
+        name: "culling",
+        up: culling,
+    },
+    Migration {
+        name: "xmp sidecars",
+        up: xmp_sidecars,
+    },
+    Migration {
+        name: "tag tree",
+        up: tag_tree,
+    },
+    Migration {
+        name: "operation journal",
+        up: operation_journal,
+    },
+    Migration {
+        name: "structured logs",
+        up: structured_logs,
+    },
+    Migration {
+        name: "index errors",
+        up: index_errors,
+    },
+    Migration {
+        name: "file fingerprints",
+        up: file_fingerprints,
+    },
+    Migration {
+        name: "tag sources",
+        up: tag_sources,
+    },
+    Migration {
+        name: "search people and tag edits",
+        up: search_people_and_tag_edits,
+    },
+    Migration {
+        name: "duplicate locations",
+        up: duplicate_locations,
+    },
+    Migration {
+        name: "duplicate fingerprints",
+        up: duplicate_fingerprints,
+    },
+    Migration {
+        name: "album removals",
+        up: album_removals,
+    },
+];
+
+/// Tables created by the unversioned schema that `Database::new` used to build.
+const LEGACY_TABLES: &[&str] = &[
+    "photo",
+    "directory",
+    "object",
+    "properties",
+    "device",
+    "faces",
+    "people",
+    "config",
+    "logs",
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Brings the database up to the latest schema version.
+/// Every migration runs in its own immediate transaction, so a failure leaves the
+/// database at the last version that applied cleanly and concurrent openers wait
+/// for each other instead of applying the same step twice.
+pub fn run(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version = current_version(conn)?;
+    if version > latest_version() {
+        warn!(
+            "Database schema version {version} is newer than this build supports ({})",
+            latest_version()
+        );
+    }
+    // Table rebuilds drop and recreate parents, which must not trip foreign key checks mid-copy.
+    // This pragma is a no-op inside a transaction, so it has to be toggled around them.
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let result = apply_pending(conn);
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    result
+}
+
+fn apply_pending(conn: &mut Connection) -> rusqlite::Result<()> {
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        let version = index as u32 + 1;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if current_version(&tx)? >= version {
+            continue;
+        }
+        info!("Applying database migration {version}: {}", migration.name);
+        (migration.up)(&tx)?;
+        check_foreign_keys(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn check_foreign_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare("PRAGMA foreign_key_check")?;
+    let violation: Option<String> = stmt.query_row([], |row| row.get(0)).ok();
+    match violation {
+        Some(table) => Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(format!("foreign key violation in table {table}")),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn table_exists(tx: &Transaction, table: &str) -> rusqlite::Result<bool> {
+    tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+fn table_columns(tx: &Transaction, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = tx.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+/// Copies the columns that `from` and `to` have in common. Older installs may predate
+/// some columns, so the shared set is worked out per database rather than hardcoded.
+fn copy_rows(tx: &Transaction, from: &str, to: &str, filter: &str) -> rusqlite::Result<()> {
+    let source = table_columns(tx, from)?;
+    let shared: Vec<String> = table_columns(tx, to)?
+        .into_iter()
+        .filter(|c| source.contains(c))
+        .collect();
+    if shared.is_empty() {
+        return Ok(());
+    }
+    let columns = shared.join(", ");
+    tx.execute(
+        &format!("INSERT OR REPLACE INTO {to} ({columns}) SELECT {columns} FROM {from} {filter} ORDER BY rowid"),
+        (),
+    )?;
+    Ok(())
+}
+
+/// Replaces the untyped tables (`STRING`, `DATE_TIME`) with properly typed ones and adds
+/// `ON DELETE CASCADE` foreign keys from `object`, `faces` and `properties` to `photo`.
+/// Rows of an existing library are carried over; metadata pointing at photos that no
+/// longer exist is dropped, since it could never be reached again.
+fn typed_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    let mut legacy = Vec::new();
+    for table in LEGACY_TABLES {
+        if table_exists(tx, table)? {
+            tx.execute_batch(&format!("ALTER TABLE {table} RENAME TO legacy_{table};"))?;
+            legacy.push(*table);
+        }
+    }
+    tx.execute_batch(
+        "DROP INDEX IF EXISTS idx_photo_location;
+         DROP INDEX IF EXISTS idx_photo_created;
+         DROP INDEX IF EXISTS idx_photo_indexed;
+
+         CREATE TABLE photo (
+             id TEXT PRIMARY KEY NOT NULL,
+             location TEXT NOT NULL,
+             encoded TEXT NOT NULL DEFAULT '',
+             created TEXT,
+             latitude REAL,
+             longitude REAL,
+             indexed INTEGER NOT NULL DEFAULT 0,
+             sync_needed INTEGER NOT NULL DEFAULT 0
+         );
+         CREATE INDEX idx_photo_location ON photo(location);
+         CREATE INDEX idx_photo_created ON photo(created);
+         CREATE INDEX idx_photo_indexed ON photo(indexed);
+
+         CREATE TABLE object (
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             class TEXT NOT NULL,
+             probability TEXT
+         );
+         CREATE INDEX idx_object_photo ON object(photo_id);
+         CREATE INDEX idx_object_class ON object(class);
+
+         CREATE TABLE properties (
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             key TEXT NOT NULL,
+             value TEXT,
+             UNIQUE (photo_id, key)
+         );
+
+         CREATE TABLE faces (
+             face_id TEXT PRIMARY KEY NOT NULL,
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             crop_path TEXT,
+             encoded TEXT,
+             embedding BLOB,
+             person_id TEXT
+         );
+         CREATE INDEX idx_faces_photo ON faces(photo_id);
+         CREATE INDEX idx_faces_person ON faces(person_id);
+
+         CREATE TABLE people (id TEXT PRIMARY KEY NOT NULL, name TEXT, embedding BLOB);
+         CREATE TABLE directory (name TEXT PRIMARY KEY NOT NULL);
+         CREATE TABLE device (ip TEXT PRIMARY KEY NOT NULL, name TEXT, offer TEXT);
+         CREATE TABLE config (key TEXT PRIMARY KEY NOT NULL, value TEXT);
+         CREATE TABLE logs (
+             id INTEGER PRIMARY KEY,
+             timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             level TEXT NOT NULL,
+             message TEXT NOT NULL
+         );",
+    )?;
+
+    for table in legacy {
+        let from = format!("legacy_{table}");
+        let filter = match table {
+            "photo" => "WHERE id IS NOT NULL AND location IS NOT NULL",
+            "object" => "WHERE class IS NOT NULL AND photo_id IN (SELECT id FROM photo)",
+            "properties" => "WHERE key IS NOT NULL AND photo_id IN (SELECT id FROM photo)",
+            "faces" => "WHERE face_id IS NOT NULL AND photo_id IN (SELECT id FROM photo)",
+            "people" => "WHERE id IS NOT NULL",
+            "directory" => "WHERE name IS NOT NULL",
+            "device" => "WHERE ip IS NOT NULL",
+            "config" => "WHERE key IS NOT NULL",
+            "logs" => "WHERE level IS NOT NULL AND message IS NOT NULL",
+            _ => "",
+        };
+        copy_rows(tx, &from, table, filter)?;
+        tx.execute_batch(&format!("DROP TABLE {from};"))?;
+    }
+    Ok(())
+}
+
+/// Adds the content hash that identifies a photo across rescans, renames and devices.
+/// It is not unique: identical copies that were imported before hashing existed keep
+/// their own rows, and the scanner dedupes new files against it instead.
+fn content_hash(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN hash TEXT;
+         CREATE INDEX idx_photo_hash ON photo(hash);",
+    )
+}
+
+/// Adds the 64-bit difference hash used to find near-duplicates, stored as the bit pattern
+/// of a signed integer, along with the pixel dimensions used to pick which copy to keep.
+fn perceptual_hash(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN phash INTEGER;
+         ALTER TABLE photo ADD COLUMN width INTEGER;
+         ALTER TABLE photo ADD COLUMN height INTEGER;",
+    )
+}
+
+/// User-curated albums. Membership carries an explicit position so albums keep the order the
+/// user arranged them in; the cover falls back to the first photo when unset or deleted.
+fn albums(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE album (
+             id TEXT PRIMARY KEY NOT NULL,
+             name TEXT NOT NULL,
+             description TEXT NOT NULL DEFAULT '',
+             cover_photo_id TEXT REFERENCES photo(id) ON DELETE SET NULL,
+             created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             updated TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+         );
+         CREATE TABLE album_photo (
+             album_id TEXT NOT NULL REFERENCES album(id) ON DELETE CASCADE,
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             position INTEGER NOT NULL,
+             PRIMARY KEY (album_id, photo_id)
+         );
+         CREATE INDEX idx_album_photo_photo ON album_photo(photo_id);",
+    )
+}
+
+/// Smart albums store only their rule tree as JSON; membership is worked out on every read.
+fn smart_albums(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE smart_album (
+             id TEXT PRIMARY KEY NOT NULL,
+             name TEXT NOT NULL,
+             rules TEXT NOT NULL,
+             created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             updated TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+         );",
+    )
+}
+
+/// Soft deletion: `trashed` is when the photo was moved to the trash and `trash_location`
+/// where its file went, so it can be restored or purged once the retention period passes.
+fn trash(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN trashed TEXT;
+         ALTER TABLE photo ADD COLUMN trash_location TEXT;
+         CREATE INDEX idx_photo_trashed ON photo(trashed);",
+    )
+}
+
+/// `created` becomes ISO-8601 UTC, with the offset of the place the photo was taken kept
+/// alongside. Existing rows stay as they are until the scanner re-resolves them from the
+/// file, which it recognizes by the missing offset.
+fn capture_time_offset(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE photo ADD COLUMN created_offset INTEGER;")
+}
+
+/// Text indexed for each photo that is not in the trash, keyed by the photo's rowid.
+/// `filename` is the part after the last separator and `path` the folders before it.
+const SEARCH_COLUMNS: &str = "p.rowid,
+    rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')),
+    replace(replace(p.location, '\\', '/'), rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')), ''),
+    (SELECT group_concat(class, ' ') FROM object WHERE photo_id = p.id),
+    (SELECT group_concat(name, ' ') FROM people WHERE id IN (SELECT person_id FROM faces WHERE photo_id = p.id)),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND (key LIKE '%City%' OR key LIKE '%Country%' OR key LIKE '%State%')),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND key IN ('caption', 'text'))";
+
+/// `SEARCH_COLUMNS` with keywords from XMP indexed as tags alongside the ML tags.
+const KEYWORD_SEARCH_COLUMNS: &str = "p.rowid,
+    rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')),
+    replace(replace(p.location, '\\', '/'), rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')), ''),
+    (SELECT group_concat(name, ' ') FROM (SELECT class AS name FROM object WHERE photo_id = p.id UNION ALL SELECT name FROM keyword WHERE photo_id = p.id)),
+    (SELECT group_concat(name, ' ') FROM people WHERE id IN (SELECT person_id FROM faces WHERE photo_id = p.id)),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND (key LIKE '%City%' OR key LIKE '%Country%' OR key LIKE '%State%')),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND key IN ('caption', 'text'))";
+
+/// `SEARCH_COLUMNS` with the paths of the user's tags indexed alongside the ML tags.
+const TAG_SEARCH_COLUMNS: &str = "p.rowid,
+    rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')),
+    replace(replace(p.location, '\\', '/'), rtrim(replace(p.location, '\\', '/'), replace(replace(p.location, '\\', '/'), '/', '')), ''),
+    (SELECT group_concat(name, ' ') FROM (SELECT class AS name FROM object WHERE photo_id = p.id UNION ALL SELECT t.path FROM photo_tag pt JOIN tag t ON t.id = pt.tag_id WHERE pt.photo_id = p.id)),
+    (SELECT group_concat(name, ' ') FROM people WHERE id IN (SELECT person_id FROM faces WHERE photo_id = p.id)),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND (key LIKE '%City%' OR key LIKE '%Country%' OR key LIKE '%State%')),
+    (SELECT group_concat(value, ' ') FROM properties WHERE photo_id = p.id AND key IN ('caption', 'text'))";
+
+/// Statements that re-index the photos matching `condition`, for use in trigger bodies.
+fn reindex(columns: &str, condition: &str) -> String {
+    format!(
+        "DELETE FROM photo_search WHERE rowid IN (SELECT rowid FROM photo p WHERE {condition});
+         INSERT INTO photo_search (rowid, path, filename, tags, people, places, captions)
+             SELECT {columns} FROM photo p WHERE {condition} AND p.trashed IS NULL;"
+    )
+}
+
+/// Triggers that keep `photo_search` current as (name, event, body), indexing `columns`.
+fn search_triggers(columns: &str) -> Vec<(&'static str, &'static str, String)> {
+    let reindex = |condition| reindex(columns, condition);
+    vec![
+        (
+            "photo_search_photo_insert",
+            "AFTER INSERT ON photo",
+            reindex("p.id = new.id"),
+        ),
+        (
+            "photo_search_photo_update",
+            "AFTER UPDATE OF location, trashed ON photo",
+            reindex("p.id = new.id"),
+        ),
+        (
+            "photo_search_photo_delete",
+            "AFTER DELETE ON photo",
+            "DELETE FROM photo_search WHERE rowid = old.rowid;".to_string(),
+        ),
+        (
+            "photo_search_object_insert",
+            "AFTER INSERT ON object",
+            reindex("p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_object_delete",
+            "AFTER DELETE ON object",
+            reindex("p.id = old.photo_id"),
+        ),
+        (
+            "photo_search_properties_insert",
+            "AFTER INSERT ON properties",
+            reindex("p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_properties_update",
+            "AFTER UPDATE ON properties",
+            reindex("p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_properties_delete",
+            "AFTER DELETE ON properties",
+            reindex("p.id = old.photo_id"),
+        ),
+        (
+            "photo_search_faces_insert",
+            "AFTER INSERT ON faces",
+            reindex("p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_faces_update",
+            "AFTER UPDATE OF person_id ON faces",
+            reindex("p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_faces_delete",
+            "AFTER DELETE ON faces",
+            reindex("p.id = old.photo_id"),
+        ),
+        (
+            "photo_search_people_update",
+            "AFTER UPDATE OF name ON people",
+            reindex("p.id IN (SELECT photo_id FROM faces WHERE person_id = new.id)"),
+        ),
+    ]
+}
+
+/// FTS5 index over each photo's path, filename, tags, people, places and captions or
+/// extracted text (`caption` and `text` properties). Triggers on every source table keep it
+/// current, so writers never touch it directly. Photo rowids may change on `VACUUM`, after
+/// which `rebuild_search_index` has to run.
+fn search_index(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE photo_search USING fts5(
+             path, filename, tags, people, places, captions,
+             tokenize = 'unicode61 remove_diacritics 2'
+         );",
+    )?;
+    let triggers = search_triggers(SEARCH_COLUMNS);
+    for (name, event, body) in triggers {
+        tx.execute_batch(&format!("CREATE TRIGGER {name} {event} BEGIN {body} END;"))?;
+    }
+    tx.execute_batch(&format!(
+        "INSERT INTO photo_search (rowid, path, filename, tags, people, places, captions)
+             SELECT {SEARCH_COLUMNS} FROM photo p WHERE p.trashed IS NULL;"
+    ))
+}
+
+/// Camera settings and file details from `metadata::read`, in typed columns so they can be
+/// filtered and faceted. `mime` is set for every photo read, so a missing one marks a photo
+/// the scanner still has to backfill.
+fn technical_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN camera_make TEXT;
+         ALTER TABLE photo ADD COLUMN camera_model TEXT;
+         ALTER TABLE photo ADD COLUMN lens TEXT;
+         ALTER TABLE photo ADD COLUMN focal_length REAL;
+         ALTER TABLE photo ADD COLUMN aperture REAL;
+         ALTER TABLE photo ADD COLUMN iso INTEGER;
+         ALTER TABLE photo ADD COLUMN exposure_time REAL;
+         ALTER TABLE photo ADD COLUMN flash INTEGER;
+         ALTER TABLE photo ADD COLUMN orientation INTEGER;
+         ALTER TABLE photo ADD COLUMN file_size INTEGER;
+         ALTER TABLE photo ADD COLUMN mime TEXT;
+         CREATE INDEX idx_photo_camera ON photo(camera_make, camera_model);
+         CREATE INDEX idx_photo_lens ON photo(lens);",
+    )
+}
+
+/// The calendar day a photo was taken on where it was taken, `YYYY-MM-DD`, which the
+/// timeline groups by. It is derived from `created` and `created_offset`, so it never needs
+/// backfilling, and NULL until `created` is a resolved ISO-8601 time.
+fn local_capture_day(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN taken_day TEXT
+             GENERATED ALWAYS AS (date(created, printf('%+d seconds', IFNULL(created_offset, 0)))) VIRTUAL;
+         CREATE INDEX idx_photo_taken_day ON photo(taken_day);",
+    )
+}
+
+/// Columns the photo grid can be sorted by, each indexed together with `id`, the tiebreaker
+/// that keeps keyset pages stable. `imported` is stamped by a trigger when a photo is first
+/// inserted, since upserts never re-insert; photos already in the library get the time of
+/// this migration. `file_name` is the part of `location` after the last separator.
+fn sort_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN imported TEXT;
+         ALTER TABLE photo ADD COLUMN rating INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE photo ADD COLUMN file_name TEXT COLLATE NOCASE
+             GENERATED ALWAYS AS (substr(location, length(rtrim(replace(location, '\\', '/'), replace(replace(location, '\\', '/'), '/', ''))) + 1)) VIRTUAL;
+         UPDATE photo SET imported = strftime('%Y-%m-%dT%H:%M:%SZ', 'now');
+         CREATE TRIGGER photo_imported AFTER INSERT ON photo WHEN new.imported IS NULL BEGIN
+             UPDATE photo SET imported = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE id = new.id;
+         END;
+         CREATE INDEX idx_photo_sort_created ON photo(IFNULL(created, ''), id);
+         CREATE INDEX idx_photo_sort_imported ON photo(imported, id);
+         CREATE INDEX idx_photo_sort_file_name ON photo(file_name, id);
+         CREATE INDEX idx_photo_sort_file_size ON photo(IFNULL(file_size, 0), id);
+         CREATE INDEX idx_photo_sort_rating ON photo(rating, id);",
+    )
+}
+
+/// Collections built from the library by `memories::refresh`. Ids are derived from what a
+/// memory is about, so rebuilding one updates it in place and a dismissed memory stays
+/// dismissed.
+fn memories(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE memories (
+             id TEXT PRIMARY KEY NOT NULL,
+             kind TEXT NOT NULL,
+             title TEXT NOT NULL,
+             created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             dismissed TEXT
+         );
+         CREATE TABLE memory_photo (
+             memory_id TEXT NOT NULL REFERENCES memories(id) ON DELETE CASCADE,
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             position INTEGER NOT NULL,
+             PRIMARY KEY (memory_id, photo_id)
+         );
+         CREATE INDEX idx_memory_photo_photo ON memory_photo(photo_id);",
+    )
+}
+
+/// Photos grouped into events by `events::update`. An event takes the id of its first photo,
+/// so rebuilding it usually keeps its id. Photos not clustered yet have a NULL `event_id`.
+fn events(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE event (
+             id TEXT PRIMARY KEY NOT NULL,
+             title TEXT NOT NULL,
+             start_time TEXT NOT NULL,
+             end_time TEXT NOT NULL,
+             latitude REAL,
+             longitude REAL
+         );
+         CREATE INDEX idx_event_start ON event(start_time);
+         ALTER TABLE photo ADD COLUMN event_id TEXT REFERENCES event(id) ON DELETE SET NULL;
+         CREATE INDEX idx_photo_event ON photo(event_id);",
+    )
+}
+
+/// Whether `geocode::backfill` has looked up a photo's place names. A photo whose position
+/// changes is looked up again.
+fn reverse_geocoding(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN geocoded INTEGER NOT NULL DEFAULT 0;
+         CREATE INDEX idx_photo_geocoded ON photo(geocoded) WHERE geocoded = 0;
+         CREATE TRIGGER photo_moved AFTER UPDATE OF latitude, longitude ON photo
+             WHEN old.latitude IS NOT new.latitude OR old.longitude IS NOT new.longitude BEGIN
+             UPDATE photo SET geocoded = 0 WHERE id = new.id;
+         END;",
+    )
+}
+
+/// R*Tree over the position of geotagged photos, keyed by photo rowid, for `map_clusters`.
+/// Photos without GPS data, stored at 0,0, are left out. Like the search index, it is kept
+/// current by triggers.
+fn map_index(tx: &Transaction) -> rusqlite::Result<()> {
+    let geotagged = |row: &str| {
+        format!("{row}.latitude IS NOT NULL AND {row}.longitude IS NOT NULL AND ({row}.latitude != 0 OR {row}.longitude != 0)")
+    };
+    tx.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE photo_location USING rtree(id, min_latitude, max_latitude, min_longitude, max_longitude);
+         INSERT INTO photo_location SELECT rowid, latitude, latitude, longitude, longitude FROM photo p WHERE {};
+         CREATE TRIGGER photo_location_insert AFTER INSERT ON photo WHEN {} BEGIN
+             INSERT INTO photo_location VALUES (new.rowid, new.latitude, new.latitude, new.longitude, new.longitude);
+         END;
+         CREATE TRIGGER photo_location_update AFTER UPDATE OF latitude, longitude ON photo BEGIN
+             DELETE FROM photo_location WHERE id = old.rowid;
+             INSERT INTO photo_location SELECT new.rowid, new.latitude, new.latitude, new.longitude, new.longitude WHERE {};
+         END;
+         CREATE TRIGGER photo_location_delete AFTER DELETE ON photo BEGIN
+             DELETE FROM photo_location WHERE id = old.rowid;
+         END;",
+        geotagged("p"),
+        geotagged("new"),
+        geotagged("new")
+    ))
+}
+
+/// Pick/reject flag (1 or -1) and color label next to the star `rating`, and when any of
+/// them last changed, which decides between two devices that culled the same photo.
+fn culling(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN flag INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE photo ADD COLUMN label TEXT;
+         ALTER TABLE photo ADD COLUMN culled TEXT;",
+    )
+}
+
+/// Keywords and face areas read from XMP, and the bookkeeping for XMP sidecars:
+/// `sidecar_modified` is when the sidecar was last read or written, in milliseconds, and
+/// `sidecar_stale` marks photos whose rating, label, keywords, tags or named faces changed
+/// since. Keywords are indexed for search with the ML tags.
+fn xmp_sidecars(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE keyword (
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             name TEXT NOT NULL,
+             PRIMARY KEY (photo_id, name)
+         );
+         CREATE INDEX idx_keyword_name ON keyword(name);
+         ALTER TABLE faces ADD COLUMN x REAL;
+         ALTER TABLE faces ADD COLUMN y REAL;
+         ALTER TABLE faces ADD COLUMN width REAL;
+         ALTER TABLE faces ADD COLUMN height REAL;
+         ALTER TABLE photo ADD COLUMN sidecar_modified INTEGER;
+         ALTER TABLE photo ADD COLUMN sidecar_stale INTEGER NOT NULL DEFAULT 0;
+         CREATE INDEX idx_photo_sidecar_stale ON photo(sidecar_stale) WHERE sidecar_stale = 1;",
+    )?;
+    let mut triggers = search_triggers(KEYWORD_SEARCH_COLUMNS);
+    for (name, _, _) in &triggers {
+        tx.execute_batch(&format!("DROP TRIGGER {name};"))?;
+    }
+    triggers.extend([
+        (
+            "photo_search_keyword_insert",
+            "AFTER INSERT ON keyword",
+            reindex(KEYWORD_SEARCH_COLUMNS, "p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_keyword_delete",
+            "AFTER DELETE ON keyword",
+            reindex(KEYWORD_SEARCH_COLUMNS, "p.id = old.photo_id"),
+        ),
+    ]);
+    let stale =
+        |photo_id: &str| format!("UPDATE photo SET sidecar_stale = 1 WHERE id = {photo_id};");
+    triggers.extend([
+        (
+            "sidecar_culling_update",
+            "AFTER UPDATE OF rating, flag, label ON photo",
+            stale("new.id"),
+        ),
+        ("sidecar_object_insert", "AFTER INSERT ON object", stale("new.photo_id")),
+        ("sidecar_object_delete", "AFTER DELETE ON object", stale("old.photo_id")),
+        ("sidecar_keyword_insert", "AFTER INSERT ON keyword", stale("new.photo_id")),
+        ("sidecar_keyword_delete", "AFTER DELETE ON keyword", stale("old.photo_id")),
+        ("sidecar_faces_insert", "AFTER INSERT ON faces", stale("new.photo_id")),
+        (
+            "sidecar_faces_update",
+            "AFTER UPDATE OF person_id, x, y, width, height ON faces",
+            stale("new.photo_id"),
+        ),
+        ("sidecar_faces_delete", "AFTER DELETE ON faces", stale("old.photo_id")),
+        (
+            "sidecar_people_update",
+            "AFTER UPDATE OF name ON people",
+            "UPDATE photo SET sidecar_stale = 1 WHERE id IN (SELECT photo_id FROM faces WHERE person_id = new.id);".to_string(),
+        ),
+    ]);
+    for (name, event, body) in triggers {
+        tx.execute_batch(&format!("CREATE TRIGGER {name} {event} BEGIN {body} END;"))?;
+    }
+    Ok(())
+}
+
+/// The user's own tags, kept apart from the ML tags in `object` so re-running the ML worker
+/// never touches them. Tags form a tree; `path` is the full `/`-joined path from the root,
+/// e.g. `Places/Portugal/Lisbon`, kept alongside `parent_id` because triggers cannot walk
+/// the tree. Keywords read from XMP become tags.
+fn tag_tree(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE tag (
+             id INTEGER PRIMARY KEY,
+             parent_id INTEGER REFERENCES tag(id) ON DELETE CASCADE,
+             name TEXT NOT NULL,
+             path TEXT NOT NULL UNIQUE COLLATE NOCASE
+         );
+         CREATE INDEX idx_tag_parent ON tag(parent_id);
+         CREATE TABLE photo_tag (
+             photo_id TEXT NOT NULL REFERENCES photo(id) ON DELETE CASCADE,
+             tag_id INTEGER NOT NULL REFERENCES tag(id) ON DELETE CASCADE,
+             PRIMARY KEY (photo_id, tag_id)
+         );
+         CREATE INDEX idx_photo_tag_tag ON photo_tag(tag_id);",
+    )?;
+    let keywords: Vec<String> = tx
+        .prepare("SELECT DISTINCT name FROM keyword")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for keyword in keywords {
+        let mut parent: Option<(i64, String)> = None;
+        for name in keyword
+            .split('/')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let path = match &parent {
+                Some((_, path)) => format!("{path}/{name}"),
+                None => name.to_string(),
+            };
+            tx.execute(
+                "INSERT INTO tag (parent_id, name, path) VALUES (?1, ?2, ?3) ON CONFLICT(path) DO NOTHING",
+                (parent.as_ref().map(|(id, _)| *id), name, &path),
+            )?;
+            parent = Some(tx.query_row(
+                "SELECT id, path FROM tag WHERE path = ?1",
+                [&path],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?);
+        }
+        if let Some((tag_id, _)) = parent {
+            tx.execute(
+                "INSERT OR IGNORE INTO photo_tag (photo_id, tag_id) SELECT photo_id, ?2 FROM keyword WHERE name = ?1",
+                (&keyword, tag_id),
+            )?;
+        }
+    }
+    let mut triggers = search_triggers(TAG_SEARCH_COLUMNS);
+    for (name, _, _) in &triggers {
+        tx.execute_batch(&format!("DROP TRIGGER {name};"))?;
+    }
+    // Its own search and sidecar triggers go with it
+    tx.execute_batch("DROP TABLE keyword;")?;
+    let stale = |condition: &str| format!("UPDATE photo SET sidecar_stale = 1 WHERE {condition};");
+    triggers.extend([
+        (
+            "photo_search_tag_insert",
+            "AFTER INSERT ON photo_tag",
+            reindex(TAG_SEARCH_COLUMNS, "p.id = new.photo_id"),
+        ),
+        (
+            "photo_search_tag_delete",
+            "AFTER DELETE ON photo_tag",
+            reindex(TAG_SEARCH_COLUMNS, "p.id = old.photo_id"),
+        ),
+        (
+            "photo_search_tag_update",
+            "AFTER UPDATE OF path ON tag",
+            reindex(
+                TAG_SEARCH_COLUMNS,
+                "p.id IN (SELECT photo_id FROM photo_tag WHERE tag_id = new.id)",
+            ),
+        ),
+        (
+            "sidecar_tag_insert",
+            "AFTER INSERT ON photo_tag",
+            stale("id = new.photo_id"),
+        ),
+        (
+            "sidecar_tag_delete",
+            "AFTER DELETE ON photo_tag",
+            stale("id = old.photo_id"),
+        ),
+        (
+            "sidecar_tag_update",
+            "AFTER UPDATE OF path ON tag",
+            stale("id IN (SELECT photo_id FROM photo_tag WHERE tag_id = new.id)"),
+        ),
+    ]);
+    for (name, event, body) in triggers {
+        tx.execute_batch(&format!("CREATE TRIGGER {name} {event} BEGIN {body} END;"))?;
+    }
+    tx.execute_batch(&reindex(
+        TAG_SEARCH_COLUMNS,
+        "p.id IN (SELECT photo_id FROM photo_tag)",
+    ))
+}
+
+/// Journal of user operations for undo and redo. Each step is an SQL statement reversing
+/// one row change, recorded by the triggers `journal.rs` creates on the writer's connection.
+fn operation_journal(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE operation (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             detail TEXT NOT NULL DEFAULT '',
+             created TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+             undone INTEGER NOT NULL DEFAULT 0
+         );
+         CREATE TABLE operation_step (
+             id INTEGER PRIMARY KEY,
+             operation_id INTEGER NOT NULL REFERENCES operation(id) ON DELETE CASCADE,
+             statement TEXT NOT NULL
+         );
+         CREATE INDEX idx_operation_step_operation ON operation_step(operation_id);",
+    )
+}
+
+/// Log entries come from `tracing` events: `level` is `WARN` or `ERROR`, `module` the Rust
+/// module that logged and `span` the spans it happened in, e.g. `scan:folder{directory=/x}`.
+fn structured_logs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE logs ADD COLUMN module TEXT;
+         ALTER TABLE logs ADD COLUMN span TEXT;
+         UPDATE logs SET level = upper(level);
+         CREATE INDEX idx_logs_timestamp ON logs(timestamp);",
+    )
+}
+
+/// Why the ML worker could not read a photo, e.g. a format the decoder does not know.
+/// Photos indexed before this are not known to have failed.
+fn index_errors(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE photo ADD COLUMN index_error TEXT;")
+}
+
+/// What a moved file is recognized by besides its content hash: `file_mtime` in Unix seconds
+/// together with `file_size` and `partial_hash`, a hash of the file's ends. `missing` is when
+/// a scan first found the file gone without finding where it went.
+fn file_fingerprints(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo ADD COLUMN file_mtime INTEGER;
+         ALTER TABLE photo ADD COLUMN partial_hash TEXT;
+         ALTER TABLE photo ADD COLUMN missing TEXT;
+         CREATE INDEX idx_photo_missing ON photo(missing) WHERE missing IS NOT NULL;",
+    )
+}
+
+/// Where a photo's tag came from: `manual` when the user tagged it, `xmp` when it was read
+/// from a sidecar. A changed sidecar only replaces the tags an earlier sidecar gave. Which of
+/// the existing tags came from XMP is not known, so they are all kept as the user's.
+fn tag_sources(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE photo_tag ADD COLUMN source TEXT NOT NULL DEFAULT 'manual' CHECK (source IN ('manual', 'xmp'));",
+    )
+}
+
+/// Re-indexes the photos that faces name a person for when the person is added, e.g. by
+/// sync after the faces, or deleted, and ML tags changed in place. The index is rebuilt as
+/// well, in case an earlier build missed such a change.
+fn search_people_and_tag_edits(tx: &Transaction) -> rusqlite::Result<()> {
+    let reindex = |condition| reindex(TAG_SEARCH_COLUMNS, condition);
+    for (name, event, body) in [
+        (
+            "photo_search_people_insert",
+            "AFTER INSERT ON people",
+            reindex("p.id IN (SELECT photo_id FROM faces WHERE person_id = new.id)"),
+        ),
+        (
+            "photo_search_people_delete",
+            "AFTER DELETE ON people",
+            reindex("p.id IN (SELECT photo_id FROM faces WHERE person_id = old.id)"),
+        ),
+        (
+            "photo_search_object_update",
+            "AFTER UPDATE ON object",
+            reindex("p.id IN (old.photo_id, new.photo_id)"),
+        ),
+    ] {
+        tx.execute_batch(&format!("CREATE TRIGGER {name} {event} BEGIN {body} END;"))?;
+    }
+    rebuild_search_index(tx)
+}
+
+/// Indexes every photo again from scratch. The index is keyed by photo rowids, which
+/// `VACUUM` may change and a restored library brings its own of, so FTS5's `rebuild`, which
+/// re-reads the index's own copy under the old rowids, would not do.
+pub(crate) fn rebuild_search_index(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(&format!(
+        "SAVEPOINT rebuild_search;
+         DELETE FROM photo_search;
+         INSERT INTO photo_search (rowid, path, filename, tags, people, places, captions)
+             SELECT {TAG_SEARCH_COLUMNS} FROM photo p WHERE p.trashed IS NULL;
+         RELEASE rebuild_search;"
+    ))
+}
+
+/// Files a scan skipped for holding the same content as a photo already in the library, or
+/// in the trash, so later scans do not hash them again. A location only counts while a photo
+/// with its `hash` is still around.
+fn duplicate_locations(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE duplicate_location (
+             location TEXT PRIMARY KEY NOT NULL,
+             hash TEXT NOT NULL
+         );",
+    )
+}
+
+/// Size and modification time of a skipped copy when it was skipped, so one that changes
+/// since is scanned again. Copies recorded before are scanned once more to get theirs.
+fn duplicate_fingerprints(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE duplicate_location ADD COLUMN file_size INTEGER;
+         ALTER TABLE duplicate_location ADD COLUMN file_mtime INTEGER;",
+    )
+}
+
+/// When a photo joined an album, and which albums and memberships were removed when, so
+/// syncing lets the later of an add and a removal win instead of restoring removed ones.
+/// Memberships from before have no `added` stamp and lose to any removal.
+fn album_removals(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE album_photo ADD COLUMN added TEXT;
+         CREATE TABLE album_removed (
+             id TEXT PRIMARY KEY NOT NULL,
+             removed TEXT NOT NULL
+         );
+         CREATE TABLE album_photo_removed (
+             album_id TEXT NOT NULL,
+             photo_id TEXT NOT NULL,
+             hash TEXT,
+             removed TEXT NOT NULL,
+             PRIMARY KEY (album_id, photo_id)
+         );",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_database_is_at_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        // Running again is a no-op
+        run(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn legacy_database_is_upgraded() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE photo (id STRING PRIMARY KEY, location STRING, encoded STRING, created DATE_TIME);
+             CREATE TABLE object(photo_id STRING, class STRING, probability STRING);
+             CREATE TABLE config(key STRING, value STRING);
+             INSERT INTO photo VALUES ('1234567', '/a.jpg', '', '2023:01:02 10:11:12');
+             INSERT INTO object VALUES ('1234567', 'a cat', '0.31');
+             INSERT INTO object VALUES ('gone', 'a dog', '0.12');
+             INSERT INTO config VALUES ('scan_threads', '2');
+             INSERT INTO config VALUES ('scan_threads', '4');",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+
+        // STRING had numeric affinity, so the id must come back as text now
+        let id: String = conn
+            .query_row("SELECT id FROM photo", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(id, "1234567");
+        let objects: i64 = conn
+            .query_row("SELECT COUNT(*) FROM object", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(objects, 1);
+        let threads: String = conn
+            .query_row(
+                "SELECT value FROM config WHERE key = 'scan_threads'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(threads, "4");
+
+        conn.execute("DELETE FROM photo WHERE id = '1234567'", ())
+            .unwrap();
+        let objects: i64 = conn
+            .query_row("SELECT COUNT(*) FROM object", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(objects, 0);
+    }
+}
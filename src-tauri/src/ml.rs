@@ -1,5 +1,5 @@
-use crate::database::{Database, Face};
-use crate::emit_log;
+use crate::database::{Face, FaceArea};
+use crate::pool::DbPool;
 use base64::Engine;
 use ndarray::{Array2, Array4};
 use std::fs;
@@ -9,6 +9,7 @@ use std::sync::{Arc, Mutex};
 use tauri::Emitter;
 use tauri::{AppHandle, Manager};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::{error, info, warn};
 
 // Conditional imports for AI Engines
 #[cfg(not(target_os = "android"))]
@@ -217,6 +218,7 @@ fn compute_text_embeddings(
 pub fn start_background_worker(
     app: &AppHandle,
     config_path: String,
+    db: DbPool,
 ) -> (
     UnboundedSender<String>,
     Arc<AtomicUsize>,
@@ -232,6 +234,7 @@ pub fn start_background_worker(
     let tx_for_thread = tx.clone();
 
     std::thread::spawn(move || {
+        let _index = tracing::info_span!("index").entered();
         let models_dir = format!("{db_path}/models");
         let faces_dir = format!("{db_path}/faces");
         let _ = fs::create_dir_all(&models_dir);
@@ -249,8 +252,7 @@ pub fn start_background_worker(
         let known_people: Arc<Mutex<Vec<(String, Vec<f32>)>>> = Arc::new(Mutex::new(Vec::new()));
         let mut engine_initialized = false;
 
-        let db = Arc::new(Mutex::new(Database::new(&db_path)));
-        let config = db.lock().unwrap().get_state();
+        let config = db.read(|db| db.get_state());
         let num_threads: usize = config
             .get("scan_threads")
             .and_then(|s| s.parse().ok())
@@ -279,10 +281,7 @@ pub fn start_background_worker(
 
             if !engine_initialized || photo_id == "__RELOAD_MODELS__" {
                 abort_clone.store(false, Ordering::SeqCst);
-                emit_log(
-                    &app_handle,
-                    "ML Worker: Initializing AI Engine...".to_string(),
-                );
+                info!("ML Worker: Initializing AI Engine...");
 
                 #[cfg(not(target_os = "android"))]
                 {
@@ -302,10 +301,7 @@ pub fn start_background_worker(
                 };
 
                 if is_ok(&ultraface_path) {
-                    emit_log(
-                        &app_handle,
-                        "ML Worker: Loading Face Detector...".to_string(),
-                    );
+                    info!("ML Worker: Loading Face Detector...");
                     #[cfg(not(target_os = "android"))]
                     {
                         match Session::builder()
@@ -317,10 +313,7 @@ pub fn start_background_worker(
                             Ok(s) => {
                                 face_detector = Some(ModelEngine::Ort(Arc::new(Mutex::new(s))))
                             }
-                            Err(e) => emit_log(
-                                &app_handle,
-                                format!("ERROR: Face Detector load failed: {e}"),
-                            ),
+                            Err(e) => error!("Face Detector load failed: {e}"),
                         }
                     }
                     #[cfg(target_os = "android")]
@@ -340,22 +333,16 @@ pub fn start_background_worker(
                                     Ok(plan) => {
                                         face_detector = Some(ModelEngine::Tract(Arc::new(plan)))
                                     }
-                                    Err(e) => emit_log(
-                                        &app_handle,
-                                        format!("ERROR: Face Detector optimization failed: {e}"),
-                                    ),
+                                    Err(e) => error!("Face Detector optimization failed: {e}"),
                                 }
                             }
-                            Err(e) => emit_log(
-                                &app_handle,
-                                format!("ERROR: Face Detector path failed: {e}"),
-                            ),
+                            Err(e) => error!("Face Detector path failed: {e}"),
                         }
                     }
                 }
 
                 if is_ok(&clip_visual_path) {
-                    emit_log(&app_handle, "ML Worker: Loading CLIP Visual...".to_string());
+                    info!("ML Worker: Loading CLIP Visual...");
                     #[cfg(not(target_os = "android"))]
                     {
                         match Session::builder()
@@ -365,10 +352,7 @@ pub fn start_background_worker(
                             .commit_from_file(&clip_visual_path)
                         {
                             Ok(s) => clip_visual = Some(ModelEngine::Ort(Arc::new(Mutex::new(s)))),
-                            Err(e) => emit_log(
-                                &app_handle,
-                                format!("ERROR: CLIP Visual load failed: {e}"),
-                            ),
+                            Err(e) => error!("CLIP Visual load failed: {e}"),
                         }
                     }
                     #[cfg(target_os = "android")]
@@ -388,22 +372,16 @@ pub fn start_background_worker(
                                     Ok(plan) => {
                                         clip_visual = Some(ModelEngine::Tract(Arc::new(plan)))
                                     }
-                                    Err(e) => emit_log(
-                                        &app_handle,
-                                        format!("ERROR: CLIP Visual initialization failed: {e}"),
-                                    ),
+                                    Err(e) => error!("CLIP Visual initialization failed: {e}"),
                                 }
                             }
-                            Err(e) => emit_log(
-                                &app_handle,
-                                format!("ERROR: CLIP Visual path failed: {e}"),
-                            ),
+                            Err(e) => error!("CLIP Visual path failed: {e}"),
                         }
                     }
                 }
 
                 if is_ok(&clip_text_path) && tokenizer.is_some() {
-                    emit_log(&app_handle, "ML Worker: Loading CLIP Text...".to_string());
+                    info!("ML Worker: Loading CLIP Text...");
                     #[cfg(not(target_os = "android"))]
                     {
                         if let Ok(mut s) = Session::builder()
@@ -440,40 +418,33 @@ pub fn start_background_worker(
                                             tokenizer.as_ref().unwrap(),
                                         ))
                                     }
-                                    Err(e) => emit_log(
-                                        &app_handle,
-                                        format!("ERROR: CLIP Text initialization failed: {e}"),
-                                    ),
+                                    Err(e) => error!("CLIP Text initialization failed: {e}"),
                                 }
                             }
                             Err(e) => {
-                                emit_log(&app_handle, format!("ERROR: CLIP Text path failed: {e}"))
+                                error!("CLIP Text path failed: {e}")
                             }
                         }
                     }
                 }
 
-                let people_vec = db.lock().unwrap().get_all_people_with_embeddings();
+                let people_vec = db.read(|db| db.get_all_people_with_embeddings());
                 if let Ok(mut lock) = known_people.lock() {
                     *lock = people_vec;
                 }
-                emit_log(&app_handle, "ML Worker: Engine Ready.".to_string());
+                info!("ML Worker: Engine Ready.");
                 if photo_id == "__RELOAD_MODELS__" {
                     continue;
                 }
             }
 
             if photo_id == "__START__" {
-                emit_log(
-                    &app_handle,
-                    "ML Worker: Checking for unindexed photos...".to_string(),
-                );
-                let unindexed = {
-                    let lock = db.lock().unwrap();
+                info!("ML Worker: Checking for unindexed photos...");
+                let unindexed = db.read(|db| {
                     let mut ids = Vec::new();
-                    if let Ok(mut stmt) = lock
+                    if let Ok(mut stmt) = db
                         .connection
-                        .prepare("SELECT id FROM photo WHERE indexed < 2")
+                        .prepare("SELECT id FROM photo WHERE indexed < 2 AND trashed IS NULL")
                     {
                         if let Ok(rows) = stmt.query_map([], |row| row.get::<_, String>(0)) {
                             for id in rows.flatten() {
@@ -482,15 +453,12 @@ pub fn start_background_worker(
                         }
                     }
                     ids
-                };
+                });
 
                 if !unindexed.is_empty() {
-                    emit_log(
-                        &app_handle,
-                        format!(
-                            "ML Worker: Found {} photos to catch up on.",
-                            unindexed.len()
-                        ),
+                    info!(
+                        "ML Worker: Found {} photos to catch up on.",
+                        unindexed.len()
                     );
                     for id in unindexed {
                         let _ = tx_for_thread.send(id);
@@ -500,26 +468,7 @@ pub fn start_background_worker(
             }
 
             // Fetch photo entry from database for processing
-            let photo_entry = {
-                let lock = db.lock().unwrap();
-                let sql = "SELECT id, location, encoded, latitude, longitude, created, indexed FROM photo WHERE id = ?1";
-                lock.connection
-                    .query_row(sql, [&photo_id], |row| {
-                        Ok(crate::database::Photo {
-                            id: row.get(0)?,
-                            location: row.get(1)?,
-                            encoded: row.get(2)?,
-                            created: row.get(5).unwrap_or_default(),
-                            objects: std::collections::HashMap::new(),
-                            properties: std::collections::HashMap::new(),
-                            latitude: row.get(3).unwrap_or(0.0),
-                            longitude: row.get(4).unwrap_or(0.0),
-                            favorite: false,
-                            indexed: row.get(6).unwrap_or(0),
-                        })
-                    })
-                    .ok()
-            };
+            let photo_entry = db.read(|db| db.get_photo(&photo_id));
 
             if let Some(photo_entry) = photo_entry {
                 if photo_entry.indexed >= 2 && photo_id != "__REPROCESS__" {
@@ -536,17 +485,40 @@ pub fn start_background_worker(
                 let text_embeddings_task = text_embeddings.clone();
                 let known_people_task = known_people.clone();
                 let faces_dir_task = faces_dir.clone();
-                let db_task = Arc::clone(&db);
+                let db_task = db.clone();
                 let abort_task = Arc::clone(&abort_clone);
+                let span = tracing::info_span!("photo", id = %photo_id_task);
 
                 pool.spawn(move || {
+                    let _photo = span.enter();
                     if abort_task.load(Ordering::SeqCst) {
                         return;
                     }
 
                     // 2. Load Image for AI
                     let image_res = image::open(&photo_loc_actual);
+                    // Videos are not decoded for ML, which is not a failure to report
+                    let error = image_res
+                        .as_ref()
+                        .err()
+                        .filter(|_| !crate::file::is_video(Path::new(&photo_loc_actual)))
+                        .map(|e| e.to_string());
+                    if let Some(e) = &error {
+                        warn!("Could not read {photo_loc_actual}: {e}");
+                    }
                     if let Ok(dynamic_img) = image_res {
+                        let (id, phash, width, height) = (
+                            photo_id_task.clone(),
+                            crate::similarity::dhash(&dynamic_img),
+                            dynamic_img.width(),
+                            dynamic_img.height(),
+                        );
+                        if let Err(e) = db_task
+                            .write(move |db| db.set_perceptual_hash(&id, phash, width, height))
+                        {
+                            warn!("Failed to save the perceptual hash of {photo_loc_actual}: {e}");
+                        }
+
                         let img = dynamic_img.to_rgb8();
 
                         // CLIP Visual
@@ -593,12 +565,24 @@ pub fn start_background_worker(
                                     b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal)
                                 });
 
-                                let lock = db_task.lock().unwrap();
-                                for (class_name, score) in similarities.iter().take(5) {
-                                    let _ = lock.connection.execute(
-                                        "INSERT INTO object (photo_id, class, probability) VALUES(?1, ?2, ?3)",
-                                        (&photo_id_task, class_name, &score.to_string()),
-                                    );
+                                let id = photo_id_task.clone();
+                                let top: Vec<(String, String)> = similarities
+                                    .iter()
+                                    .take(5)
+                                    .map(|(class_name, score)| {
+                                        (class_name.to_string(), score.to_string())
+                                    })
+                                    .collect();
+                                let stored = db_task.write(move |db| {
+                                    for (class_name, score) in &top {
+                                        let _ = db.connection.execute(
+                                            "INSERT INTO object (photo_id, class, probability) VALUES(?1, ?2, ?3)",
+                                            (&id, class_name, score),
+                                        );
+                                    }
+                                });
+                                if let Err(e) = stored {
+                                    warn!("Failed to save the objects in {photo_loc_actual}: {e}");
                                 }
                             }
                         }
@@ -657,9 +641,22 @@ pub fn start_background_worker(
                                                     &img, xmin, ymin, w, h,
                                                 )
                                                 .to_image();
-                                                let face_id = format!(
-                                                    "{photo_id_task}_face_{xmin}_{ymin}"
-                                                );
+                                                let area = FaceArea {
+                                                    x: xmin as f32 / orig_w,
+                                                    y: ymin as f32 / orig_h,
+                                                    width: w as f32 / orig_w,
+                                                    height: h as f32 / orig_h,
+                                                };
+                                                // A face named in an XMP sidecar before it
+                                                // was detected keeps its id and name
+                                                let named = db_task
+                                                    .read(|db| db.face_at(&photo_id_task, &area));
+                                                let face_id = match &named {
+                                                    Some((face_id, _)) => face_id.clone(),
+                                                    None => format!(
+                                                        "{photo_id_task}_face_{xmin}_{ymin}"
+                                                    ),
+                                                };
                                                 let crop_path =
                                                     format!("{faces_dir_task}/{face_id}.jpg");
                                                 if face_crop.save(&crop_path).is_ok() {
@@ -714,7 +711,9 @@ pub fn start_background_worker(
                                                     }
 
                                                     let mut assigned_person_id = None;
-                                                    if !face_embedding.is_empty() {
+                                                    if let Some((_, person_id)) = &named {
+                                                        assigned_person_id = person_id.clone();
+                                                    } else if !face_embedding.is_empty() {
                                                         if let Ok(mut lock) =
                                                             known_people_task.lock()
                                                         {
@@ -745,25 +744,38 @@ pub fn start_background_worker(
                                                                 assigned_person_id =
                                                                     best_match_id;
                                                             } else {
-                                                                let lock_db =
-                                                                    db_task.lock().unwrap();
-                                                                let new_id = lock_db
-                                                                    .create_anonymous_person(
-                                                                        &face_embedding,
-                                                                    );
-                                                                lock.push((
-                                                                    new_id.clone(),
-                                                                    face_embedding.clone(),
-                                                                ));
-                                                                assigned_person_id =
-                                                                    Some(new_id);
+                                                                let embedding =
+                                                                    face_embedding.clone();
+                                                                match db_task.write(move |db| {
+                                                                    db.create_anonymous_person(
+                                                                        &embedding,
+                                                                    )
+                                                                }) {
+                                                                    Ok(new_id) => {
+                                                                        lock.push((
+                                                                            new_id.clone(),
+                                                                            face_embedding.clone(),
+                                                                        ));
+                                                                        assigned_person_id =
+                                                                            Some(new_id);
+                                                                    }
+                                                                    Err(e) => warn!(
+                                                                        "Failed to add a person for {face_id}: {e}"
+                                                                    ),
+                                                                }
                                                             }
                                                         }
                                                     } else {
-                                                        let lock_db = db_task.lock().unwrap();
-                                                        let new_id = lock_db
-                                                            .create_anonymous_person(&[]);
-                                                        assigned_person_id = Some(new_id);
+                                                        match db_task.write(|db| {
+                                                            db.create_anonymous_person(&[])
+                                                        }) {
+                                                            Ok(new_id) => {
+                                                                assigned_person_id = Some(new_id)
+                                                            }
+                                                            Err(e) => warn!(
+                                                                "Failed to add a person for {face_id}: {e}"
+                                                            ),
+                                                        }
                                                     }
 
                                                     let mut buffer =
@@ -777,15 +789,26 @@ pub fn start_background_worker(
                                                         base64::engine::general_purpose::STANDARD
                                                             .encode(buffer.get_ref())
                                                     );
-                                                    let lock = db_task.lock().unwrap();
-                                                    lock.store_face(Face {
+                                                    let face = Face {
                                                         photo_id: photo_id_task.clone(),
                                                         face_id: face_id.clone(),
                                                         crop_path,
                                                         encoded,
                                                         embedding: face_embedding,
                                                         person_id: assigned_person_id,
+                                                        area: Some(area),
+                                                    };
+                                                    let named_person =
+                                                        named.and_then(|(_, person)| person);
+                                                    let stored = db_task.write(move |db| {
+                                                        db.store_face(face);
+                                                        if let Some(person_id) = named_person {
+                                                            db.update_person_centroid(&person_id);
+                                                        }
                                                     });
+                                                    if let Err(e) = stored {
+                                                        warn!("Failed to save {face_id}: {e}");
+                                                    }
                                                 }
                                             }
                                         }
@@ -796,21 +819,26 @@ pub fn start_background_worker(
                     }
 
                     // Mark as FULLY INDEXED
-                    {
-                        let lock = db_task.lock().unwrap();
-                        lock.update_photo_indexed(&photo_id_task, 2);
-                        let _ = lock.connection.execute(
+                    let id = photo_id_task.clone();
+                    let indexed = db_task.write(move |db| {
+                        db.update_photo_indexed(&id, 2);
+                        db.set_index_error(&id, error.as_deref());
+                        let _ = db.connection.execute(
                             "UPDATE photo SET sync_needed = 1 WHERE id = ?1",
-                            [&photo_id_task],
+                            [&id],
                         );
+                    });
+                    if let Err(e) = indexed {
+                        warn!("Failed to mark {photo_loc_actual} as indexed: {e}");
                     }
 
                     // Proactively notify peer with FULL AI data
                     if let Some(state) = app_handle_task.try_state::<crate::WebRtcState>() {
                         let mut tx_lock = state.sync_tx.blocking_lock();
                         if let Some(tx) = tx_lock.as_mut() {
-                            let db = db_task.lock().unwrap();
-                            if let Ok(info) = db.get_photo_sync_info_by_id(&photo_id_task) {
+                            let info =
+                                db_task.read(|db| db.get_photo_sync_info_by_id(&photo_id_task));
+                            if let Ok(info) = info {
                                 let _ = tx.send(crate::transport::SyncMessage::SyncFile {
                                     photo: info,
                                 });
@@ -820,6 +848,10 @@ pub fn start_background_worker(
 
                     let current = pending_count_task.fetch_sub(1, Ordering::SeqCst);
                     let _ = app_handle_task.emit("indexing-progress", current.saturating_sub(1));
+                    if current == 1 {
+                        // The queue is drained, so the new tags and faces can go to sidecars
+                        crate::xmp::write_back(&db_task);
+                    }
                 });
             }
         }
@@ -0,0 +1,295 @@
+use crate::database::Database;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::error;
+
+/// Reader connections kept open. Reads run concurrently with each other and, thanks to WAL,
+/// with the writer.
+const READERS: usize = 4;
+/// Most queued writes committed together in one transaction.
+const MAX_BATCH: usize = 256;
+
+/// A queued write. It returns the reply to deliver once its batch has been committed, or
+/// has failed to.
+type Task = Box<dyn FnOnce(&mut Database) -> Reply + Send>;
+type Reply = Box<dyn FnOnce(Result<(), String>) + Send>;
+
+struct Job {
+    task: Task,
+    /// Runs outside any transaction, for what SQLite refuses to do inside one.
+    alone: bool,
+}
+
+/// The app's one handle on `siegu.db`, kept in Tauri state and cloned into background
+/// workers. Reads borrow one of a few open connections; writes are queued to a single writer
+/// thread, which applies whatever has queued up in one transaction instead of each caller
+/// committing on its own and waiting on the others' locks.
+#[derive(Clone)]
+pub struct DbPool {
+    readers: Arc<(Mutex<Vec<Database>>, Condvar)>,
+    writer: Sender<Job>,
+}
+
+impl DbPool {
+    /// Opens the database, running any pending migrations once, and starts the writer.
+    pub fn open(config_path: &str) -> Self {
+        let writer_db = Database::new(config_path);
+        let readers = (0..READERS).map(|_| Database::open(config_path)).collect();
+        let (writer, jobs) = mpsc::channel();
+        std::thread::spawn(move || run_writer(writer_db, jobs));
+        Self {
+            readers: Arc::new((Mutex::new(readers), Condvar::new())),
+            writer,
+        }
+    }
+
+    /// Runs `f` on a reader connection, waiting for one to be free. Reads must not write:
+    /// use `write` for anything that changes the database.
+    pub fn read<T>(&self, f: impl FnOnce(&Database) -> T) -> T {
+        let lease = Lease::take(&self.readers);
+        f(lease.db.as_ref().unwrap())
+    }
+
+    /// Queues `f` for the writer and waits until the transaction it ran in has been
+    /// committed, so a read made afterwards sees its changes. `f` must not call back into
+    /// the pool. Fails if `f` panics or its batch fails to commit, as its changes are lost.
+    pub fn write<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Database) -> T + Send + 'static,
+    {
+        self.queue(f, false)
+    }
+
+    /// Like `write`, but runs `f` on its own outside a transaction, e.g. to restore a backup
+    /// into the database.
+    pub fn write_alone<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Database) -> T + Send + 'static,
+    {
+        self.queue(f, true)
+    }
+
+    fn queue<T, F>(&self, f: F, alone: bool) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Database) -> T + Send + 'static,
+    {
+        let (reply, result) = mpsc::sync_channel(1);
+        let task: Task = Box::new(move |db| {
+            let value = f(db);
+            Box::new(move |committed: Result<(), String>| {
+                let _ = reply.send(committed.map(|_| value));
+            })
+        });
+        self.writer
+            .send(Job { task, alone })
+            .map_err(|_| "The database writer has stopped".to_string())?;
+        // A job that panicked drops its reply unsent
+        result
+            .recv()
+            .unwrap_or_else(|_| Err("The database write failed".to_string()))
+    }
+}
+
+/// A reader connection on loan, handed back when dropped even if the read panicked.
+struct Lease<'a> {
+    readers: &'a (Mutex<Vec<Database>>, Condvar),
+    db: Option<Database>,
+}
+
+impl<'a> Lease<'a> {
+    fn take(readers: &'a (Mutex<Vec<Database>>, Condvar)) -> Self {
+        let (idle, freed) = readers;
+        let mut idle = idle.lock().unwrap();
+        loop {
+            if let Some(db) = idle.pop() {
+                return Self {
+                    readers,
+                    db: Some(db),
+                };
+            }
+            idle = freed.wait(idle).unwrap();
+        }
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let (idle, freed) = self.readers;
+        if let (Some(db), Ok(mut idle)) = (self.db.take(), idle.lock()) {
+            idle.push(db);
+            freed.notify_one();
+        }
+    }
+}
+
+fn run_writer(mut db: Database, jobs: Receiver<Job>) {
+    let mut next = None;
+    loop {
+        let first = match next.take() {
+            Some(job) => job,
+            None => match jobs.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+        };
+        if first.alone {
+            if let Ok(reply) = panic::catch_unwind(AssertUnwindSafe(|| (first.task)(&mut db))) {
+                reply(Ok(()));
+            }
+            continue;
+        }
+        let mut batch = vec![first.task];
+        while batch.len() < MAX_BATCH {
+            match jobs.try_recv() {
+                Ok(job) if job.alone => {
+                    next = Some(job);
+                    break;
+                }
+                Ok(job) => batch.push(job.task),
+                Err(_) => break,
+            }
+        }
+        run_batch(&mut db, batch);
+    }
+}
+
+/// Runs the jobs in one transaction, each under its own savepoint so a job that panics
+/// leaves nothing behind, and delivers their replies once it has been committed. If the
+/// commit fails, every caller of the batch gets the error.
+fn run_batch(db: &mut Database, batch: Vec<Task>) {
+    let began = db.connection.execute_batch("BEGIN IMMEDIATE").is_ok();
+    let mut replies = Vec::with_capacity(batch.len());
+    for task in batch {
+        let _ = db.connection.execute_batch("SAVEPOINT job");
+        match panic::catch_unwind(AssertUnwindSafe(|| task(&mut *db))) {
+            Ok(reply) => {
+                let _ = db.connection.execute_batch("RELEASE job");
+                replies.push(reply);
+            }
+            // Its reply is dropped, which fails only its own caller
+            Err(_) => {
+                let _ = db.connection.execute_batch("ROLLBACK TO job; RELEASE job");
+            }
+        }
+    }
+    if began {
+        if let Err(e) = db.connection.execute_batch("COMMIT") {
+            error!("Database batch commit failed: {e}");
+            let _ = db.connection.execute_batch("ROLLBACK");
+            for reply in replies {
+                reply(Err(format!("Failed to save changes: {e}")));
+            }
+            return;
+        }
+    }
+    for reply in replies {
+        reply(Ok(()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_are_visible_to_readers_once_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = DbPool::open(dir.path().to_str().unwrap());
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for j in 0..25 {
+                        let id = format!("{i}-{j}");
+                        pool.write(move |db| {
+                            db.import_photo(
+                                &id,
+                                &format!("/{id}.jpg"),
+                                "",
+                                None,
+                                None,
+                                "[]",
+                                "[]",
+                                "",
+                            )
+                        })
+                        .unwrap();
+                        let id = format!("{i}-{j}");
+                        assert!(pool.read(|db| db.get_photo(&id)).is_some());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(pool.read(|db| db.get_media_counts()), (200, 0));
+
+        // A failing write fails its caller without taking the writer down with it or
+        // leaving half its changes
+        let failed = pool.write::<(), _>(|db| {
+            db.toggle_favorite("0-1");
+            panic!("boom")
+        });
+        assert!(failed.is_err());
+        assert!(!pool.read(|db| db.get_photo("0-1")).unwrap().favorite);
+        pool.write(|db| db.toggle_favorite("0-0")).unwrap();
+        assert!(pool.read(|db| db.get_photo("0-0")).unwrap().favorite);
+        assert_eq!(
+            pool.write_alone(|db| db.connection.is_autocommit()),
+            Ok(true)
+        );
+
+        pool.write(|db| db.clear()).unwrap();
+        assert_eq!(pool.read(|db| db.get_media_counts()), (0, 0));
+    }
+
+    /// Writes from several threads through the pool against each write committing on its
+    /// own connection, as every command used to. Run with
+    /// `cargo test --release pool::tests::throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn throughput() {
+        const THREADS: usize = 8;
+        const WRITES: usize = 250;
+        fn import(db: &Database, id: &str) {
+            db.import_photo(id, &format!("/{id}.jpg"), "", None, None, "[]", "[]", "");
+        }
+        fn time(write: impl Fn(String) + Send + Sync + 'static) -> f64 {
+            let write = Arc::new(write);
+            let started = std::time::Instant::now();
+            let threads: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let write = write.clone();
+                    std::thread::spawn(move || {
+                        for j in 0..WRITES {
+                            write(format!("{i}-{j}"));
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            (THREADS * WRITES) as f64 / started.elapsed().as_secs_f64()
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let separate_path = dir.path().join("separate").to_string_lossy().to_string();
+        Database::new(&separate_path);
+        let separate = time(move |id| import(&Database::open(&separate_path), &id));
+
+        let pool = DbPool::open(&dir.path().join("pooled").to_string_lossy());
+        let pooled = time(move |id| pool.write(move |db| import(db, &id)).unwrap());
+
+        println!("one connection and transaction per write: {separate:.0} writes/s");
+        println!(
+            "pooled writer: {pooled:.0} writes/s ({:.1}x)",
+            pooled / separate
+        );
+    }
+}
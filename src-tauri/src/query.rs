@@ -0,0 +1,314 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+
+/// A parsed search box query: every term must match.
+/// `person:Alice tag:beach after:2022-06 -tag:screenshot sunset` has five terms.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Query {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub filter: Filter,
+    /// Written with a leading `-`.
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Free text, matched against the full-text index as a prefix.
+    Text(String),
+    /// Person id or name.
+    Person(String),
+    Tag(String),
+    /// User tag, by full path or name, including the tags below it.
+    Keyword(String),
+    /// On or after a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date.
+    After(String),
+    /// Before a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date.
+    Before(String),
+    /// Album, smart album or folder name.
+    In(String),
+    Video(bool),
+    Favorite(bool),
+    Camera(String),
+    Lens(String),
+    Aperture(Range),
+    Iso(Range),
+    /// Millimetres.
+    FocalLength(Range),
+}
+
+/// Inclusive numeric bounds, written as `2.8`, `100-400`, `>800` or `<35`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Why a query could not be parsed, with the character range to highlight.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryError {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+const FILTERS: &[&str] = &[
+    "person", "tag", "keyword", "after", "before", "in", "type", "fav", "camera", "lens", "f",
+    "aperture", "iso", "focal",
+];
+
+impl QueryError {
+    fn new(message: impl Into<String>, start: usize, end: usize) -> Self {
+        Self {
+            message: message.into(),
+            start,
+            end,
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Query, QueryError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut terms = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        if chars[pos].is_whitespace() {
+            pos += 1;
+            continue;
+        }
+        let negated = chars[pos] == '-' && chars.get(pos + 1).is_some_and(|c| !c.is_whitespace());
+        if negated {
+            pos += 1;
+        }
+
+        let key_start = pos;
+        let mut key_end = pos;
+        while key_end < chars.len() && chars[key_end].is_alphabetic() {
+            key_end += 1;
+        }
+        let filter = if key_end > key_start && chars.get(key_end) == Some(&':') {
+            let key: String = chars[key_start..key_end].iter().collect();
+            let key = key.to_lowercase();
+            if !FILTERS.contains(&key.as_str()) {
+                return Err(QueryError::new(
+                    format!(
+                        "Unknown filter `{key}:`, expected one of {}",
+                        FILTERS.join(", ")
+                    ),
+                    key_start,
+                    key_end,
+                ));
+            }
+            let value_start = key_end + 1;
+            let (value, end) = read_value(&chars, value_start)?;
+            pos = end;
+            if value.is_empty() {
+                return Err(QueryError::new(
+                    format!("Missing value for `{key}:`"),
+                    key_start,
+                    end,
+                ));
+            }
+            filter(&key, value, value_start, end)?
+        } else {
+            let (value, end) = read_value(&chars, key_start)?;
+            pos = end;
+            if value.is_empty() {
+                continue;
+            }
+            Filter::Text(value)
+        };
+        terms.push(Term { filter, negated });
+    }
+    Ok(Query { terms })
+}
+
+/// Reads a bare word or a `"quoted value"` starting at `start`, returning it and the
+/// position just past it.
+fn read_value(chars: &[char], start: usize) -> Result<(String, usize), QueryError> {
+    if chars.get(start) == Some(&'"') {
+        let Some(close) = chars[start + 1..].iter().position(|&c| c == '"') else {
+            return Err(QueryError::new("Unclosed quote", start, chars.len()));
+        };
+        let end = start + 1 + close;
+        return Ok((chars[start + 1..end].iter().collect(), end + 1));
+    }
+    let mut end = start;
+    while end < chars.len() && !chars[end].is_whitespace() {
+        end += 1;
+    }
+    Ok((chars[start..end].iter().collect(), end))
+}
+
+fn filter(key: &str, value: String, start: usize, end: usize) -> Result<Filter, QueryError> {
+    Ok(match key {
+        "person" => Filter::Person(value),
+        "tag" => Filter::Tag(value),
+        "keyword" => Filter::Keyword(value),
+        "after" => Filter::After(date(value, start, end)?),
+        "before" => Filter::Before(date(value, start, end)?),
+        "in" => Filter::In(value),
+        "camera" => Filter::Camera(value),
+        "lens" => Filter::Lens(value),
+        "f" | "aperture" => Filter::Aperture(range(&value, start, end)?),
+        "iso" => Filter::Iso(range(&value, start, end)?),
+        "focal" => Filter::FocalLength(range(&value, start, end)?),
+        "type" => match value.to_lowercase().as_str() {
+            "video" | "videos" => Filter::Video(true),
+            "photo" | "photos" | "image" | "images" => Filter::Video(false),
+            _ => {
+                return Err(QueryError::new(
+                    "Expected `type:photo` or `type:video`",
+                    start,
+                    end,
+                ))
+            }
+        },
+        _ => match value.to_lowercase().as_str() {
+            "yes" | "true" | "1" => Filter::Favorite(true),
+            "no" | "false" | "0" => Filter::Favorite(false),
+            _ => {
+                return Err(QueryError::new(
+                    "Expected `fav:yes` or `fav:no`",
+                    start,
+                    end,
+                ))
+            }
+        },
+    })
+}
+
+fn range(value: &str, start: usize, end: usize) -> Result<Range, QueryError> {
+    let number = |s: &str| s.trim_end_matches("mm").parse::<f64>().ok();
+    let range = if let Some(min) = value.strip_prefix('>') {
+        number(min).map(|min| Range {
+            min: Some(min),
+            max: None,
+        })
+    } else if let Some(max) = value.strip_prefix('<') {
+        number(max).map(|max| Range {
+            min: None,
+            max: Some(max),
+        })
+    } else if let Some((min, max)) = value.split_once('-') {
+        number(min).zip(number(max)).map(|(min, max)| Range {
+            min: Some(min),
+            max: Some(max),
+        })
+    } else {
+        number(value).map(|n| Range {
+            min: Some(n),
+            max: Some(n),
+        })
+    };
+    range.ok_or_else(|| {
+        QueryError::new(
+            "Expected a number like 2.8, a range like 100-400, or >800 or <35",
+            start,
+            end,
+        )
+    })
+}
+
+/// Checks a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date. It is kept as written, since comparing
+/// it as a prefix of the ISO capture date covers the whole year or month.
+fn date(value: String, start: usize, end: usize) -> Result<String, QueryError> {
+    let full = match value.len() {
+        4 => format!("{value}-01-01"),
+        7 => format!("{value}-01"),
+        _ => value.clone(),
+    };
+    if value.len() < 4 || NaiveDate::parse_from_str(&full, "%Y-%m-%d").is_err() {
+        return Err(QueryError::new(
+            "Expected a date like 2023, 2023-06 or 2023-06-15",
+            start,
+            end,
+        ));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(filter: Filter, negated: bool) -> Term {
+        Term { filter, negated }
+    }
+
+    #[test]
+    fn parses_filters_and_negation() {
+        let query = parse(
+            r#"person:Alice tag:beach keyword:"Places/Portugal" after:2022-06 before:2023 in:"Holidays" type:video fav:yes camera:"Pixel 7" -tag:screenshot sunset -"old town""#,
+        )
+        .unwrap();
+        assert_eq!(
+            query.terms,
+            vec![
+                term(Filter::Person("Alice".to_string()), false),
+                term(Filter::Tag("beach".to_string()), false),
+                term(Filter::Keyword("Places/Portugal".to_string()), false),
+                term(Filter::After("2022-06".to_string()), false),
+                term(Filter::Before("2023".to_string()), false),
+                term(Filter::In("Holidays".to_string()), false),
+                term(Filter::Video(true), false),
+                term(Filter::Favorite(true), false),
+                term(Filter::Camera("Pixel 7".to_string()), false),
+                term(Filter::Tag("screenshot".to_string()), true),
+                term(Filter::Text("sunset".to_string()), false),
+                term(Filter::Text("old town".to_string()), true),
+            ]
+        );
+        assert_eq!(
+            parse("camera:X100V f:2 iso:>800 focal:23-35mm")
+                .unwrap()
+                .terms,
+            vec![
+                term(Filter::Camera("X100V".to_string()), false),
+                term(
+                    Filter::Aperture(Range {
+                        min: Some(2.0),
+                        max: Some(2.0)
+                    }),
+                    false
+                ),
+                term(
+                    Filter::Iso(Range {
+                        min: Some(800.0),
+                        max: None
+                    }),
+                    false
+                ),
+                term(
+                    Filter::FocalLength(Range {
+                        min: Some(23.0),
+                        max: Some(35.0)
+                    }),
+                    false
+                ),
+            ]
+        );
+        // Colons inside words that are not filters stay text
+        assert_eq!(
+            parse("12:30").unwrap().terms,
+            vec![term(Filter::Text("12:30".to_string()), false)]
+        );
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let error = |input: &str| {
+            let e = parse(input).unwrap_err();
+            (e.start, e.end)
+        };
+        assert_eq!(error("tag:a perosn:Alice"), (6, 12));
+        assert_eq!(error("after:2023-13"), (6, 13));
+        assert_eq!(error("type:gif"), (5, 8));
+        assert_eq!(error("iso:high"), (4, 8));
+        assert_eq!(error("fav:"), (0, 4));
+        assert_eq!(error(r#"in:"Holi"#), (3, 8));
+    }
+}
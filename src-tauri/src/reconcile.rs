@@ -0,0 +1,218 @@
+//! Matches photos whose file was moved or renamed outside Siegu to the files a scan finds,
+//! so they keep their id, faces, tags, albums and culling instead of coming back as new
+//! photos. A file matches by size and modification time confirmed by a hash of its ends,
+//! which needs no full read, or else by content hash.
+
+use crate::database::PhotoFile;
+use crate::pool::DbPool;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Bytes hashed from each end of a file for its partial hash.
+const PARTIAL_BYTES: u64 = 64 * 1024;
+
+/// What a file is recognized by after a move, short of its content hash. Moves and renames
+/// keep the modification time, which copies made by most tools do too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Unix seconds.
+    pub modified: i64,
+    /// SHA-256 over the first and last 64 KiB, hex encoded.
+    pub partial_hash: String,
+}
+
+pub fn fingerprint(path: &Path) -> std::io::Result<Fingerprint> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::new();
+    (&mut file).take(PARTIAL_BYTES).read_to_end(&mut buffer)?;
+    hasher.update(&buffer);
+    if size > PARTIAL_BYTES {
+        // The tail, without hashing bytes the head already covered
+        file.seek(SeekFrom::Start(PARTIAL_BYTES.max(size - PARTIAL_BYTES)))?;
+        buffer.clear();
+        file.read_to_end(&mut buffer)?;
+        hasher.update(&buffer);
+    }
+    Ok(Fingerprint {
+        size,
+        modified: modified(&metadata),
+        partial_hash: hex::encode(hasher.finalize()),
+    })
+}
+
+/// Size and modification time of the file at `path`, the part of its fingerprint that
+/// needs no read.
+pub fn size_and_time(path: &Path) -> Option<(u64, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), modified(&metadata)))
+}
+
+fn modified(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Photos whose file was gone when a scan started, which it claims one by one as it finds
+/// where they went. Shared by the scan's threads.
+pub struct Missing {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    photos: HashMap<String, PhotoFile>,
+    by_hash: HashMap<String, Vec<String>>,
+    by_size_and_time: HashMap<(u64, i64), Vec<String>>,
+}
+
+impl Missing {
+    /// Checks every photo in the library, trashed ones aside, for its file.
+    pub fn find(db: &DbPool) -> Self {
+        let files = db.read(|db| db.photo_files());
+        Self::new(
+            files
+                .into_iter()
+                .filter(|photo| !Path::new(&photo.location).exists())
+                .collect(),
+        )
+    }
+
+    pub fn new(photos: Vec<PhotoFile>) -> Self {
+        let mut state = State::default();
+        for photo in photos {
+            if let Some(hash) = &photo.hash {
+                state
+                    .by_hash
+                    .entry(hash.clone())
+                    .or_default()
+                    .push(photo.id.clone());
+            }
+            if let Some(fingerprint) = &photo.fingerprint {
+                state
+                    .by_size_and_time
+                    .entry((fingerprint.size, fingerprint.modified))
+                    .or_default()
+                    .push(photo.id.clone());
+            }
+            state.photos.insert(photo.id.clone(), photo);
+        }
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// The missing photo the file at `path` is by its fingerprint, which no longer counts as
+    /// missing. Only files the size and modification time of a missing photo get hashed.
+    pub fn claim_by_fingerprint(&self, path: &Path) -> Option<PhotoFile> {
+        let key = size_and_time(path)?;
+        if !self
+            .state
+            .lock()
+            .unwrap()
+            .by_size_and_time
+            .contains_key(&key)
+        {
+            return None;
+        }
+        let found = fingerprint(path).ok()?;
+        let mut state = self.state.lock().unwrap();
+        let candidates = state.by_size_and_time.get(&key)?.clone();
+        state.claim(&candidates, |photo| {
+            photo.fingerprint.as_ref() == Some(&found)
+        })
+    }
+
+    /// Whether a photo with content hash `hash` is missing and has not been found yet.
+    pub fn has_hash(&self, hash: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .by_hash
+            .get(hash)
+            .is_some_and(|ids| ids.iter().any(|id| state.photos.contains_key(id)))
+    }
+
+    /// The missing photo with content hash `hash`, which no longer counts as missing.
+    pub fn claim_by_hash(&self, hash: &str) -> Option<PhotoFile> {
+        let mut state = self.state.lock().unwrap();
+        let candidates = state.by_hash.get(hash)?.clone();
+        state.claim(&candidates, |_| true)
+    }
+
+    /// Photos nothing was found for.
+    pub fn remaining(self) -> Vec<PhotoFile> {
+        let state = self.state.into_inner().unwrap();
+        let mut photos: Vec<PhotoFile> = state.photos.into_values().collect();
+        photos.sort_by(|a, b| a.location.cmp(&b.location));
+        photos
+    }
+}
+
+impl State {
+    fn claim(&mut self, ids: &[String], accept: impl Fn(&PhotoFile) -> bool) -> Option<PhotoFile> {
+        let id = ids
+            .iter()
+            .find(|id| self.photos.get(*id).is_some_and(&accept))?;
+        self.photos.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gone(id: &str, hash: Option<&str>, fingerprint: Option<Fingerprint>) -> PhotoFile {
+        PhotoFile {
+            id: id.to_string(),
+            location: format!("/gone/{id}.jpg"),
+            hash: hash.map(str::to_string),
+            fingerprint,
+        }
+    }
+
+    #[test]
+    fn claims_moved_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let moved = dir.path().join("renamed.jpg");
+        let mut bytes = vec![7u8; 200 * 1024];
+        fs::write(&moved, &bytes).unwrap();
+        let print = fingerprint(&moved).unwrap();
+        assert_eq!(print.size, 200 * 1024);
+
+        // Same size and modification time, but another file
+        bytes[0] = 0;
+        let other = dir.path().join("other.jpg");
+        fs::write(&other, &bytes).unwrap();
+        let file = File::options().write(true).open(&other).unwrap();
+        file.set_modified(fs::metadata(&moved).unwrap().modified().unwrap())
+            .unwrap();
+
+        let missing = Missing::new(vec![
+            gone("a", Some("hash-a"), Some(print)),
+            gone("b", Some("hash-b"), None),
+            gone("c", None, None),
+        ]);
+        assert!(missing.claim_by_fingerprint(&other).is_none());
+        assert_eq!(missing.claim_by_fingerprint(&moved).unwrap().id, "a");
+        // Claimed once only
+        assert!(missing.claim_by_fingerprint(&moved).is_none());
+        assert!(missing.claim_by_hash("hash-a").is_none());
+        assert!(!missing.has_hash("hash-a"));
+        assert!(missing.has_hash("hash-b"));
+        assert_eq!(missing.claim_by_hash("hash-b").unwrap().id, "b");
+        let left: Vec<String> = missing.remaining().into_iter().map(|p| p.id).collect();
+        assert_eq!(left, vec!["c"]);
+    }
+}
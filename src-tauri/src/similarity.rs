@@ -0,0 +1,172 @@
+use image::DynamicImage;
+use std::collections::HashMap;
+
+/// Hamming distance used when the caller does not pick one. Re-saved and resized copies
+/// of the same image usually land within a few bits of each other.
+pub const DEFAULT_MAX_DISTANCE: u32 = 8;
+
+/// 64-bit difference hash: each bit records whether a pixel is brighter than its right-hand
+/// neighbour in a 9x8 grayscale thumbnail. Survives resizing, recompression and small edits.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Burkhard-Keller tree over Hamming distance, so near neighbours can be found without
+/// comparing every pair of photos in the library.
+struct BkTree {
+    nodes: Vec<(u64, usize, HashMap<u32, usize>)>, // (hash, item index, children by distance)
+}
+
+impl BkTree {
+    fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    fn insert(&mut self, hash: u64, item: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push((hash, item, HashMap::new()));
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let distance = hamming(self.nodes[current].0, hash);
+            match self.nodes[current].2.get(&distance) {
+                Some(&child) => current = child,
+                None => {
+                    let index = self.nodes.len();
+                    self.nodes.push((hash, item, HashMap::new()));
+                    self.nodes[current].2.insert(distance, index);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = if self.nodes.is_empty() {
+            Vec::new()
+        } else {
+            vec![0]
+        };
+        while let Some(current) = stack.pop() {
+            let (node_hash, item, children) = &self.nodes[current];
+            let distance = hamming(*node_hash, hash);
+            if distance <= max_distance {
+                found.push(*item);
+            }
+            let low = distance.saturating_sub(max_distance);
+            let high = distance + max_distance;
+            for (&d, &child) in children {
+                if d >= low && d <= high {
+                    stack.push(child);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Groups photos whose hashes are all within `max_distance` bits of each other, so a chain of
+/// small differences (a near b, b near c) never puts two unrelated photos together. Each photo
+/// joins at most one group, the first one whose every member it is close to. Only groups with
+/// at least two members are returned, largest first.
+pub fn cluster(hashes: &[(String, u64)], max_distance: u32) -> Vec<Vec<String>> {
+    let mut tree = BkTree::new();
+    for (i, (_, hash)) in hashes.iter().enumerate() {
+        tree.insert(*hash, i);
+    }
+
+    let mut grouped = vec![false; hashes.len()];
+    let mut clusters = Vec::new();
+    for (i, (_, hash)) in hashes.iter().enumerate() {
+        if grouped[i] {
+            continue;
+        }
+        grouped[i] = true;
+        let mut neighbours = tree.find(*hash, max_distance);
+        neighbours.sort_by_key(|&j| (hamming(*hash, hashes[j].1), j));
+        let mut members = vec![i];
+        for j in neighbours {
+            if grouped[j] {
+                continue;
+            }
+            if members
+                .iter()
+                .all(|&m| hamming(hashes[m].1, hashes[j].1) <= max_distance)
+            {
+                grouped[j] = true;
+                members.push(j);
+            }
+        }
+        if members.len() > 1 {
+            let mut ids: Vec<String> = members.iter().map(|&m| hashes[m].0.clone()).collect();
+            ids.sort();
+            clusters.push(ids);
+        }
+    }
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    clusters
+}
+
+/// The photos in `others` close enough to `kept` to count as its copies. Groups can come from
+/// anywhere, so resolving one checks each photo against the one it keeps.
+pub fn copies_of(kept: u64, others: &[(String, u64)], max_distance: u32) -> Vec<String> {
+    others
+        .iter()
+        .filter(|(_, hash)| hamming(kept, *hash) <= max_distance)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops::FilterType, GrayImage, Luma};
+
+    fn waves(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+            let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+            Luma([(128.0 + 100.0 * (fx * 9.0).sin() * (fy * 7.0).cos()) as u8])
+        }))
+    }
+
+    #[test]
+    fn resized_copy_hashes_close() {
+        let original = waves(640, 480);
+        let resized = original.resize_exact(320, 240, FilterType::Triangle);
+        assert!(hamming(dhash(&original), dhash(&resized)) <= DEFAULT_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn clusters_need_every_pair_close() {
+        let hashes = vec![
+            ("a".to_string(), 0b0000u64),
+            ("b".to_string(), 0b0011u64),
+            ("c".to_string(), 0b1111u64),
+            ("d".to_string(), u64::MAX),
+        ];
+        // a-b and b-c are within two bits, a-c is not, d is far from everything
+        assert_eq!(
+            cluster(&hashes, 2),
+            vec![vec!["a".to_string(), "b".to_string()]]
+        );
+        assert!(cluster(&hashes, 0).is_empty());
+        // Resolving the whole chain with a kept keeps c
+        assert_eq!(copies_of(0b0000, &hashes[1..], 2), vec!["b".to_string()]);
+    }
+}
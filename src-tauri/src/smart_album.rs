@@ -0,0 +1,221 @@
+use crate::database::{
+    folder_filter, folder_path, keyword_filter, person_id_filter, person_name_filter, tag_filter,
+    tag_path, SqlParams, CREATED_DATE, FAVORITE_FILTER, VIDEO_FILTER,
+};
+use serde::{Deserialize, Serialize};
+
+/// A smart album's rule tree, stored as JSON and compiled to SQL each time it is listed.
+/// Serialized with a `type` tag, e.g.
+/// `{"type":"all","rules":[{"type":"favorite"},{"type":"person","person":"Alice"}]}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rule {
+    All {
+        rules: Vec<Rule>,
+    },
+    Any {
+        rules: Vec<Rule>,
+    },
+    Not {
+        rule: Box<Rule>,
+    },
+    /// Person id or name.
+    Person {
+        person: String,
+    },
+    /// Substring of an ML tag, matched like the search box does.
+    Tag {
+        tag: String,
+    },
+    /// User tag, by full path or name, including the tags below it.
+    Keyword {
+        keyword: String,
+    },
+    /// Inclusive capture dates as `YYYY-MM-DD`; either end may be open.
+    DateRange {
+        from: Option<String>,
+        to: Option<String>,
+    },
+    /// GPS bounding box. `west` greater than `east` wraps across the antimeridian.
+    Area {
+        north: f64,
+        south: f64,
+        east: f64,
+        west: f64,
+    },
+    MediaType {
+        media: MediaType,
+    },
+    Favorite,
+    /// Photos anywhere below this folder.
+    Folder {
+        path: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Photo,
+    Video,
+}
+
+impl Rule {
+    /// Compiles the rule to a boolean SQL expression over `photo p`, binding its values
+    /// into `params`.
+    pub fn to_sql(&self, params: &mut SqlParams) -> String {
+        match self {
+            Rule::All { rules } => join(rules, " AND ", "1=1", params),
+            Rule::Any { rules } => join(rules, " OR ", "0=1", params),
+            Rule::Not { rule } => format!("NOT ({})", rule.to_sql(params)),
+            Rule::Person { person } => {
+                let n = params.bind(person.clone());
+                format!("({} OR {})", person_id_filter(n), person_name_filter(n))
+            }
+            Rule::Tag { tag } => tag_filter(params.bind(format!("%{tag}%"))),
+            Rule::Keyword { keyword } => {
+                let keyword = tag_path(keyword).unwrap_or_default();
+                keyword_filter(params.bind(keyword))
+            }
+            Rule::DateRange { from, to } => {
+                let mut parts = vec!["p.created IS NOT NULL".to_string()];
+                if let Some(from) = from {
+                    parts.push(format!("{CREATED_DATE} >= ?{}", params.bind(from.clone())));
+                }
+                if let Some(to) = to {
+                    parts.push(format!("{CREATED_DATE} <= ?{}", params.bind(to.clone())));
+                }
+                format!("({})", parts.join(" AND "))
+            }
+            Rule::Area {
+                north,
+                south,
+                east,
+                west,
+            } => {
+                let (south, north) = (params.bind(*south), params.bind(*north));
+                let (west, east) = (params.bind(*west), params.bind(*east));
+                // Photos without GPS are stored at 0,0
+                format!(
+                    "(p.latitude BETWEEN ?{south} AND ?{north} AND CASE WHEN ?{west} <= ?{east} THEN p.longitude BETWEEN ?{west} AND ?{east} ELSE p.longitude >= ?{west} OR p.longitude <= ?{east} END AND NOT (p.latitude = 0.0 AND p.longitude = 0.0))"
+                )
+            }
+            Rule::MediaType {
+                media: MediaType::Video,
+            } => VIDEO_FILTER.to_string(),
+            Rule::MediaType {
+                media: MediaType::Photo,
+            } => format!("NOT {VIDEO_FILTER}"),
+            Rule::Favorite => FAVORITE_FILTER.to_string(),
+            Rule::Folder { path } => folder_filter(params.bind(folder_path(path))),
+        }
+    }
+}
+
+fn join(rules: &[Rule], separator: &str, empty: &str, params: &mut SqlParams) -> String {
+    if rules.is_empty() {
+        return empty.to_string();
+    }
+    let parts: Vec<String> = rules.iter().map(|r| r.to_sql(params)).collect();
+    format!("({})", parts.join(separator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, Page, PhotoFilter};
+
+    #[test]
+    fn rules_filter_photos_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Database::new(dir.path().to_str().unwrap());
+        let objects = r#"[{"class":"a beach","probability":"0.4"}]"#;
+        database.import_photo(
+            "a",
+            "/pics/a.jpg",
+            "2023-07-01 10:00:00",
+            Some(52.1),
+            Some(4.3),
+            objects,
+            "[]",
+            "",
+        );
+        database.import_photo(
+            "b",
+            "/pics/b.mp4",
+            "2023:08:01 10:00:00",
+            None,
+            None,
+            objects,
+            "[]",
+            "",
+        );
+        database.import_photo(
+            "c",
+            "/other/c.jpg",
+            "2022-07-01 10:00:00",
+            None,
+            None,
+            objects,
+            "[]",
+            "",
+        );
+        database.toggle_favorite("a");
+        database.toggle_favorite("b");
+
+        let rules: Rule = serde_json::from_str(
+            r#"{"type":"all","rules":[
+                {"type":"favorite"},
+                {"type":"tag","tag":"beach"},
+                {"type":"date_range","from":"2023-01-01","to":"2023-12-31"},
+                {"type":"not","rule":{"type":"media_type","media":"video"}}
+            ]}"#,
+        )
+        .unwrap();
+        let album = database.create_smart_album("Beach 2023", &rules).unwrap();
+        assert_eq!(album.photo_count, 1);
+
+        let ids = |rules: &Rule| -> Vec<String> {
+            database.update_smart_album(&album.id, "", rules);
+            database
+                .list_photos(
+                    &PhotoFilter {
+                        album_id: Some(album.id.clone()),
+                        ..Default::default()
+                    },
+                    &Page {
+                        limit: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .photos
+                .into_iter()
+                .map(|p| p.id)
+                .collect()
+        };
+        assert_eq!(ids(&rules), vec!["a"]);
+        assert_eq!(
+            ids(&Rule::Any {
+                rules: vec![
+                    Rule::Folder {
+                        path: "/other".to_string()
+                    },
+                    Rule::Area {
+                        north: 53.0,
+                        south: 52.0,
+                        east: 5.0,
+                        west: 4.0
+                    },
+                ]
+            }),
+            vec!["a", "c"]
+        );
+        // A folder holds what is below it, not its siblings sharing a prefix
+        let folder = |path: &str| Rule::Folder {
+            path: path.to_string(),
+        };
+        assert_eq!(ids(&folder("/other/")), vec!["c"]);
+        assert!(ids(&folder("/pic")).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::database::{Database, IndexCoverage, StorageGroup};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// What the library holds and what it takes up on disk. Photo sizes are the originals' file
+/// sizes; trashed photos are left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryStats {
+    pub directories: Vec<StorageGroup>,
+    pub years: Vec<StorageGroup>,
+    pub cameras: Vec<StorageGroup>,
+    pub file_types: Vec<StorageGroup>,
+    pub coverage: IndexCoverage,
+    /// Inline photo thumbnails kept in the database.
+    pub thumbnail_bytes: u64,
+    /// Face crops in `faces/` plus their inline copies in the database.
+    pub face_crop_bytes: u64,
+    /// `siegu.db` with its write-ahead log.
+    pub database_bytes: u64,
+}
+
+pub fn collect(config_path: &str, db: &Database) -> LibraryStats {
+    let (thumbnails, inline_faces) = db.thumbnail_bytes();
+    let config = Path::new(config_path);
+    let database_bytes = ["siegu.db", "siegu.db-wal", "siegu.db-shm"]
+        .iter()
+        .map(|name| file_size(&config.join(name)))
+        .sum();
+    LibraryStats {
+        directories: db.storage_by_directory(),
+        years: db.storage_by_year(),
+        cameras: db.storage_by_camera(),
+        file_types: db.storage_by_file_type(),
+        coverage: db.index_coverage(),
+        thumbnail_bytes: thumbnails as u64,
+        face_crop_bytes: dir_size(&config.join("faces")) + inline_faces as u64,
+        database_bytes,
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            _ => file_size(&entry.path()),
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::MediaInfo;
+
+    #[test]
+    fn breaks_down_storage_and_coverage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().to_str().unwrap();
+        let database = Database::new(config_path);
+        database.add_directory("/photos/trip");
+        database.add_directory("/photos/home/");
+        database.add_directory("/photos/trip_2");
+        for (id, location, created) in [
+            ("a", "/photos/trip/a.jpg", "2021-05-01T10:00:00Z"),
+            ("b", "/photos/trip/b.jpg", "2023-05-01T10:00:00Z"),
+            ("c", "/photos/home/c.png", ""),
+            ("d", "/photos/trip2/d.mp4", ""),
+        ] {
+            database.import_photo(id, location, created, None, None, "[]", "[]", "");
+        }
+        for (id, camera, mime, size) in [
+            ("a", "X100V", "image/jpeg", 300),
+            ("b", "X100V", "image/jpeg", 200),
+            ("c", "Pixel 7", "image/png", 1000),
+            ("d", "Pixel 7", "video/mp4", 0),
+        ] {
+            let info = MediaInfo {
+                camera_model: Some(camera.to_string()),
+                mime: mime.to_string(),
+                file_size: size,
+                ..Default::default()
+            };
+            database.set_media_info(id, &info);
+        }
+        database.update_photo_indexed("a", 2);
+        database.update_photo_indexed("b", 2);
+        database.set_index_error("b", Some("unsupported format"));
+        // Stored before videos were skipped
+        database.update_photo_indexed("d", 2);
+        database.set_index_error("d", Some("unsupported format"));
+        fs::create_dir_all(dir.path().join("faces")).unwrap();
+        fs::write(dir.path().join("faces/a_face.jpg"), [0u8; 64]).unwrap();
+
+        let stats = collect(config_path, &database);
+        let group = |key: Option<&str>, count, bytes| StorageGroup {
+            key: key.map(str::to_string),
+            count,
+            bytes,
+        };
+        assert_eq!(
+            stats.directories,
+            vec![
+                group(Some("/photos/home/"), 1, 1000),
+                group(Some("/photos/trip"), 2, 500),
+                group(Some("/photos/trip_2"), 0, 0)
+            ]
+        );
+        assert_eq!(
+            stats.years,
+            vec![
+                group(None, 2, 1000),
+                group(Some("2021"), 1, 300),
+                group(Some("2023"), 1, 200)
+            ]
+        );
+        assert_eq!(
+            stats.cameras,
+            vec![
+                group(Some("Pixel 7"), 2, 1000),
+                group(Some("X100V"), 2, 500)
+            ]
+        );
+        assert_eq!(stats.file_types[1], group(Some("image/jpeg"), 2, 500));
+        assert_eq!(
+            stats.coverage,
+            IndexCoverage {
+                total: 4,
+                indexed: 3,
+                pending: 1,
+                failed: 1,
+                without_faces: 1,
+                without_tags: 1,
+            }
+        );
+        assert_eq!(stats.face_crop_bytes, 64);
+        assert!(stats.database_bytes > 0);
+    }
+}
@@ -0,0 +1,216 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use exif::{In, Reader, Tag, Value};
+use regex::Regex;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// When a photo or video was captured, as a UTC instant plus the UTC offset of the place it
+/// was taken. When the source has no offset, the offset of this machine's time zone is assumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureTime {
+    pub utc: DateTime<Utc>,
+    pub offset_seconds: i32,
+}
+
+impl CaptureTime {
+    /// ISO-8601 in UTC, which sorts chronologically as plain text.
+    pub fn to_iso(self) -> String {
+        self.utc.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    }
+
+    fn from_local(naive: NaiveDateTime, offset_seconds: Option<i32>) -> Option<Self> {
+        let local = match offset_seconds {
+            Some(seconds) => FixedOffset::east_opt(seconds)?
+                .from_local_datetime(&naive)
+                .single()?,
+            None => Local.from_local_datetime(&naive).earliest()?.fixed_offset(),
+        };
+        Some(Self {
+            utc: local.with_timezone(&Utc),
+            offset_seconds: local.offset().local_minus_utc(),
+        })
+    }
+
+    fn from_utc(utc: DateTime<Utc>) -> Self {
+        Self {
+            utc,
+            offset_seconds: Local
+                .from_utc_datetime(&utc.naive_utc())
+                .offset()
+                .local_minus_utc(),
+        }
+    }
+}
+
+/// Works out when a file was captured, trying in order: the EXIF original date with its
+/// offset, the video container's creation time, a date in the filename, the EXIF date the
+/// file was last changed and the file's mtime.
+pub fn resolve(path: &Path) -> Option<CaptureTime> {
+    exif_time(path, Tag::DateTimeOriginal, Tag::OffsetTimeOriginal)
+        .or_else(|| video_time(path))
+        .or_else(|| filename_time(path))
+        .or_else(|| exif_time(path, Tag::DateTime, Tag::OffsetTime))
+        .or_else(|| modified_time(path))
+}
+
+fn exif_time(path: &Path, date_tag: Tag, offset_tag: Tag) -> Option<CaptureTime> {
+    let file = File::open(path).ok()?;
+    let exif = Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    let ascii = |tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+    let mut date = exif::DateTime::from_ascii(&ascii(date_tag)?).ok()?;
+    if let Some(offset) = ascii(offset_tag) {
+        let _ = date.parse_offset(&offset);
+    }
+    let day = NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())?;
+    let naive = day.and_hms_opt(date.hour.into(), date.minute.into(), date.second.into())?;
+    CaptureTime::from_local(naive, date.offset.map(|minutes| i32::from(minutes) * 60))
+}
+
+/// Seconds between the QuickTime epoch (1904-01-01) and the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Creation time from the `moov/mvhd` box of MP4 and QuickTime files, which is in UTC.
+fn video_time(path: &Path) -> Option<CaptureTime> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    if !["mp4", "mov", "m4v", "3gp"].contains(&ext.as_str()) {
+        return None;
+    }
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let (moov, moov_len) = find_box(&mut file, 0, len, b"moov")?;
+    let (mvhd, _) = find_box(&mut file, moov, moov + moov_len, b"mvhd")?;
+
+    file.seek(SeekFrom::Start(mvhd)).ok()?;
+    let mut version = [0u8; 4]; // version byte and three flag bytes
+    file.read_exact(&mut version).ok()?;
+    let created = if version[0] == 1 {
+        let mut buf = [0u8; 8];
+        file.read_exact(&mut buf).ok()?;
+        u64::from_be_bytes(buf) as i64
+    } else {
+        let mut buf = [0u8; 4];
+        file.read_exact(&mut buf).ok()?;
+        u32::from_be_bytes(buf) as i64
+    };
+    // Many encoders leave the field zeroed
+    if created == 0 {
+        return None;
+    }
+    let utc = Utc
+        .timestamp_opt(created - QUICKTIME_EPOCH_OFFSET, 0)
+        .single()?;
+    Some(CaptureTime::from_utc(utc))
+}
+
+/// Finds a box by type between `start` and `end`, returning its payload offset and length.
+fn find_box(file: &mut File, start: u64, end: u64, name: &[u8; 4]) -> Option<(u64, u64)> {
+    let mut pos = start;
+    while pos + 8 <= end {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).ok()?;
+        let size = u32::from_be_bytes(header[..4].try_into().ok()?) as u64;
+        let (header_len, size) = match size {
+            0 => (8, end - pos), // Box runs to the end of its parent
+            1 => {
+                let mut large = [0u8; 8];
+                file.read_exact(&mut large).ok()?;
+                (16, u64::from_be_bytes(large))
+            }
+            _ => (8, size),
+        };
+        if size < header_len {
+            return None;
+        }
+        if &header[4..] == name {
+            return Some((pos + header_len, size - header_len));
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Dates embedded by cameras and apps, e.g. `IMG_20230102_101112.jpg`,
+/// `PXL_20230102_101112345.jpg`, `Screenshot_2023-01-02-10-11-12.png` or `IMG-20230102-WA0001.jpg`.
+fn filename_time(path: &Path) -> Option<CaptureTime> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(concat!(
+            r"(?:^|\D)((?:19|20)\d{2})[-_.]?(\d{2})[-_.]?(\d{2})",
+            r"(?:[-_ T.]?(\d{2})[-_.:]?(\d{2})[-_.:]?(\d{2}))?"
+        ))
+        .unwrap()
+    });
+    let name = path.file_stem()?.to_string_lossy();
+    pattern.captures_iter(&name).find_map(|caps| {
+        let number = |i: usize| caps.get(i).and_then(|m| m.as_str().parse::<u32>().ok());
+        let date = NaiveDate::from_ymd_opt(number(1)? as i32, number(2)?, number(3)?)?;
+        let naive = match (number(4), number(5), number(6)) {
+            (Some(h), Some(m), Some(s)) => date.and_hms_opt(h, m, s)?,
+            _ => date.and_hms_opt(0, 0, 0)?,
+        };
+        CaptureTime::from_local(naive, None)
+    })
+}
+
+fn modified_time(path: &Path) -> Option<CaptureTime> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(CaptureTime::from_utc(modified.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_patterns() {
+        let parse = |name: &str| {
+            let time = filename_time(Path::new(name)).unwrap();
+            let local = time
+                .utc
+                .with_timezone(&FixedOffset::east_opt(time.offset_seconds).unwrap());
+            local.format("%Y-%m-%d %H:%M:%S").to_string()
+        };
+        assert_eq!(parse("IMG_20230102_101112.jpg"), "2023-01-02 10:11:12");
+        assert_eq!(
+            parse("PXL_20230102_101112345.MP.jpg"),
+            "2023-01-02 10:11:12"
+        );
+        assert_eq!(
+            parse("Screenshot_2023-01-02-10-11-12.png"),
+            "2023-01-02 10:11:12"
+        );
+        assert_eq!(parse("IMG-20230102-WA0001.jpg"), "2023-01-02 00:00:00");
+        assert!(filename_time(Path::new("IMG_1234.jpg")).is_none());
+        assert!(filename_time(Path::new("IMG_20231399_101112.jpg")).is_none());
+    }
+
+    #[test]
+    fn mp4_creation_time() {
+        // ftyp, then moov containing a version 0 mvhd with creation time 2023-01-02T10:11:12Z
+        let created = (1_672_654_272 + QUICKTIME_EPOCH_OFFSET) as u32;
+        let mut mvhd = vec![0, 0, 0, 0];
+        mvhd.extend_from_slice(&created.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 12]);
+        let boxed = |name: &[u8], payload: &[u8]| {
+            let mut b = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(name);
+            b.extend_from_slice(payload);
+            b
+        };
+        let mut data = boxed(b"ftyp", b"isom");
+        data.extend(boxed(b"moov", &boxed(b"mvhd", &mvhd)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.mp4");
+        fs::write(&path, data).unwrap();
+        assert_eq!(resolve(&path).unwrap().to_iso(), "2023-01-02T10:11:12Z");
+    }
+}
@@ -20,10 +20,12 @@ use webrtc::{
     },
 };
 
-use crate::database::{Database, PhotoSyncInfo};
+use crate::database::{AlbumRemoval, Culling, PhotoAlbums, PhotoCulling, PhotoSyncInfo};
+use crate::pool::DbPool;
 use std::collections::HashMap;
 use tauri::Emitter;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, info, warn};
 use warp::Filter;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,6 +44,15 @@ pub enum SyncMessage {
     ManifestRequest,
     ManifestResponse {
         photos: Vec<PhotoSyncInfo>,
+        /// Missing from peers that only send culling within `photos`.
+        #[serde(default)]
+        culling: Vec<PhotoCulling>,
+        /// Missing from peers that only send albums within `photos`.
+        #[serde(default)]
+        albums: Vec<PhotoAlbums>,
+        /// Missing from peers that never send album removals.
+        #[serde(default)]
+        album_removals: Vec<AlbumRemoval>,
     },
     FileRequest {
         id: String,
@@ -55,6 +66,10 @@ pub enum SyncMessage {
         longitude: Option<f64>,
         objects: String,
         faces: String,
+        #[serde(default)]
+        albums: String,
+        #[serde(default)]
+        culling: Culling,
     },
     FileChunk {
         id: String,
@@ -108,6 +123,7 @@ pub struct WebRtcClient {
     pub signaling_url: String,
     pub app_handle: Option<AppHandle>,
     pub config_path: String,
+    pub db: DbPool,
     pub sync_tx: Arc<tokio::sync::Mutex<Option<UnboundedSender<SyncMessage>>>>,
 }
 
@@ -123,6 +139,8 @@ struct IncomingFile {
     longitude: Option<f64>,
     objects: String,
     faces: String,
+    albums: String,
+    culling: Culling,
     file: tokio::fs::File,
 }
 
@@ -167,6 +185,7 @@ impl WebRtcClient {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn send_file(
         &self,
         dc: Arc<webrtc::data_channel::RTCDataChannel>,
@@ -177,6 +196,8 @@ impl WebRtcClient {
         longitude: Option<f64>,
         objects: String,
         faces: String,
+        albums: String,
+        culling: Culling,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let path = Path::new(&file_path);
         if !path.exists() {
@@ -203,6 +224,8 @@ impl WebRtcClient {
                 longitude,
                 objects,
                 faces,
+                albums,
+                culling,
             },
         )
         .await?;
@@ -258,7 +281,7 @@ impl WebRtcClient {
         )
         .await
         {
-            println!("Error sending FileEnd: {e}");
+            warn!("Error sending FileEnd: {e}");
             return Err(e);
         }
 
@@ -286,12 +309,12 @@ impl WebRtcClient {
 
         if self_arc.room_id.is_empty() {
             let err_msg = "Room ID is missing".to_string();
-            println!("{err_msg}");
+            error!("{err_msg}");
             self_arc.emit("webrtc-state", err_msg.clone());
             return Err(err_msg.into());
         }
 
-        println!(
+        info!(
             "Attempting to connect to signaling at {} for room {}",
             self_arc.signaling_url, self_arc.room_id
         );
@@ -303,12 +326,12 @@ impl WebRtcClient {
         let req = url_str.into_client_request()?;
         let (ws_stream, _) = match connect_async(req).await {
             Ok(s) => {
-                println!("Successfully connected to signaling server!");
+                info!("Successfully connected to signaling server!");
                 s
             }
             Err(e) => {
                 let err_msg = format!("Signaling connection failed: {e}");
-                println!("{err_msg}");
+                warn!("{err_msg}");
                 self_arc.emit("webrtc-state", err_msg);
                 return Err(e.into());
             }
@@ -360,16 +383,16 @@ impl WebRtcClient {
         let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
         let app_handle_state = self_arc.app_handle.clone();
-        let config_path_state = self_arc.config_path.clone();
+        let db_state = self_arc.db.clone();
         let room_id_state = self_arc.room_id.clone();
 
         peer_connection.on_peer_connection_state_change(Box::new(
             move |s: RTCPeerConnectionState| {
                 let app_handle = app_handle_state.clone();
-                let config_path = config_path_state.clone();
+                let db = db_state.clone();
                 let room_id = room_id_state.clone();
                 Box::pin(async move {
-                    println!("Peer Connection State changed to: {s:?}");
+                    info!("Peer Connection State changed to: {s:?}");
                     let status = match s {
                         RTCPeerConnectionState::Connected => "Connected",
                         RTCPeerConnectionState::Connecting => "Connecting WebRTC...",
@@ -383,12 +406,16 @@ impl WebRtcClient {
                         let _ = app.emit("webrtc-state", status);
 
                         if s == RTCPeerConnectionState::Connected {
-                            let db = Database::new(&config_path);
                             let peer_name = format!("Peer ({})", &room_id[..8]);
-                            let _ = db.connection.execute(
-                                "INSERT OR REPLACE INTO device(ip, name) VALUES(?1, ?2)",
-                                (&room_id, &peer_name),
-                            );
+                            let saved = db.write(move |db| {
+                                let _ = db.connection.execute(
+                                    "INSERT OR REPLACE INTO device(ip, name) VALUES(?1, ?2)",
+                                    (&room_id, &peer_name),
+                                );
+                            });
+                            if let Err(e) = saved {
+                                warn!("Failed to remember the connected peer: {e}");
+                            }
                             let _ = app.emit("refresh-devices", ());
                         }
                     }
@@ -463,6 +490,7 @@ impl WebRtcClient {
             let dc_clone_msg = Arc::clone(&data_channel);
             let incoming_files_clone = Arc::clone(&incoming_files);
             let config_path_dc = self_initiator.config_path.clone();
+            let db_dc = self_initiator.db.clone();
             let app_handle_initiator_msg = self_initiator.app_handle.clone();
             let self_initiator_msg = Arc::clone(&self_initiator);
             let items_completed_shared = Arc::new(std::sync::atomic::AtomicUsize::new(0));
@@ -473,6 +501,7 @@ impl WebRtcClient {
                     let dc = Arc::clone(&dc_clone_msg);
                     let incoming_files = Arc::clone(&incoming_files_clone);
                     let config_path = config_path_dc.clone();
+                    let db = db_dc.clone();
                     let app_handle = app_handle_initiator_msg.clone();
                     let self_inner = self_initiator_msg.clone();
                     let items_completed = Arc::clone(&items_completed_shared);
@@ -482,15 +511,19 @@ impl WebRtcClient {
                         let text = String::from_utf8_lossy(&msg.data);
                         if let Ok(sync_msg) = serde_json::from_str::<SyncMessage>(&text) {
                             match sync_msg {
-                                SyncMessage::ManifestResponse { photos } => {
-                                    let db = Database::new(&config_path);
-                                    let my_manifest = db.get_photo_sync_info();
-                                    let mut to_request = Vec::new();
-                                    for peer_photo in &photos {
-                                        if !my_manifest.iter().any(|p| p.id == peer_photo.id) {
-                                            to_request.push(peer_photo.id.clone());
-                                        }
+                                SyncMessage::ManifestResponse { photos, culling, albums, album_removals } => {
+                                    // Ratings, flags and labels of photos both devices hold, whichever side culled last
+                                    let manifest: Vec<PhotoCulling> = if culling.is_empty() { photos.iter().map(PhotoCulling::from).collect() } else { culling };
+                                    if let Err(e) = db.write(move |db| db.merge_culling(&manifest)).and_then(|merged| merged) {
+                                        warn!("Failed to merge culling from peer: {e}");
+                                    }
+                                    // Album membership of photos both devices hold, whichever side added or removed last
+                                    let manifest: Vec<PhotoAlbums> = if albums.is_empty() { photos.iter().map(PhotoAlbums::from).collect() } else { albums };
+                                    if let Err(e) = db.write(move |db| db.merge_albums(&manifest, &album_removals)).and_then(|merged| merged) {
+                                        warn!("Failed to merge albums from peer: {e}");
                                     }
+                                    // Compared by content hash, so a photo both devices already hold is never transferred twice
+                                    let to_request = db.read(|db| db.missing_from_manifest(&photos));
 
                                     if !to_request.is_empty() {
                                         let total = to_request.len();
@@ -534,12 +567,12 @@ impl WebRtcClient {
                                         });
                                     }
                                 }
-                                SyncMessage::FileHeader { id, filename, size, created, latitude, longitude, objects, faces } => {
+                                SyncMessage::FileHeader { id, filename, size, created, latitude, longitude, objects, faces, albums, culling } => {
                                     let save_path = Path::new(&config_path).join("sync_temp").join(&filename);
                                     if let Some(parent) = save_path.parent() { let _ = tokio::fs::create_dir_all(parent).await; }
                                     if let Ok(file) = tokio::fs::File::create(&save_path).await {
                                         let mut incoming = incoming_files.lock().await;
-                                        incoming.insert(id.clone(), IncomingFile { id, filename, size, received: 0, created, latitude, longitude, objects, faces, file });
+                                        incoming.insert(id.clone(), IncomingFile { id, filename, size, received: 0, created, latitude, longitude, objects, faces, albums, culling, file });
                                     }
                                 }
                                 SyncMessage::FileChunk { id, data } => {
@@ -562,12 +595,9 @@ impl WebRtcClient {
                                         let _ = file_state.file.flush().await;
                                         drop(file_state.file);
                                         let temp_path = Path::new(&config_path).join("sync_temp").join(&file_state.filename);
-                                        let db = Database::new(&config_path);
-
                                         // Fetch sync_path directly from database state to be sure
-                                        let state = db.get_state();
+                                        let (state, dirs) = db.read(|db| (db.get_state(), db.list_directories()));
                                         let sync_path_str = state.get("sync_path");
-                                        let dirs = db.list_directories();
 
                                         let target_dir = if let Some(sp) = sync_path_str {
                                             PathBuf::from(sp).join("siegu")
@@ -593,16 +623,31 @@ impl WebRtcClient {
                                             let created_thumb = file_state.created.clone();
                                             let lat_thumb = file_state.latitude.unwrap_or(0.0);
                                             let lon_thumb = file_state.longitude.unwrap_or(0.0);
-                                            let config_path_thumb = config_path.clone();
+                                            let db_thumb = db.clone();
                                             let objects_thumb = file_state.objects.clone();
                                             let faces_thumb = file_state.faces.clone();
+                                            let albums_thumb = file_state.albums.clone();
+                                            let culling_thumb = file_state.culling.clone();
 
                                             tokio::task::spawn_blocking(move || {
                                                 let thumb = String::new();
-                                                let db = Database::new(&config_path_thumb);
+                                                // Hash what actually arrived rather than trusting the sender
+                                                let hash = crate::file::hash_file(Path::new(&path_thumb)).ok();
 
                                                 // Now import with thumbnail included - only now it becomes visible in library
-                                                db.import_photo(&id_thumb, &path_thumb, &created_thumb, Some(lat_thumb), Some(lon_thumb), &objects_thumb, &faces_thumb, &thumb);
+                                                let (id, path, created, encoded, stored_hash, culling) = (id_thumb.clone(), path_thumb.clone(), created_thumb.clone(), thumb.clone(), hash.clone(), culling_thumb.clone());
+                                                let imported = db_thumb.write(move |db| {
+                                                    db.import_photo(&id, &path, &created, Some(lat_thumb), Some(lon_thumb), &objects_thumb, &faces_thumb, &encoded);
+                                                    db.import_photo_albums(&id, &albums_thumb);
+                                                    db.import_photo_culling(&id, &culling);
+                                                    if let Some(hash) = &stored_hash {
+                                                        db.set_photo_hash(&id, hash);
+                                                    }
+                                                });
+                                                if let Err(e) = imported {
+                                                    error!("Failed to import received photo {path_thumb}: {e}");
+                                                    return;
+                                                }
 
                                                 let _ = app_thumb.emit("photo-received", crate::database::Photo {
                                                     id: id_thumb,
@@ -615,6 +660,13 @@ impl WebRtcClient {
                                                     longitude: lon_thumb,
                                                     favorite: false,
                                                     indexed: 2,
+                                                    hash,
+                                                    created_offset: None,
+                                                    rating: culling_thumb.rating,
+                                                    flag: culling_thumb.flag,
+                                                    label: culling_thumb.label,
+                                                    media: None,
+                                                    xmp: None,
                                                 });
                                             });
 
@@ -637,22 +689,23 @@ impl WebRtcClient {
                                     }
                                 }
                                 SyncMessage::SyncFile { photo } => {
-                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id: photo.id }).await;
+                                    let known = db.read(|db| db.has_photo(&photo));
+                                    if !known {
+                                        let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id: photo.id }).await;
+                                    }
                                 }
                                 SyncMessage::StartSync => {
                                     if let Some(app) = &app_handle { let _ = app.emit("start-sync", ()); }
                                     let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestRequest).await;
                                 }
                                 SyncMessage::ManifestRequest => {
-                                    let db = Database::new(&config_path);
-                                    let photos = db.get_photo_sync_info();
-                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestResponse { photos }).await;
+                                    let (photos, culling, albums, album_removals) = db.read(|db| (db.get_photo_sync_info(), db.get_culling_sync(), db.get_album_sync(), db.get_album_removals()));
+                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestResponse { photos, culling, albums, album_removals }).await;
                                 }
                                 SyncMessage::CatchUp => {
-                                    let db = Database::new(&config_path);
                                     // Collect IDs first to avoid Send issues
-                                    let ids: Vec<String> = {
-                                        let sql = "SELECT id FROM photo WHERE sync_needed = 1 AND location NOT LIKE '%/siegu/%' AND location NOT LIKE '%\\siegu\\%'";
+                                    let ids: Vec<String> = db.read(|db| {
+                                        let sql = "SELECT id FROM photo WHERE sync_needed = 1 AND trashed IS NULL AND location NOT LIKE '%/siegu/%' AND location NOT LIKE '%\\siegu\\%'";
                                         if let Ok(mut stmt) = db.connection.prepare(sql) {
                                             stmt.query_map([], |row| row.get::<_, String>(0))
                                                 .map(|rows| rows.flatten().collect())
@@ -660,7 +713,7 @@ impl WebRtcClient {
                                         } else {
                                             Vec::new()
                                         }
-                                    };
+                                    });
 
                                     for id in ids {
                                         let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id }).await;
@@ -679,18 +732,20 @@ impl WebRtcClient {
                                     }
                                 }
                                 SyncMessage::FileRequest { id } => {
-                                    let db = Database::new(&config_path);
-                                    if let Ok((path, created, lat, lon, objects, faces)) = db.connection.query_row(
+                                    let row = db.read(|db| {
+                                        db.connection.query_row(
                                         "SELECT p.location, p.created, p.latitude, p.longitude,
                                          (SELECT json_group_array(json_object('class', class, 'probability', probability)) FROM object WHERE photo_id = p.id),
                                          (SELECT json_group_array(json_object('face_id', face_id, 'crop_path', crop_path, 'encoded', encoded, 'person_id', person_id)) FROM faces WHERE photo_id = p.id)
-                                         FROM photo p WHERE p.id = ?1",
+                                         FROM photo p WHERE p.id = ?1 AND p.trashed IS NULL",
                                         [&id],
                                         |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<f64>>(2)?, row.get::<_, Option<f64>>(3)?, row.get::<_, String>(4).unwrap_or("[]".to_string()), row.get::<_, String>(5).unwrap_or("[]".to_string()))),
-                                    ) {
+                                        ).map(|row| (row, db.album_sync_json(&id), db.photo_culling(&id)))
+                                    });
+                                    if let Ok(((path, created, lat, lon, objects, faces), albums, culling)) = row {
                                         let dc_send = Arc::clone(&dc);
                                         let self_task = self_inner.clone();
-                                        tokio::spawn(async move { let _ = self_task.send_file(dc_send, id, path, created, lat, lon, objects, faces).await; });
+                                        tokio::spawn(async move { let _ = self_task.send_file(dc_send, id, path, created, lat, lon, objects, faces, albums, culling).await; });
                                     }
                                 }
                             }
@@ -702,6 +757,7 @@ impl WebRtcClient {
             let app_handle_opt = self_arc.app_handle.clone();
             let incoming_files_clone = Arc::new(Mutex::new(HashMap::new()));
             let config_path_dc = self_arc.config_path.clone();
+            let db_dc = self_arc.db.clone();
             let sync_msg_rx_shared_receiver = Arc::clone(&sync_msg_rx_shared);
             let self_receiver = Arc::clone(&self_arc);
             let items_completed_shared = Arc::new(std::sync::atomic::AtomicUsize::new(0));
@@ -711,6 +767,7 @@ impl WebRtcClient {
                 let dc_clone = Arc::clone(&d);
                 let incoming_files = Arc::clone(&incoming_files_clone);
                 let config_path = config_path_dc.clone();
+                let db_dc = db_dc.clone();
                 let app_handle = app_handle_opt.clone();
                 let sync_msg_rx_inner_shared = Arc::clone(&sync_msg_rx_shared_receiver);
                 let self_receiver_msg = Arc::clone(&self_receiver);
@@ -721,6 +778,7 @@ impl WebRtcClient {
                     let dc = Arc::clone(&dc_clone);
                     let incoming_files = Arc::clone(&incoming_files);
                     let config_path = config_path.clone();
+                    let db = db_dc.clone();
                     let app_handle = app_handle.clone();
                     let self_inner = self_receiver_msg.clone();
                     let items_completed = Arc::clone(&items_completed);
@@ -731,22 +789,23 @@ impl WebRtcClient {
                         if let Ok(sync_msg) = serde_json::from_str::<SyncMessage>(&text) {
                             match sync_msg {
                                 SyncMessage::SyncFile { photo } => {
-                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id: photo.id }).await;
+                                    let known = db.read(|db| db.has_photo(&photo));
+                                    if !known {
+                                        let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id: photo.id }).await;
+                                    }
                                 }
                                 SyncMessage::StartSync => {
                                     if let Some(app) = &app_handle { let _ = app.emit("start-sync", ()); }
                                     let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestRequest).await;
                                 }
                                 SyncMessage::ManifestRequest => {
-                                    let db = Database::new(&config_path);
-                                    let photos = db.get_photo_sync_info();
-                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestResponse { photos }).await;
+                                    let (photos, culling, albums, album_removals) = db.read(|db| (db.get_photo_sync_info(), db.get_culling_sync(), db.get_album_sync(), db.get_album_removals()));
+                                    let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::ManifestResponse { photos, culling, albums, album_removals }).await;
                                 }
                                 SyncMessage::CatchUp => {
-                                    let db = Database::new(&config_path);
                                     // Collect IDs first to avoid Send issues
-                                    let ids: Vec<String> = {
-                                        let sql = "SELECT id FROM photo WHERE sync_needed = 1 AND location NOT LIKE '%/siegu/%' AND location NOT LIKE '%\\siegu\\%'";
+                                    let ids: Vec<String> = db.read(|db| {
+                                        let sql = "SELECT id FROM photo WHERE sync_needed = 1 AND trashed IS NULL AND location NOT LIKE '%/siegu/%' AND location NOT LIKE '%\\siegu\\%'";
                                         if let Ok(mut stmt) = db.connection.prepare(sql) {
                                             stmt.query_map([], |row| row.get::<_, String>(0))
                                                 .map(|rows| rows.flatten().collect())
@@ -754,21 +813,25 @@ impl WebRtcClient {
                                         } else {
                                             Vec::new()
                                         }
-                                    };
+                                    });
 
                                     for id in ids {
                                         let _ = WebRtcClient::send_sync_message(&dc, &SyncMessage::FileRequest { id }).await;
                                     }
                                 }
-                                SyncMessage::ManifestResponse { photos } => {
-                                    let db = Database::new(&config_path);
-                                    let my_manifest = db.get_photo_sync_info();
-                                    let mut to_request = Vec::new();
-                                    for peer_photo in &photos {
-                                        if !my_manifest.iter().any(|p| p.id == peer_photo.id) {
-                                            to_request.push(peer_photo.id.clone());
-                                        }
+                                SyncMessage::ManifestResponse { photos, culling, albums, album_removals } => {
+                                    // Ratings, flags and labels of photos both devices hold, whichever side culled last
+                                    let manifest: Vec<PhotoCulling> = if culling.is_empty() { photos.iter().map(PhotoCulling::from).collect() } else { culling };
+                                    if let Err(e) = db.write(move |db| db.merge_culling(&manifest)).and_then(|merged| merged) {
+                                        warn!("Failed to merge culling from peer: {e}");
+                                    }
+                                    // Album membership of photos both devices hold, whichever side added or removed last
+                                    let manifest: Vec<PhotoAlbums> = if albums.is_empty() { photos.iter().map(PhotoAlbums::from).collect() } else { albums };
+                                    if let Err(e) = db.write(move |db| db.merge_albums(&manifest, &album_removals)).and_then(|merged| merged) {
+                                        warn!("Failed to merge albums from peer: {e}");
                                     }
+                                    // Compared by content hash, so a photo both devices already hold is never transferred twice
+                                    let to_request = db.read(|db| db.missing_from_manifest(&photos));
 
                                     if !to_request.is_empty() {
                                         let items_total = to_request.len();
@@ -822,26 +885,28 @@ impl WebRtcClient {
                                     }
                                 }
                                 SyncMessage::FileRequest { id } => {
-                                    let db = Database::new(&config_path);
-                                    if let Ok((path, created, lat, lon, objects, faces)) = db.connection.query_row(
+                                    let row = db.read(|db| {
+                                        db.connection.query_row(
                                         "SELECT p.location, p.created, p.latitude, p.longitude,
                                          (SELECT json_group_array(json_object('class', class, 'probability', probability)) FROM object WHERE photo_id = p.id),
                                          (SELECT json_group_array(json_object('face_id', face_id, 'crop_path', crop_path, 'encoded', encoded, 'person_id', person_id)) FROM faces WHERE photo_id = p.id)
-                                         FROM photo p WHERE p.id = ?1",
+                                         FROM photo p WHERE p.id = ?1 AND p.trashed IS NULL",
                                         [&id],
                                         |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<f64>>(2)?, row.get::<_, Option<f64>>(3)?, row.get::<_, String>(4).unwrap_or("[]".to_string()), row.get::<_, String>(5).unwrap_or("[]".to_string()))),
-                                    ) {
+                                        ).map(|row| (row, db.album_sync_json(&id), db.photo_culling(&id)))
+                                    });
+                                    if let Ok(((path, created, lat, lon, objects, faces), albums, culling)) = row {
                                         let dc_send = Arc::clone(&dc);
                                         let self_task = self_inner.clone();
-                                        tokio::spawn(async move { let _ = self_task.send_file(dc_send, id, path, created, lat, lon, objects, faces).await; });
+                                        tokio::spawn(async move { let _ = self_task.send_file(dc_send, id, path, created, lat, lon, objects, faces, albums, culling).await; });
                                     }
                                 }
-                                SyncMessage::FileHeader { id, filename, size, created, latitude, longitude, objects, faces } => {
+                                SyncMessage::FileHeader { id, filename, size, created, latitude, longitude, objects, faces, albums, culling } => {
                                     let save_path = Path::new(&config_path).join("sync_temp").join(&filename);
                                     if let Some(parent) = save_path.parent() { let _ = tokio::fs::create_dir_all(parent).await; }
                                     if let Ok(file) = tokio::fs::File::create(&save_path).await {
                                         let mut incoming = incoming_files.lock().await;
-                                        incoming.insert(id.clone(), IncomingFile { id, filename, size, received: 0, created, latitude, longitude, objects, faces, file });
+                                        incoming.insert(id.clone(), IncomingFile { id, filename, size, received: 0, created, latitude, longitude, objects, faces, albums, culling, file });
                                     }
                                 }
                                 SyncMessage::FileChunk { id, data } => {
@@ -864,12 +929,9 @@ impl WebRtcClient {
                                         let _ = file_state.file.flush().await;
                                         drop(file_state.file);
                                         let temp_path = Path::new(&config_path).join("sync_temp").join(&file_state.filename);
-                                        let db = Database::new(&config_path);
-
                                         // Fetch sync_path directly from database state to be sure
-                                        let state = db.get_state();
+                                        let (state, dirs) = db.read(|db| (db.get_state(), db.list_directories()));
                                         let sync_path_str = state.get("sync_path");
-                                        let dirs = db.list_directories();
 
                                         let target_dir = if let Some(sp) = sync_path_str {
                                             PathBuf::from(sp).join("siegu")
@@ -895,16 +957,31 @@ impl WebRtcClient {
                                             let created_thumb = file_state.created.clone();
                                             let lat_thumb = file_state.latitude.unwrap_or(0.0);
                                             let lon_thumb = file_state.longitude.unwrap_or(0.0);
-                                            let config_path_thumb = config_path.clone();
+                                            let db_thumb = db.clone();
                                             let objects_thumb = file_state.objects.clone();
                                             let faces_thumb = file_state.faces.clone();
+                                            let albums_thumb = file_state.albums.clone();
+                                            let culling_thumb = file_state.culling.clone();
 
                                             tokio::task::spawn_blocking(move || {
                                                 let thumb = String::new();
-                                                let db = Database::new(&config_path_thumb);
+                                                // Hash what actually arrived rather than trusting the sender
+                                                let hash = crate::file::hash_file(Path::new(&path_thumb)).ok();
 
                                                 // Now import with thumbnail included - only now it becomes visible in library
-                                                db.import_photo(&id_thumb, &path_thumb, &created_thumb, Some(lat_thumb), Some(lon_thumb), &objects_thumb, &faces_thumb, &thumb);
+                                                let (id, path, created, encoded, stored_hash, culling) = (id_thumb.clone(), path_thumb.clone(), created_thumb.clone(), thumb.clone(), hash.clone(), culling_thumb.clone());
+                                                let imported = db_thumb.write(move |db| {
+                                                    db.import_photo(&id, &path, &created, Some(lat_thumb), Some(lon_thumb), &objects_thumb, &faces_thumb, &encoded);
+                                                    db.import_photo_albums(&id, &albums_thumb);
+                                                    db.import_photo_culling(&id, &culling);
+                                                    if let Some(hash) = &stored_hash {
+                                                        db.set_photo_hash(&id, hash);
+                                                    }
+                                                });
+                                                if let Err(e) = imported {
+                                                    error!("Failed to import received photo {path_thumb}: {e}");
+                                                    return;
+                                                }
 
                                                 let _ = app_thumb.emit("photo-received", crate::database::Photo {
                                                     id: id_thumb,
@@ -917,6 +994,13 @@ impl WebRtcClient {
                                                     longitude: lon_thumb,
                                                     favorite: false,
                                                     indexed: 2,
+                                                    hash,
+                                                    created_offset: None,
+                                                    rating: culling_thumb.rating,
+                                                    flag: culling_thumb.flag,
+                                                    label: culling_thumb.label,
+                                                    media: None,
+                                                    xmp: None,
                                                 });
                                             });
 
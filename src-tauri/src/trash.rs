@@ -0,0 +1,243 @@
+use crate::database::{Database, TrashEntry};
+use crate::journal;
+use crate::pool::DbPool;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+/// Days a trashed photo is kept before it is purged, unless `trash_retention_days` is set.
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
+
+pub fn trash_dir(config_path: &str) -> PathBuf {
+    Path::new(config_path).join("trash")
+}
+
+/// Moves a file, falling back to copy and delete when a rename is not possible,
+/// e.g. for photos on an external drive.
+pub(crate) fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Moves files in order. If one cannot be moved, the ones already moved go back.
+pub(crate) fn move_files(moves: &[(PathBuf, PathBuf)]) -> Result<(), String> {
+    for (i, (from, to)) in moves.iter().enumerate() {
+        if let Err(e) = move_file(from, to) {
+            move_back(&moves[..i]);
+            return Err(format!("Failed to move {}: {e}", from.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Moves files moved by `move_files` back to where they came from, last one first.
+pub(crate) fn move_back(moves: &[(PathBuf, PathBuf)]) {
+    for (from, to) in moves.iter().rev() {
+        if let Err(e) = move_file(to, from) {
+            error!("Failed to move {} back: {e}", to.display());
+        }
+    }
+}
+
+/// Moves the photos' files into the trash folder, then marks their rows as trashed as the
+/// operation `name`. The files are moved outside the writer, which a copy to another drive
+/// would hold up, and go back if the rows cannot be marked. Photos whose file is already
+/// gone are still marked, so they leave the library too.
+pub fn trash_photos(
+    config_path: &str,
+    db: &DbPool,
+    ids: &[String],
+    name: &'static str,
+    detail: String,
+) -> Result<(), String> {
+    let photos = db.read(|db| {
+        ids.iter()
+            .filter_map(|id| db.get_photo(id))
+            .collect::<Vec<_>>()
+    });
+    let mut moves = Vec::new();
+    let entries: Vec<TrashEntry> = photos
+        .into_iter()
+        .map(|photo| {
+            let source = PathBuf::from(&photo.location);
+            let trash_location = source.exists().then(|| {
+                let name = source
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let target = trash_dir(config_path).join(format!("{}_{name}", photo.id));
+                let trash_location = target.to_string_lossy().to_string();
+                moves.push((source, target));
+                trash_location
+            });
+            TrashEntry {
+                id: photo.id,
+                location: photo.location,
+                trash_location,
+            }
+        })
+        .collect();
+    move_files(&moves)?;
+    let marked = db
+        .write(move |db| journal::record(db, name, &detail, |db| db.mark_trashed(&entries)))
+        .and_then(|marked| marked);
+    if marked.is_err() {
+        move_back(&moves);
+    }
+    marked
+}
+
+/// Moves trashed photos back to where they came from and takes them out of the trash, the
+/// same way `trash_photos` put them there. Nothing is restored if the original location of
+/// one has been taken by another file in the meantime.
+pub fn restore_photos(db: &DbPool, ids: &[String], detail: String) -> Result<(), String> {
+    let entries = db.read(|db| {
+        ids.iter()
+            .filter_map(|id| db.get_trash_entry(id))
+            .collect::<Vec<_>>()
+    });
+    let mut moves = Vec::new();
+    for entry in &entries {
+        if let Some(trash_location) = &entry.trash_location {
+            let target = PathBuf::from(&entry.location);
+            if target.exists() {
+                return Err(format!(
+                    "Cannot restore {}: a file already exists there",
+                    entry.location
+                ));
+            }
+            moves.push((PathBuf::from(trash_location), target));
+        }
+    }
+    move_files(&moves)?;
+    let restored = db
+        .write(move |db| journal::record(db, "Restore", &detail, |db| db.restore_trashed(&entries)))
+        .and_then(|restored| restored);
+    if restored.is_err() {
+        move_back(&moves);
+    }
+    restored
+}
+
+/// Permanently deletes trashed photos, or only those trashed more than `older_than_days`
+/// ago. Emptying the whole trash also clears files no row points at anymore.
+/// Returns the number of photos removed.
+pub fn purge(config_path: &str, db: &Database, older_than_days: Option<u32>) -> usize {
+    let expired = db.get_trashed_before(older_than_days);
+    for (id, trash_location) in &expired {
+        if let Some(trash_location) = trash_location {
+            let _ = fs::remove_file(trash_location);
+        }
+        for face in db.get_faces_for_photo(id) {
+            let _ = fs::remove_file(&face.crop_path);
+        }
+        let _ = db.delete_photo(id);
+    }
+    if older_than_days.is_none() {
+        if let Ok(entries) = fs::read_dir(trash_dir(config_path)) {
+            for entry in entries.flatten() {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    expired.len()
+}
+
+/// Purges photos that have been in the trash longer than the configured retention period.
+pub fn purge_expired(config_path: &str, db: &Database) -> usize {
+    let days = db
+        .get_state()
+        .get("trash_retention_days")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    purge(config_path, db, Some(days))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Page, PhotoFilter};
+
+    #[test]
+    fn trash_restore_and_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config").to_string_lossy().to_string();
+        let photo_path = dir.path().join("a.jpg");
+        fs::write(&photo_path, b"jpeg").unwrap();
+        let location = photo_path.to_string_lossy().to_string();
+
+        let pool = DbPool::open(&config_path);
+        pool.write(move |db| {
+            db.import_photo("a", &location, "", None, None, "[]", "[]", "");
+            db.set_photo_hash("a", "hash-a");
+        })
+        .unwrap();
+        let ids = vec!["a".to_string()];
+        let listed = |pool: &DbPool| {
+            pool.read(|db| {
+                db.list_photos(
+                    &PhotoFilter::default(),
+                    &Page {
+                        limit: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .photos
+                .len()
+            })
+        };
+        let trash = |pool: &DbPool| pool.read(|db| db.list_trash().len());
+
+        trash_photos(&config_path, &pool, &ids, "Delete", String::new()).unwrap();
+        assert!(!photo_path.exists());
+        assert_eq!(listed(&pool), 0);
+        assert_eq!(trash(&pool), 1);
+
+        // A copy turning up in a scan neither relinks nor restores it
+        pool.read(|db| {
+            assert!(db.find_photo_by_hash("hash-a").is_none());
+            assert!(db.is_hash_trashed("hash-a"));
+        });
+        pool.write(|db| db.relink_photo("a", "/elsewhere/a.jpg"))
+            .unwrap();
+        assert_eq!(trash(&pool), 1);
+
+        // A file taking the photo's place keeps it in the trash, file included
+        fs::write(&photo_path, b"other").unwrap();
+        assert!(restore_photos(&pool, &ids, String::new()).is_err());
+        assert_eq!(trash(&pool), 1);
+        fs::remove_file(&photo_path).unwrap();
+
+        restore_photos(&pool, &ids, String::new()).unwrap();
+        assert!(photo_path.exists());
+        assert_eq!(listed(&pool), 1);
+
+        // A file that cannot be moved, here because it already was, sends the others back
+        let moved = vec!["a".to_string(), "a".to_string()];
+        assert!(trash_photos(&config_path, &pool, &moved, "Delete", String::new()).is_err());
+        assert!(photo_path.exists());
+        assert_eq!(trash(&pool), 0);
+
+        // Freshly trashed photos survive the retention purge but not emptying the trash
+        trash_photos(&config_path, &pool, &ids, "Delete", String::new()).unwrap();
+        let expired = pool.write({
+            let config_path = config_path.clone();
+            move |db| purge_expired(&config_path, db)
+        });
+        assert_eq!(expired, Ok(0));
+        let emptied = pool.write({
+            let config_path = config_path.clone();
+            move |db| purge(&config_path, db, None)
+        });
+        assert_eq!(emptied, Ok(1));
+        assert_eq!(trash(&pool), 0);
+        assert_eq!(fs::read_dir(trash_dir(&config_path)).unwrap().count(), 0);
+    }
+}
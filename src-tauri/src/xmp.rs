@@ -0,0 +1,749 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use regex::Regex;
+use tracing::warn;
+
+use crate::database::{ColorLabel, FaceArea, Flag};
+use crate::pool::DbPool;
+
+/// How far into a file to look for an embedded XMP packet. JPEG, PNG and HEIC keep it near
+/// the start, so reading the whole file is not worth it.
+const EMBEDDED_SEARCH_BYTES: u64 = 1024 * 1024;
+/// Top-level keyword the ML tags are written under, so they are not read back as keywords.
+const TAG_ROOT: &str = "Siegu";
+/// Photos whose sidecars are written per database round trip.
+const WRITE_BATCH: usize = 200;
+/// Properties Siegu owns in a sidecar it writes to. Everything else is left as it was.
+const MANAGED_ATTRIBUTES: &[&str] = &["xmp:Rating", "xmp:Label"];
+const MANAGED_ELEMENTS: &[&str] = &[
+    "xmp:Rating",
+    "xmp:Label",
+    "dc:subject",
+    "lr:hierarchicalSubject",
+    "digiKam:TagsList",
+    "mwg-rs:Regions",
+];
+/// Namespaces of the properties Siegu writes.
+const NAMESPACES: &[(&str, &str)] = &[
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("lr", "http://ns.adobe.com/lightroom/1.0/"),
+    ("digiKam", "http://www.digikam.org/ns/1.0/"),
+    (
+        "mwg-rs",
+        "http://www.metadataworkinggroup.com/schemas/regions/",
+    ),
+    ("stArea", "http://ns.adobe.com/xmp/sType/Area#"),
+    ("stDim", "http://ns.adobe.com/xap/1.0/sType/Dimensions#"),
+];
+const EMPTY_PACKET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="Siegu">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+/// Metadata other photo managers such as darktable, digiKam or Lightroom keep in XMP.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Xmp {
+    /// `xmp:Rating`: 0-5 stars, or -1 for a rejected photo.
+    pub rating: Option<i8>,
+    pub label: Option<ColorLabel>,
+    /// Hierarchical keywords are joined with `/`, e.g. `Places/Portugal/Lisbon`.
+    pub keywords: Vec<String>,
+    pub description: Option<String>,
+    /// Named face regions from the Metadata Working Group schema.
+    pub faces: Vec<Region>,
+    /// When the sidecar this was read from was last modified, in milliseconds since the
+    /// epoch. `None` for XMP embedded in the photo.
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub area: FaceArea,
+}
+
+/// What Siegu writes back to a photo's sidecar.
+#[derive(Debug, Clone, Default)]
+pub struct Sidecar {
+    pub id: String,
+    pub location: String,
+    pub rating: u8,
+    pub flag: Flag,
+    pub label: Option<ColorLabel>,
+    pub keywords: Vec<String>,
+    /// ML tags, written under the `Siegu` keyword.
+    pub tags: Vec<String>,
+    pub faces: Vec<Region>,
+    /// Pixel width and height the face regions apply to, when known.
+    pub dimensions: Option<(u32, u32)>,
+    /// When the sidecar was last read or written, to tell whether it changed since.
+    pub modified: Option<i64>,
+}
+
+impl Xmp {
+    /// Fills in what `self` does not set from `fallback`.
+    fn or(self, fallback: Xmp) -> Xmp {
+        Xmp {
+            rating: self.rating.or(fallback.rating),
+            label: self.label.or(fallback.label),
+            keywords: if self.keywords.is_empty() {
+                fallback.keywords
+            } else {
+                self.keywords
+            },
+            description: self.description.or(fallback.description),
+            faces: if self.faces.is_empty() {
+                fallback.faces
+            } else {
+                self.faces
+            },
+            modified: self.modified.or(fallback.modified),
+        }
+    }
+}
+
+/// Sidecars a photo may have: `photo.jpg.xmp` as written by darktable and digiKam, then
+/// `photo.xmp` as written by Lightroom.
+fn sidecar_paths(photo: &Path) -> [PathBuf; 2] {
+    let mut appended = photo.as_os_str().to_owned();
+    appended.push(".xmp");
+    [PathBuf::from(appended), photo.with_extension("xmp")]
+}
+
+/// The photo's existing sidecar, if it has one.
+pub fn sidecar_path(photo: &Path) -> Option<PathBuf> {
+    sidecar_paths(photo).into_iter().find(|path| path.is_file())
+}
+
+/// Modification time of a file in milliseconds since the epoch.
+pub fn modified(path: &Path) -> Option<i64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as i64)
+}
+
+/// XMP for a photo from its sidecar, with anything the sidecar leaves out taken from the
+/// packet embedded in the file. `None` when there is neither.
+pub fn read(photo: &Path) -> Option<Xmp> {
+    let embedded = read_embedded(photo);
+    match (read_sidecar(photo), embedded) {
+        (Some(sidecar), Some(embedded)) => Some(sidecar.or(embedded)),
+        (sidecar, embedded) => sidecar.or(embedded),
+    }
+}
+
+pub fn read_sidecar(photo: &Path) -> Option<Xmp> {
+    let path = sidecar_path(photo)?;
+    let xml = fs::read_to_string(&path).ok()?;
+    Some(Xmp {
+        modified: modified(&path),
+        ..parse(&xml)
+    })
+}
+
+fn read_embedded(photo: &Path) -> Option<Xmp> {
+    let mut bytes = Vec::new();
+    File::open(photo)
+        .ok()?
+        .take(EMBEDDED_SEARCH_BYTES)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    let text = String::from_utf8_lossy(&bytes);
+    let start = text.find("<x:xmpmeta")?;
+    let end = text[start..].find("</x:xmpmeta>")? + start;
+    Some(parse(&text[start..end]))
+}
+
+/// Reads the properties Siegu understands from an XMP packet. Each may be written as an
+/// attribute of `rdf:Description` or as an element, and unknown properties are ignored.
+pub fn parse(xml: &str) -> Xmp {
+    let rating = property(xml, "xmp:Rating")
+        .and_then(|rating| rating.parse::<f32>().ok())
+        .map(|rating| (rating.round() as i8).clamp(-1, 5));
+    // darktable numbers its labels from 0 for red in the same order
+    let label = property(xml, "xmp:Label")
+        .and_then(|label| ColorLabel::parse(&label.to_lowercase()))
+        .or_else(|| {
+            items(xml, "darktable:colorlabels")
+                .iter()
+                .filter_map(|index| index.parse::<usize>().ok())
+                .find_map(|index| ColorLabel::ALL.get(index).copied())
+        });
+    let description = items(xml, "dc:description")
+        .into_iter()
+        .next()
+        .or_else(|| property(xml, "dc:description"));
+    Xmp {
+        rating,
+        label,
+        keywords: keywords(xml),
+        description,
+        faces: regions(xml),
+        modified: None,
+    }
+}
+
+/// Keywords from the hierarchical lists of Lightroom and digiKam, plus flat `dc:subject`
+/// entries that are not already the leaf of a hierarchical one. ML tags Siegu wrote under
+/// `TAG_ROOT` are skipped.
+fn keywords(xml: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    let mut leaves = Vec::new();
+    for (name, separator) in [("lr:hierarchicalSubject", '|'), ("digiKam:TagsList", '/')] {
+        for item in items(xml, name) {
+            let path: Vec<&str> = item
+                .split(separator)
+                .map(str::trim)
+                .filter(|part| !part.is_empty())
+                .collect();
+            let Some(leaf) = path.last() else {
+                continue;
+            };
+            leaves.push(leaf.to_string());
+            let keyword = path.join("/");
+            if path[0] != TAG_ROOT && !keywords.contains(&keyword) {
+                keywords.push(keyword);
+            }
+        }
+    }
+    for subject in items(xml, "dc:subject") {
+        if !leaves.contains(&subject) && !keywords.contains(&subject) {
+            keywords.push(subject);
+        }
+    }
+    keywords
+}
+
+/// Named face regions from `mwg-rs:RegionList`. Areas are stored by their centre, and
+/// regions of other types or with another unit are skipped.
+fn regions(xml: &str) -> Vec<Region> {
+    let Some(list) = element(xml, "mwg-rs:RegionList") else {
+        return Vec::new();
+    };
+    list.split("<rdf:li")
+        .skip(1)
+        .filter_map(|region| {
+            let name = property(region, "mwg-rs:Name").filter(|name| !name.is_empty())?;
+            if property(region, "mwg-rs:Type").is_some_and(|kind| kind != "Face")
+                || property(region, "stArea:unit").is_some_and(|unit| unit != "normalized")
+            {
+                return None;
+            }
+            let value = |name| property(region, name)?.parse::<f32>().ok();
+            let (width, height) = (value("stArea:w")?, value("stArea:h")?);
+            Some(Region {
+                name,
+                area: FaceArea {
+                    x: value("stArea:x")? - width / 2.0,
+                    y: value("stArea:y")? - height / 2.0,
+                    width,
+                    height,
+                },
+            })
+        })
+        .collect()
+}
+
+/// The compiled regex for `pattern`, built once per pattern. Patterns are made from the
+/// fixed property names parsed and written, so only a few dozen are ever built.
+fn cached(pattern: String) -> Regex {
+    static CACHE: OnceLock<Mutex<HashMap<String, Regex>>> = OnceLock::new();
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+    cache
+        .entry(pattern)
+        .or_insert_with_key(|pattern| Regex::new(pattern).unwrap())
+        .clone()
+}
+
+/// Value of a simple property, written either as an attribute (`xmp:Rating="3"`) or as an
+/// element (`<xmp:Rating>3</xmp:Rating>`).
+fn property(xml: &str, name: &str) -> Option<String> {
+    let attribute = cached(format!(
+        r#"\s{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+        regex::escape(name)
+    ));
+    if let Some(captures) = attribute.captures(xml) {
+        let value = captures.get(1).or(captures.get(2))?;
+        return Some(unescape(value.as_str().trim()));
+    }
+    element(xml, name)
+        .filter(|content| !content.contains('<'))
+        .map(|content| unescape(content.trim()))
+}
+
+/// Content of the first `name` element.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let name = regex::escape(name);
+    let element = cached(format!(r"(?s)<{name}(?:\s[^>]*)?>(.*?)</{name}>"));
+    Some(element.captures(xml)?.get(1)?.as_str())
+}
+
+/// Entries of an `rdf:Bag`, `rdf:Seq` or `rdf:Alt` property.
+fn items(xml: &str, name: &str) -> Vec<String> {
+    let Some(content) = element(xml, name) else {
+        return Vec::new();
+    };
+    static ITEM: OnceLock<Regex> = OnceLock::new();
+    let item = ITEM.get_or_init(|| Regex::new(r"<rdf:li(?:\s[^>]*)?>([^<]*)</rdf:li>").unwrap());
+    item.captures_iter(content)
+        .map(|captures| unescape(captures[1].trim()))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    static ENTITY: OnceLock<Regex> = OnceLock::new();
+    let entity = ENTITY
+        .get_or_init(|| Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap());
+    entity
+        .replace_all(text, |captures: &regex::Captures| {
+            let name = &captures[1];
+            let code = if let Some(hex) = name.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok()
+            } else if let Some(decimal) = name.strip_prefix('#') {
+                decimal.parse().ok()
+            } else {
+                None
+            };
+            match (name, code.and_then(char::from_u32)) {
+                (_, Some(c)) => c.to_string(),
+                ("amp", _) => "&".to_string(),
+                ("lt", _) => "<".to_string(),
+                ("gt", _) => ">".to_string(),
+                ("quot", _) => "\"".to_string(),
+                ("apos", _) => "'".to_string(),
+                _ => captures[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Replaces the properties Siegu manages in an existing XMP packet, or a new one when
+/// `existing` is `None`, with its own `rdf:Description`. Properties of other tools, such
+/// as darktable's edit history, are kept as they were.
+pub fn update(existing: Option<&str>, sidecar: &Sidecar) -> Result<String, String> {
+    let mut xml = existing.unwrap_or(EMPTY_PACKET).to_string();
+    for name in MANAGED_ATTRIBUTES {
+        let attribute = cached(format!(
+            r#"\s+{}\s*=\s*(?:"[^"]*"|'[^']*')"#,
+            regex::escape(name)
+        ));
+        xml = attribute.replace_all(&xml, "").into_owned();
+    }
+    for name in MANAGED_ELEMENTS {
+        let name = regex::escape(name);
+        let element = cached(format!(r"(?s)\s*<{name}(?:\s[^>]*?)?(?:/>|>.*?</{name}>)"));
+        xml = element.replace_all(&xml, "").into_owned();
+    }
+    // Descriptions left without properties, like the one Siegu wrote last time
+    static EMPTY: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE_NAMES: OnceLock<Regex> = OnceLock::new();
+    let empty = EMPTY.get_or_init(|| {
+        Regex::new(r"(?s)\s*<rdf:Description\b([^>]*)>\s*</rdf:Description>").unwrap()
+    });
+    let attribute_names = ATTRIBUTE_NAMES
+        .get_or_init(|| Regex::new(r#"([^\s=]+)\s*=\s*(?:"[^"]*"|'[^']*')"#).unwrap());
+    xml = empty
+        .replace_all(&xml, |captures: &regex::Captures| {
+            if captures[1].ends_with('/') {
+                return captures[0].to_string();
+            }
+            let only_namespaces = attribute_names
+                .captures_iter(&captures[1])
+                .all(|attribute| attribute[1].starts_with("xmlns") || &attribute[1] == "rdf:about");
+            if only_namespaces {
+                String::new()
+            } else {
+                captures[0].to_string()
+            }
+        })
+        .into_owned();
+    let end = xml
+        .rfind("</rdf:RDF>")
+        .ok_or("Not an XMP packet: rdf:RDF is missing")?;
+    // Lined up with the rdf:RDF element it goes into
+    let indent = xml[..end]
+        .rfind('\n')
+        .map(|line| &xml[line + 1..end])
+        .filter(|indent| indent.trim().is_empty())
+        .unwrap_or("");
+    let description = description(sidecar);
+    xml.insert_str(end, &format!(" {description}\n{indent}"));
+    Ok(xml)
+}
+
+/// Siegu's `rdf:Description` for a sidecar.
+fn description(sidecar: &Sidecar) -> String {
+    let mut xml = String::from("<rdf:Description rdf:about=\"\"");
+    for (prefix, uri) in NAMESPACES {
+        xml += &format!("\n    xmlns:{prefix}=\"{uri}\"");
+    }
+    let rating = match sidecar.flag {
+        Flag::Rejected => -1,
+        _ => sidecar.rating as i8,
+    };
+    xml += &format!("\n    xmp:Rating=\"{rating}\"");
+    if let Some(label) = sidecar.label {
+        let name = label.as_str();
+        xml += &format!(
+            "\n    xmp:Label=\"{}{}\"",
+            name[..1].to_uppercase(),
+            &name[1..]
+        );
+    }
+    xml += ">";
+
+    let bag = |name: &str, items: &[String]| {
+        if items.is_empty() {
+            return String::new();
+        }
+        let items: String = items
+            .iter()
+            .map(|item| format!("\n     <rdf:li>{}</rdf:li>", escape(item)))
+            .collect();
+        format!("\n   <{name}>\n    <rdf:Bag>{items}\n    </rdf:Bag>\n   </{name}>")
+    };
+    let mut subjects: Vec<String> = Vec::new();
+    for leaf in sidecar
+        .keywords
+        .iter()
+        .filter_map(|keyword| keyword.rsplit('/').next())
+        .chain(sidecar.tags.iter().map(String::as_str))
+    {
+        if !subjects.iter().any(|subject| subject == leaf) {
+            subjects.push(leaf.to_string());
+        }
+    }
+    let hierarchical: Vec<String> = sidecar
+        .keywords
+        .iter()
+        .map(|keyword| keyword.replace('/', "|"))
+        .chain(sidecar.tags.iter().map(|tag| format!("{TAG_ROOT}|{tag}")))
+        .collect();
+    xml += &bag("dc:subject", &subjects);
+    xml += &bag("lr:hierarchicalSubject", &hierarchical);
+    xml += &bag("digiKam:TagsList", &sidecar.keywords);
+
+    if !sidecar.faces.is_empty() {
+        xml += "\n   <mwg-rs:Regions rdf:parseType=\"Resource\">";
+        if let Some((width, height)) = sidecar.dimensions {
+            xml += &format!("\n    <mwg-rs:AppliedToDimensions stDim:w=\"{width}\" stDim:h=\"{height}\" stDim:unit=\"pixel\"/>");
+        }
+        xml += "\n    <mwg-rs:RegionList>\n     <rdf:Bag>";
+        for face in &sidecar.faces {
+            let area = &face.area;
+            xml += &format!(
+                "\n      <rdf:li>\n       <rdf:Description mwg-rs:Name=\"{}\" mwg-rs:Type=\"Face\">\n        <mwg-rs:Area stArea:x=\"{:.5}\" stArea:y=\"{:.5}\" stArea:w=\"{:.5}\" stArea:h=\"{:.5}\" stArea:unit=\"normalized\"/>\n       </rdf:Description>\n      </rdf:li>",
+                escape(&face.name),
+                area.x + area.width / 2.0,
+                area.y + area.height / 2.0,
+                area.width,
+                area.height
+            );
+        }
+        xml += "\n     </rdf:Bag>\n    </mwg-rs:RegionList>\n   </mwg-rs:Regions>";
+    }
+    xml + "\n  </rdf:Description>"
+}
+
+/// Writes Siegu's metadata into the photo's sidecar, creating `photo.jpg.xmp` when it has
+/// none. A sidecar changed by another tool since Siegu last read it is left alone until the
+/// next scan has read it. Returns the sidecar's new modification time.
+fn write(sidecar: &Sidecar) -> Result<i64, String> {
+    let photo = Path::new(&sidecar.location);
+    if !photo.is_file() {
+        return Err("the photo is missing".to_string());
+    }
+    let (path, existing) = match sidecar_path(photo) {
+        Some(path) => {
+            if modified(&path) != sidecar.modified {
+                return Err("the sidecar changed since it was last read".to_string());
+            }
+            let existing = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            (path, Some(existing))
+        }
+        None => (sidecar_paths(photo)[0].clone(), None),
+    };
+    let xml = update(existing.as_deref(), sidecar)?;
+    fs::write(&path, xml).map_err(|e| e.to_string())?;
+    modified(&path).ok_or_else(|| "the sidecar is gone".to_string())
+}
+
+/// Writes the sidecars of every photo whose rating, label, keywords, tags or named faces
+/// changed since they were last written, when `xmp_write_back` is enabled. Returns the
+/// number of sidecars written.
+pub fn write_back(db: &DbPool) -> usize {
+    let enabled = db
+        .read(|db| db.get_state())
+        .get("xmp_write_back")
+        .is_some_and(|value| value == "true");
+    if !enabled {
+        return 0;
+    }
+    let mut written = 0;
+    let mut after = String::new();
+    loop {
+        let batch = match db.write(move |db| db.take_stale_sidecars(&after, WRITE_BATCH)) {
+            Ok(batch) => batch,
+            Err(e) => {
+                warn!("Not writing sidecars: {e}");
+                break;
+            }
+        };
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.id.clone();
+        for sidecar in batch {
+            let id = sidecar.id.clone();
+            match write(&sidecar) {
+                Ok(modified) => {
+                    written += 1;
+                    if let Err(e) = db.write(move |db| db.set_sidecar_modified(&id, modified)) {
+                        warn!(
+                            "Failed to save the sidecar time of {}: {e}",
+                            sidecar.location
+                        );
+                    }
+                }
+                Err(e) => {
+                    warn!("Not writing the sidecar of {}: {e}", sidecar.location);
+                    if let Err(e) = db.write(move |db| db.mark_sidecar_stale(&id)) {
+                        warn!(
+                            "Failed to keep the sidecar of {} pending: {e}",
+                            sidecar.location
+                        );
+                    }
+                }
+            }
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, Page, PhotoFilter};
+
+    const DARKTABLE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="XMP Core 4.4.0-Exiv2">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:darktable="http://darktable.sf.net/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmlns:mwg-rs="http://www.metadataworkinggroup.com/schemas/regions/"
+    xmlns:stArea="http://ns.adobe.com/xmp/sType/Area#"
+    xmp:Rating="4"
+    darktable:history_end="2">
+   <darktable:colorlabels>
+    <rdf:Seq>
+     <rdf:li>3</rdf:li>
+    </rdf:Seq>
+   </darktable:colorlabels>
+   <dc:description>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Tram 28 &amp; the cathedral</rdf:li>
+    </rdf:Alt>
+   </dc:description>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>Lisbon</rdf:li>
+     <rdf:li>tram</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+   <lr:hierarchicalSubject>
+    <rdf:Bag>
+     <rdf:li>Places|Portugal|Lisbon</rdf:li>
+    </rdf:Bag>
+   </lr:hierarchicalSubject>
+   <mwg-rs:Regions rdf:parseType="Resource">
+    <mwg-rs:RegionList>
+     <rdf:Bag>
+      <rdf:li>
+       <rdf:Description mwg-rs:Name="Alice" mwg-rs:Type="Face">
+        <mwg-rs:Area stArea:x="0.5" stArea:y="0.4" stArea:w="0.2" stArea:h="0.3" stArea:unit="normalized"/>
+       </rdf:Description>
+      </rdf:li>
+      <rdf:li>
+       <rdf:Description mwg-rs:Type="Face">
+        <mwg-rs:Area stArea:x="0.1" stArea:y="0.1" stArea:w="0.1" stArea:h="0.1" stArea:unit="normalized"/>
+       </rdf:Description>
+      </rdf:li>
+     </rdf:Bag>
+    </mwg-rs:RegionList>
+   </mwg-rs:Regions>
+   <darktable:history>
+    <rdf:Seq>
+     <rdf:li darktable:operation="exposure" darktable:enabled="1"/>
+    </rdf:Seq>
+   </darktable:history>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+    #[test]
+    fn reads_and_updates_sidecars() {
+        let xmp = parse(DARKTABLE);
+        assert_eq!(xmp.rating, Some(4));
+        assert_eq!(xmp.label, Some(ColorLabel::Blue));
+        assert_eq!(xmp.description.as_deref(), Some("Tram 28 & the cathedral"));
+        assert_eq!(xmp.keywords, vec!["Places/Portugal/Lisbon", "tram"]);
+        assert_eq!(xmp.faces.len(), 1);
+        assert_eq!(xmp.faces[0].name, "Alice");
+        assert!((xmp.faces[0].area.x - 0.4).abs() < 1e-6);
+        assert!((xmp.faces[0].area.y - 0.25).abs() < 1e-6);
+
+        let sidecar = Sidecar {
+            rating: 2,
+            flag: Flag::Rejected,
+            label: Some(ColorLabel::Red),
+            keywords: vec!["Places/Portugal/Lisbon".to_string()],
+            tags: vec!["a tram".to_string()],
+            faces: vec![Region {
+                name: "Bob & Alice".to_string(),
+                area: FaceArea {
+                    x: 0.1,
+                    y: 0.2,
+                    width: 0.3,
+                    height: 0.4,
+                },
+            }],
+            ..Default::default()
+        };
+        let updated = update(Some(DARKTABLE), &sidecar).unwrap();
+        // darktable's own data survives
+        assert!(updated.contains(r#"darktable:operation="exposure""#));
+        assert!(updated.contains("darktable:history_end=\"2\""));
+        assert!(updated.contains("Tram 28 &amp; the cathedral"));
+        let xmp = parse(&updated);
+        assert_eq!(xmp.rating, Some(-1));
+        assert_eq!(xmp.label, Some(ColorLabel::Red));
+        // The ML tag is written under Siegu and not read back as a keyword
+        assert_eq!(xmp.keywords, vec!["Places/Portugal/Lisbon"]);
+        assert_eq!(xmp.faces.len(), 1);
+        assert_eq!(xmp.faces[0].name, "Bob & Alice");
+        assert!((xmp.faces[0].area.height - 0.4).abs() < 1e-4);
+
+        // Writing again replaces Siegu's description instead of adding another
+        let again = update(Some(&updated), &sidecar).unwrap();
+        assert_eq!(again, updated);
+        assert_eq!(
+            parse(&update(None, &sidecar).unwrap()).keywords,
+            xmp.keywords
+        );
+    }
+
+    #[test]
+    fn applies_sidecars_to_the_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let photo = dir.path().join("tram.jpg");
+        fs::write(&photo, b"not really a jpeg").unwrap();
+        fs::write(dir.path().join("tram.jpg.xmp"), DARKTABLE).unwrap();
+        let location = photo.to_str().unwrap();
+
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        database.import_photo(
+            "a",
+            location,
+            "2023-07-01T10:00:00Z",
+            None,
+            None,
+            "[]",
+            "[]",
+            "",
+        );
+        let xmp = read(&photo).unwrap();
+        assert!(xmp.modified.is_some());
+        database.apply_xmp("a", &xmp).unwrap();
+
+        let search = |query: &str| -> Vec<String> {
+            let filter = PhotoFilter {
+                query: query.to_string(),
+                ..Default::default()
+            };
+            let page = Page {
+                limit: 10,
+                ..Default::default()
+            };
+            let photos = database.list_photos(&filter, &page).unwrap().photos;
+            photos.into_iter().map(|p| p.id).collect()
+        };
+        assert_eq!(search("portugal"), vec!["a"]);
+        assert_eq!(search("cathedral"), vec!["a"]);
+        assert_eq!(search("alice"), vec!["a"]);
+        let culling = database.photo_culling("a");
+        assert_eq!(culling.rating, 4);
+        assert_eq!(culling.label, Some(ColorLabel::Blue));
+
+        // Reading the sidecar again keeps a single face for Alice
+        database.apply_xmp("a", &xmp).unwrap();
+        assert_eq!(database.get_faces_for_photo("a").len(), 1);
+
+        // What was read is not written straight back
+        assert!(database.take_stale_sidecars("", 10).is_empty());
+
+        // A change made in Siegu is, along with everything read, leaving the sidecar
+        // unchanged since
+        database.set_rating(&["a".to_string()], 5).unwrap();
+        let stale = database.take_stale_sidecars("", 10);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].rating, 5);
+        assert_eq!(stale[0].keywords.len(), 2);
+        assert_eq!(stale[0].faces[0].name, "Alice");
+        assert_eq!(
+            write(&stale[0]).ok(),
+            modified(&dir.path().join("tram.jpg.xmp"))
+        );
+        assert!(database.take_stale_sidecars("", 10).is_empty());
+    }
+
+    #[test]
+    fn sidecar_changes_keep_manual_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new(dir.path().to_str().unwrap());
+        database.import_photo("a", "/a.jpg", "", None, None, "[]", "[]", "");
+        let sidecar = |modified, keywords: &[&str]| Xmp {
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            modified: Some(modified),
+            ..Default::default()
+        };
+        let tags = |database: &Database| -> Vec<String> {
+            let tags = database.list_tags().into_iter();
+            tags.filter(|t| t.photo_count > 0).map(|t| t.path).collect()
+        };
+        database
+            .apply_xmp("a", &sidecar(1, &["Trips/Lisbon", "Family"]))
+            .unwrap();
+        database
+            .assign_tags(&["a".to_string()], &["Favourites".to_string()])
+            .unwrap();
+        // Tagging by hand what the sidecar tagged keeps it too
+        database
+            .assign_tags(&["a".to_string()], &["Family".to_string()])
+            .unwrap();
+
+        // Another tool edits the sidecar and drops both its keywords
+        database
+            .apply_xmp("a", &sidecar(2, &["Trips/Porto"]))
+            .unwrap();
+        assert_eq!(tags(&database), vec!["Family", "Favourites", "Trips/Porto"]);
+    }
+}